@@ -1,24 +1,247 @@
-use crate::app::Message;
+use crate::app::{Message, estimate_tokens};
 use anyhow::{Result, anyhow};
 use futures_util::StreamExt;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use tokio::sync::mpsc::Sender;
 
+/// A fully resolved request target: a built-in provider or a standalone
+/// OpenAI-compatible endpoint.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Provider {
+        provider: String,
+        model: String,
+        api_key: String,
+    },
+    Custom {
+        endpoint: String,
+        model: String,
+        api_key: Option<String>,
+    },
+}
+
+/// Why a reply stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finish {
+    Complete,
+    /// Cut off by the token limit.
+    Length,
+}
+
+/// Tokens spent on a reply, including its regenerations and continuations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Counted locally, about four characters per token, because the
+    /// provider did not report usage.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub estimated: bool,
+    /// USD, from `[pricing]` at the time of the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<f64>,
+}
+
+impl Usage {
+    /// A local count for a request without reported usage.
+    fn estimate(messages: &[Message], output: &str) -> Self {
+        Self {
+            input_tokens: messages
+                .iter()
+                .map(|m| estimate_tokens(&m.wire_content()) as u64)
+                .sum(),
+            output_tokens: estimate_tokens(output) as u64,
+            estimated: true,
+            cost: None,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+
+    /// Adds a later request's usage, e.g. of a continuation.
+    pub fn add(&mut self, other: Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.estimated |= other.estimated;
+        self.cost = match (self.cost, other.cost) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0.0) + b.unwrap_or(0.0)),
+        };
+    }
+}
+
+/// How a streamed reply ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reply {
+    pub finish: Finish,
+    pub usage: Usage,
+}
+
+/// Sampling overrides sent with a request; unset fields use the provider's
+/// defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Reasoning effort: "low", "medium" or "high".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+}
+
+impl GenerationParams {
+    pub const KEYS: [&'static str; 3] = ["temperature", "max_tokens", "reasoning"];
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets `key` from its text form; an empty value clears it.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match key {
+            "temperature" => {
+                self.temperature = match value {
+                    "" => None,
+                    v => Some(
+                        v.parse::<f32>()
+                            .ok()
+                            .filter(|t| (0.0..=2.0).contains(t))
+                            .ok_or("temperature must be a number between 0 and 2")?,
+                    ),
+                }
+            }
+            "max_tokens" => {
+                self.max_tokens = match value {
+                    "" => None,
+                    v => Some(
+                        v.parse::<u32>()
+                            .ok()
+                            .filter(|n| *n > 0)
+                            .ok_or("max_tokens must be a positive integer")?,
+                    ),
+                }
+            }
+            "reasoning" => {
+                self.reasoning = match value {
+                    "" => None,
+                    "low" | "medium" | "high" => Some(value.to_string()),
+                    _ => return Err("reasoning must be low, medium or high".to_string()),
+                }
+            }
+            _ => return Err(format!("Unknown setting: {}", key)),
+        }
+        Ok(())
+    }
+
+    /// These params with the unset fields taken from `defaults`.
+    pub fn or(self, defaults: &GenerationParams) -> Self {
+        Self {
+            temperature: self.temperature.or(defaults.temperature),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            reasoning: self.reasoning.or_else(|| defaults.reasoning.clone()),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "temperature" => self.temperature.map(|t| t.to_string()),
+            "max_tokens" => self.max_tokens.map(|n| n.to_string()),
+            "reasoning" => self.reasoning.clone(),
+            _ => None,
+        }
+    }
+
+    fn apply_openai(&self, body: &mut Value) {
+        if let Some(t) = self.temperature {
+            body["temperature"] = json!(t);
+        }
+        if let Some(n) = self.max_tokens {
+            body["max_tokens"] = json!(n);
+        }
+        if let Some(effort) = &self.reasoning {
+            body["reasoning_effort"] = json!(effort);
+        }
+    }
+
+    fn apply_anthropic(&self, body: &mut Value) {
+        if let Some(n) = self.max_tokens {
+            body["max_tokens"] = json!(n);
+        }
+        let budget = match self.reasoning.as_deref() {
+            Some("low") => 1024,
+            Some("medium") => 4096,
+            Some("high") => 16384,
+            _ => 0,
+        };
+        if budget > 0 {
+            // Extended thinking needs room for the answer on top of the
+            // budget and does not accept a custom temperature.
+            let max_tokens = body["max_tokens"].as_u64().unwrap_or(4096);
+            body["max_tokens"] = json!(max_tokens.max(budget + 1024));
+            body["thinking"] = json!({ "type": "enabled", "budget_tokens": budget });
+        } else if let Some(t) = self.temperature {
+            body["temperature"] = json!(t.min(1.0));
+        }
+    }
+}
+
+impl Endpoint {
+    pub async fn stream(
+        &self,
+        messages: &[Message],
+        params: &GenerationParams,
+        tx: Sender<String>,
+    ) -> Result<Reply> {
+        match self {
+            Endpoint::Provider {
+                provider,
+                model,
+                api_key,
+            } => stream_message(api_key, provider, model, messages, params, tx).await,
+            Endpoint::Custom {
+                endpoint,
+                model,
+                api_key,
+            } => {
+                stream_openai_compatible(
+                    endpoint,
+                    api_key.as_deref(),
+                    false,
+                    model,
+                    messages,
+                    params,
+                    tx,
+                )
+                .await
+            }
+        }
+    }
+}
+
 pub async fn stream_message(
     api_key: &str,
     provider: &str,
     model: &str,
     messages: &[Message],
+    params: &GenerationParams,
     tx: Sender<String>,
-) -> Result<()> {
+) -> Result<Reply> {
     match provider {
-        "Anthropic" => stream_anthropic(api_key, model, messages, tx).await,
+        "Anthropic" => stream_anthropic(api_key, model, messages, params, tx).await,
         "OpenAI" => {
             stream_openai_compatible(
                 "https://api.openai.com/v1/chat/completions",
                 Some(api_key),
+                true,
                 model,
                 messages,
+                params,
                 tx,
             )
             .await
@@ -27,8 +250,10 @@ pub async fn stream_message(
             stream_openai_compatible(
                 "https://api.x.ai/v1/chat/completions",
                 Some(api_key),
+                true,
                 model,
                 messages,
+                params,
                 tx,
             )
             .await
@@ -37,8 +262,10 @@ pub async fn stream_message(
             stream_openai_compatible(
                 "https://openrouter.ai/api/v1/chat/completions",
                 Some(api_key),
+                true,
                 model,
                 messages,
+                params,
                 tx,
             )
             .await
@@ -47,34 +274,89 @@ pub async fn stream_message(
     }
 }
 
+/// Strips local-only metadata so providers only see `{role, content}`;
+/// attached files are inlined into the content.
+fn wire_messages(messages: &[Message]) -> Vec<serde_json::Value> {
+    messages
+        .iter()
+        .map(|m| json!({ "role": m.role, "content": m.wire_content() }))
+        .collect()
+}
+
+/// Turns an HTTP error response into an error carrying the provider's
+/// message, e.g. "401 Unauthorized: invalid x-api-key".
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|json| {
+            json.pointer("/error/message")
+                .and_then(|m| m.as_str())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| body.chars().take(200).collect());
+    Err(anyhow!("{}: {}", status, message.trim()))
+}
+
 pub async fn stream_openai_compatible(
     endpoint: &str,
     api_key: Option<&str>,
+    ask_usage: bool,
     model: &str,
     messages: &[Message],
+    params: &GenerationParams,
     tx: Sender<String>,
-) -> Result<()> {
+) -> Result<Reply> {
     let client = reqwest::Client::new();
-    let mut req = client.post(endpoint).json(&json!({
+    let mut body = json!({
         "model": model,
-        "messages": messages,
+        "messages": wire_messages(messages),
         "stream": true
-    }));
+    });
+    // Custom endpoints are not asked for usage, since some servers reject
+    // the option; their replies are counted locally.
+    if ask_usage {
+        body["stream_options"] = json!({ "include_usage": true });
+    }
+    params.apply_openai(&mut body);
+    let mut req = client.post(endpoint).json(&body);
     if let Some(key) = api_key {
         req = req.bearer_auth(key);
     }
-    let response = req.send().await?;
+    let response = check_status(req.send().await?).await?;
     let mut stream = response.bytes_stream();
+    let mut finish = Finish::Complete;
+    let mut output = String::new();
+    let mut usage = None;
 
-    while let Some(chunk) = stream.next().await {
+    'stream: while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         let chunk_str = std::str::from_utf8(&chunk)?;
         for line in chunk_str.lines() {
             if let Some(data) = line.strip_prefix("data: ") {
                 if data == "[DONE]" {
-                    return Ok(());
+                    break 'stream;
                 }
                 if let Ok(json) = serde_json::from_str::<serde_json::Value>(data) {
+                    if let Some(reported) = json.get("usage").filter(|u| u.is_object()) {
+                        let count = |key: &str| reported.get(key).and_then(Value::as_u64);
+                        usage = Some(Usage {
+                            input_tokens: count("prompt_tokens").unwrap_or(0),
+                            output_tokens: count("completion_tokens").unwrap_or(0),
+                            ..Usage::default()
+                        });
+                    }
+                    if json
+                        .pointer("/choices/0/finish_reason")
+                        .and_then(|r| r.as_str())
+                        == Some("length")
+                    {
+                        finish = Finish::Length;
+                    }
                     if let Some(delta) = json
                         .get("choices")
                         .and_then(|c| c.get(0))
@@ -82,42 +364,62 @@ pub async fn stream_openai_compatible(
                         .and_then(|d| d.get("content"))
                         .and_then(|c| c.as_str())
                     {
+                        output.push_str(delta);
+                        let _ = tx.send(delta.to_string()).await;
+                    } else if let Some(typ) = json.get("type").and_then(|t| t.as_str())
+                        && typ == "response.output_text.delta"
+                        && let Some(delta) = json.get("delta").and_then(|d| d.as_str())
+                    {
+                        output.push_str(delta);
                         let _ = tx.send(delta.to_string()).await;
-                    } else if let Some(typ) = json.get("type").and_then(|t| t.as_str()) {
-                        if typ == "response.output_text.delta" {
-                            if let Some(delta) = json.get("delta").and_then(|d| d.as_str()) {
-                                let _ = tx.send(delta.to_string()).await;
-                            }
-                        }
                     }
                 }
             }
         }
     }
-    Ok(())
+    Ok(Reply {
+        finish,
+        usage: usage.unwrap_or_else(|| Usage::estimate(messages, &output)),
+    })
 }
 
 pub async fn stream_anthropic(
     api_key: &str,
     model: &str,
     messages: &[Message],
+    params: &GenerationParams,
     tx: Sender<String>,
-) -> Result<()> {
+) -> Result<Reply> {
     let client = reqwest::Client::new();
-    let mut stream = client
+    // Anthropic takes the prompts in the top-level `system` field rather
+    // than as messages.
+    let (system, chat): (Vec<Message>, Vec<Message>) =
+        messages.iter().cloned().partition(|m| m.role == "system");
+    let mut body = json!({
+        "model": model,
+        "max_tokens": 4096,
+        "messages": wire_messages(&chat),
+        "stream": true
+    });
+    if !system.is_empty() {
+        let system: Vec<_> = system.iter().map(Message::wire_content).collect();
+        body["system"] = json!(system.join("\n\n"));
+    }
+    params.apply_anthropic(&mut body);
+    let response = client
         .post("https://api.anthropic.com/v1/messages")
         .bearer_auth(api_key)
         .header("x-api-key", api_key)
         .header("anthropic-version", "2023-06-01")
-        .json(&json!({
-            "model": model,
-            "max_tokens": 4096,
-            "messages": messages,
-            "stream": true
-        }))
+        .json(&body)
         .send()
-        .await?
-        .bytes_stream();
+        .await?;
+    let mut stream = check_status(response).await?.bytes_stream();
+    let mut finish = Finish::Complete;
+    let mut output = String::new();
+    // `message_start` reports the input, `message_delta` the output so far.
+    let mut input_tokens = None;
+    let mut output_tokens = None;
 
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
@@ -127,17 +429,40 @@ pub async fn stream_anthropic(
                 if data.is_empty() {
                     continue;
                 }
-                if let Ok(json) = serde_json::from_str::<serde_json::Value>(data) {
-                    if let Some(content) = json
-                        .get("delta")
-                        .and_then(|d| d.get("text"))
-                        .and_then(|t| t.as_str())
-                    {
-                        let _ = tx.send(content.to_string()).await;
-                    }
+                let Ok(json) = serde_json::from_str::<serde_json::Value>(data) else {
+                    continue;
+                };
+                if json.pointer("/delta/stop_reason").and_then(|r| r.as_str()) == Some("max_tokens")
+                {
+                    finish = Finish::Length;
+                }
+                if let Some(tokens) = json
+                    .pointer("/message/usage/input_tokens")
+                    .and_then(Value::as_u64)
+                {
+                    input_tokens = Some(tokens);
+                }
+                if let Some(tokens) = json.pointer("/usage/output_tokens").and_then(Value::as_u64) {
+                    output_tokens = Some(tokens);
+                }
+                if let Some(content) = json
+                    .get("delta")
+                    .and_then(|d| d.get("text"))
+                    .and_then(|t| t.as_str())
+                {
+                    output.push_str(content);
+                    let _ = tx.send(content.to_string()).await;
                 }
             }
         }
     }
-    Ok(())
+    let usage = match (input_tokens, output_tokens) {
+        (Some(input_tokens), Some(output_tokens)) => Usage {
+            input_tokens,
+            output_tokens,
+            ..Usage::default()
+        },
+        _ => Usage::estimate(messages, &output),
+    };
+    Ok(Reply { finish, usage })
 }
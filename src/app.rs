@@ -1,14 +1,30 @@
-use crate::config::{CustomModel, Prompt};
+use crate::api::{Endpoint, Finish, GenerationParams, Reply, Usage};
+use crate::config::{
+    self, AutoTitle, Chain, CustomModel, KeyBindings, LargeChats, LoadingAnimation, Prompt,
+    Retention, UiSettings,
+};
+use crate::palette;
+use crate::redact::Redactor;
+use crate::search::{self, Hit};
+use crate::secrets::{self, Vault};
+use crate::storage;
 use ratatui::text::Line;
 use regex_lite::Regex;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
-use std::collections::{HashMap, HashSet};
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio::sync::oneshot;
+use tokio::task::{self, AbortHandle};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Role {
+    System,
     User,
     Assistant,
 }
@@ -17,6 +33,7 @@ impl Role {
     #[inline(always)]
     pub fn as_str(&self) -> &'static str {
         match self {
+            Role::System => "system",
             Role::User => "user",
             Role::Assistant => "assistant",
         }
@@ -26,6 +43,7 @@ impl Role {
 impl From<&str> for Role {
     fn from(s: &str) -> Self {
         match s {
+            "system" => Role::System,
             "user" => Role::User,
             "assistant" => Role::Assistant,
             _ => Role::User,
@@ -33,14 +51,234 @@ impl From<&str> for Role {
     }
 }
 
+/// What a rendered transcript line represents, used when yanking selections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineKind {
+    Text,
+    CodeBorder,
+    /// A code-block source line, stored without the `│ ` gutter.
+    Code(String),
+    /// Continuation row of a code line too wide for the view.
+    CodeWrap,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CachedMessage<'a> {
+    pub lines: Vec<Line<'a>>,
+    pub kinds: Vec<LineKind>,
+    /// Wrapped lines left out by `truncate_lines`; 0 when shown whole.
+    pub hidden: usize,
+    /// Index of the message in the chat.
+    pub msg_idx: usize,
+    /// Hash of everything the lines were built from; `None` when they also
+    /// depend on later messages and must be rebuilt next time.
+    pub key: Option<u64>,
+    pub code_blocks: Vec<CodeBlock>,
+}
+
+impl<'a> CachedMessage<'a> {
+    #[inline]
+    pub fn push(&mut self, line: Line<'a>, kind: LineKind) {
+        self.lines.push(line);
+        self.kinds.push(kind);
+    }
+}
+
+/// A row of the flattened chat view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayLine {
+    /// Line `.1` of `line_cache[.0]`.
+    Cached(usize, usize),
+    /// The note under a collapsed message in `line_cache[.0]`.
+    Ellipsis(usize),
+    /// The gap after a message.
+    Blank,
+}
+
 #[derive(Debug, Clone)]
 pub struct CodeBlock {
+    /// Language tag of the opening fence, if any.
+    pub language: Option<String>,
     pub content: String,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// File extensions for common fence languages, as `(tags, extension)`.
+const LANGUAGE_EXTENSIONS: &[(&[&str], &str)] = &[
+    (&["rust", "rs"], "rs"),
+    (&["python", "py"], "py"),
+    (&["javascript", "js"], "js"),
+    (&["typescript", "ts"], "ts"),
+    (&["tsx"], "tsx"),
+    (&["jsx"], "jsx"),
+    (&["bash", "sh", "shell", "zsh"], "sh"),
+    (&["go", "golang"], "go"),
+    (&["c"], "c"),
+    (&["cpp", "c++", "cxx"], "cpp"),
+    (&["java"], "java"),
+    (&["kotlin", "kt"], "kt"),
+    (&["ruby", "rb"], "rb"),
+    (&["html"], "html"),
+    (&["css"], "css"),
+    (&["json"], "json"),
+    (&["yaml", "yml"], "yaml"),
+    (&["toml"], "toml"),
+    (&["sql"], "sql"),
+    (&["markdown", "md"], "md"),
+    (&["lua"], "lua"),
+    (&["diff", "patch"], "diff"),
+];
+
+/// The language in a fence's info string: its first word, without a
+/// `:path` suffix (`rust:src/main.rs`, `python title="app.py"`).
+pub fn fence_language(info: &str) -> &str {
+    let word = info.split_whitespace().next().unwrap_or("");
+    word.split(':').next().unwrap_or("")
+}
+
+impl CodeBlock {
+    /// The language tag, lowercase.
+    fn tag(&self) -> String {
+        fence_language(self.language.as_deref().unwrap_or("")).to_lowercase()
+    }
+
+    /// File extension matching the block's language, `txt` if unknown.
+    pub fn extension(&self) -> &str {
+        let language = self.tag();
+        LANGUAGE_EXTENSIONS
+            .iter()
+            .find(|(tags, _)| tags.contains(&language.as_str()))
+            .map_or("txt", |(_, ext)| ext)
+    }
+
+    /// The file the model said the block belongs in: from the fence
+    /// (```` ```rust:src/main.rs ````, ```` ```rust src/main.rs ````,
+    /// ```` ```rust title="main.rs" ````) or a comment on its first line
+    /// (`# filename: app.py`, `// file: src/lib.rs`). Paths leaving the
+    /// current directory are cut down to their file name.
+    pub fn filename(&self) -> Option<String> {
+        let info = self.language.as_deref().unwrap_or("").trim();
+        let fence_re =
+            Regex::new(r#"^\S*?:(\S+)|\b(?:title|file|filename|path)="?([^"\s]+)|^\S+\s+([\w./-]*[./][\w./-]*)$"#)
+                .unwrap();
+        let comment_re =
+            Regex::new(r"^\s*(?:#|//|--|;|/\*|<!--)\s*(?:file|filename|path)\s*:\s*(\S+)").unwrap();
+        let name = fence_re
+            .captures(info)
+            .and_then(|caps| caps.iter().skip(1).flatten().next())
+            .or_else(|| {
+                let first = self.content.lines().next()?;
+                comment_re.captures(first)?.get(1)
+            })?
+            .as_str()
+            .trim_end_matches("-->")
+            .trim_end_matches("*/");
+        let path = std::path::Path::new(name);
+        let inside = path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if inside {
+            Some(name.to_string())
+        } else {
+            Some(path.file_name()?.to_string_lossy().into_owned())
+        }
+    }
+
+    /// Default file name offered when saving the block.
+    pub fn suggested_filename(&self) -> String {
+        self.filename()
+            .unwrap_or_else(|| format!("snippet.{}", self.extension()))
+    }
+
+    /// The command that runs this block, or `None` for languages without a
+    /// known interpreter.
+    pub fn run_command(&self) -> Option<Vec<String>> {
+        let language = self.tag();
+        let (_, program, flag) = INTERPRETERS
+            .iter()
+            .find(|(tags, _, _)| tags.contains(&language.as_str()))?;
+        Some(vec![
+            program.to_string(),
+            flag.to_string(),
+            self.content.clone(),
+        ])
+    }
+}
+
+/// Interpreters for runnable code blocks, as `(tags, program, flag)`; the
+/// script is passed as the argument after the flag.
+const INTERPRETERS: &[(&[&str], &str, &str)] = &[
+    (&["bash", "sh", "shell"], "bash", "-c"),
+    (&["zsh"], "zsh", "-c"),
+    (&["python", "py"], "python3", "-c"),
+    (&["javascript", "js", "node"], "node", "-e"),
+    (&["ruby", "rb"], "ruby", "-e"),
+    (&["perl"], "perl", "-e"),
+    (&["lua"], "lua", "-e"),
+];
+
+/// Longest output of a code block run kept in the chat.
+const MAX_RUN_OUTPUT_BYTES: usize = 16 * 1024;
+/// Code block runs are killed after this long.
+const RUN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// A code block running in a subshell. Its output is added to the chat it
+/// was started from.
+pub struct BlockRun {
+    pub chat_id: String,
+    outcome: oneshot::Receiver<String>,
+}
+
+/// Runs `argv` and describes the result as a chat message.
+async fn run_block_command(argv: Vec<String>) -> String {
+    let program = argv[0].clone();
+    let mut command = tokio::process::Command::new(&program);
+    command
+        .args(&argv[1..])
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+    let output = match tokio::time::timeout(RUN_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return format!("Could not run `{}`: {}", program, e),
+        Err(_) => {
+            return format!(
+                "`{}` was stopped after {} seconds",
+                program,
+                RUN_TIMEOUT.as_secs()
+            );
+        }
+    };
+    let status = match output.status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "killed by a signal".to_string(),
+    };
+    let mut text = format!("Ran the code block with `{}` ({}).", program, status);
+    for (label, bytes) in [("stdout", &output.stdout), ("stderr", &output.stderr)] {
+        if bytes.is_empty() {
+            continue;
+        }
+        let mut captured = String::from_utf8_lossy(bytes).into_owned();
+        if captured.len() > MAX_RUN_OUTPUT_BYTES {
+            let mut end = MAX_RUN_OUTPUT_BYTES;
+            while !captured.is_char_boundary(end) {
+                end -= 1;
+            }
+            captured.truncate(end);
+            captured.push_str("\n[output truncated]");
+        }
+        text.push_str(&format!(
+            "\n\n{}:\n```text\n{}\n```",
+            label,
+            captured.trim_end()
+        ));
+    }
+    text
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Focus {
     Sidebar,
+    #[default]
     Chat,
 }
 
@@ -67,13 +305,274 @@ pub enum Mode {
     RenameChat,
     CustomModelInput,
     PromptInput,
+    PromptRename,
+    PromptPreview,
     Visual,
+    Compare,
+    KeyCapture,
+    Confirm,
+    Bookmarks,
+    Notes,
+    ChatPicker,
+    Search,
+    Restore,
+    Trash,
+    Prune,
+    Stats,
+    LargeChat,
+}
+
+impl Mode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Command => "COMMAND",
+            Mode::Settings => "SETTINGS",
+            Mode::ModelSelect => "MODEL",
+            Mode::ApiKeyInput => "API KEY",
+            Mode::RenameChat => "RENAME",
+            Mode::CustomModelInput => "CUSTOM MODEL",
+            Mode::PromptInput => "PROMPT",
+            Mode::PromptRename => "RENAME",
+            Mode::PromptPreview => "PREVIEW",
+            Mode::Visual => "VISUAL",
+            Mode::Compare => "COMPARE",
+            Mode::KeyCapture => "BIND KEY",
+            Mode::Confirm => "CONFIRM",
+            Mode::Bookmarks => "BOOKMARKS",
+            Mode::Notes => "NOTES",
+            Mode::ChatPicker => "CHATS",
+            Mode::Search => "SEARCH",
+            Mode::Restore => "RESTORE",
+            Mode::Trash => "TRASH",
+            Mode::Prune => "PRUNE",
+            Mode::LargeChat => "LARGE CHAT",
+            Mode::Stats => "STATS",
+        }
+    }
+}
+
+/// Rough token estimate (~4 characters per token) used for local counters.
+#[inline]
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+pub fn estimate_chat_tokens(chat: &Chat) -> usize {
+    chat.messages
+        .iter()
+        .map(|m| {
+            estimate_tokens(&m.content)
+                + m.attachments
+                    .iter()
+                    .map(|a| estimate_tokens(&a.content))
+                    .sum::<usize>()
+        })
+        .sum()
+}
+
+/// The fenced code blocks of a message, in order. An unclosed block (still
+/// streaming) runs to the end of the text.
+fn parse_code_blocks(content: &str) -> Vec<CodeBlock> {
+    let opening_re = Regex::new(r"^```(\w+)?\s*$").unwrap();
+    let closing_re = Regex::new(r"^```\s*$").unwrap();
+
+    let mut blocks = Vec::new();
+    let mut idx = 0;
+    let lines: Vec<&str> = content.lines().collect();
+    while idx < lines.len() {
+        if let Some(caps) = opening_re.captures(lines[idx]) {
+            let language = caps.get(1).map(|m| m.as_str().to_string());
+            idx += 1;
+            let mut code_lines = Vec::new();
+            while idx < lines.len() && !closing_re.is_match(lines[idx]) {
+                code_lines.push(lines[idx]);
+                idx += 1;
+            }
+            blocks.push(CodeBlock {
+                language,
+                content: code_lines.join("\n"),
+            });
+        }
+        idx += 1;
+    }
+    blocks
+}
+
+/// Drops the oldest messages until the estimate fits in `budget` tokens.
+/// System prompts, pinned messages and the final message are always kept.
+fn trim_to_fit(messages: &mut Vec<Message>, budget: usize) {
+    let size = |m: &Message| estimate_tokens(&m.wire_content());
+    let mut total: usize = messages.iter().map(size).sum();
+    let mut idx = 0;
+    while total > budget && idx + 1 < messages.len() {
+        let m = &messages[idx];
+        if m.role == "system" || m.pinned {
+            idx += 1;
+            continue;
+        }
+        total -= size(m);
+        messages.remove(idx);
+    }
+}
+
+/// Sent (but not shown) to get the rest of a reply cut off by the token
+/// limit.
+const CONTINUE_PROMPT: &str = "Your last reply was cut off. Continue exactly where it \
+     stopped, without repeating anything or adding a preamble.";
+
+/// Largest text file `:attach` accepts.
+pub const MAX_ATTACHMENT_BYTES: usize = 100 * 1024;
+/// Largest other file `:attach` accepts; these go to the attachment store.
+pub const MAX_BLOB_BYTES: usize = 20 * 1024 * 1024;
+/// Budget for all files attached to one message.
+pub const MAX_ATTACHMENTS_TOTAL_BYTES: usize = 256 * 1024;
+
+/// A file sent along with a user message. Text files are kept inline; other
+/// files live in the attachment store and `content` is empty.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<Blob>,
+}
+
+/// A file in the attachment store, named by the SHA-256 of its contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Blob {
+    pub hash: String,
+    pub mime: String,
+    pub bytes: u64,
+}
+
+pub fn size_label(bytes: u64) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+impl Attachment {
+    /// Reads `path`: UTF-8 text is kept inline, anything else is added to
+    /// the attachment store. Oversized files are rejected.
+    pub fn read(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let text = std::str::from_utf8(&bytes).is_ok_and(|text| !text.contains('\0'));
+        let limit = if text {
+            MAX_ATTACHMENT_BYTES
+        } else {
+            MAX_BLOB_BYTES
+        };
+        if bytes.len() > limit {
+            return Err(format!(
+                "{} is {}; {} attachments are limited to {}",
+                path,
+                size_label(bytes.len() as u64),
+                if text { "text" } else { "other" },
+                size_label(limit as u64)
+            ));
+        }
+        if text {
+            return Ok(Self {
+                name: path.to_string(),
+                content: String::from_utf8(bytes).unwrap_or_default(),
+                blob: None,
+            });
+        }
+        let hash =
+            crate::attachments::put(&bytes).map_err(|e| format!("Cannot store {}: {}", path, e))?;
+        Ok(Self {
+            name: path.to_string(),
+            content: String::new(),
+            blob: Some(Blob {
+                hash,
+                mime: crate::attachments::mime_type(path).to_string(),
+                bytes: bytes.len() as u64,
+            }),
+        })
+    }
+
+    /// Short description shown in place of the file contents.
+    pub fn summary(&self) -> String {
+        if let Some(blob) = &self.blob {
+            return format!("{} ({}, {})", self.name, blob.mime, size_label(blob.bytes));
+        }
+        let lines = self.content.lines().count();
+        let size = size_label(self.content.len() as u64);
+        format!(
+            "{} ({} line{}, {})",
+            self.name,
+            lines,
+            if lines == 1 { "" } else { "s" },
+            size
+        )
+    }
+
+    /// The file as a fenced block headed by its name, as sent to the model.
+    /// Only text is sent; a stored file is mentioned by name.
+    fn fenced(&self) -> String {
+        if self.blob.is_some() {
+            return format!("File: {} (contents not included)", self.summary());
+        }
+        let longest_run = self
+            .content
+            .split(|c| c != '`')
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let fence = "`".repeat(longest_run.max(2) + 1);
+        let language = std::path::Path::new(&self.name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("");
+        format!(
+            "File: {}\n{}{}\n{}\n{}",
+            self.name,
+            fence,
+            language,
+            self.content.trim_end_matches('\n'),
+            fence
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     pub content: String,
+    /// Unix timestamp (seconds) of when the message was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// Earlier answers this message replaced (regenerated or promoted from
+    /// a comparison), oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bookmarked: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Why sending this user message failed; cleared by a retry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed: Option<String>,
+    /// This reply stopped at the token limit and can be continued.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cut_off: bool,
+    /// Kept in requests when older messages are trimmed to fit the model's
+    /// context window.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+    /// The model that wrote this reply, as `provider:model`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Tokens and cost of the requests that wrote this reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 impl Message {
@@ -82,7 +581,54 @@ impl Message {
         Self {
             role: role.as_str().to_string(),
             content: content.into(),
+            timestamp: Some(chrono::Utc::now().timestamp()),
+            alternatives: Vec::new(),
+            bookmarked: false,
+            attachments: Vec::new(),
+            failed: None,
+            cut_off: false,
+            pinned: false,
+            model: None,
+            usage: None,
+        }
+    }
+
+    /// An assistant message written by `model`.
+    pub fn reply(model: &str, content: impl Into<String>) -> Self {
+        Self {
+            model: Some(model.to_string()),
+            ..Self::new(Role::Assistant, content)
+        }
+    }
+
+    /// Content as sent to the model, with attached files appended.
+    pub fn wire_content(&self) -> Cow<'_, str> {
+        if self.attachments.is_empty() {
+            return Cow::Borrowed(&self.content);
+        }
+        let mut text = self.content.clone();
+        for attachment in &self.attachments {
+            text.push_str("\n\n");
+            text.push_str(&attachment.fenced());
+        }
+        Cow::Owned(text)
+    }
+
+    /// Swaps the content with the next (`forward`) or previous alternative.
+    pub fn cycle_alternative(&mut self, forward: bool) -> bool {
+        if self.alternatives.is_empty() {
+            return false;
+        }
+        if forward {
+            let next = self.alternatives.remove(0);
+            self.alternatives
+                .push(std::mem::replace(&mut self.content, next));
+        } else {
+            let prev = self.alternatives.pop().unwrap_or_default();
+            self.alternatives
+                .insert(0, std::mem::replace(&mut self.content, prev));
         }
+        true
     }
 }
 
@@ -93,13 +639,288 @@ pub struct Chat {
     pub messages: Vec<Message>,
     pub model: String,
     pub streaming: bool,
+    /// Set on chats created with "fork here".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forked_from: Option<ForkOrigin>,
+    /// Names of library prompts attached to this chat with `:prompt use`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prompts: Vec<String>,
+    /// Send only the attached prompts, dropping the system prompts the chat
+    /// was created with.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prompts_override: bool,
+    /// Sampling overrides set with `:set`.
+    #[serde(default, skip_serializing_if = "GenerationParams::is_empty")]
+    pub params: GenerationParams,
+    /// `M{a-z}` marks as (message index, line within the message).
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub marks: HashMap<char, (usize, usize)>,
+    /// Unsent input, restored when Insert mode is entered again.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub draft: String,
+    /// Messages written while a reply was streaming, sent in order once it
+    /// finishes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub queued: Vec<Message>,
+    /// Steps of a running `:chain` still to be sent.
+    #[serde(skip)]
+    pub chain: Option<ChainRun>,
+    /// Automatic continuations of the current reply so far.
+    #[serde(skip)]
+    pub auto_continued: u32,
+    /// Free-form notes kept with the chat, one entry per line.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+    /// Archived chats refuse new messages and edits.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+    /// Set while only the chat's index entry is loaded; the rest is read
+    /// when the chat is opened.
+    #[serde(skip)]
+    pub stub: Option<ChatStub>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What the history index knows about a chat whose messages are not loaded.
+#[derive(Debug, Clone, Default)]
+pub struct ChatStub {
+    pub updated_at: Option<i64>,
+    pub messages: usize,
+    /// Has bookmarked or pinned messages.
+    pub protected: bool,
+    /// Size of the chat's file.
+    pub bytes: u64,
+    /// Loading the chat failed; it stays a stub and refuses edits.
+    pub unreadable: bool,
+}
+
+impl Chat {
+    pub fn new(id: String, title: String, model: String, messages: Vec<Message>) -> Self {
+        Self {
+            id,
+            title,
+            messages,
+            model,
+            streaming: false,
+            forked_from: None,
+            prompts: Vec::new(),
+            prompts_override: false,
+            params: GenerationParams::default(),
+            marks: HashMap::new(),
+            draft: String::new(),
+            queued: Vec::new(),
+            chain: None,
+            auto_continued: 0,
+            notes: String::new(),
+            read_only: false,
+            stub: None,
+        }
+    }
+
+    /// Time of the latest message, if any message has one.
+    pub fn last_activity(&self) -> Option<i64> {
+        match &self.stub {
+            Some(stub) => stub.updated_at,
+            None => self.messages.iter().filter_map(|m| m.timestamp).max(),
+        }
+    }
+
+    pub fn message_count(&self) -> usize {
+        self.stub
+            .as_ref()
+            .map_or(self.messages.len(), |s| s.messages)
+    }
+
+    /// Has bookmarked or pinned messages.
+    pub fn has_kept_messages(&self) -> bool {
+        match &self.stub {
+            Some(stub) => stub.protected,
+            None => self.messages.iter().any(|m| m.bookmarked || m.pinned),
+        }
+    }
+
+    /// Size of the chat as saved.
+    pub fn size(&self) -> u64 {
+        match &self.stub {
+            Some(stub) => stub.bytes,
+            None => serde_json::to_vec(self).map_or(0, |v| v.len() as u64),
+        }
+    }
+
+    /// Chats retention and `:prune` never remove.
+    pub fn protected(&self) -> bool {
+        self.read_only || self.has_kept_messages()
+    }
+
+    /// Folds in another copy of this chat, as left by a sync tool when two
+    /// machines changed it. Messages are matched by role, time and text;
+    /// those only one copy has are kept, in time order, so neither side's
+    /// messages are lost.
+    pub fn merge(&mut self, theirs: Chat) {
+        let same = |a: &Message, b: &Message| {
+            a.role == b.role && a.timestamp == b.timestamp && a.content == b.content
+        };
+        let combine = |ours: &mut Message, theirs: Message| {
+            ours.bookmarked |= theirs.bookmarked;
+            ours.pinned |= theirs.pinned;
+            for alternative in theirs.alternatives {
+                if !ours.alternatives.contains(&alternative) {
+                    ours.alternatives.push(alternative);
+                }
+            }
+        };
+        let mut ours = std::mem::take(&mut self.messages);
+        let mut only_theirs = Vec::new();
+        for message in theirs.messages {
+            match ours.iter_mut().find(|m| same(m, &message)) {
+                Some(m) => combine(m, message),
+                None => only_theirs.push(message),
+            }
+        }
+        // Both lists are in time order; interleave them.
+        let mut only_theirs = only_theirs.into_iter().peekable();
+        for message in ours {
+            while let Some(next) = only_theirs.peek() {
+                match (next.timestamp, message.timestamp) {
+                    (Some(t), Some(o)) if t < o => self.messages.extend(only_theirs.next()),
+                    _ => break,
+                }
+            }
+            self.messages.push(message);
+        }
+        self.messages.extend(only_theirs);
+        self.read_only |= theirs.read_only;
+        for line in theirs.notes.lines() {
+            if !self.notes.lines().any(|l| l == line) {
+                if !self.notes.is_empty() {
+                    self.notes.push('\n');
+                }
+                self.notes.push_str(line);
+            }
+        }
+    }
+
+    /// Errors with a hint when the chat is read-only.
+    pub fn writable(&self) -> Result<(), String> {
+        if self.stub.is_some() {
+            return Err("This chat could not be loaded".to_string());
+        }
+        if self.read_only {
+            return Err(
+                "This chat is read-only; :readonly to unlock it or F to fork it".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Progress of a `:chain` in a chat.
+#[derive(Debug, Clone)]
+pub struct ChainRun {
+    pub name: String,
+    pub steps: Vec<String>,
+    pub input: String,
+    /// Index of the next step to send.
+    pub next: usize,
+}
+
+impl ChainRun {
+    /// The next step's prompt with `{{prev}}` replaced by `prev`.
+    fn next_prompt(&mut self, prev: &str) -> Option<String> {
+        let step = self.steps.get(self.next)?;
+        self.next += 1;
+        Some(
+            step.replace("{{input}}", &self.input)
+                .replace("{{prev}}", prev),
+        )
+    }
+}
+
+/// Reading position and collapsed messages of a chat that is not shown,
+/// restored when it is switched back to.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatView {
+    /// Cursor as (message index, line within the message).
+    anchor: Option<(usize, usize)>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub truncated_messages: HashSet<usize>,
+}
+
+/// The view saved at exit and brought back at the next start
+/// (`[startup] restore`). Chats are referred to by id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub current_chat: Option<String>,
+    pub selected_chat: Option<String>,
+    pub focus: Focus,
+    pub sidebar_visible: bool,
+    pub settings_tab: SettingsTab,
+    pub settings_open: bool,
+    /// Scroll offset of the shown chat; `None` when it was at the bottom.
+    pub scroll: Option<usize>,
+    /// Directory `:wblock` last saved to.
+    pub save_dir: Option<PathBuf>,
+    /// Reading position and collapsed messages of each chat viewed.
+    pub views: HashMap<String, ChatView>,
+}
+
+/// Where a forked chat branched off: the original chat and the index of the
+/// last message copied from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForkOrigin {
+    pub chat_id: String,
+    pub message_idx: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SettingsTab {
+    #[default]
     Providers,
     Shortcuts,
     Prompts,
+    General,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneralSetting {
+    Theme,
+    SidebarWidth,
+    SidebarPosition,
+    Timestamps,
+    Colors,
+    TruncateLines,
+    Scrolloff,
+    DefaultModel,
+    AutosaveSecs,
+}
+
+impl GeneralSetting {
+    pub const ALL: [GeneralSetting; 9] = [
+        GeneralSetting::Theme,
+        GeneralSetting::SidebarWidth,
+        GeneralSetting::SidebarPosition,
+        GeneralSetting::Timestamps,
+        GeneralSetting::Colors,
+        GeneralSetting::TruncateLines,
+        GeneralSetting::Scrolloff,
+        GeneralSetting::DefaultModel,
+        GeneralSetting::AutosaveSecs,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            GeneralSetting::Theme => "Code theme",
+            GeneralSetting::SidebarWidth => "Sidebar width",
+            GeneralSetting::SidebarPosition => "Sidebar position",
+            GeneralSetting::Timestamps => "Timestamps",
+            GeneralSetting::Colors => "Colors",
+            GeneralSetting::TruncateLines => "Truncate after (lines)",
+            GeneralSetting::Scrolloff => "Scrolloff (lines)",
+            GeneralSetting::DefaultModel => "Default model",
+            GeneralSetting::AutosaveSecs => "Autosave every (s)",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -108,11 +929,131 @@ pub struct Provider {
     pub api_key: String,
     pub models: Vec<String>,
     pub enabled_models: Vec<String>,
+    pub params: GenerationParams,
     pub expanded: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamTarget {
+    /// Chunks are appended to the chat's last assistant message, written
+    /// by `model`.
+    Chat { model: String },
+    /// Chunks go to a candidate column of the comparison with this id.
+    Candidate { comparison_id: String, idx: usize },
+    /// Chunks are collected into a title for the chat with this id.
+    Title { chat_id: String, text: String },
+}
+
 pub struct StreamTask {
     pub rx: Receiver<String>,
+    pub target: StreamTarget,
+    handle: AbortHandle,
+    /// Receives how the request ended (or its error) before `rx` closes.
+    outcome: oneshot::Receiver<Result<Reply, String>>,
+}
+
+impl Drop for StreamTask {
+    /// Dropping a task (stop, discarded comparison, deleted chat) also stops
+    /// the request behind it.
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// True for a chat that still has its default "Chat N" name and has just
+/// received its first reply.
+fn needs_title(chat: &Chat) -> bool {
+    let default_name = chat
+        .title
+        .strip_prefix("Chat ")
+        .is_some_and(|n| n.parse::<usize>().is_ok());
+    let replies = chat
+        .messages
+        .iter()
+        .filter(|m| m.role == "assistant")
+        .count();
+    default_name && replies == 1
+}
+
+fn clean_title(raw: &str) -> String {
+    let line = raw.trim().lines().next().unwrap_or("");
+    let line = line.trim_matches(|c: char| c == '"' || c == '\'' || c == '.' || c.is_whitespace());
+    line.chars().take(40).collect()
+}
+
+/// A regeneration that produced no text falls back to the answer it was
+/// meant to replace.
+fn restore_empty_regeneration(chat: &mut Chat) {
+    if let Some(last) = chat.messages.last_mut()
+        && last.role == "assistant"
+        && last.content.is_empty()
+        && let Some(previous) = last.alternatives.pop()
+    {
+        last.content = previous;
+    }
+}
+
+/// Streams `endpoint`'s answer to `messages` into a new channel.
+fn spawn_stream(
+    endpoint: Endpoint,
+    messages: Vec<Message>,
+    params: GenerationParams,
+) -> (
+    Receiver<String>,
+    AbortHandle,
+    oneshot::Receiver<Result<Reply, String>>,
+) {
+    let (tx, rx) = mpsc::channel(100);
+    let (outcome_tx, outcome_rx) = oneshot::channel();
+    let handle = task::spawn(async move {
+        // Holding a sender until the outcome is reported keeps `rx` open, so
+        // the outcome is always there once the stream reads as closed.
+        let keep_open = tx.clone();
+        let outcome = endpoint
+            .stream(&messages, &params, tx)
+            .await
+            .map_err(|e| format!("{:#}", e));
+        let _ = outcome_tx.send(outcome);
+        drop(keep_open);
+    });
+    (rx, handle.abort_handle(), outcome_rx)
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub model: String,
+    pub content: String,
+    pub done: bool,
+}
+
+/// An action waiting for a y/n answer in [`Mode::Confirm`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingAction {
+    /// Delete this message of the current chat.
+    DeleteMessage(usize),
+    /// Send the current chat's draft although it seems to contain secrets.
+    SendDraft,
+    /// Run a code block with this command line.
+    RunBlock(Vec<String>),
+    /// Apply a diff that passed a dry run, stripping `strip` leading path
+    /// components.
+    ApplyPatch { diff: String, strip: u8 },
+    /// Remove this chat from the trash for good.
+    PurgeTrash(String),
+    /// Let saves replace the history, which could not be read for this
+    /// reason, after copying it aside.
+    ReleaseHistory(String),
+}
+
+/// Side-by-side answers to the same prompt, one of which can be promoted
+/// into the chat history.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub id: String,
+    pub chat_id: String,
+    pub candidates: Vec<Candidate>,
+    pub selected: usize,
+    pub scroll: u16,
 }
 
 pub struct App<'a> {
@@ -130,19 +1071,87 @@ pub struct App<'a> {
     pub selected_line: usize,
     pub api_key_input: String,
     pub selected_sidebar_idx: usize,
-    pub chat_scroll: u16,
-    pub max_chat_scroll: u16,
+    pub chat_scroll: usize,
+    pub max_chat_scroll: usize,
+    /// Rows of transcript visible in the chat view, updated on every draw.
+    pub chat_viewport_height: usize,
+    /// Message and line offset to put the cursor on once the transcript is
+    /// (re)built, e.g. after a resize or when jumping to a message.
+    pub cursor_anchor: Option<(usize, usize)>,
+    /// Recent `(chat id, message index)` positions for `Ctrl-o`/`Ctrl-i`.
+    pub jump_list: Vec<(String, usize)>,
+    pub jump_idx: usize,
+    pub selected_bookmark_idx: usize,
+    /// Listed by `:restore`, newest first.
+    pub backups: Vec<storage::Backup>,
+    pub selected_backup_idx: usize,
+    /// Chats listed by `:trash`.
+    pub trash: Vec<storage::Trashed>,
+    pub selected_trash_idx: usize,
+    /// What `:stats` shows.
+    pub stats: crate::stats::Stats,
+    /// Ids of the chats `:prune` is about to remove.
+    pub prune: Vec<String>,
+    pub selected_prune_idx: usize,
+    /// Row selected in the chat picker, an index into `picker_chats()`.
+    pub selected_picker_idx: usize,
+    /// Typing a filter into the chat picker after `/`.
+    pub picker_filtering: bool,
+    /// The text searched for with `/`, or the chat picker's filter.
+    pub search_query: String,
+    pub search_hits: Vec<Hit>,
+    pub selected_search_idx: usize,
+    pub notes_scroll: u16,
+    /// Id of the chat that was active before the current one (`Ctrl-^`).
+    pub previous_chat: Option<String>,
     pub cursor_line: usize,
     pub show_full_message: Option<usize>,
     pub last_width: usize,
-    pub line_cache: Vec<(Vec<Line<'a>>, bool)>,
+    pub line_cache: Vec<CachedMessage<'a>>,
     pub truncated_messages: HashSet<usize>,
+    /// Saved views of the chats not currently shown, by chat id.
+    pub chat_views: HashMap<String, ChatView>,
+    /// Directory `:wblock` last saved to; its suggestions go there.
+    pub save_dir: Option<PathBuf>,
+    pub pending_action: Option<PendingAction>,
+    /// First key of a two-key Normal-mode command (e.g. the `y` of `yy`).
+    pub pending_key: Option<char>,
+    /// Digits typed after `c` so far, in a message with ten or more code blocks.
+    pub pending_block: String,
+    /// Register chosen with `"x` for the next yank or paste.
+    pub pending_register: Option<char>,
+    /// Vim-style registers; `"` holds the last yank.
+    pub registers: HashMap<char, String>,
+    /// Files added with `:attach`, sent with the next message.
+    pub pending_attachments: Vec<Attachment>,
+    /// Code block to open in `$EDITOR` once the terminal is suspended.
+    pub pending_editor: Option<CodeBlock>,
+    pub block_runs: Vec<BlockRun>,
+    pub auto_title: AutoTitle,
+    pub retention: Retention,
+    pub large_chats: LargeChats,
+    /// Chats already reported as large this session.
+    pub large_warned: HashSet<String>,
+    /// `[storage] trash_days`.
+    pub trash_days: u64,
     pub need_rebuild_cache: bool,
+    /// Hash of the settings `line_cache` was built with.
+    pub cache_layout: u64,
+    /// Rows of the shown chat; only the visible ones are drawn each frame.
+    pub display_lines: Vec<DisplayLine>,
+    /// Chat `display_lines` were flattened for.
+    pub display_chat: Option<String>,
+    /// Whether `display_lines` were flattened while the chat was streaming.
+    pub display_streaming: bool,
     pub line_to_message: Vec<(usize, bool)>,
     pub focus: Focus,
+    /// Keeps focus where it is when switching focus or showing the sidebar.
+    pub focus_locked: bool,
     pub stream_tasks: HashMap<String, StreamTask>,
+    pub comparison: Option<Comparison>,
     pub error_message: Option<String>,
-    pub code_blocks: Vec<(usize, CodeBlock)>,
+    /// Code blocks of the shown chat, by message index.
+    pub code_blocks: BTreeMap<usize, Vec<CodeBlock>>,
     pub api_key_old: String,
     pub api_key_editing_started: bool,
     pub info_message: Option<String>,
@@ -153,13 +1162,43 @@ pub struct App<'a> {
     pub custom_model_model_input: String,
     pub custom_model_api_key_choice: Option<String>,
     pub custom_model_api_key_input: String,
-    pub loading_frame: usize,
+    pub loading_animation: LoadingAnimation,
+    pub started_at: Instant,
+    pub ui_settings: UiSettings,
+    /// The theme file named by `ui_settings.color_theme`.
+    pub theme: palette::Theme,
+    pub pricing: HashMap<String, f64>,
+    pub context_windows: HashMap<String, usize>,
+    pub model_prompts: HashMap<String, Vec<String>>,
+    pub keybindings: KeyBindings,
+    pub selected_shortcut_idx: usize,
+    pub selected_general_idx: usize,
+    pub default_model: Option<String>,
+    pub autosave_secs: u64,
+    /// Chats may have changed since history was last written.
+    pub history_dirty: bool,
+    /// Fingerprint of each chat as last written, in sidebar order.
+    pub saved_chats: Vec<(String, u64)>,
+    /// Something worth saving right away happened (a chat was renamed or
+    /// titled), rather than at the next autosave.
+    pub save_soon: bool,
+    /// Settings changed in the Settings screen and not yet written to
+    /// config.toml.
+    pub config_dirty: bool,
     pub prompts: Vec<Prompt>,
+    pub chains: Vec<Chain>,
+    /// Continuations sent on their own when a reply hits the token limit.
+    pub auto_continue: u32,
+    pub redactor: Redactor,
+    /// Unlocked with the passphrase when config.toml holds encrypted keys.
+    pub vault: Option<Vault>,
     pub selected_prompt_idx: usize,
     pub prompt_edit_idx: Option<usize>,
+    pub prompt_preview_scroll: u16,
     pub visual_start: Option<usize>,
     pub visual_end: Option<usize>,
     pub display_buffer_text_content: Vec<String>,
+    pub display_line_kinds: Vec<LineKind>,
 }
 
 impl<'a> App<'a> {
@@ -170,6 +1209,7 @@ impl<'a> App<'a> {
                 api_key: String::new(),
                 models: crate::config::openai_models(),
                 enabled_models: crate::config::openai_models(),
+                params: GenerationParams::default(),
                 expanded: false,
             },
             Provider {
@@ -177,6 +1217,7 @@ impl<'a> App<'a> {
                 api_key: String::new(),
                 models: crate::config::anthropic_models(),
                 enabled_models: crate::config::anthropic_models(),
+                params: GenerationParams::default(),
                 expanded: false,
             },
             Provider {
@@ -184,6 +1225,7 @@ impl<'a> App<'a> {
                 api_key: String::new(),
                 models: crate::config::grok_models(),
                 enabled_models: crate::config::grok_models(),
+                params: GenerationParams::default(),
                 expanded: false,
             },
         ];
@@ -203,19 +1245,59 @@ impl<'a> App<'a> {
             selected_line: 0,
             api_key_input: String::new(),
             selected_sidebar_idx: 0,
-            chat_scroll: u16::MAX,
+            chat_scroll: usize::MAX,
             max_chat_scroll: 0,
+            chat_viewport_height: 10,
+            cursor_anchor: None,
+            jump_list: Vec::new(),
+            jump_idx: 0,
+            selected_bookmark_idx: 0,
+            backups: Vec::new(),
+            selected_backup_idx: 0,
+            trash: Vec::new(),
+            selected_trash_idx: 0,
+            prune: Vec::new(),
+            selected_prune_idx: 0,
+            stats: crate::stats::Stats::default(),
+            selected_picker_idx: 0,
+            picker_filtering: false,
+            search_query: String::new(),
+            search_hits: Vec::new(),
+            selected_search_idx: 0,
+            notes_scroll: 0,
+            previous_chat: None,
             cursor_line: 0,
             show_full_message: None,
             last_width: 0,
             line_cache: Vec::new(),
             truncated_messages: HashSet::new(),
+            chat_views: HashMap::new(),
+            save_dir: None,
+            pending_action: None,
+            pending_key: None,
+            pending_block: String::new(),
+            pending_register: None,
+            registers: HashMap::new(),
+            pending_attachments: Vec::new(),
+            pending_editor: None,
+            block_runs: Vec::new(),
+            auto_title: AutoTitle::default(),
+            retention: Retention::default(),
+            large_chats: LargeChats::default(),
+            large_warned: HashSet::new(),
+            trash_days: 30,
             need_rebuild_cache: true,
+            cache_layout: 0,
+            display_lines: Vec::new(),
+            display_chat: None,
+            display_streaming: false,
             line_to_message: Vec::new(),
             focus: Focus::Chat,
+            focus_locked: false,
             stream_tasks: HashMap::new(),
+            comparison: None,
             error_message: None,
-            code_blocks: Vec::new(),
+            code_blocks: BTreeMap::new(),
             api_key_old: String::new(),
             api_key_editing_started: false,
             info_message: None,
@@ -226,13 +1308,34 @@ impl<'a> App<'a> {
             custom_model_model_input: String::new(),
             custom_model_api_key_choice: None,
             custom_model_api_key_input: String::new(),
-            loading_frame: 0,
+            loading_animation: LoadingAnimation::default(),
+            started_at: Instant::now(),
+            ui_settings: UiSettings::default(),
+            theme: palette::Theme::default(),
+            pricing: HashMap::new(),
+            context_windows: HashMap::new(),
+            model_prompts: HashMap::new(),
+            keybindings: KeyBindings::default(),
+            selected_shortcut_idx: 0,
+            selected_general_idx: 0,
+            default_model: None,
+            autosave_secs: 0,
+            history_dirty: false,
+            saved_chats: Vec::new(),
+            save_soon: false,
+            config_dirty: false,
             prompts: Vec::new(),
+            chains: Vec::new(),
+            auto_continue: 0,
+            redactor: Redactor::default(),
+            vault: None,
             selected_prompt_idx: 0,
             prompt_edit_idx: None,
+            prompt_preview_scroll: 0,
             visual_start: None,
             visual_end: None,
             display_buffer_text_content: Vec::new(),
+            display_line_kinds: Vec::new(),
         };
         if app.chats.is_empty() {
             app.create_new_chat();
@@ -243,7 +1346,7 @@ impl<'a> App<'a> {
     #[inline(always)]
     pub fn toggle_sidebar(&mut self) {
         self.sidebar_visible = !self.sidebar_visible;
-        self.focus = if self.sidebar_visible {
+        self.focus = if self.sidebar_visible && !self.focus_locked {
             Focus::Sidebar
         } else {
             Focus::Chat
@@ -254,150 +1357,1662 @@ impl<'a> App<'a> {
         let mut initial_messages = Vec::new();
         for prompt in &self.prompts {
             if prompt.active {
-                initial_messages.push(Message {
-                    role: "system".to_string(),
-                    content: prompt.content.to_string(),
-                });
+                initial_messages.push(Message::new(Role::System, prompt.content.to_string()));
             }
         }
-        let chat = Chat {
-            id: Uuid::new_v4().to_string(),
-            title: format!("Chat {}", self.chats.len() + 1),
-            messages: initial_messages,
-            model: self.current_model.clone(),
-            streaming: false,
-        };
+        self.previous_chat = self.chats.get(self.current_chat).map(|c| c.id.clone());
+        if let Some(model) = &self.default_model {
+            self.current_model = model.clone();
+        }
+        let chat = Chat::new(
+            Uuid::new_v4().to_string(),
+            format!("Chat {}", self.chats.len() + 1),
+            self.current_model.clone(),
+            initial_messages,
+        );
+        self.stash_view();
         self.chats.push(chat);
         self.current_chat = self.chats.len() - 1;
+        self.set_chat_model(self.current_chat, self.current_model.clone());
         self.selected_sidebar_idx = self.current_chat;
-        self.chat_scroll = u16::MAX;
+        self.chat_scroll = usize::MAX;
         self.cursor_line = 0;
         self.need_rebuild_cache = true;
-        self.truncated_messages.clear();
     }
 
-    #[inline(always)]
-    pub fn current_model_name(&self) -> &str {
-        &self.current_model
+    /// The library prompts `model_prompts` attaches to chats using `model`.
+    fn model_prompts(&self, model: &str) -> &[String] {
+        let name = model.split_once(':').map_or(model, |(_, name)| name);
+        self.model_prompts
+            .get(model)
+            .or_else(|| self.model_prompts.get(name))
+            .map_or(&[], Vec::as_slice)
     }
 
-    pub fn enabled_models_flat(&self) -> Vec<(Cow<'_, str>, Cow<'_, str>)> {
-        let mut list = Vec::with_capacity(8);
-        for p in &self.providers {
-            for m in &p.enabled_models {
-                list.push((Cow::Borrowed(p.name.as_str()), Cow::Borrowed(m.as_str())));
+    /// Switches chat `idx` to `model`, detaching the prompts `model_prompts`
+    /// gave the old model and attaching those of the new one.
+    pub fn set_chat_model(&mut self, idx: usize, model: String) {
+        let Some(old) = self.chats.get(idx).map(|c| c.model.clone()) else {
+            return;
+        };
+        let removed = self.model_prompts(&old).to_vec();
+        let added = self.model_prompts(&model).to_vec();
+        let chat = &mut self.chats[idx];
+        chat.prompts
+            .retain(|name| !removed.contains(name) || added.contains(name));
+        for name in added {
+            if !chat.prompts.contains(&name) {
+                chat.prompts.push(name);
             }
         }
-        for cm in &self.custom_models {
-            match cm {
-                CustomModel::Derived { provider, model } => {
-                    list.push((
-                        Cow::Borrowed(provider.as_str()),
-                        Cow::Borrowed(model.as_str()),
-                    ));
-                }
-                CustomModel::Standalone { name, .. } => {
-                    list.push((Cow::Borrowed("Custom"), Cow::Borrowed(name.as_str())));
-                }
-            }
+        chat.model = model;
+    }
+
+    /// The messages to send for `history` of `chat`: its attached prompts
+    /// as system messages first, then the history itself.
+    pub fn outgoing_messages(&self, chat: &Chat, history: &[Message]) -> Vec<Message> {
+        let attached = chat.prompts.iter().filter_map(|name| {
+            self.prompts
+                .iter()
+                .find(|p| *p.name == **name)
+                .map(|p| Message::new(Role::System, p.content.to_string()))
+        });
+        let history = history
+            .iter()
+            .filter(|m| m.failed.is_none())
+            .filter(|m| !(chat.prompts_override && m.role == "system"))
+            .cloned();
+        let mut messages: Vec<Message> = attached.chain(history).collect();
+        if let Some(window) = self.context_window(&chat.model) {
+            let reserve = self
+                .request_params(&chat.model, chat.params.clone())
+                .max_tokens
+                .unwrap_or(4096) as usize;
+            trim_to_fit(&mut messages, window.saturating_sub(reserve));
         }
-        list
+        messages
     }
 
-    pub fn jump_to_last_message(&mut self) {
-        let mut total_lines = 0;
-        for (lines, is_truncated) in &self.line_cache {
-            total_lines += lines.len();
-            if *is_truncated {
-                total_lines += 1;
+    /// Reads the rest of chat `idx` if only its index entry is loaded.
+    pub fn load_chat(&mut self, idx: usize) {
+        let Some(chat) = self.chats.get_mut(idx) else {
+            return;
+        };
+        if chat.stub.as_ref().is_none_or(|s| s.unreadable) {
+            return;
+        }
+        if let Err(e) = storage::load_chat(chat) {
+            if let Some(stub) = &mut chat.stub {
+                stub.unreadable = true;
             }
-            total_lines += 1;
+            let title = chat.title.clone();
+            self.set_error(&format!("Cannot load \"{}\": {}", title, e));
         }
-        self.cursor_line = if total_lines > 0 { total_lines - 1 } else { 0 };
-        self.chat_scroll = u16::MAX;
+        self.need_rebuild_cache = true;
     }
 
-    pub fn start_stream(&mut self, chat_id: String) -> Sender<String> {
-        let (tx, rx) = mpsc::channel(100);
-        self.stream_tasks.insert(chat_id.clone(), StreamTask { rx });
-        tx
+    /// Loads every chat, for features that look at all messages.
+    pub fn load_all_chats(&mut self) {
+        for idx in 0..self.chats.len() {
+            self.load_chat(idx);
+        }
     }
 
-    pub fn process_stream(&mut self) {
-        let mut to_remove = Vec::new();
-        let mut content_updated = false;
-        let mut new_code_blocks = Vec::new();
-        let mut processed_chunks = Vec::new();
-
-        for (chat_id, task) in self.stream_tasks.iter_mut() {
-            while let Ok(chunk) = task.rx.try_recv() {
-                if let Some(chat) = self.chats.iter_mut().find(|c| c.id == *chat_id) {
-                    chat.streaming = true;
-                    let msg_idx = chat.messages.len();
-                    if let Some(last_msg) = chat.messages.last_mut() {
-                        if last_msg.role == "assistant" {
-                            last_msg.content.push_str(&chunk);
-                            processed_chunks.push((msg_idx - 1, last_msg.content.clone()));
-                        } else {
-                            chat.messages.push(Message::new(Role::Assistant, &chunk));
-                            processed_chunks.push((msg_idx, chunk.clone()));
-                        }
-                    } else {
-                        chat.messages.push(Message::new(Role::Assistant, &chunk));
-                        processed_chunks.push((msg_idx, chunk.clone()));
-                    }
-                    self.need_rebuild_cache = true;
-                    content_updated = true;
-                    self.truncated_messages.remove(&msg_idx);
+    pub fn switch_chat(&mut self, idx: usize) {
+        self.load_chat(idx);
+        let Some(chat) = self.chats.get(idx) else {
+            return;
+        };
+        let model = chat.model.clone();
+        let view = self.chat_views.remove(&chat.id);
+        if idx != self.current_chat
+            && let Some(current) = self.chats.get(self.current_chat)
+        {
+            self.previous_chat = Some(current.id.clone());
+            self.stash_view();
+        }
+        self.current_chat = idx;
+        self.selected_sidebar_idx = idx;
+        self.current_model = model;
+        match view {
+            Some(view) => {
+                self.truncated_messages = view.truncated_messages;
+                self.cursor_anchor = view.anchor;
+                if view.anchor.is_none() {
+                    self.cursor_line = usize::MAX;
                 }
             }
-            if task.rx.is_closed() {
-                if let Some(chat) = self.chats.iter_mut().find(|c| c.id == *chat_id) {
-                    chat.streaming = false;
-                }
-                to_remove.push(chat_id.clone());
+            None => {
+                self.truncated_messages.clear();
+                self.cursor_line = usize::MAX;
             }
         }
-        for chat_id in to_remove {
+        self.chat_scroll = usize::MAX;
+        self.need_rebuild_cache = true;
+    }
+
+    /// Remembers where the reader is in the current chat before another
+    /// chat is shown.
+    pub fn stash_view(&mut self) {
+        let Some(chat) = self.chats.get(self.current_chat) else {
+            return;
+        };
+        let view = ChatView {
+            anchor: self.cursor_anchor_here(),
+            truncated_messages: std::mem::take(&mut self.truncated_messages),
+        };
+        self.chat_views.insert(chat.id.clone(), view);
+    }
+
+    /// The current view, to be saved at exit.
+    pub fn session(&self) -> Session {
+        let mut views = self.chat_views.clone();
+        let current = self.chats.get(self.current_chat);
+        if let Some(chat) = current {
+            views.insert(
+                chat.id.clone(),
+                ChatView {
+                    anchor: self.cursor_anchor_here(),
+                    truncated_messages: self.truncated_messages.clone(),
+                },
+            );
+        }
+        views.retain(|_, view| view.anchor.is_some() || !view.truncated_messages.is_empty());
+        Session {
+            current_chat: current.map(|c| c.id.clone()),
+            selected_chat: self
+                .chats
+                .get(self.selected_sidebar_idx)
+                .map(|c| c.id.clone()),
+            focus: self.focus,
+            sidebar_visible: self.sidebar_visible,
+            settings_tab: self.settings_tab,
+            settings_open: self.mode == Mode::Settings,
+            scroll: (self.chat_scroll < self.max_chat_scroll).then_some(self.chat_scroll),
+            save_dir: self.save_dir.clone(),
+            views,
+        }
+    }
+
+    /// Brings back a view saved by `session`. Chats deleted since are
+    /// skipped; if the open one is gone, the current chat stays.
+    pub fn restore_session(&mut self, session: Session) {
+        let position = |id: &Option<String>| {
+            id.as_ref()
+                .and_then(|id| self.chats.iter().position(|c| &c.id == id))
+        };
+        let current = position(&session.current_chat);
+        let selected = position(&session.selected_chat);
+        self.chat_views = session
+            .views
+            .into_iter()
+            .filter(|(id, _)| self.chats.iter().any(|c| &c.id == id))
+            .collect();
+        if let Some(idx) = current {
+            self.switch_chat(idx);
+            if let Some(scroll) = session.scroll {
+                self.chat_scroll = scroll;
+            }
+        }
+        if let Some(idx) = selected {
+            self.selected_sidebar_idx = idx;
+        }
+        self.focus = session.focus;
+        self.sidebar_visible = session.sidebar_visible;
+        self.settings_tab = session.settings_tab;
+        self.save_dir = session.save_dir.filter(|dir| dir.is_dir());
+        if session.settings_open {
+            self.mode = Mode::Settings;
+        }
+        self.need_rebuild_cache = true;
+    }
+
+    /// Moves the chat selected in the sidebar one place up or down, keeping
+    /// the current chat and the selection on the same chats.
+    pub fn move_selected_chat(&mut self, up: bool) -> bool {
+        let from = self.selected_sidebar_idx;
+        let to = if up {
+            match from.checked_sub(1) {
+                Some(to) => to,
+                None => return false,
+            }
+        } else {
+            from + 1
+        };
+        if from >= self.chats.len() || to >= self.chats.len() {
+            return false;
+        }
+        self.chats.swap(from, to);
+        if self.current_chat == from {
+            self.current_chat = to;
+        } else if self.current_chat == to {
+            self.current_chat = from;
+        }
+        self.selected_sidebar_idx = to;
+        true
+    }
+
+    /// Flips to the previously active chat, like vim's alternate buffer.
+    pub fn switch_to_alternate_chat(&mut self) -> Result<(), String> {
+        let idx = self
+            .previous_chat
+            .as_ref()
+            .and_then(|id| self.chats.iter().position(|c| c.id == *id))
+            .ok_or_else(|| "No alternate chat".to_string())?;
+        self.push_jump();
+        self.switch_chat(idx);
+        Ok(())
+    }
+
+    /// Creates a new chat with the current chat's messages up to and
+    /// including `msg_idx`, and switches to it.
+    pub fn fork_chat(&mut self, msg_idx: usize) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        if msg_idx >= chat.messages.len() {
+            return Err("No message at the cursor".to_string());
+        }
+        let fork = Chat {
+            id: Uuid::new_v4().to_string(),
+            title: format!("Fork of {}", chat.title),
+            messages: chat.messages[..=msg_idx].to_vec(),
+            model: chat.model.clone(),
+            streaming: false,
+            forked_from: Some(ForkOrigin {
+                chat_id: chat.id.clone(),
+                message_idx: msg_idx,
+            }),
+            prompts: chat.prompts.clone(),
+            prompts_override: chat.prompts_override,
+            params: chat.params.clone(),
+            marks: chat
+                .marks
+                .iter()
+                .filter(|(_, (msg, _))| *msg <= msg_idx)
+                .map(|(name, mark)| (*name, *mark))
+                .collect(),
+            draft: String::new(),
+            queued: Vec::new(),
+            chain: None,
+            auto_continued: 0,
+            notes: String::new(),
+            read_only: false,
+            stub: None,
+        };
+        let idx = self.current_chat + 1;
+        self.chats.insert(idx, fork);
+        self.switch_chat(idx);
+        Ok(())
+    }
+
+    /// Why the chat at `idx` is past the `[large_chats]` limits, as
+    /// "612 messages, 1.2 MB", or `None` if it is not.
+    pub fn large_chat(&self, idx: usize) -> Option<String> {
+        let chat = self.chats.get(idx)?;
+        let limits = &self.large_chats;
+        let count = chat.messages.len();
+        let bytes: usize = chat.messages.iter().map(|m| m.content.len()).sum();
+        let large = (limits.messages > 0 && count > limits.messages)
+            || (limits.size_kb > 0 && bytes > limits.size_kb * 1024);
+        large.then(|| format!("{} messages, {}", count, size_label(bytes as u64)))
+    }
+
+    /// Points out the open chat once per session when it is, or grows,
+    /// past the `[large_chats]` limits.
+    pub fn warn_large_chat(&mut self) {
+        let Some(id) = self.chats.get(self.current_chat).map(|c| c.id.clone()) else {
+            return;
+        };
+        if self.large_warned.contains(&id) {
+            return;
+        }
+        if let Some(reason) = self.large_chat(self.current_chat) {
+            self.large_warned.insert(id);
+            self.set_info(&format!(
+                "This chat is large ({}); :large to summarize, split or archive it",
+                reason
+            ));
+        }
+    }
+
+    /// Opens a new chat after the current one and asks its model for a
+    /// summary of the current chat. Only the latest messages are included
+    /// when the whole chat would not fit the model's context window.
+    pub fn summarize_chat(&mut self) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        let Some(last) = chat.messages.len().checked_sub(1) else {
+            return Err("Nothing to summarize".to_string());
+        };
+        // Room is left for the instructions and the summary itself.
+        let budget = self
+            .context_window(&chat.model)
+            .map_or(usize::MAX, |window| window / 4 * 3);
+        let mut used = 0;
+        let mut parts = Vec::new();
+        for message in chat.messages.iter().rev().filter(|m| m.role != "system") {
+            let part = format!("{}: {}", message.role, message.content);
+            used += estimate_tokens(&part);
+            if used > budget {
+                break;
+            }
+            parts.push(part);
+        }
+        let omitted = chat.messages.iter().filter(|m| m.role != "system").count() - parts.len();
+        parts.reverse();
+        let mut prompt = "Summarize the conversation below so it can be continued in a new \
+                          chat. Keep decisions, open questions, code and facts that later \
+                          messages rely on."
+            .to_string();
+        if omitted > 0 {
+            prompt.push_str(&format!(
+                " The first {} message(s) did not fit and are left out.",
+                omitted
+            ));
+        }
+        prompt.push_str("\n\n");
+        prompt.push_str(&parts.join("\n\n"));
+        let summary = Chat {
+            id: Uuid::new_v4().to_string(),
+            title: format!("Summary of {}", chat.title),
+            messages: Vec::new(),
+            model: chat.model.clone(),
+            streaming: false,
+            forked_from: Some(ForkOrigin {
+                chat_id: chat.id.clone(),
+                message_idx: last,
+            }),
+            prompts: chat.prompts.clone(),
+            prompts_override: chat.prompts_override,
+            params: chat.params.clone(),
+            marks: HashMap::new(),
+            draft: String::new(),
+            queued: Vec::new(),
+            chain: None,
+            auto_continued: 0,
+            notes: String::new(),
+            read_only: false,
+            stub: None,
+        };
+        let idx = self.current_chat + 1;
+        self.chats.insert(idx, summary);
+        self.switch_chat(idx);
+        self.send_message(idx, Message::new(Role::User, prompt))
+    }
+
+    /// Moves all but the latest `[large_chats] keep` messages of the current
+    /// chat into a new chat after it; system messages stay in both. Returns
+    /// the number of messages moved.
+    pub fn split_chat(&mut self) -> Result<usize, String> {
+        let keep = self.large_chats.keep.max(1);
+        let chat = self
+            .chats
+            .get_mut(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.writable()?;
+        if chat.streaming {
+            return Err("Wait for the current response to finish".to_string());
+        }
+        let mut kept = 0;
+        let Some(mut cut) = chat.messages.iter().rposition(|m| {
+            kept += usize::from(m.role != "system");
+            kept == keep
+        }) else {
+            return Err(format!(
+                "Only {} message(s); nothing to split off",
+                chat.messages.len()
+            ));
+        };
+        // The part left behind starts with a question where possible.
+        if let Some(offset) = chat.messages[cut..].iter().position(|m| m.role == "user") {
+            cut += offset;
+        }
+        let moved = chat.messages[..cut]
+            .iter()
+            .filter(|m| m.role != "system")
+            .count();
+        if moved == 0 {
+            return Err("Nothing to split off".to_string());
+        }
+        let earlier: Vec<Message> = chat.messages.drain(..cut).collect();
+        let system: Vec<Message> = earlier
+            .iter()
+            .filter(|m| m.role == "system")
+            .cloned()
+            .collect();
+        let shift = cut - system.len();
+        chat.messages.splice(..0, system);
+        let marks = std::mem::take(&mut chat.marks);
+        let (earlier_marks, later_marks): (HashMap<_, _>, HashMap<_, _>) =
+            marks.into_iter().partition(|(_, (msg, _))| *msg < cut);
+        chat.marks = later_marks
+            .into_iter()
+            .map(|(name, (msg, line))| (name, (msg - shift, line)))
+            .collect();
+        let split = Chat {
+            id: Uuid::new_v4().to_string(),
+            title: format!("{} (earlier)", chat.title),
+            messages: earlier,
+            model: chat.model.clone(),
+            streaming: false,
+            forked_from: None,
+            prompts: chat.prompts.clone(),
+            prompts_override: chat.prompts_override,
+            params: chat.params.clone(),
+            marks: earlier_marks,
+            draft: String::new(),
+            queued: Vec::new(),
+            chain: None,
+            auto_continued: 0,
+            notes: String::new(),
+            read_only: false,
+            stub: None,
+        };
+        self.chats.insert(self.current_chat + 1, split);
+        self.truncated_messages.clear();
+        self.cursor_line = usize::MAX;
+        self.chat_scroll = usize::MAX;
+        self.need_rebuild_cache = true;
+        self.save_soon = true;
+        Ok(moved)
+    }
+
+    /// The usable form of a configured API key: decrypted if it was
+    /// encrypted with the passphrase, with `${VAR}` references expanded.
+    fn secret(&self, value: &str) -> Result<String, String> {
+        if !secrets::is_encrypted(value) {
+            return config::expand_env(value);
+        }
+        self.vault
+            .as_ref()
+            .and_then(|vault| vault.get(value))
+            .map(str::to_string)
+            .ok_or_else(|| {
+                "API key is encrypted; restart meowi to enter the passphrase".to_string()
+            })
+    }
+
+    /// Resolves a "Provider:model" string to an endpoint with credentials,
+    /// falling back to the provider's API key environment variable.
+    pub fn resolve_endpoint(&self, model: &str) -> Result<Endpoint, String> {
+        let Some((provider_name, model_name)) = model.split_once(':') else {
+            return Err("Invalid model format".to_string());
+        };
+
+        if provider_name == "Custom" {
+            let (endpoint, model, api_key, use_key_from) = self
+                .custom_models
+                .iter()
+                .find_map(|cm| match cm {
+                    CustomModel::Standalone {
+                        name,
+                        endpoint,
+                        model,
+                        api_key,
+                        use_key_from,
+                    } if name == model_name => Some((endpoint, model, api_key, use_key_from)),
+                    _ => None,
+                })
+                .ok_or_else(|| "Custom model not found".to_string())?;
+            let key = match api_key {
+                Some(key) => Some(self.secret(key)?),
+                None => match use_key_from.as_ref().and_then(|p_name| {
+                    self.providers
+                        .iter()
+                        .find(|p| &p.name == p_name && !p.api_key.is_empty())
+                }) {
+                    Some(p) => Some(self.secret(&p.api_key)?),
+                    None => None,
+                },
+            };
+            return Ok(Endpoint::Custom {
+                endpoint: config::expand_env(endpoint)?,
+                model: model.clone(),
+                api_key: key,
+            });
+        }
+
+        let api_key = match self.providers.iter().find(|p| p.name == provider_name) {
+            Some(p) if !p.api_key.is_empty() => self.secret(&p.api_key)?,
+            _ => {
+                let env_key = match provider_name {
+                    "OpenAI" => "OPENAI_API_KEY",
+                    "Grok" => "GROK_API_KEY",
+                    "Anthropic" => "ANTHROPIC_API_KEY",
+                    _ => return Err(format!("No API key set for provider {}", provider_name)),
+                };
+                match env::var(env_key) {
+                    Ok(key) if !key.is_empty() => key,
+                    _ => {
+                        return Err(format!(
+                            "No API key set for provider {}. Set {} or configure in settings.",
+                            provider_name, env_key
+                        ));
+                    }
+                }
+            }
+        };
+        Ok(Endpoint::Provider {
+            provider: provider_name.to_string(),
+            model: model_name.to_string(),
+            api_key,
+        })
+    }
+
+    pub fn general_setting_value(&self, setting: GeneralSetting) -> String {
+        let on_off = |enabled: bool| if enabled { "on" } else { "off" }.to_string();
+        match setting {
+            GeneralSetting::Theme => self.ui_settings.theme.clone(),
+            GeneralSetting::SidebarWidth => self.ui_settings.sidebar_width.to_string(),
+            GeneralSetting::SidebarPosition => self.ui_settings.sidebar.label().to_string(),
+            GeneralSetting::Timestamps => on_off(self.ui_settings.timestamps),
+            GeneralSetting::Colors => {
+                let configured = self.ui_settings.colors;
+                if configured.effective() != configured {
+                    format!("{} (NO_COLOR set)", configured.label())
+                } else {
+                    configured.label().to_string()
+                }
+            }
+            GeneralSetting::TruncateLines => match self.ui_settings.truncate_lines {
+                0 => "off".to_string(),
+                n => n.to_string(),
+            },
+            GeneralSetting::Scrolloff => self.ui_settings.scrolloff.to_string(),
+            GeneralSetting::DefaultModel => self
+                .default_model
+                .clone()
+                .unwrap_or_else(|| "(first enabled with a key)".to_string()),
+            GeneralSetting::AutosaveSecs => match self.autosave_secs {
+                0 => "off".to_string(),
+                n => n.to_string(),
+            },
+        }
+    }
+
+    #[inline(always)]
+    pub fn current_model_name(&self) -> &str {
+        &self.current_model
+    }
+
+    /// Context window of a "Provider:model" key: the configured size, else
+    /// the built-in size for well-known models.
+    pub fn context_window(&self, model: &str) -> Option<usize> {
+        self.context_windows.get(model).copied().or_else(|| {
+            let name = model.split_once(':').map_or(model, |(_, name)| name);
+            crate::config::known_context_window(name)
+        })
+    }
+
+    /// `params` for a request to `model` ("Provider:model"), with the unset
+    /// fields filled from the provider's configured defaults.
+    pub fn request_params(&self, model: &str, params: GenerationParams) -> GenerationParams {
+        let provider = model
+            .split_once(':')
+            .map_or(model, |(provider, _)| provider);
+        match self.providers.iter().find(|p| p.name == provider) {
+            Some(p) => params.or(&p.params),
+            None => params,
+        }
+    }
+
+    /// Loads the theme file named by `ui_settings.color_theme`, or the
+    /// built-in colors if none is set.
+    pub fn load_theme(&mut self) -> Result<(), String> {
+        self.theme = match &self.ui_settings.color_theme {
+            Some(name) => palette::Theme::new(name, &config::load_theme(name)?)?,
+            None => palette::Theme::default(),
+        };
+        self.need_rebuild_cache = true;
+        Ok(())
+    }
+
+    /// The model for a chat that has nothing to inherit one from: the
+    /// configured default, else the first enabled model that has an API key,
+    /// else the first enabled model.
+    pub fn new_chat_model(&self) -> Option<String> {
+        if let Some(model) = &self.default_model {
+            return Some(model.clone());
+        }
+        let enabled: Vec<String> = self
+            .enabled_models_flat()
+            .iter()
+            .map(|(provider, model)| format!("{}:{}", provider, model))
+            .collect();
+        enabled
+            .iter()
+            .find(|model| self.resolve_endpoint(model).is_ok())
+            .or(enabled.first())
+            .cloned()
+    }
+
+    pub fn enabled_models_flat(&self) -> Vec<(Cow<'_, str>, Cow<'_, str>)> {
+        let mut list = Vec::with_capacity(8);
+        for p in &self.providers {
+            for m in &p.enabled_models {
+                list.push((Cow::Borrowed(p.name.as_str()), Cow::Borrowed(m.as_str())));
+            }
+        }
+        for cm in &self.custom_models {
+            match cm {
+                CustomModel::Derived { provider, model } => {
+                    list.push((
+                        Cow::Borrowed(provider.as_str()),
+                        Cow::Borrowed(model.as_str()),
+                    ));
+                }
+                CustomModel::Standalone { name, .. } => {
+                    list.push((Cow::Borrowed("Custom"), Cow::Borrowed(name.as_str())));
+                }
+            }
+        }
+        list
+    }
+
+    pub fn jump_to_last_message(&mut self) {
+        let mut total_lines = 0;
+        for cached in &self.line_cache {
+            total_lines += cached.lines.len();
+            if cached.hidden > 0 {
+                total_lines += 1;
+            }
+            total_lines += 1;
+        }
+        self.cursor_line = if total_lines > 0 { total_lines - 1 } else { 0 };
+        self.chat_scroll = usize::MAX;
+    }
+
+    pub fn start_stream(
+        &mut self,
+        chat_id: String,
+        model: String,
+        endpoint: Endpoint,
+        mut messages: Vec<Message>,
+    ) {
+        let params = self
+            .chats
+            .iter()
+            .find(|c| c.id == chat_id)
+            .map(|c| c.params.clone())
+            .unwrap_or_default();
+        let params = self.request_params(&model, params);
+        self.redact_outgoing(&mut messages);
+        let (rx, handle, outcome) = spawn_stream(endpoint, messages, params);
+        self.stream_tasks.insert(
+            chat_id,
+            StreamTask {
+                rx,
+                target: StreamTarget::Chat { model },
+                handle,
+                outcome,
+            },
+        );
+    }
+
+    /// Scrubs secrets from a request about to be sent and says so.
+    fn redact_outgoing(&mut self, messages: &mut [Message]) {
+        let redacted = self.redactor.scrub(messages);
+        if redacted > 0 {
+            self.set_info(&format!(
+                "Redacted {} possible secret(s) from the request",
+                redacted
+            ));
+        }
+    }
+
+    pub fn start_candidate_stream(
+        &mut self,
+        comparison_id: &str,
+        idx: usize,
+        endpoint: Endpoint,
+        mut messages: Vec<Message>,
+        params: GenerationParams,
+    ) {
+        self.redact_outgoing(&mut messages);
+        let (rx, handle, outcome) = spawn_stream(endpoint, messages, params);
+        self.stream_tasks.insert(
+            format!("{}#{}", comparison_id, idx),
+            StreamTask {
+                rx,
+                target: StreamTarget::Candidate {
+                    comparison_id: comparison_id.to_string(),
+                    idx,
+                },
+                handle,
+                outcome,
+            },
+        );
+    }
+
+    /// Replaces the current chat's last answer with a fresh one from `model`
+    /// (the chat's model if `None`). The old answer is kept in the new
+    /// message's alternatives.
+    pub fn regenerate(&mut self, model: Option<&str>) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.writable()?;
+        if chat.streaming {
+            return Err("Wait for the current response to finish".to_string());
+        }
+        if chat.messages.last().is_none_or(|m| m.role != "assistant") {
+            return Err("Nothing to regenerate: the last message is not a reply".to_string());
+        }
+        let model = model.map_or_else(|| chat.model.clone(), str::to_string);
+        let endpoint = self.resolve_endpoint(&model)?;
+
+        let messages = self.outgoing_messages(chat, &chat.messages[..chat.messages.len() - 1]);
+
+        let chat = &mut self.chats[self.current_chat];
+        let old = chat.messages.pop().unwrap();
+        let mut fresh = Message::reply(&model, "");
+        fresh.alternatives = old.alternatives;
+        fresh.alternatives.push(old.content);
+        chat.messages.push(fresh);
+        chat.streaming = true;
+        let chat_id = chat.id.clone();
+
+        self.start_stream(chat_id, model, endpoint, messages);
+        self.need_rebuild_cache = true;
+        self.jump_to_last_message();
+        Ok(())
+    }
+
+    /// Appends a user message to the chat at `chat_idx` and starts streaming
+    /// the reply. If the chat's model cannot be used the message is kept,
+    /// marked as failed.
+    pub fn send_message(&mut self, chat_idx: usize, mut message: Message) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(chat_idx)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        let endpoint = self.resolve_endpoint(&chat.model);
+        let mut messages = self.outgoing_messages(chat, &chat.messages);
+        let endpoint = match endpoint {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                message.failed = Some(e.clone());
+                self.push_user_message(chat_idx, message);
+                return Err(e);
+            }
+        };
+        messages.push(message.clone());
+        self.push_user_message(chat_idx, message);
+        let chat = &mut self.chats[chat_idx];
+        chat.streaming = true;
+        chat.auto_continued = 0;
+        let chat_id = chat.id.clone();
+        let model = chat.model.clone();
+        self.start_stream(chat_id, model, endpoint, messages);
+        Ok(())
+    }
+
+    fn push_user_message(&mut self, chat_idx: usize, message: Message) {
+        if chat_idx == self.current_chat {
+            self.add_user_message(message);
+            self.jump_to_last_message();
+        } else {
+            self.chats[chat_idx].messages.push(message);
+        }
+    }
+
+    /// Sends the next queued message or chain step of every chat that is no
+    /// longer streaming. A failed send holds the rest of that chat's queue.
+    pub fn dispatch_queued(&mut self) {
+        for chat_idx in 0..self.chats.len() {
+            let chat = &mut self.chats[chat_idx];
+            if chat.streaming || chat.messages.last().is_some_and(|m| m.failed.is_some()) {
+                continue;
+            }
+            let message = if !chat.queued.is_empty() {
+                let mut message = chat.queued.remove(0);
+                message.timestamp = Some(chrono::Utc::now().timestamp());
+                message
+            } else if let Some(run) = &mut chat.chain {
+                let prev = chat
+                    .messages
+                    .iter()
+                    .rev()
+                    .find(|m| m.role == "assistant")
+                    .map_or("", |m| m.content.as_str());
+                match run.next_prompt(prev) {
+                    Some(prompt) => Message::new(Role::User, prompt),
+                    None => {
+                        let name = run.name.clone();
+                        chat.chain = None;
+                        self.set_info(&format!("Chain \"{}\" finished", name));
+                        continue;
+                    }
+                }
+            } else {
+                continue;
+            };
+            if let Err(e) = self.send_message(chat_idx, message) {
+                self.set_error(&format!("{} (press r to retry)", e));
+            }
+        }
+    }
+
+    /// Starts the configured chain `name` in the current chat; its steps are
+    /// sent by [`App::dispatch_queued`] as each answer finishes.
+    pub fn start_chain(&mut self, name: &str, input: &str) -> Result<(), String> {
+        let chain = self
+            .chains
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| format!("No chain named \"{}\"", name))?;
+        if chain.steps.is_empty() {
+            return Err(format!("Chain \"{}\" has no steps", name));
+        }
+        let run = ChainRun {
+            name: chain.name.clone(),
+            steps: chain.steps.clone(),
+            input: input.to_string(),
+            next: 0,
+        };
+        let chat = self
+            .chats
+            .get_mut(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.writable()?;
+        if chat.chain.is_some() {
+            return Err("A chain is already running in this chat".to_string());
+        }
+        chat.chain = Some(run);
+        Ok(())
+    }
+
+    /// Asks for the rest of a reply that stopped at the token limit. The
+    /// continuation streams onto the end of the same message.
+    pub fn continue_reply(&mut self, chat_idx: usize) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(chat_idx)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.writable()?;
+        if chat.streaming {
+            return Err("Wait for the current response to finish".to_string());
+        }
+        if chat.messages.last().is_none_or(|m| !m.cut_off) {
+            return Err("Nothing to continue: the last reply is complete".to_string());
+        }
+        let endpoint = self.resolve_endpoint(&chat.model)?;
+        let mut messages = self.outgoing_messages(chat, &chat.messages);
+        messages.push(Message::new(Role::User, CONTINUE_PROMPT));
+
+        let chat = &mut self.chats[chat_idx];
+        if let Some(last) = chat.messages.last_mut() {
+            last.cut_off = false;
+        }
+        chat.streaming = true;
+        let chat_id = chat.id.clone();
+        let model = chat.model.clone();
+        self.start_stream(chat_id, model, endpoint, messages);
+        self.need_rebuild_cache = true;
+        Ok(())
+    }
+
+    /// Sends the current chat's failed last message again.
+    pub fn retry_failed(&mut self) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.writable()?;
+        if chat.streaming {
+            return Err("Wait for the current response to finish".to_string());
+        }
+        if chat.messages.last().is_none_or(|m| m.failed.is_none()) {
+            return Err("Nothing to retry: the last message was sent".to_string());
+        }
+        let endpoint = self.resolve_endpoint(&chat.model);
+        let chat = &mut self.chats[self.current_chat];
+        let last = chat.messages.last_mut().unwrap();
+        let endpoint = match endpoint {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                last.failed = Some(e.clone());
+                return Err(e);
+            }
+        };
+        last.failed = None;
+        chat.streaming = true;
+        let chat = &self.chats[self.current_chat];
+        let messages = self.outgoing_messages(chat, &chat.messages);
+        let chat_id = chat.id.clone();
+        let model = chat.model.clone();
+
+        self.start_stream(chat_id, model, endpoint, messages);
+        self.need_rebuild_cache = true;
+        self.jump_to_last_message();
+        Ok(())
+    }
+
+    /// Asks the titling model for a short title for the chat's first
+    /// exchange. Failures are silent; the chat keeps its default name.
+    fn start_title_stream(&mut self, chat_idx: usize) {
+        let chat = &self.chats[chat_idx];
+        let model = self.auto_title.model.as_deref().unwrap_or(&chat.model);
+        let Ok(endpoint) = self.resolve_endpoint(model) else {
+            return;
+        };
+        let exchange: String = chat
+            .messages
+            .iter()
+            .filter(|m| m.role != "system")
+            .map(|m| format!("{}: {}\n", m.role, m.content))
+            .collect::<String>()
+            .chars()
+            .take(2000)
+            .collect();
+        let prompt = format!(
+            "Summarize this conversation in 5 words or fewer. \
+             Reply with the title only, no quotes or punctuation.\n\n{}",
+            exchange
+        );
+        let mut messages = vec![Message::new(Role::User, prompt)];
+        self.redactor.scrub(&mut messages);
+        let params = self.request_params(model, GenerationParams::default());
+        let (rx, handle, outcome) = spawn_stream(endpoint, messages, params);
+        self.stream_tasks.insert(
+            format!("{}#title", chat.id),
+            StreamTask {
+                rx,
+                target: StreamTarget::Title {
+                    chat_id: chat.id.clone(),
+                    text: String::new(),
+                },
+                handle,
+                outcome,
+            },
+        );
+    }
+
+    /// Aborts the response streaming into `chat_id`, keeping whatever text
+    /// already arrived. Returns false if nothing was streaming.
+    pub fn stop_stream(&mut self, chat_id: &str) -> bool {
+        let Some(mut task) = self.stream_tasks.remove(chat_id) else {
+            return false;
+        };
+        task.rx.close();
+        let mut partial = String::new();
+        while let Ok(chunk) = task.rx.try_recv() {
+            partial.push_str(&chunk);
+        }
+        let model = match &task.target {
+            StreamTarget::Chat { model } => model.clone(),
+            _ => String::new(),
+        };
+        drop(task);
+        if let Some(chat) = self.chats.iter_mut().find(|c| c.id == chat_id) {
+            chat.streaming = false;
+            chat.chain = None;
+            if !partial.is_empty() {
+                match chat.messages.last_mut() {
+                    Some(last) if last.role == "assistant" => last.content.push_str(&partial),
+                    _ => chat.messages.push(Message::reply(&model, partial)),
+                }
+            }
+            restore_empty_regeneration(chat);
+        }
+        self.need_rebuild_cache = true;
+        true
+    }
+
+    /// Drops the comparison and stops any candidate streams still running.
+    pub fn close_comparison(&mut self) {
+        if let Some(cmp) = self.comparison.take() {
+            self.stream_tasks.retain(|_, task| {
+                !matches!(&task.target, StreamTarget::Candidate { comparison_id, .. } if *comparison_id == cmp.id)
+            });
+        }
+    }
+
+    /// Writes the selected candidate into the chat, replacing the answer it
+    /// was compared against (if any).
+    pub fn promote_candidate(&mut self) -> Result<String, String> {
+        let Some(cmp) = &self.comparison else {
+            return Err("No comparison open".to_string());
+        };
+        let candidate = cmp
+            .candidates
+            .get(cmp.selected)
+            .ok_or_else(|| "No candidate selected".to_string())?;
+        if !candidate.done {
+            return Err("Candidate is still streaming".to_string());
+        }
+        let chat = self
+            .chats
+            .iter_mut()
+            .find(|c| c.id == cmp.chat_id)
+            .ok_or_else(|| "Chat no longer exists".to_string())?;
+        match chat.messages.last_mut() {
+            Some(last) if last.role == "assistant" => {
+                let previous = std::mem::replace(&mut last.content, candidate.content.clone());
+                if previous != last.content {
+                    last.alternatives.push(previous);
+                }
+                last.model = Some(candidate.model.clone());
+            }
+            _ => chat
+                .messages
+                .push(Message::reply(&candidate.model, candidate.content.clone())),
+        }
+        let model = candidate.model.clone();
+        self.close_comparison();
+        self.need_rebuild_cache = true;
+        self.jump_to_last_message();
+        Ok(model)
+    }
+
+    pub fn process_stream(&mut self) {
+        let mut to_remove = Vec::new();
+        let mut content_updated = false;
+        let mut processed_chunks = Vec::new();
+
+        let mut to_title = Vec::new();
+        let mut to_continue = Vec::new();
+
+        for (chat_id, task) in self.stream_tasks.iter_mut() {
+            if let StreamTarget::Title {
+                chat_id: titled_chat,
+                text,
+            } = &mut task.target
+            {
+                while let Ok(chunk) = task.rx.try_recv() {
+                    text.push_str(&chunk);
+                }
+                if task.rx.is_closed() {
+                    let title = clean_title(text);
+                    if let Some(chat) = self.chats.iter_mut().find(|c| c.id == *titled_chat)
+                        && !title.is_empty()
+                    {
+                        chat.title = title;
+                        self.history_dirty = true;
+                        self.save_soon = true;
+                    }
+                    to_remove.push(chat_id.clone());
+                }
+                continue;
+            }
+            if let StreamTarget::Candidate { comparison_id, idx } = &task.target {
+                let candidate = self
+                    .comparison
+                    .as_mut()
+                    .filter(|cmp| cmp.id == *comparison_id)
+                    .and_then(|cmp| cmp.candidates.get_mut(*idx));
+                if let Some(candidate) = candidate {
+                    while let Ok(chunk) = task.rx.try_recv() {
+                        candidate.content.push_str(&chunk);
+                    }
+                    if task.rx.is_closed() {
+                        if let Ok(Err(error)) = task.outcome.try_recv()
+                            && candidate.content.is_empty()
+                        {
+                            candidate.content = format!("Error: {}", error);
+                        }
+                        candidate.done = true;
+                        to_remove.push(chat_id.clone());
+                    }
+                } else {
+                    to_remove.push(chat_id.clone());
+                }
+                continue;
+            }
+            let current_id = self.chats.get(self.current_chat).map(|c| c.id.clone());
+            let is_current = current_id.as_deref() == Some(chat_id.as_str());
+            while let Ok(chunk) = task.rx.try_recv() {
+                if let Some(chat) = self.chats.iter_mut().find(|c| c.id == *chat_id) {
+                    chat.streaming = true;
+                    let msg_idx = chat.messages.len();
+                    let new_chunk = match chat.messages.last_mut() {
+                        Some(last_msg) if last_msg.role == "assistant" => {
+                            last_msg.content.push_str(&chunk);
+                            (msg_idx - 1, last_msg.content.clone())
+                        }
+                        _ => {
+                            let model = match &task.target {
+                                StreamTarget::Chat { model } => model.as_str(),
+                                _ => "",
+                            };
+                            chat.messages.push(Message::reply(model, &chunk));
+                            (msg_idx, chunk.clone())
+                        }
+                    };
+                    self.history_dirty = true;
+                    // A chat streaming in the background only touches its own
+                    // saved view, never the cache and cursor of the one shown.
+                    if !is_current {
+                        if let Some(view) = self.chat_views.get_mut(chat_id) {
+                            view.truncated_messages.remove(&msg_idx);
+                        }
+                        continue;
+                    }
+                    processed_chunks.push(new_chunk);
+                    self.need_rebuild_cache = true;
+                    content_updated = true;
+                    self.truncated_messages.remove(&msg_idx);
+                }
+            }
+            if task.rx.is_closed() {
+                let outcome = task.outcome.try_recv().ok();
+                if let Some(chat_idx) = self.chats.iter().position(|c| c.id == *chat_id) {
+                    let chat = &mut self.chats[chat_idx];
+                    chat.streaming = false;
+                    restore_empty_regeneration(chat);
+                    if let Some(Ok(reply)) = &outcome
+                        && matches!(task.target, StreamTarget::Chat { .. })
+                        && let Some(last) = chat.messages.last_mut()
+                        && last.role == "assistant"
+                    {
+                        let mut usage = reply.usage;
+                        usage.cost = last
+                            .model
+                            .as_ref()
+                            .and_then(|model| self.pricing.get(model))
+                            .map(|per_million| usage.total() as f64 * per_million / 1_000_000.0);
+                        match &mut last.usage {
+                            Some(total) => total.add(usage),
+                            None => last.usage = Some(usage),
+                        }
+                    }
+                    if outcome.as_ref().is_some_and(|o| {
+                        o.as_ref().is_ok_and(|reply| reply.finish == Finish::Length)
+                    }) && let Some(last) = chat.messages.last_mut()
+                        && last.role == "assistant"
+                    {
+                        last.cut_off = true;
+                        if chat.auto_continued < self.auto_continue {
+                            chat.auto_continued += 1;
+                            to_continue.push(chat_idx);
+                        } else {
+                            self.info_message = Some(
+                                "The reply hit the token limit; press r to continue".to_string(),
+                            );
+                        }
+                        self.need_rebuild_cache = true;
+                    }
+                    if let Some(Err(error)) = outcome {
+                        chat.chain = None;
+                        match chat.messages.last_mut() {
+                            Some(last) if last.role == "user" => {
+                                last.failed = Some(error.clone());
+                            }
+                            _ => {}
+                        }
+                        self.error_message = Some(error);
+                        self.need_rebuild_cache = true;
+                    }
+                    if self.auto_title.enabled && needs_title(chat) {
+                        to_title.push(chat_idx);
+                    }
+                    if self.ui_settings.truncate_assistant
+                        && chat_idx == self.current_chat
+                        && chat.messages.last().is_some_and(|m| m.role == "assistant")
+                    {
+                        self.truncated_messages.insert(chat.messages.len() - 1);
+                        self.need_rebuild_cache = true;
+                    }
+                }
+                to_remove.push(chat_id.clone());
+            }
+        }
+        if !to_remove.is_empty() {
+            self.history_dirty = true;
+        }
+        for chat_id in to_remove {
             self.stream_tasks.remove(&chat_id);
         }
+        for chat_idx in to_title {
+            self.start_title_stream(chat_idx);
+        }
+        for chat_idx in to_continue {
+            if let Err(e) = self.continue_reply(chat_idx) {
+                self.set_error(&e);
+            }
+        }
 
         for (msg_idx, content) in processed_chunks {
-            new_code_blocks.extend(self.parse_code_blocks_helper(msg_idx, &content));
+            self.code_blocks
+                .insert(msg_idx, parse_code_blocks(&content));
         }
 
-        self.code_blocks.extend(new_code_blocks);
         if content_updated {
             self.jump_to_last_message();
         }
     }
 
-    fn parse_code_blocks_helper(&self, msg_idx: usize, content: &str) -> Vec<(usize, CodeBlock)> {
-        let opening_re = Regex::new(r"^```(\w+)?\s*$").unwrap();
-        let closing_re = Regex::new(r"^```\s*$").unwrap();
+    /// Text of display lines `lo..=hi` for yanking. Code-block lines yield their
+    /// raw source (no gutter or line numbers) and block borders are dropped.
+    pub fn selection_text(&self, lo: usize, hi: usize) -> Vec<String> {
+        (lo..=hi)
+            .filter_map(|i| match self.display_line_kinds.get(i) {
+                Some(LineKind::CodeBorder) => None,
+                // A selection starting mid-line still yanks that whole line.
+                Some(LineKind::CodeWrap) if i == lo => self.display_line_kinds[..i]
+                    .iter()
+                    .rev()
+                    .find_map(|kind| match kind {
+                        LineKind::Code(raw) => Some(raw.clone()),
+                        _ => None,
+                    }),
+                Some(LineKind::CodeWrap) => None,
+                Some(LineKind::Code(raw)) => Some(raw.clone()),
+                _ => self.display_buffer_text_content.get(i).cloned(),
+            })
+            .collect()
+    }
+
+    /// The Visual selection as display lines `lo..=hi`. It outlives Visual
+    /// mode while a `:` command typed from it runs.
+    pub fn selection(&self) -> Option<(usize, usize)> {
+        let (start, end) = (self.visual_start?, self.visual_end?);
+        Some((start.min(end), start.max(end)))
+    }
 
-        let mut blocks = Vec::new();
-        let mut idx = 0;
-        let lines: Vec<&str> = content.lines().collect();
-        while idx < lines.len() {
-            if let Some(_) = opening_re.captures(lines[idx]) {
-                idx += 1;
-                let mut code_lines = Vec::new();
-                while idx < lines.len() && !closing_re.is_match(lines[idx]) {
-                    code_lines.push(lines[idx]);
-                    idx += 1;
+    /// The full text of the messages display lines `lo..=hi` are part of,
+    /// as written rather than as shown, separated by blank lines.
+    pub fn selection_messages(&self, lo: usize, hi: usize) -> Vec<String> {
+        let chat = self.chats.get(self.current_chat);
+        let mut indices: Vec<usize> = (lo..=hi)
+            .filter_map(|i| self.line_to_message.get(i).map(|&(msg_idx, _)| msg_idx))
+            .collect();
+        indices.dedup();
+        indices
+            .into_iter()
+            .filter_map(|idx| chat?.messages.get(idx))
+            .map(|message| message.content.clone())
+            .collect()
+    }
+
+    /// Line, word, character and estimated token counts of the Visual
+    /// selection, as shown in the status bar.
+    pub fn selection_stats(&self) -> Option<String> {
+        let (start, end) = (self.visual_start?, self.visual_end?);
+        let text = self
+            .selection_text(start.min(end), start.max(end))
+            .join("\n");
+        Some(format!(
+            "{} lines · {} words · {} chars · ~{} tok",
+            text.lines().count().max(1),
+            text.split_whitespace().count(),
+            text.chars().count(),
+            estimate_tokens(&text)
+        ))
+    }
+
+    /// Display lines of the code block at `line`: just the source lines, or
+    /// `around` it including the borders.
+    pub fn code_block_range(&self, line: usize, around: bool) -> Option<(usize, usize)> {
+        let kinds = &self.display_line_kinds;
+        let is_code =
+            |i: usize| matches!(kinds.get(i), Some(LineKind::Code(_) | LineKind::CodeWrap));
+        let start = match kinds.get(line)? {
+            LineKind::Code(_) | LineKind::CodeWrap => line,
+            // On a border: step into the block it closes or opens.
+            LineKind::CodeBorder if line + 1 < kinds.len() && is_code(line + 1) => line + 1,
+            LineKind::CodeBorder if line > 0 && is_code(line - 1) => line - 1,
+            _ => return None,
+        };
+        let mut lo = start;
+        while lo > 0 && is_code(lo - 1) {
+            lo -= 1;
+        }
+        let mut hi = start;
+        while is_code(hi + 1) {
+            hi += 1;
+        }
+        if around {
+            if lo > 0 && kinds[lo - 1] == LineKind::CodeBorder {
+                lo -= 1;
+            }
+            if kinds.get(hi + 1) == Some(&LineKind::CodeBorder) {
+                hi += 1;
+            }
+        }
+        Some((lo, hi))
+    }
+
+    /// The code block the cursor is in, or the only block of the message
+    /// under the cursor.
+    pub fn block_under_cursor(&self) -> Result<&CodeBlock, String> {
+        if let Some((msg_idx, block)) = self.code_block_at(self.cursor_line) {
+            return self
+                .code_blocks
+                .get(&msg_idx)
+                .and_then(|blocks| blocks.get(block))
+                .ok_or_else(|| "The code block is not ready yet".to_string());
+        }
+        let (msg_idx, _) = self
+            .line_to_message
+            .get(self.cursor_line)
+            .ok_or("No message at the cursor")?;
+        match self.code_blocks.get(msg_idx).map(Vec::as_slice) {
+            Some([block]) => Ok(block),
+            None | Some([]) => Err("No code block in this message".to_string()),
+            Some(_) => Err("Move the cursor into one of this message's code blocks".to_string()),
+        }
+    }
+
+    /// The message and block index of the code block at `line`, counting
+    /// the block borders between the message's first line and the block.
+    pub fn code_block_at(&self, line: usize) -> Option<(usize, usize)> {
+        let (lo, _) = self.code_block_range(line, true)?;
+        let (msg_idx, _) = *self.line_to_message.get(lo)?;
+        let borders = (0..lo)
+            .rev()
+            .take_while(|&i| self.line_to_message.get(i).map(|m| m.0) == Some(msg_idx))
+            .filter(|&i| self.display_line_kinds.get(i) == Some(&LineKind::CodeBorder))
+            .count();
+        Some((msg_idx, borders / 2))
+    }
+
+    /// Stores a yank in the pending register (uppercase appends to the
+    /// lowercase one) and in the unnamed register. Returns the register name
+    /// if one was chosen, in which case the clipboard is left alone.
+    pub fn store_yank(&mut self, text: &str) -> Option<char> {
+        self.registers.insert('"', text.to_string());
+        let register = self.pending_register.take()?;
+        let name = register.to_ascii_lowercase();
+        let slot = self.registers.entry(name).or_default();
+        if register.is_ascii_uppercase() && !slot.is_empty() {
+            slot.push('\n');
+            slot.push_str(text);
+        } else {
+            *slot = text.to_string();
+        }
+        Some(name)
+    }
+
+    /// Index of the message under the cursor in the current chat.
+    pub fn cursor_message(&self) -> Option<usize> {
+        self.line_to_message
+            .get(self.cursor_line)
+            .map(|&(msg_idx, _)| msg_idx)
+    }
+
+    /// The cursor as (message index, line within that message).
+    fn cursor_anchor_here(&self) -> Option<(usize, usize)> {
+        let &(msg_idx, _) = self.line_to_message.get(self.cursor_line)?;
+        let first = self
+            .line_to_message
+            .iter()
+            .position(|(m, _)| *m == msg_idx)
+            .unwrap_or(self.cursor_line);
+        Some((msg_idx, self.cursor_line - first))
+    }
+
+    pub fn set_mark(&mut self, name: char) -> Result<(), String> {
+        let anchor = self
+            .cursor_anchor_here()
+            .ok_or_else(|| "No message at the cursor".to_string())?;
+        let chat = self
+            .chats
+            .get_mut(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.marks.insert(name, anchor);
+        Ok(())
+    }
+
+    pub fn jump_to_mark(&mut self, name: char) -> Result<(), String> {
+        let mark = self
+            .chats
+            .get(self.current_chat)
+            .and_then(|c| c.marks.get(&name).copied())
+            .ok_or_else(|| format!("Mark '{}' is not set in this chat", name))?;
+        self.push_jump();
+        self.cursor_anchor = Some(mark);
+        self.need_rebuild_cache = true;
+        Ok(())
+    }
+
+    fn current_position(&self) -> Option<(String, usize)> {
+        let chat = self.chats.get(self.current_chat)?;
+        Some((chat.id.clone(), self.cursor_message().unwrap_or(0)))
+    }
+
+    /// Records the cursor position before a jump, dropping any positions
+    /// that were ahead of it in the jump list.
+    pub fn push_jump(&mut self) {
+        let Some(position) = self.current_position() else {
+            return;
+        };
+        self.jump_list.truncate(self.jump_idx);
+        if self.jump_list.last() != Some(&position) {
+            self.jump_list.push(position);
+        }
+        if self.jump_list.len() > 100 {
+            self.jump_list.remove(0);
+        }
+        self.jump_idx = self.jump_list.len();
+    }
+
+    /// Moves `Ctrl-o` (back) or `Ctrl-i` (forward) through the jump list.
+    pub fn jump_back(&mut self, forward: bool) -> bool {
+        if !forward && self.jump_idx == self.jump_list.len() {
+            // Remember where we left from so Ctrl-i can return here.
+            self.push_jump();
+            self.jump_idx = self.jump_list.len() - 1;
+        }
+        let target = if forward {
+            self.jump_idx + 1
+        } else {
+            match self.jump_idx.checked_sub(1) {
+                Some(idx) => idx,
+                None => return false,
+            }
+        };
+        let Some((chat_id, msg_idx)) = self.jump_list.get(target).cloned() else {
+            return false;
+        };
+        self.jump_idx = target;
+        self.go_to_message(&chat_id, msg_idx)
+    }
+
+    /// Switches to `chat_id` if needed and puts the cursor on `msg_idx`.
+    pub fn go_to_message(&mut self, chat_id: &str, msg_idx: usize) -> bool {
+        let Some(chat_idx) = self.chats.iter().position(|c| c.id == chat_id) else {
+            return false;
+        };
+        if chat_idx != self.current_chat {
+            self.switch_chat(chat_idx);
+        }
+        self.cursor_anchor = Some((msg_idx, 0));
+        self.need_rebuild_cache = true;
+        true
+    }
+
+    /// Chat indices, the chat with the most recent message first. Chats
+    /// without timestamps count as oldest, newer-created ones first.
+    pub fn chats_by_recency(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.chats.len()).collect();
+        order.sort_by_key(|&idx| {
+            let last = self.chats[idx].last_activity().unwrap_or(0);
+            std::cmp::Reverse((last, idx))
+        });
+        order
+    }
+
+    /// Brings the search index up to date with the chats in memory and
+    /// clears the query, before `/` or the chat picker filter is used.
+    pub fn start_search(&mut self) {
+        if let Err(e) = search::update(&self.chats) {
+            self.set_error(&format!("Search index not updated: {}", e));
+        }
+        self.search_query.clear();
+        self.search_hits.clear();
+        self.selected_search_idx = 0;
+    }
+
+    /// Re-runs the search for `search_query`.
+    pub fn refresh_search(&mut self) {
+        self.selected_search_idx = 0;
+        self.selected_picker_idx = 0;
+        self.search_hits = match search::search(&self.search_query, 500) {
+            Ok(hits) => hits,
+            Err(e) => {
+                self.set_error(&format!("Search failed: {}", e));
+                Vec::new()
+            }
+        };
+    }
+
+    /// The chats the picker lists: by recency, or those matching its filter
+    /// with the best match first.
+    pub fn picker_chats(&self) -> Vec<usize> {
+        if self.search_query.trim().is_empty() {
+            return self.chats_by_recency();
+        }
+        let mut order = Vec::new();
+        for hit in &self.search_hits {
+            if let Some(idx) = self.chats.iter().position(|c| c.id == hit.chat_id)
+                && !order.contains(&idx)
+            {
+                order.push(idx);
+            }
+        }
+        order
+    }
+
+    /// Removes the oldest chats beyond the `[retention]` limits, sparing
+    /// protected chats, streaming ones and the open one. They go to the
+    /// trash, or to a file in `archives/` when the trash is off, so a limit
+    /// set too low can be undone; a chat that cannot be put away stays.
+    /// Returns how many were removed.
+    pub fn apply_retention(&mut self) -> usize {
+        let limits = &self.retention;
+        if limits.max_chats == 0 && limits.max_age_days == 0 && limits.max_size_mb == 0 {
+            return 0;
+        }
+        let current = self.chats.get(self.current_chat).map(|c| c.id.clone());
+        let removable = |chat: &Chat| {
+            !chat.protected() && !chat.streaming && Some(&chat.id) != current.as_ref()
+        };
+        let mut doomed: HashSet<String> = HashSet::new();
+        let oldest_first: Vec<usize> = self.chats_by_recency().into_iter().rev().collect();
+
+        if limits.max_age_days > 0 {
+            let cutoff = chrono::Utc::now().timestamp() - limits.max_age_days as i64 * 86_400;
+            for chat in &self.chats {
+                if removable(chat) && chat.last_activity().is_some_and(|t| t < cutoff) {
+                    doomed.insert(chat.id.clone());
                 }
-                let content_str = code_lines.join("\n");
-                blocks.push((
-                    msg_idx,
-                    CodeBlock {
-                        content: content_str,
-                    },
-                ));
             }
-            idx += 1;
         }
-        blocks
+        if limits.max_chats > 0 {
+            let mut count = self.chats.len() - doomed.len();
+            for &idx in &oldest_first {
+                if count <= limits.max_chats {
+                    break;
+                }
+                let chat = &self.chats[idx];
+                if removable(chat) && doomed.insert(chat.id.clone()) {
+                    count -= 1;
+                }
+            }
+        }
+        if limits.max_size_mb > 0 {
+            let limit = limits.max_size_mb * 1024 * 1024;
+            let mut total: u64 = self
+                .chats
+                .iter()
+                .filter(|c| !doomed.contains(&c.id))
+                .map(Chat::size)
+                .sum();
+            for &idx in &oldest_first {
+                if total <= limit {
+                    break;
+                }
+                let chat = &self.chats[idx];
+                if removable(chat) && doomed.insert(chat.id.clone()) {
+                    total = total.saturating_sub(chat.size());
+                }
+            }
+        }
+        let mut removed = Vec::new();
+        for idx in 0..self.chats.len() {
+            if doomed.contains(&self.chats[idx].id) {
+                self.load_chat(idx);
+                if self.chats[idx].stub.is_none() {
+                    removed.push(self.chats[idx].clone());
+                }
+            }
+        }
+        let mut error = None;
+        if self.trash_days > 0 {
+            removed.retain(|chat| storage::trash(chat).map_err(|e| error = Some(e)).is_ok());
+        } else if !removed.is_empty()
+            && let Err(e) = storage::archive("retention", &removed)
+        {
+            error = Some(e);
+            removed.clear();
+        }
+        if let Some(e) = error {
+            self.set_error(&format!(
+                "Kept chats past the retention limits that could not be put away: {}",
+                e
+            ));
+        }
+        let removed: HashSet<String> = removed.into_iter().map(|c| c.id).collect();
+        self.remove_chats(&removed);
+        removed.len()
+    }
+
+    /// Removes the chats with these ids, keeping the open and selected chats
+    /// where they are if they stay.
+    pub fn remove_chats(&mut self, doomed: &HashSet<String>) {
+        if doomed.is_empty() {
+            return;
+        }
+        let current = self.chats.get(self.current_chat).map(|c| c.id.clone());
+        let selected = self
+            .chats
+            .get(self.selected_sidebar_idx)
+            .map(|c| c.id.clone());
+        self.chats.retain(|c| !doomed.contains(&c.id));
+        for id in doomed {
+            self.chat_views.remove(id);
+        }
+        let position = |id: &Option<String>| {
+            id.as_ref()
+                .and_then(|id| self.chats.iter().position(|c| &c.id == id))
+        };
+        self.current_chat = position(&current).unwrap_or(0);
+        self.selected_sidebar_idx = position(&selected).unwrap_or(self.current_chat);
+        self.need_rebuild_cache = true;
+    }
+
+    /// Bookmarked messages across all chats as `(chat index, message index)`.
+    pub fn bookmarks(&self) -> Vec<(usize, usize)> {
+        self.chats
+            .iter()
+            .enumerate()
+            .flat_map(|(chat_idx, chat)| {
+                chat.messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, m)| m.bookmarked)
+                    .map(move |(msg_idx, _)| (chat_idx, msg_idx))
+            })
+            .collect()
+    }
+
+    /// Invalidates the wrapped transcript after a terminal resize, keeping
+    /// the cursor on the same message and the view pinned to the bottom if
+    /// it was there.
+    pub fn handle_resize(&mut self) {
+        if let Some(anchor) = self.cursor_anchor_here() {
+            self.cursor_anchor = Some(anchor);
+        }
+        if self.chat_scroll >= self.max_chat_scroll {
+            self.chat_scroll = usize::MAX;
+        }
+        self.need_rebuild_cache = true;
     }
 
     #[inline(always)]
@@ -425,13 +3040,196 @@ impl<'a> App<'a> {
         self.need_rebuild_cache = true;
     }
 
-    pub fn add_user_message(&mut self, content: String) {
+    /// Removes a message from the current chat, shifting the indices of
+    /// later messages in the truncation state.
+    pub fn delete_message(&mut self, msg_idx: usize) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get_mut(self.current_chat)
+            .ok_or_else(|| "No chat selected".to_string())?;
+        chat.writable()?;
+        if chat.streaming {
+            return Err("Wait for the current response to finish".to_string());
+        }
+        if msg_idx >= chat.messages.len() {
+            return Err("No message at the cursor".to_string());
+        }
+        chat.messages.remove(msg_idx);
+        chat.marks.retain(|_, (idx, _)| *idx != msg_idx);
+        for (idx, _) in chat.marks.values_mut() {
+            if *idx > msg_idx {
+                *idx -= 1;
+            }
+        }
+        self.truncated_messages = self
+            .truncated_messages
+            .iter()
+            .filter(|&&idx| idx != msg_idx)
+            .map(|&idx| if idx > msg_idx { idx - 1 } else { idx })
+            .collect();
+        self.code_blocks = std::mem::take(&mut self.code_blocks)
+            .into_iter()
+            .filter(|(idx, _)| *idx != msg_idx)
+            .map(|(idx, blocks)| (if idx > msg_idx { idx - 1 } else { idx }, blocks))
+            .collect();
+        self.need_rebuild_cache = true;
+        Ok(())
+    }
+
+    /// Enters Insert mode with the current chat's draft in the input.
+    pub fn begin_insert(&mut self) -> Result<(), String> {
         if let Some(chat) = self.chats.get_mut(self.current_chat) {
-            let msg_idx = chat.messages.len();
-            chat.messages.push(Message::new(Role::User, &content));
+            chat.writable()?;
+            self.input = std::mem::take(&mut chat.draft);
+        }
+        self.mode = Mode::Insert;
+        Ok(())
+    }
+
+    /// Copies the input into the current chat's draft while in Insert mode,
+    /// so it is saved with the history.
+    pub fn sync_draft(&mut self) {
+        if self.mode == Mode::Insert
+            && let Some(chat) = self.chats.get_mut(self.current_chat)
+        {
+            chat.draft.clone_from(&self.input);
+        }
+    }
+
+    fn chat_fingerprints(&self) -> Vec<(String, u64)> {
+        self.chats
+            .iter()
+            .map(|chat| {
+                let mut hasher = DefaultHasher::new();
+                serde_json::to_vec(chat)
+                    .unwrap_or_default()
+                    .hash(&mut hasher);
+                (chat.id.clone(), hasher.finish())
+            })
+            .collect()
+    }
+
+    /// What a key press can change in the history, in one hash: the chats in
+    /// order, the contents of the shown chat and of the one selected in the
+    /// sidebar, and the input that becomes the shown chat's draft. Cheaper
+    /// than `history_changed`, which looks at every chat.
+    pub fn edit_fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.input.hash(&mut hasher);
+        for chat in &self.chats {
+            chat.id.hash(&mut hasher);
+        }
+        for idx in [self.current_chat, self.selected_sidebar_idx] {
+            idx.hash(&mut hasher);
+            if let Some(chat) = self.chats.get(idx) {
+                serde_json::to_vec(chat)
+                    .unwrap_or_default()
+                    .hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Whether a save would change anything: a chat was edited, added,
+    /// deleted or moved since the history was last written.
+    pub fn history_changed(&self) -> bool {
+        self.chat_fingerprints() != self.saved_chats
+    }
+
+    /// Records the chats as written.
+    pub fn mark_saved(&mut self) {
+        self.saved_chats = self.chat_fingerprints();
+        self.history_dirty = false;
+        self.save_soon = false;
+    }
+
+    /// Whether one of the Settings screens or its popups is open.
+    pub fn in_settings(&self) -> bool {
+        matches!(
+            self.mode,
+            Mode::Settings
+                | Mode::ApiKeyInput
+                | Mode::CustomModelInput
+                | Mode::KeyCapture
+                | Mode::PromptInput
+                | Mode::PromptRename
+                | Mode::PromptPreview
+        )
+    }
+
+    /// Leaves Insert mode, keeping unsent input as the chat's draft.
+    /// Returns whether a draft was kept or dropped.
+    pub fn end_insert(&mut self) -> bool {
+        self.sync_draft();
+        let had_input = !self.input.is_empty();
+        self.input.clear();
+        self.mode = Mode::Normal;
+        had_input
+    }
+
+    /// Inserts pasted text into whichever input is being edited. The command
+    /// line and chat titles are single-line, so newlines become spaces there.
+    pub fn paste_text(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        match self.mode {
+            Mode::Insert | Mode::PromptInput => self.input.push_str(&text),
+            Mode::RenameChat => self.input.push_str(&text.replace('\n', " ")),
+            Mode::Command => self.command.push_str(&text.replace('\n', " ")),
+            _ => {}
+        }
+    }
+
+    /// Starts running a code block for the current chat.
+    pub fn run_block(&mut self, argv: Vec<String>) -> Result<(), String> {
+        let chat = self
+            .chats
+            .get(self.current_chat)
+            .ok_or("No chat selected")?;
+        chat.writable()?;
+        let (tx, outcome) = oneshot::channel();
+        task::spawn(async move {
+            let _ = tx.send(run_block_command(argv).await);
+        });
+        self.block_runs.push(BlockRun {
+            chat_id: chat.id.clone(),
+            outcome,
+        });
+        Ok(())
+    }
+
+    /// Adds the output of finished code block runs to their chats.
+    pub fn process_block_runs(&mut self) {
+        let mut finished = Vec::new();
+        self.block_runs
+            .retain_mut(|run| match run.outcome.try_recv() {
+                Ok(text) => {
+                    finished.push((run.chat_id.clone(), text));
+                    false
+                }
+                Err(oneshot::error::TryRecvError::Empty) => true,
+                Err(oneshot::error::TryRecvError::Closed) => false,
+            });
+        for (chat_id, text) in finished {
+            self.history_dirty = true;
+            let shown = self.chats.get(self.current_chat).map(|c| c.id.clone());
+            if shown.as_deref() == Some(chat_id.as_str()) {
+                self.add_user_message(Message::new(Role::User, text));
+                self.set_info("Code block output added to the chat");
+            } else if let Some(chat) = self.chats.iter_mut().find(|c| c.id == chat_id) {
+                chat.messages.push(Message::new(Role::User, text));
+            }
+        }
+    }
+
+    pub fn add_user_message(&mut self, message: Message) {
+        let Some(msg_idx) = self.chats.get(self.current_chat).map(|c| c.messages.len()) else {
+            return;
+        };
+        let blocks = parse_code_blocks(&message.content);
+        if let Some(chat) = self.chats.get_mut(self.current_chat) {
+            chat.messages.push(message);
             self.truncated_messages.insert(msg_idx);
-            self.code_blocks
-                .extend(self.parse_code_blocks_helper(msg_idx, &content));
+            self.code_blocks.insert(msg_idx, blocks);
             self.need_rebuild_cache = true;
         }
     }
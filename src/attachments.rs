@@ -0,0 +1,126 @@
+use crate::storage;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Stored files this recent are kept even if no saved chat refers to them
+/// yet, since the message they belong to may not have been saved.
+const GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Where a file with this hash is stored: `attachments/ab/abcd...`.
+fn blob_path(hash: &str) -> io::Result<PathBuf> {
+    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not an attachment hash", hash),
+        ));
+    }
+    Ok(storage::attachments_dir().join(&hash[..2]).join(hash))
+}
+
+/// Adds `data` to the store, returning its SHA-256 hash. Files are named by
+/// their contents, so the same file attached twice is stored once.
+pub fn put(data: &[u8]) -> io::Result<String> {
+    let hash = format!("{:x}", Sha256::digest(data));
+    let path = blob_path(&hash)?;
+    if !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        storage::write_atomic(&path, data)?;
+    }
+    Ok(hash)
+}
+
+/// The contents of a stored file.
+pub fn get(hash: &str) -> io::Result<Vec<u8>> {
+    storage::read_file(&blob_path(hash)?)
+}
+
+/// The media type of a file, from its extension.
+pub fn mime_type(name: &str) -> &'static str {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("pdf") => "application/pdf",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Every stored file.
+pub fn files() -> io::Result<Vec<PathBuf>> {
+    let shards = match fs::read_dir(storage::attachments_dir()) {
+        Ok(shards) => shards,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for shard in shards.flatten() {
+        if shard.path().is_dir() {
+            files.extend(fs::read_dir(shard.path())?.flatten().map(|e| e.path()));
+        }
+    }
+    Ok(files)
+}
+
+/// Collects the hashes mentioned in `data` into `hashes`.
+fn hashes_in(data: &[u8], hashes: &mut HashSet<String>) {
+    for run in data.split(|b| !b.is_ascii_hexdigit()) {
+        if run.len() == 64 {
+            hashes.insert(String::from_utf8_lossy(run).to_ascii_lowercase());
+        }
+    }
+}
+
+/// Removes stored files that no saved chat refers to any more, returning how
+/// many were removed. Backups and recovery files count as saved chats, so
+/// restoring one never finds its attachments gone. Nothing is removed if
+/// any copy of the history cannot be read.
+pub fn collect_garbage() -> io::Result<usize> {
+    let files = files()?;
+    if files.is_empty() {
+        return Ok(0);
+    }
+    let mut referenced = HashSet::new();
+    for path in storage::history_files()? {
+        match storage::read_file(&path) {
+            Ok(data) => hashes_in(&data, &mut referenced),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let mut removed = 0;
+    for path in files {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if referenced.contains(name) || name.ends_with(".tmp") {
+            continue;
+        }
+        let age = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        if age.is_some_and(|age| age > GRACE) && fs::remove_file(&path).is_ok() {
+            removed += 1;
+            // Drops the shard directory once it is empty.
+            if let Some(shard) = path.parent() {
+                let _ = fs::remove_dir(shard);
+            }
+        }
+    }
+    Ok(removed)
+}
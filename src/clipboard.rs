@@ -41,10 +41,34 @@ pub async fn copy_to_clipboard(text: &str) -> Result<()> {
     Ok(())
 }
 
+pub async fn paste_from_clipboard() -> Result<String> {
+    if !is_wayland_session() {
+        return Clipboard::new()
+            .context("Failed to initialize clipboard")?
+            .get_text()
+            .context("Failed to read clipboard text");
+    }
+
+    let output = TokioCommand::new("wl-paste")
+        .arg("--no-newline")
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .await
+        .context("Failed to run wl-paste")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "wl-paste exited with status: {}",
+            output.status
+        ));
+    }
+    String::from_utf8(output.stdout).context("Clipboard does not contain text")
+}
+
 #[inline(always)]
 fn is_wayland_session() -> bool {
     env::var_os("WAYLAND_DISPLAY").is_some()
         || env::var_os("XDG_SESSION_TYPE")
             .as_deref()
-            .map_or(false, |v| v == OsStr::new("wayland"))
+            .is_some_and(|v| v == OsStr::new("wayland"))
 }
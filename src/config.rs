@@ -1,6 +1,18 @@
-use directories::ProjectDirs;
+use crate::api::GenerationParams;
+use directories::{BaseDirs, ProjectDirs};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+    },
+    time::Duration,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct Prompt {
@@ -9,6 +21,15 @@ pub struct Prompt {
     pub active: bool,
 }
 
+/// A named sequence of prompts run one after another with `:chain`. Each
+/// step may use `{{prev}}` (the previous step's answer) and `{{input}}` (the
+/// text given after the chain name).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Chain {
+    pub name: String,
+    pub steps: Vec<String>,
+}
+
 impl Prompt {
     #[inline]
     pub fn new<N: Into<Box<str>>, C: Into<Box<str>>>(name: N, content: C, active: bool) -> Self {
@@ -25,6 +46,10 @@ pub struct ProviderConfig {
     pub name: String,
     pub api_key: String,
     pub enabled_models: Vec<String>,
+    /// Defaults for every request to this provider; a chat's own `:set`
+    /// values take precedence.
+    #[serde(default, skip_serializing_if = "GenerationParams::is_empty")]
+    pub params: GenerationParams,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -52,6 +77,7 @@ impl CustomModel {
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct KeyBindings {
     pub new_chat: String,
     pub toggle_sidebar: String,
@@ -63,16 +89,671 @@ pub struct KeyBindings {
     pub exit_insert_mode: String,
     pub command_mode: String,
     pub open_settings: String,
+    pub stop_generation: String,
+    pub paste: String,
+    /// Keys that copy the first, second, ... code block of the message under
+    /// the cursor. Empty by default; `c` followed by a number does the same.
     pub copy_code_blocks: Vec<String>,
 }
 
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            new_chat: "n".into(),
+            toggle_sidebar: "s".into(),
+            switch_focus: "Tab".into(),
+            lock_focus: "l".into(),
+            delete_chat: "d".into(),
+            copy_code: "y".into(),
+            copy_code_blocks: Vec::new(),
+            insert_mode: "i".into(),
+            exit_insert_mode: "Esc".into(),
+            command_mode: ":".into(),
+            open_settings: "o".into(),
+            stop_generation: "Ctrl-c".into(),
+            paste: "Ctrl-v".into(),
+        }
+    }
+}
+
+/// Rebindable actions as `(config key, label)`, in the order shown in the
+/// Shortcuts tab.
+pub const KEY_ACTIONS: &[(&str, &str)] = &[
+    ("new_chat", "New chat"),
+    ("toggle_sidebar", "Toggle sidebar"),
+    ("switch_focus", "Switch focus"),
+    ("lock_focus", "Lock focus"),
+    ("delete_chat", "Delete chat"),
+    ("copy_code", "Yank selection"),
+    ("insert_mode", "Insert mode"),
+    ("exit_insert_mode", "Leave insert mode"),
+    ("command_mode", "Command mode"),
+    ("open_settings", "Open settings"),
+    ("stop_generation", "Stop generation"),
+    ("paste", "Paste clipboard"),
+];
+
+impl KeyBindings {
+    /// Config key, label and binding for the `idx`-th row of the Shortcuts
+    /// tab.
+    pub fn entry(&self, idx: usize) -> Option<(&'static str, &'static str, &str)> {
+        let (action, label) = KEY_ACTIONS.get(idx)?;
+        self.get(action).map(|key| (*action, *label, key))
+    }
+
+    /// Puts `action` back on its default key and returns that key.
+    pub fn reset(&mut self, action: &str) -> Option<String> {
+        let default = Self::default().get(action)?.to_string();
+        *self.get_mut(action)? = default.clone();
+        Some(default)
+    }
+
+    pub fn get(&self, action: &str) -> Option<&str> {
+        let key = match action {
+            "new_chat" => &self.new_chat,
+            "toggle_sidebar" => &self.toggle_sidebar,
+            "switch_focus" => &self.switch_focus,
+            "lock_focus" => &self.lock_focus,
+            "delete_chat" => &self.delete_chat,
+            "copy_code" => &self.copy_code,
+            "insert_mode" => &self.insert_mode,
+            "exit_insert_mode" => &self.exit_insert_mode,
+            "command_mode" => &self.command_mode,
+            "open_settings" => &self.open_settings,
+            "stop_generation" => &self.stop_generation,
+            "paste" => &self.paste,
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    pub fn get_mut(&mut self, action: &str) -> Option<&mut String> {
+        let key = match action {
+            "new_chat" => &mut self.new_chat,
+            "toggle_sidebar" => &mut self.toggle_sidebar,
+            "switch_focus" => &mut self.switch_focus,
+            "lock_focus" => &mut self.lock_focus,
+            "delete_chat" => &mut self.delete_chat,
+            "copy_code" => &mut self.copy_code,
+            "insert_mode" => &mut self.insert_mode,
+            "exit_insert_mode" => &mut self.exit_insert_mode,
+            "command_mode" => &mut self.command_mode,
+            "open_settings" => &mut self.open_settings,
+            "stop_generation" => &mut self.stop_generation,
+            "paste" => &mut self.paste,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpinnerPreset {
+    #[default]
+    Cat,
+    Ascii,
+    Dots,
+}
+
+impl SpinnerPreset {
+    pub fn frames(self) -> &'static [&'static str] {
+        match self {
+            SpinnerPreset::Cat => CAT_FRAMES,
+            SpinnerPreset::Ascii => ASCII_FRAMES,
+            SpinnerPreset::Dots => DOTS_FRAMES,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LoadingAnimation {
+    pub preset: SpinnerPreset,
+    /// Custom frames; when non-empty they replace the preset.
+    pub frames: Vec<String>,
+    pub interval_ms: u64,
+}
+
+impl Default for LoadingAnimation {
+    fn default() -> Self {
+        Self {
+            preset: SpinnerPreset::Cat,
+            frames: Vec::new(),
+            interval_ms: 300,
+        }
+    }
+}
+
+impl LoadingAnimation {
+    pub fn frame_at(&self, elapsed: Duration) -> &str {
+        let interval = self.interval_ms.max(1) as u128;
+        let tick = (elapsed.as_millis() / interval) as usize;
+        if self.frames.is_empty() {
+            let frames = self.preset.frames();
+            frames[tick % frames.len()]
+        } else {
+            &self.frames[tick % self.frames.len()]
+        }
+    }
+}
+
+/// What to do with secrets found in an outgoing message.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactAction {
+    /// Replace them with "[REDACTED]" in the request; the chat keeps the
+    /// original text.
+    #[default]
+    Redact,
+    /// Ask before sending the message unchanged.
+    Warn,
+    Off,
+}
+
+/// Filters applied to requests before they reach a provider. Built-in rules
+/// cover AWS keys, `sk-...` API keys, GitHub and Slack tokens and private key
+/// blocks; `patterns` adds regular expressions.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Redaction {
+    pub action: RedactAction,
+    pub patterns: Vec<String>,
+}
+
+/// How many colors the UI may use. `NO_COLOR` in the environment always wins.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// RGB syntax highlighting and 256-color accents.
+    #[default]
+    Truecolor,
+    /// Only the 16 ANSI colors, so the terminal's own palette applies.
+    Basic,
+    /// No colors at all; highlights fall back to reverse video.
+    None,
+}
+
+impl ColorMode {
+    pub fn effective(self) -> ColorMode {
+        static NO_COLOR: OnceLock<bool> = OnceLock::new();
+        let no_color =
+            *NO_COLOR.get_or_init(|| std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()));
+        if no_color { ColorMode::None } else { self }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ColorMode::Truecolor => "truecolor",
+            ColorMode::Basic => "basic",
+            ColorMode::None => "none",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageLayout {
+    #[default]
+    Flat,
+    Bubble,
+}
+
+/// Which side of the screen the chat list is on.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SidebarPosition {
+    #[default]
+    Left,
+    Right,
+}
+
+impl SidebarPosition {
+    pub fn label(self) -> &'static str {
+        match self {
+            SidebarPosition::Left => "left",
+            SidebarPosition::Right => "right",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct UiSettings {
+    pub message_layout: MessageLayout,
+    /// Show a "You" / model name header with a timestamp above each message.
+    pub role_headers: bool,
+    pub status_format: String,
+    pub code_line_numbers: bool,
+    /// Collapse long messages to this many lines; 0 disables truncation.
+    pub truncate_lines: usize,
+    pub truncate_assistant: bool,
+    /// Lines kept visible above and below the cursor when scrolling.
+    pub scrolloff: usize,
+    /// Syntax-highlighting theme for code blocks.
+    pub theme: String,
+    pub sidebar_width: u16,
+    pub sidebar: SidebarPosition,
+    pub timestamps: bool,
+    /// strftime pattern for displayed times, or `12h` / `24h`.
+    pub time_format: String,
+    pub colors: ColorMode,
+    /// Draw emoji, box drawing and other symbols as ASCII.
+    pub ascii: bool,
+    /// Theme file from the `themes` directory next to config.toml.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_theme: Option<String>,
+}
+
+impl UiSettings {
+    /// Options `:set` can change, by name.
+    pub const OPTIONS: [&'static str; 12] = [
+        "theme",
+        "sidebar_width",
+        "sidebar",
+        "timestamps",
+        "time_format",
+        "headers",
+        "line_numbers",
+        "truncate_lines",
+        "scrolloff",
+        "layout",
+        "colors",
+        "ascii",
+    ];
+
+    /// True for options that take no value (`:set timestamps`, `:set
+    /// notimestamps`).
+    pub fn is_flag(key: &str) -> bool {
+        matches!(key, "timestamps" | "headers" | "line_numbers" | "ascii")
+    }
+
+    /// Sets `key` from its text form.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let flag = || match value {
+            "" | "true" | "on" | "yes" | "1" => Ok(true),
+            "false" | "off" | "no" | "0" => Ok(false),
+            _ => Err(format!("{} must be on or off", key)),
+        };
+        match key {
+            "theme" if value.is_empty() => return Err("theme needs a name".to_string()),
+            "theme" => self.theme = value.to_string(),
+            "sidebar_width" => {
+                self.sidebar_width = value
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|w| (12..=60).contains(w))
+                    .ok_or("sidebar_width must be between 12 and 60")?
+            }
+            "sidebar" => {
+                self.sidebar = match value {
+                    "left" => SidebarPosition::Left,
+                    "right" => SidebarPosition::Right,
+                    _ => return Err("sidebar must be left or right".to_string()),
+                }
+            }
+            "timestamps" => self.timestamps = flag()?,
+            "time_format" => {
+                if !valid_time_format(expand_time_format(value)) {
+                    return Err(format!("Invalid time format: {}", value));
+                }
+                self.time_format = value.to_string();
+            }
+            "headers" => self.role_headers = flag()?,
+            "line_numbers" => self.code_line_numbers = flag()?,
+            "truncate_lines" => {
+                self.truncate_lines = value
+                    .parse()
+                    .map_err(|_| "truncate_lines must be a number (0 disables)")?
+            }
+            "scrolloff" => {
+                self.scrolloff = value.parse().map_err(|_| "scrolloff must be a number")?
+            }
+            "layout" => {
+                self.message_layout = match value {
+                    "flat" => MessageLayout::Flat,
+                    "bubble" => MessageLayout::Bubble,
+                    _ => return Err("layout must be flat or bubble".to_string()),
+                }
+            }
+            "colors" => {
+                self.colors = match value {
+                    "truecolor" => ColorMode::Truecolor,
+                    "basic" => ColorMode::Basic,
+                    "none" => ColorMode::None,
+                    _ => return Err("colors must be truecolor, basic or none".to_string()),
+                }
+            }
+            "ascii" => self.ascii = flag()?,
+            _ => return Err(format!("Unknown option: {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Current value of an option in the form `:set` takes.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let value = match key {
+            "theme" => self.theme.clone(),
+            "sidebar_width" => self.sidebar_width.to_string(),
+            "sidebar" => self.sidebar.label().to_string(),
+            "timestamps" => self.timestamps.to_string(),
+            "time_format" => self.time_format.clone(),
+            "headers" => self.role_headers.to_string(),
+            "line_numbers" => self.code_line_numbers.to_string(),
+            "truncate_lines" => self.truncate_lines.to_string(),
+            "scrolloff" => self.scrolloff.to_string(),
+            "layout" => match self.message_layout {
+                MessageLayout::Flat => "flat".to_string(),
+                MessageLayout::Bubble => "bubble".to_string(),
+            },
+            "colors" => match self.colors {
+                ColorMode::Truecolor => "truecolor".to_string(),
+                ColorMode::Basic => "basic".to_string(),
+                ColorMode::None => "none".to_string(),
+            },
+            "ascii" => self.ascii.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// The strftime pattern for displayed times. An invalid `time_format`
+    /// falls back to 24-hour time.
+    pub fn time_pattern(&self) -> &str {
+        let pattern = expand_time_format(&self.time_format);
+        if valid_time_format(pattern) {
+            pattern
+        } else {
+            "%H:%M"
+        }
+    }
+}
+
+fn expand_time_format(format: &str) -> &str {
+    match format {
+        "12h" => "%-I:%M %p",
+        "24h" => "%H:%M",
+        other => other,
+    }
+}
+
+fn valid_time_format(pattern: &str) -> bool {
+    !pattern.is_empty()
+        && !chrono::format::StrftimeItems::new(pattern)
+            .any(|item| matches!(item, chrono::format::Item::Error))
+}
+
+impl Default for UiSettings {
+    fn default() -> Self {
+        Self {
+            message_layout: MessageLayout::Flat,
+            role_headers: true,
+            status_format: DEFAULT_STATUS_FORMAT.into(),
+            code_line_numbers: false,
+            truncate_lines: 10,
+            truncate_assistant: false,
+            scrolloff: 0,
+            theme: DEFAULT_THEME.into(),
+            sidebar_width: 20,
+            sidebar: SidebarPosition::Left,
+            timestamps: true,
+            time_format: "24h".into(),
+            colors: ColorMode::Truecolor,
+            ascii: false,
+            color_theme: None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Settings {
+    /// Format version of the file; older files are migrated on load.
+    pub version: u32,
     pub providers: Vec<ProviderConfig>,
     pub keybindings: KeyBindings,
-    pub copy_code_blocks: Vec<String>,
     pub custom_models: Vec<CustomModel>,
     pub prompts: Vec<Prompt>,
+    /// Directory of `.md` files to use as the prompt library instead of
+    /// `prompts` above.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompts_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub loading_animation: LoadingAnimation,
+    #[serde(default)]
+    pub ui: UiSettings,
+    /// USD per million tokens, keyed by "Provider:model".
+    #[serde(default)]
+    pub pricing: HashMap<String, f64>,
+    /// Context window sizes in tokens, keyed by "Provider:model"; overrides
+    /// the built-in sizes of well-known models.
+    #[serde(default)]
+    pub context_windows: HashMap<String, usize>,
+    /// Library prompts attached to a chat while it uses a model, keyed by
+    /// "Provider:model" or the bare model name.
+    #[serde(default)]
+    pub model_prompts: HashMap<String, Vec<String>>,
+    /// "Provider:model" used when there is no chat to take the model from.
+    #[serde(default)]
+    pub default_model: Option<String>,
+    /// Seconds between automatic history saves; 0 only saves on exit.
+    #[serde(default = "default_autosave_secs")]
+    pub autosave_secs: u64,
+    #[serde(default)]
+    pub auto_title: AutoTitle,
+    #[serde(default)]
+    pub chains: Vec<Chain>,
+    /// Times a reply cut off by the token limit is continued automatically;
+    /// 0 leaves it to `r`.
+    #[serde(default)]
+    pub auto_continue: u32,
+    #[serde(default)]
+    pub redaction: Redaction,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub startup: Startup,
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub large_chats: LargeChats,
+}
+
+/// Limits on kept history, applied at startup and whenever history is
+/// saved. Read-only chats and chats with bookmarked or pinned messages are
+/// never removed. 0 disables a limit.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Retention {
+    pub max_chats: usize,
+    /// Days since a chat's latest message.
+    pub max_age_days: u64,
+    /// Total size of the history in megabytes.
+    pub max_size_mb: u64,
+}
+
+/// Sizes past which a chat is flagged as large and `:large` offers to
+/// summarize, split or archive it. 0 disables a limit.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LargeChats {
+    /// Messages in the chat.
+    pub messages: usize,
+    /// Total length of the message text in kilobytes.
+    pub size_kb: usize,
+    /// Latest messages a split leaves in the chat.
+    pub keep: usize,
+}
+
+impl Default for LargeChats {
+    fn default() -> Self {
+        Self {
+            messages: 500,
+            size_kb: 512,
+            keep: 20,
+        }
+    }
+}
+
+/// What meowi shows when it starts.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Startup {
+    pub open: StartupChat,
+    /// Start with the chat list shown.
+    pub sidebar: bool,
+    /// Bring back the view left at the last exit: the open chat, cursor and
+    /// scroll positions, focus, sidebar and Settings tab.
+    pub restore: bool,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Self {
+            open: StartupChat::Last,
+            sidebar: true,
+            restore: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupChat {
+    /// The chat with the most recent message.
+    #[default]
+    Last,
+    /// A fresh chat.
+    New,
+    /// A list of chats to pick from.
+    Picker,
+}
+
+/// How chat history is stored; read once at startup.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Storage {
+    /// Only written when it is not the default, so a later change of the
+    /// default can tell a chosen backend from a saved default.
+    #[serde(skip_serializing_if = "StorageBackend::is_default")]
+    pub backend: StorageBackend,
+    /// The history file (or directory, for `files`) instead of the default
+    /// one in the data directory. A leading `~/` is the home directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_path: Option<PathBuf>,
+    /// Copies of the history kept in `backups/` next to it; 0 disables them.
+    pub backups: usize,
+    /// A save backs up the history it replaces if the newest backup is at
+    /// least this old; 0 backs up before every save.
+    pub backup_minutes: u64,
+    /// Encrypt the history with a passphrase asked for at startup.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt: bool,
+    /// Days deleted chats are kept in the trash; 0 deletes them right away.
+    pub trash_days: u64,
+    /// Keep the history's directory as a git repository and commit to it
+    /// when meowi exits.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub git: bool,
+    /// Message of those commits; `{date}` and `{files}` are filled in.
+    pub git_message: String,
+    /// A server the `files` history is mirrored to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<Remote>,
+}
+
+/// A WebDAV folder or S3 bucket holding a copy of the history, so several
+/// machines share it. Secrets may be `${VAR}` references.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Remote {
+    /// `https://...` for a WebDAV folder, `s3://bucket/prefix` for S3.
+    pub url: String,
+    /// WebDAV login.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub password: String,
+    /// S3 service URL, for services other than AWS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// S3 region.
+    pub region: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub access_key: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub secret_key: String,
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            endpoint: None,
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+        }
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::default(),
+            history_path: None,
+            backups: 10,
+            backup_minutes: 15,
+            encrypt: false,
+            trash_days: 30,
+            git: false,
+            git_message: "meowi session {date} ({files} files)".to_string(),
+            remote: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// All chats in one `history.json`.
+    Json,
+    /// A SQLite database, `history.sqlite3`.
+    Sqlite,
+    /// One file per chat in `chats/`, plus an index giving their order.
+    #[default]
+    Files,
+}
+
+impl StorageBackend {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Naming new chats from their first exchange with a short background request.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AutoTitle {
+    pub enabled: bool,
+    /// "Provider:model" used for titling; the chat's own model if unset.
+    pub model: Option<String>,
+}
+
+impl Default for AutoTitle {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            model: None,
+        }
+    }
+}
+
+fn default_autosave_secs() -> u64 {
+    60
 }
 
 const OPENAI_MODELS: &[&str] = &[
@@ -90,7 +771,21 @@ const ANTHROPIC_MODELS: &[&str] = &[
     "claude-3-sonnet",
 ];
 const GROK_MODELS: &[&str] = &["grok-3-latest", "grok-3-mini-beta"];
-const COPY_CODE_BLOCKS: &[&str] = &["c", "C", "x", "X"];
+/// Context window sizes of built-in models, matched by model-name prefix.
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("claude-", 200_000),
+    ("grok-3", 131_072),
+];
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+const DEFAULT_STATUS_FORMAT: &str =
+    "{focus} │ {model} {streaming} {queued} │ {context} tok {cost} │ {message}";
+const CAT_FRAMES: &[&str] = &["🐱   ", "🐱.  ", "🐱.. ", "🐱...", "🐱 ..", "🐱  ."];
+const ASCII_FRAMES: &[&str] = &["|", "/", "-", "\\"];
+const DOTS_FRAMES: &[&str] = &["   ", ".  ", ".. ", "...", " ..", "  ."];
 
 impl Default for Settings {
     fn default() -> Self {
@@ -100,64 +795,623 @@ impl Default for Settings {
                     name: "OpenAI".into(),
                     api_key: String::new(),
                     enabled_models: OPENAI_MODELS.iter().map(|&s| s.into()).collect(),
+                    params: GenerationParams::default(),
                 },
                 ProviderConfig {
                     name: "Anthropic".into(),
                     api_key: String::new(),
                     enabled_models: ANTHROPIC_MODELS.iter().map(|&s| s.into()).collect(),
+                    params: GenerationParams::default(),
                 },
                 ProviderConfig {
                     name: "Grok".into(),
                     api_key: String::new(),
                     enabled_models: GROK_MODELS.iter().map(|&s| s.into()).collect(),
+                    params: GenerationParams::default(),
                 },
             ],
-            keybindings: KeyBindings {
-                new_chat: "n".into(),
-                toggle_sidebar: "s".into(),
-                switch_focus: "Tab".into(),
-                lock_focus: "l".into(),
-                delete_chat: "d".into(),
-                copy_code: "y".into(),
-                copy_code_blocks: COPY_CODE_BLOCKS.iter().map(|&s| s.into()).collect(),
-                insert_mode: "i".into(),
-                exit_insert_mode: "Esc".into(),
-                command_mode: ":".into(),
-                open_settings: "o".into(),
-            },
-            copy_code_blocks: COPY_CODE_BLOCKS.iter().map(|&s| s.into()).collect(),
+            version: CONFIG_VERSION,
+            keybindings: KeyBindings::default(),
             custom_models: Vec::new(),
             prompts: vec![Prompt::new("Default", "You are a helpful assistant.", true)],
+            prompts_dir: None,
+            loading_animation: LoadingAnimation::default(),
+            ui: UiSettings::default(),
+            pricing: HashMap::new(),
+            context_windows: HashMap::new(),
+            model_prompts: HashMap::new(),
+            default_model: None,
+            autosave_secs: default_autosave_secs(),
+            auto_title: AutoTitle::default(),
+            chains: Vec::new(),
+            auto_continue: 0,
+            redaction: Redaction::default(),
+            storage: Storage::default(),
+            startup: Startup::default(),
+            retention: Retention::default(),
+            large_chats: LargeChats::default(),
         }
     }
 }
 
-pub fn get_config_path() -> PathBuf {
-    let proj_dirs = ProjectDirs::from("com", "yourname", "meowi").unwrap();
-    let config_dir = proj_dirs.config_dir();
-    fs::create_dir_all(config_dir).unwrap();
-    config_dir.join("config.toml")
+/// Built-in context window of `model` (without the provider prefix).
+pub fn known_context_window(model: &str) -> Option<usize> {
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, size)| *size)
 }
 
-pub fn load_or_create_config() -> Settings {
-    let path = get_config_path();
-    if path.exists() {
-        let content = fs::read_to_string(&path).unwrap();
-        toml::from_str(&content).unwrap_or_else(|_| {
-            let default = Settings::default();
-            save_config(&default);
-            default
-        })
-    } else {
+/// Replaces `${VAR}` references in a config value with the environment
+/// variable's value. Values are expanded where they are used, so the raw
+/// references are what gets written back to config.toml.
+pub fn expand_env(value: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(format!("Unclosed ${{ in config value \"{}\"", value));
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        match std::env::var(name) {
+            Ok(v) => out.push_str(&v),
+            Err(_) => return Err(format!("Environment variable {} is not set", name)),
+        }
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Uses `path` as the config file instead of the default location
+/// (`--config`). Takes effect only before the config is first read.
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+/// The config file: the `--config` path, else `config.toml` in
+/// `$MEOWI_CONFIG_DIR`, else in the platform's config directory.
+pub fn get_config_path() -> io::Result<PathBuf> {
+    let path = match CONFIG_PATH.get() {
+        Some(path) => path.clone(),
+        None => match std::env::var_os("MEOWI_CONFIG_DIR").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir).join("config.toml"),
+            None => ProjectDirs::from("com", "yourname", "meowi")
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "No config directory found; set MEOWI_CONFIG_DIR or pass --config",
+                    )
+                })?
+                .config_dir()
+                .join("config.toml"),
+        },
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(path)
+}
+
+/// Set while config.toml holds a file that does not parse, so that saving
+/// settings cannot overwrite it.
+static SAVE_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Set while `prompts_dir` could not be read, so that saving cannot delete or
+/// overwrite prompt files this session never saw.
+static PROMPTS_BLOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Loads config.toml, creating it with defaults on first run. A file that
+/// does not parse is copied to `config.toml.bak` and never overwritten: the
+/// defaults are used and nothing is saved until it parses again. The error
+/// and any notes from upgrading an older file are returned alongside the
+/// settings so they can be shown in the app.
+pub fn load_or_create_config() -> io::Result<(Settings, Option<String>, Vec<String>)> {
+    let path = get_config_path()?;
+    if !path.exists() {
         let default = Settings::default();
-        save_config(&default);
-        default
+        let error = save_config(&default).err();
+        return Ok((default, error, Vec::new()));
+    }
+    let content = fs::read_to_string(&path)?;
+    match parse_config(&content, "config.toml") {
+        Ok((mut settings, from_version, notes)) => {
+            let mut error = None;
+            if from_version < CONFIG_VERSION {
+                // Keep the original around in case the upgrade lost something.
+                let backup = path.with_extension(format!("toml.v{}.bak", from_version));
+                if fs::copy(&path, &backup).is_ok() {
+                    error = save_config(&settings).err();
+                }
+            }
+            let error = load_prompts_dir(&mut settings).err().or(error);
+            Ok((settings, error, notes))
+        }
+        Err(e) => {
+            SAVE_BLOCKED.store(true, Ordering::Relaxed);
+            let backup = path.with_extension("toml.bak");
+            let kept = match fs::copy(&path, &backup) {
+                Ok(_) => format!("backup in {}", backup.display()),
+                Err(_) => "could not write a backup".to_string(),
+            };
+            let error = format!(
+                "{}; using defaults and not saving settings until it is fixed ({})",
+                e, kept
+            );
+            Ok((Settings::default(), Some(error), Vec::new()))
+        }
+    }
+}
+
+/// Reads and parses config.toml without touching it on failure. A file
+/// that does not parse blocks saving, as at startup, so the edit in
+/// progress is not overwritten with the settings in memory.
+pub fn read_config() -> Result<Settings, String> {
+    let path = get_config_path().map_err(|e| e.to_string())?;
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let (mut settings, _, _) = parse_config(&content, "config.toml").map_err(|e| {
+        SAVE_BLOCKED.store(true, Ordering::Relaxed);
+        format!("{}; settings are not saved until it is fixed", e)
+    })?;
+    load_prompts_dir(&mut settings)?;
+    SAVE_BLOCKED.store(false, Ordering::Relaxed);
+    Ok(settings)
+}
+
+/// `ours` with the changes `theirs` made since `base`; a setting changed on
+/// both sides takes `theirs`.
+pub fn merge_settings(
+    base: &Settings,
+    ours: &Settings,
+    theirs: &Settings,
+) -> Result<Settings, String> {
+    let value = |settings: &Settings| toml::Value::try_from(settings).map_err(|e| e.to_string());
+    let merged = merge_value(Some(value(base)?), Some(value(ours)?), Some(value(theirs)?));
+    merged
+        .unwrap_or(toml::Value::Table(toml::Table::new()))
+        .try_into()
+        .map_err(|e: toml::de::Error| e.to_string())
+}
+
+/// Merges tables key by key; any other value is taken whole.
+fn merge_value(
+    base: Option<toml::Value>,
+    ours: Option<toml::Value>,
+    theirs: Option<toml::Value>,
+) -> Option<toml::Value> {
+    match (base, ours, theirs) {
+        (
+            Some(toml::Value::Table(mut base)),
+            Some(toml::Value::Table(mut ours)),
+            Some(toml::Value::Table(mut theirs)),
+        ) => {
+            let mut keys: Vec<String> = ours.keys().chain(theirs.keys()).cloned().collect();
+            keys.sort();
+            keys.dedup();
+            let merged = keys
+                .into_iter()
+                .filter_map(|key| {
+                    let value =
+                        merge_value(base.remove(&key), ours.remove(&key), theirs.remove(&key))?;
+                    Some((key, value))
+                })
+                .collect();
+            Some(toml::Value::Table(merged))
+        }
+        (base, _, theirs) if theirs != base => theirs,
+        (_, ours, _) => ours,
+    }
+}
+
+/// Version of the config format this build writes.
+pub const CONFIG_VERSION: u32 = 3;
+
+/// Upgrades from older formats; `MIGRATIONS[n]` takes a version `n` file to
+/// version `n + 1`, given the file's text, and may return a note for the
+/// user about what it changed. Files without a `version` are version 0.
+type Migration = fn(&mut toml::Table, &str) -> Option<String>;
+const MIGRATIONS: [Migration; CONFIG_VERSION as usize] = [
+    migrate_copy_code_blocks,
+    drop_default_copy_code_blocks,
+    drop_default_json_backend,
+];
+
+/// 0 → 1: the top-level `copy_code_blocks` list moved into `[keybindings]`.
+fn migrate_copy_code_blocks(table: &mut toml::Table, _: &str) -> Option<String> {
+    let keys = table.remove("copy_code_blocks")?;
+    let bindings = table
+        .entry("keybindings")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if let toml::Value::Table(bindings) = bindings {
+        bindings.entry("copy_code_blocks").or_insert(keys);
+    }
+    None
+}
+
+/// 1 → 2: `copy_code_blocks` used to default to `c C x X` without being
+/// read; now that it is, the saved default would shadow `c`.
+fn drop_default_copy_code_blocks(table: &mut toml::Table, _: &str) -> Option<String> {
+    let Some(toml::Value::Table(bindings)) = table.get_mut("keybindings") else {
+        return None;
+    };
+    let old_default = ["c", "C", "x", "X"].map(|k| toml::Value::String(k.to_string()));
+    if bindings
+        .get("copy_code_blocks")
+        .and_then(toml::Value::as_array)
+        .is_some_and(|keys| keys[..] == old_default[..])
+    {
+        bindings.remove("copy_code_blocks");
+    }
+    None
+}
+
+/// 2 → 3: the default backend changed from `json` to `files`, and saved
+/// configs spelled out the old default. Only a `[storage]` table exactly as
+/// meowi wrote it, in a file without comments (which meowi never writes),
+/// is taken for the saved default; any sign of a hand-made choice keeps
+/// `json`.
+fn drop_default_json_backend(table: &mut toml::Table, content: &str) -> Option<String> {
+    let Some(toml::Value::Table(storage)) = table.get_mut("storage") else {
+        return None;
+    };
+    let hand_edited = content.lines().any(|l| l.trim_start().starts_with('#'));
+    if storage.len() != 1
+        || storage.get("backend").and_then(toml::Value::as_str) != Some("json")
+        || hand_edited
+    {
+        return None;
+    }
+    storage.remove("backend");
+    Some(
+        "Chats are now stored one file per chat (the new default); to keep a single \
+         history.json, set backend = \"json\" in [storage] and rename \
+         history.json.migrated back to history.json"
+            .to_string(),
+    )
+}
+
+/// Parses config.toml, migrating older formats, and returns the settings
+/// with the version the file was written in. Errors name the line of the
+/// first problem where possible.
+fn parse_config(content: &str, name: &str) -> Result<(Settings, u32, Vec<String>), String> {
+    let describe = |e: toml::de::Error| match e.span() {
+        Some(span) => {
+            let line = content[..span.start].matches('\n').count() + 1;
+            format!("{} line {}: {}", name, line, e.message())
+        }
+        None => format!("{}: {}", name, e.message()),
+    };
+    let mut table: toml::Table = toml::from_str(content).map_err(describe)?;
+    let version = match table.get("version") {
+        None => 0,
+        Some(toml::Value::Integer(v)) => u32::try_from(*v).unwrap_or(u32::MAX),
+        Some(_) => return Err(format!("{}: version must be a number", name)),
+    };
+    if version > CONFIG_VERSION {
+        return Err(format!(
+            "{} is version {}, newer than this meowi supports ({})",
+            name, version, CONFIG_VERSION
+        ));
+    }
+    if version == CONFIG_VERSION {
+        return Ok((
+            toml::from_str(content).map_err(describe)?,
+            version,
+            Vec::new(),
+        ));
+    }
+    let notes = MIGRATIONS[version as usize..]
+        .iter()
+        .filter_map(|migrate| migrate(&mut table, content))
+        .collect();
+    table.insert(
+        "version".into(),
+        toml::Value::Integer(CONFIG_VERSION.into()),
+    );
+    let settings = table
+        .try_into()
+        .map_err(|e: toml::de::Error| format!("{}: {}", name, e.message()))?;
+    Ok((settings, version, notes))
+}
+
+/// True for API key values that are safe to share: empty, or only naming
+/// environment variables.
+fn shareable_key(key: &str) -> bool {
+    key.trim().is_empty() || key.trim().starts_with("${") && key.trim().ends_with('}')
+}
+
+impl Settings {
+    /// A copy with the API keys and remote storage secrets removed; `${VAR}`
+    /// references are kept.
+    pub fn without_secrets(&self) -> Settings {
+        let mut settings = self.clone();
+        for p in &mut settings.providers {
+            if !shareable_key(&p.api_key) {
+                p.api_key.clear();
+            }
+        }
+        for cm in &mut settings.custom_models {
+            if let CustomModel::Standalone { api_key, .. } = cm
+                && api_key.as_deref().is_some_and(|key| !shareable_key(key))
+            {
+                *api_key = None;
+            }
+        }
+        if let Some(remote) = &mut settings.storage.remote {
+            for secret in [&mut remote.password, &mut remote.secret_key] {
+                if !shareable_key(secret) {
+                    secret.clear();
+                }
+            }
+        }
+        settings
+    }
+}
+
+/// Writes `settings` without secrets to `path`, for `import_config` on
+/// another machine.
+pub fn export_config(settings: &Settings, path: &Path) -> Result<(), String> {
+    let text = toml::to_string_pretty(&settings.without_secrets()).map_err(|e| e.to_string())?;
+    fs::write(path, text).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Reads exported settings from `path`. API keys missing from the file are
+/// taken from `current`, so importing never drops the keys of this machine.
+pub fn import_config(path: &Path, current: &Settings) -> Result<Settings, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let name = path.display().to_string();
+    let (mut settings, _, _) = parse_config(&content, &name)?;
+    for p in &mut settings.providers {
+        if p.api_key.is_empty()
+            && let Some(old) = current.providers.iter().find(|old| old.name == p.name)
+        {
+            p.api_key = old.api_key.clone();
+        }
+    }
+    for cm in &mut settings.custom_models {
+        if let CustomModel::Standalone { name, api_key, .. } = cm
+            && api_key.is_none()
+        {
+            *api_key = current.custom_models.iter().find_map(|old| match old {
+                CustomModel::Standalone {
+                    name: old_name,
+                    api_key,
+                    ..
+                } if old_name == name => api_key.clone(),
+                _ => None,
+            });
+        }
+    }
+    if let (Some(remote), Some(old)) = (&mut settings.storage.remote, &current.storage.remote)
+        && remote.url == old.url
+    {
+        if remote.password.is_empty() {
+            remote.password = old.password.clone();
+        }
+        if remote.secret_key.is_empty() {
+            remote.secret_key = old.secret_key.clone();
+        }
+    }
+    Ok(settings)
+}
+
+/// A theme file, `themes/<name>.toml` in the config directory.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeFile {
+    /// Code highlighting theme used instead of `ui.theme` while selected.
+    pub code: Option<String>,
+    /// UI colors to replace, by name or value: `blue = "#5e81ac"`.
+    pub colors: HashMap<String, String>,
+}
+
+/// `path` with a leading `~` replaced by the home directory.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), BaseDirs::new()) {
+        (Ok(rest), Some(dirs)) => dirs.home_dir().join(rest),
+        _ => path.to_path_buf(),
     }
 }
 
-pub fn save_config(settings: &Settings) {
-    let path = get_config_path();
-    fs::write(&path, toml::to_string_pretty(settings).unwrap()).unwrap();
+/// Replaces `settings.prompts` with the `.md` files in `prompts_dir`, sorted
+/// by name. A directory that does not exist yet is created from the prompts
+/// in config.toml.
+fn load_prompts_dir(settings: &mut Settings) -> Result<(), String> {
+    let Some(dir) = settings.prompts_dir.as_deref().map(expand_home) else {
+        return Ok(());
+    };
+    if !dir.exists() {
+        PROMPTS_BLOCKED.store(false, Ordering::Relaxed);
+        return save_prompts_dir(&dir, &settings.prompts);
+    }
+    let read = || -> io::Result<Vec<Prompt>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_file() && path.extension().is_some_and(|e| e == "md"))
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| {
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                Ok(parse_prompt_file(&name, &fs::read_to_string(path)?))
+            })
+            .collect()
+    };
+    match read() {
+        Ok(prompts) => {
+            settings.prompts = prompts;
+            PROMPTS_BLOCKED.store(false, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(e) => {
+            PROMPTS_BLOCKED.store(true, Ordering::Relaxed);
+            Err(format!("Cannot read prompts from {}: {}", dir.display(), e))
+        }
+    }
+}
+
+/// A prompt file: optional `---` frontmatter with `active: true`, then the
+/// prompt text.
+fn parse_prompt_file(name: &str, text: &str) -> Prompt {
+    let frontmatter = text.strip_prefix("---\n").and_then(|rest| {
+        rest.split_once("\n---\n")
+            .or_else(|| rest.split_once("\n---"))
+    });
+    let (header, body) = frontmatter.unwrap_or(("", text));
+    let active = header.lines().any(|line| {
+        line.split_once(':')
+            .is_some_and(|(key, value)| key.trim() == "active" && value.trim() == "true")
+    });
+    Prompt::new(name, body.trim(), active)
+}
+
+fn prompt_file_text(prompt: &Prompt) -> String {
+    format!("---\nactive: {}\n---\n{}\n", prompt.active, prompt.content)
+}
+
+/// Why `name` cannot name a prompt. A prompt in `prompts_dir` is named by
+/// its file, so a name must read back from the file name unchanged.
+pub fn check_prompt_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Prompt name cannot be empty".to_string());
+    }
+    if name.contains(['/', '\\']) {
+        return Err(format!("Prompt name '{}' cannot contain / or \\", name));
+    }
+    Ok(())
+}
+
+/// Writes one file per prompt into `dir`, skipping files that already say
+/// the same, and removes the `.md` files of prompts that are gone. Nothing
+/// is written if a prompt's name cannot be a file name.
+fn save_prompts_dir(dir: &Path, prompts: &[Prompt]) -> Result<(), String> {
+    let fail = |e: io::Error| format!("Cannot write prompts to {}: {}", dir.display(), e);
+    for prompt in prompts {
+        check_prompt_name(&prompt.name)
+            .map_err(|e| format!("Cannot write prompts to {}: {}", dir.display(), e))?;
+    }
+    fs::create_dir_all(dir).map_err(fail)?;
+    let mut names = Vec::new();
+    for prompt in prompts {
+        let name: &str = &prompt.name;
+        let path = dir.join(format!("{}.md", name));
+        let current = fs::read_to_string(&path).ok();
+        if current
+            .as_deref()
+            .map(|text| parse_prompt_file(name, text))
+            .as_ref()
+            != Some(prompt)
+        {
+            fs::write(&path, prompt_file_text(prompt)).map_err(fail)?;
+        }
+        names.push(name);
+    }
+    for entry in fs::read_dir(dir).map_err(fail)?.flatten() {
+        let path = entry.path();
+        let stale = path.extension().is_some_and(|e| e == "md")
+            && path
+                .file_stem()
+                .is_some_and(|stem| !names.iter().any(|name| stem == *name));
+        if stale {
+            fs::remove_file(&path).map_err(fail)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn themes_dir() -> io::Result<PathBuf> {
+    let path = get_config_path()?;
+    Ok(path.with_file_name("themes"))
+}
+
+/// Names of the theme files in the themes directory, sorted.
+pub fn theme_names() -> Vec<String> {
+    let Ok(entries) = themes_dir().and_then(fs::read_dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "toml" {
+                return None;
+            }
+            Some(path.file_stem()?.to_string_lossy().into_owned())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+pub fn load_theme(name: &str) -> Result<ThemeFile, String> {
+    let path = themes_dir()
+        .map_err(|e| e.to_string())?
+        .join(format!("{}.toml", name));
+    let content = fs::read_to_string(&path)
+        .map_err(|_| format!("No theme {} in {}", name, path.display()))?;
+    toml::from_str(&content).map_err(|e| format!("Theme {}: {}", name, e.message()))
+}
+
+/// Watches the config directory and `prompts_dir`; the receiver gets a
+/// message whenever config.toml or a prompt file is written, created,
+/// replaced or removed.
+pub fn watch_config(settings: &Settings) -> notify::Result<(RecommendedWatcher, Receiver<()>)> {
+    let path = get_config_path().map_err(notify::Error::io)?;
+    let prompts_dir = settings.prompts_dir.as_deref().map(expand_home);
+    let (tx, rx) = mpsc::channel();
+    let name = path.file_name().map(|n| n.to_os_string());
+    let prompts = prompts_dir.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        let ours = event.paths.iter().any(|p| {
+            p.file_name() == name.as_deref()
+                || (p.parent() == prompts.as_deref() && p.extension().is_some_and(|e| e == "md"))
+        });
+        if ours && !event.kind.is_access() {
+            let _ = tx.send(());
+        }
+    })?;
+    // Editors often save by replacing the file, so watch its directory.
+    if let Some(dir) = path.parent() {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    if let Some(dir) = prompts_dir.filter(|dir| dir.is_dir()) {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok((watcher, rx))
+}
+
+/// Writes config.toml through a temporary file, so a full or read-only
+/// disk leaves the old file in place and is reported instead. Fails
+/// without writing while config.toml does not parse. A prompt library in
+/// `prompts_dir` that cannot be written is reported after the rest is saved.
+pub fn save_config(settings: &Settings) -> Result<(), String> {
+    if SAVE_BLOCKED.load(Ordering::Relaxed) {
+        return Err("Settings are not saved until config.toml parses again".to_string());
+    }
+    let path = get_config_path().map_err(|e| format!("Cannot save config.toml: {}", e))?;
+    // A symlinked config (e.g. from a dotfiles repo) stays a symlink.
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    let mut settings = settings.clone();
+    let mut prompts = Ok(());
+    if let Some(dir) = settings.prompts_dir.as_deref().map(expand_home) {
+        // The files are the prompt library; config.toml keeps none.
+        if !PROMPTS_BLOCKED.load(Ordering::Relaxed) {
+            prompts = save_prompts_dir(&dir, &settings.prompts);
+        }
+        settings.prompts.clear();
+    }
+    let content =
+        toml::to_string_pretty(&settings).map_err(|e| format!("Cannot save config.toml: {}", e))?;
+    crate::storage::replace_file(&path, content.as_bytes())
+        .map_err(|e| format!("Cannot save {}: {}", path.display(), e))?;
+    prompts
 }
 
 pub fn openai_models() -> Vec<String> {
@@ -169,3 +1423,136 @@ pub fn anthropic_models() -> Vec<String> {
 pub fn grok_models() -> Vec<String> {
     GROK_MODELS.iter().map(|&s| s.into()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(settings: &Settings) -> String {
+        toml::to_string_pretty(settings).unwrap()
+    }
+
+    #[test]
+    fn saved_settings_read_back_unchanged() {
+        let mut settings = Settings {
+            autosave_secs: 5,
+            ..Default::default()
+        };
+        settings.startup.sidebar = false;
+        settings.keybindings.copy_code_blocks = vec!["y".into()];
+        let (read, version, notes) = parse_config(&text(&settings), "config.toml").unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert!(notes.is_empty());
+        assert_eq!(text(&read), text(&settings));
+    }
+
+    #[test]
+    fn prompt_names_survive_the_prompts_dir() {
+        let dir = std::env::temp_dir().join(format!("meowi-prompts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let prompts = vec![
+            Prompt::new("a-b", "First.", true),
+            Prompt::new("a.b c", "Second.", false),
+        ];
+        save_prompts_dir(&dir, &prompts).unwrap();
+        let mut settings = Settings {
+            prompts_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        load_prompts_dir(&mut settings).unwrap();
+        assert_eq!(settings.prompts, prompts);
+
+        // A separator would turn into another name, so nothing is written.
+        let renamed = vec![Prompt::new("a/b", "First.", true)];
+        assert!(save_prompts_dir(&dir, &renamed).is_err());
+        assert!(dir.join("a-b.md").exists());
+        assert!(check_prompt_name("a\\b").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn merge_keeps_changes_from_both_sides() {
+        let base = Settings::default();
+        let mut ours = base.clone();
+        ours.autosave_secs = 5;
+        ours.startup.sidebar = false;
+        let mut theirs = base.clone();
+        theirs.autosave_secs = 10;
+        theirs.providers[0].api_key = "${OPENAI_API_KEY}".into();
+
+        let merged = merge_settings(&base, &ours, &theirs).unwrap();
+        // Changed on both sides: theirs wins.
+        assert_eq!(merged.autosave_secs, 10);
+        assert!(!merged.startup.sidebar);
+        assert_eq!(merged.providers[0].api_key, "${OPENAI_API_KEY}");
+        assert_eq!(merged.providers.len(), base.providers.len());
+    }
+
+    #[test]
+    fn merge_value_takes_non_tables_whole() {
+        let list = |items: &[&str]| {
+            Some(toml::Value::Array(
+                items.iter().map(|&s| toml::Value::from(s)).collect(),
+            ))
+        };
+        let merged = merge_value(list(&["a"]), list(&["a", "b"]), list(&["a"]));
+        assert_eq!(merged, list(&["a", "b"]));
+        let merged = merge_value(list(&["a"]), list(&["a", "b"]), list(&["c"]));
+        assert_eq!(merged, list(&["c"]));
+        // Removed by them, kept by us unchanged: gone.
+        assert_eq!(merge_value(list(&["a"]), list(&["a"]), None), None);
+    }
+
+    #[test]
+    fn version_0_copy_code_blocks_moves_into_keybindings() {
+        let (settings, version, _) =
+            parse_config("copy_code_blocks = [\"a\", \"b\"]\n", "config.toml").unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(settings.version, CONFIG_VERSION);
+        assert_eq!(settings.keybindings.copy_code_blocks, ["a", "b"]);
+    }
+
+    #[test]
+    fn version_1_drops_the_old_copy_code_blocks_default() {
+        let old = "version = 1\n[keybindings]\ncopy_code_blocks = [\"c\", \"C\", \"x\", \"X\"]\n";
+        let (settings, _, _) = parse_config(old, "config.toml").unwrap();
+        assert_eq!(
+            text(&settings),
+            text(&Settings::default()),
+            "the old default is replaced by the new one"
+        );
+        let chosen = "version = 1\n[keybindings]\ncopy_code_blocks = [\"y\"]\n";
+        let (settings, _, _) = parse_config(chosen, "config.toml").unwrap();
+        assert_eq!(settings.keybindings.copy_code_blocks, ["y"]);
+    }
+
+    #[test]
+    fn version_2_json_backend_moves_only_when_saved_by_meowi() {
+        let saved = "version = 2\n[storage]\nbackend = \"json\"\n";
+        let (settings, _, notes) = parse_config(saved, "config.toml").unwrap();
+        assert_eq!(settings.storage.backend, StorageBackend::Files);
+        assert_eq!(notes.len(), 1);
+
+        for chosen in [
+            "# kept on purpose\nversion = 2\n[storage]\nbackend = \"json\"\n",
+            "version = 2\n[storage]\nbackend = \"json\"\nbackups = 3\n",
+        ] {
+            let (settings, _, notes) = parse_config(chosen, "config.toml").unwrap();
+            assert_eq!(settings.storage.backend, StorageBackend::Json);
+            assert!(notes.is_empty());
+        }
+    }
+
+    #[test]
+    fn parse_errors_name_the_line_and_newer_versions_are_refused() {
+        let bad = format!("version = {}\nautosave_secs = \"soon\"\n", CONFIG_VERSION);
+        let error = parse_config(&bad, "config.toml").err().unwrap();
+        assert!(error.starts_with("config.toml line 2:"), "{}", error);
+        let error = parse_config("version = 99\n", "config.toml").err().unwrap();
+        assert!(
+            error.contains("newer than this meowi supports"),
+            "{}",
+            error
+        );
+    }
+}
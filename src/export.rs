@@ -0,0 +1,369 @@
+use crate::app::{Chat, Message};
+use crate::attachments;
+use crate::ui;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{Local, TimeZone};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::{fs, io, path::Path};
+use syntect::html::highlighted_html_for_string;
+
+const STYLE: &str = "body{max-width:52rem;margin:2rem auto;padding:0 1rem;\
+font-family:system-ui,sans-serif;line-height:1.5;color:#222;background:#fafafa}\
+header{border-bottom:1px solid #ddd;margin-bottom:1rem}\
+header p{color:#666;margin:.2rem 0}\
+details{border:1px solid #ddd;border-radius:6px;margin:.8rem 0;background:#fff}\
+details.user{background:#f3f7ff}details.system{background:#f6f6f6}\
+summary{cursor:pointer;padding:.4rem .8rem;font-weight:600}\
+summary .meta{color:#888;font-weight:400}\
+.body{padding:0 .8rem .6rem}.text{white-space:pre-wrap}\
+pre{padding:.6rem;border-radius:4px;overflow-x:auto}\
+.lang{color:#888;font-size:.8rem;margin-bottom:-.4rem}\
+.attachment{color:#666;font-size:.9rem}.attachment img{display:block;max-width:100%}\
+nav{margin-bottom:1rem}a{color:#2456b3}\
+#search{width:100%;padding:.5rem;font-size:1rem;margin:.5rem 0 1rem;box-sizing:border-box}\
+table{width:100%;border-collapse:collapse}td,th{text-align:left;padding:.3rem .5rem}\
+tr+tr{border-top:1px solid #eee}td.num{text-align:right;color:#666}";
+
+/// Filters the chat list of a `site` export as you type: every word must
+/// appear in a chat's title or messages. `CHATS` comes from `search.js`.
+const SEARCH_SCRIPT: &str = "const input=document.getElementById('search');\
+const rows=[...document.querySelectorAll('tbody tr')];\
+const count=document.getElementById('count');\
+input.addEventListener('input',()=>{\
+const words=input.value.toLowerCase().split(/\\s+/).filter(w=>w);let shown=0;\
+rows.forEach((row,i)=>{const hit=words.every(w=>CHATS[i].includes(w));\
+row.hidden=!hit;if(hit)shown++;});\
+count.textContent=words.length?shown+' of '+rows.length+' chats':rows.length+' chats';});";
+
+/// A file name for exports of `chat`: its title with anything but letters,
+/// digits, `-` and `_` turned into `-`.
+pub fn file_stem(chat: &Chat) -> String {
+    let stem: String = chat
+        .title
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let stem = stem.trim_matches('-');
+    if stem.is_empty() {
+        "chat".to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+/// `chat` as a single self-contained HTML page: one collapsible section per
+/// message, code blocks highlighted with `code_theme` and times shown with
+/// the strftime pattern `time_format`. System prompts start collapsed.
+pub fn html(chat: &Chat, code_theme: &str, time_format: &str) -> String {
+    page(chat, code_theme, time_format, None)
+}
+
+/// The page of `chat`, with a link back to the chat list at `back` when it is
+/// part of a `site`.
+fn page(chat: &Chat, code_theme: &str, time_format: &str, back: Option<&str>) -> String {
+    let date_format = format!("%Y-%m-%d {}", time_format);
+    let time = |ts: Option<i64>| {
+        ts.and_then(|t| Local.timestamp_opt(t, 0).single())
+            .map(|ts| ts.format(&date_format).to_string())
+    };
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str(&format!("<title>{}</title>\n", escape(&chat.title)));
+    out.push_str(&format!("<style>{}</style>\n</head>\n<body>\n", STYLE));
+    if let Some(back) = back {
+        out.push_str(&format!(
+            "<nav><a href=\"{}\">← All chats</a></nav>\n",
+            back
+        ));
+    }
+    out.push_str(&format!(
+        "<header>\n<h1>{}</h1>\n<p>Model: {}</p>\n<p>{} messages",
+        escape(&chat.title),
+        escape(&chat.model),
+        chat.messages.len()
+    ));
+    if let Some(last) = time(chat.last_activity()) {
+        out.push_str(&format!(" · last active {}", last));
+    }
+    out.push_str(&format!(
+        "</p>\n<p>Exported from meowi on {}</p>\n</header>\n",
+        Local::now().format(&date_format)
+    ));
+    for message in &chat.messages {
+        let author = match message.role.as_str() {
+            "user" => "You".to_string(),
+            "system" => "System prompt".to_string(),
+            _ => message.model.clone().unwrap_or_else(|| chat.model.clone()),
+        };
+        let open = if message.role == "system" {
+            ""
+        } else {
+            " open"
+        };
+        out.push_str(&format!(
+            "<details class=\"{}\"{}>\n<summary>{}",
+            escape(&message.role),
+            open,
+            escape(&author)
+        ));
+        if let Some(when) = time(message.timestamp) {
+            out.push_str(&format!(" <span class=\"meta\">· {}</span>", when));
+        }
+        out.push_str("</summary>\n<div class=\"body\">\n");
+        push_content(&mut out, message, code_theme);
+        push_attachments(&mut out, message);
+        out.push_str("</div>\n</details>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Writes `chats` to `dir` as a static site that can be served from any web
+/// server or opened from disk: `index.html` lists the chats, newest first,
+/// with a search box, and each chat gets a page like `html` makes in
+/// `chats/`. Returns the number of pages written.
+pub fn site(dir: &Path, chats: &[&Chat], code_theme: &str, time_format: &str) -> io::Result<usize> {
+    let pages = dir.join("chats");
+    fs::create_dir_all(&pages)?;
+    let mut chats = chats.to_vec();
+    chats.sort_by_key(|c| std::cmp::Reverse(c.last_activity()));
+    let date_format = format!("%Y-%m-%d {}", time_format);
+    let mut used = HashSet::new();
+    let mut rows = String::new();
+    let mut texts = Vec::new();
+    for chat in &chats {
+        // Chats with the same title get numbered pages.
+        let stem = file_stem(chat);
+        let mut name = format!("{}.html", stem);
+        for n in 2.. {
+            if used.insert(name.clone()) {
+                break;
+            }
+            name = format!("{}-{}.html", stem, n);
+        }
+        fs::write(
+            pages.join(&name),
+            page(chat, code_theme, time_format, Some("../index.html")),
+        )?;
+        let last = chat
+            .last_activity()
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+            .map(|t| t.format(&date_format).to_string())
+            .unwrap_or_default();
+        rows.push_str(&format!(
+            "<tr><td><a href=\"chats/{}\">{}</a></td><td>{}</td><td class=\"num\">{}</td><td>{}</td></tr>\n",
+            escape(&name),
+            escape(&chat.title),
+            escape(&chat.model),
+            chat.messages.len(),
+            last
+        ));
+        let mut text = chat.title.to_lowercase();
+        for message in &chat.messages {
+            text.push('\n');
+            text.push_str(&message.content.to_lowercase());
+        }
+        texts.push(text);
+    }
+    // A script rather than JSON, so search also works on pages opened from
+    // disk, where browsers refuse to fetch files.
+    let search = format!(
+        "const CHATS={};\n",
+        serde_json::to_string(&texts).map_err(io::Error::other)?
+    );
+    fs::write(dir.join("search.js"), search)?;
+    let mut index = String::new();
+    index.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    index.push_str(&format!(
+        "<title>Chats</title>\n<style>{}</style>\n</head>\n<body>\n",
+        STYLE
+    ));
+    index.push_str(&format!(
+        "<header>\n<h1>Chats</h1>\n<p>Exported from meowi on {}</p>\n</header>\n",
+        Local::now().format(&date_format)
+    ));
+    index.push_str("<input id=\"search\" type=\"search\" placeholder=\"Search titles and messages\" autofocus>\n");
+    index.push_str(&format!("<p id=\"count\">{} chats</p>\n", chats.len()));
+    index.push_str("<table>\n<thead><tr><th>Chat</th><th>Model</th><th>Messages</th><th>Last active</th></tr></thead>\n<tbody>\n");
+    index.push_str(&rows);
+    index.push_str("</tbody>\n</table>\n<script src=\"search.js\"></script>\n");
+    index.push_str(&format!(
+        "<script>{}</script>\n</body>\n</html>\n",
+        SEARCH_SCRIPT
+    ));
+    fs::write(dir.join("index.html"), index)?;
+    Ok(chats.len())
+}
+
+/// Lists a message's attachments; stored images are embedded so the page
+/// stays self-contained.
+fn push_attachments(out: &mut String, message: &Message) {
+    for attachment in &message.attachments {
+        out.push_str(&format!(
+            "<div class=\"attachment\">📎 {}",
+            escape(&attachment.summary())
+        ));
+        if let Some(blob) = &attachment.blob
+            && blob.mime.starts_with("image/")
+            && let Ok(data) = attachments::get(&blob.hash)
+        {
+            out.push_str(&format!(
+                "<img src=\"data:{};base64,{}\" alt=\"{}\">",
+                blob.mime,
+                STANDARD.encode(data),
+                escape(&attachment.name)
+            ));
+        }
+        out.push_str("</div>\n");
+    }
+}
+
+/// Appends a message's text, with fenced code blocks highlighted.
+fn push_content(out: &mut String, message: &Message, code_theme: &str) {
+    let syntax_set = ui::get_syntax_set();
+    let theme = ui::get_theme(code_theme);
+    let mut text = String::new();
+    let mut code: Option<(String, String)> = None;
+    let flush_text = |out: &mut String, text: &mut String| {
+        if !text.trim().is_empty() {
+            out.push_str(&format!(
+                "<div class=\"text\">{}</div>\n",
+                escape(text.trim_matches('\n'))
+            ));
+        }
+        text.clear();
+    };
+    let push_code = |out: &mut String, language: &str, source: &str| {
+        let syntax = syntax_set
+            .find_syntax_by_token(crate::app::fence_language(language))
+            .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+        if !language.is_empty() {
+            out.push_str(&format!("<div class=\"lang\">{}</div>\n", escape(language)));
+        }
+        match highlighted_html_for_string(source, syntax_set, syntax, theme) {
+            Ok(html) => out.push_str(&html),
+            Err(_) => out.push_str(&format!("<pre>{}</pre>\n", escape(source))),
+        }
+    };
+    for line in message.content.lines() {
+        match &mut code {
+            Some((language, source)) => {
+                if line.trim() == "```" {
+                    push_code(out, language, source);
+                    code = None;
+                } else {
+                    source.push_str(line);
+                    source.push('\n');
+                }
+            }
+            None => match line.strip_prefix("```") {
+                Some(language) => {
+                    flush_text(out, &mut text);
+                    code = Some((language.trim().to_string(), String::new()));
+                }
+                None => {
+                    text.push_str(line);
+                    text.push('\n');
+                }
+            },
+        }
+    }
+    // A reply cut off inside a code block still shows what it has.
+    if let Some((language, source)) = code {
+        push_code(out, &language, &source);
+    }
+    flush_text(out, &mut text);
+}
+
+/// What the JSON exports include besides each message's role and content.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonOptions {
+    /// System prompts.
+    pub system: bool,
+    /// Chat title, id and model, and each message's time and model.
+    pub meta: bool,
+}
+
+/// A chat in OpenAI's chat format, `{"messages": [{"role", "content"}]}`,
+/// with the metadata fields left out unless asked for.
+#[derive(Serialize)]
+struct Conversation<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    messages: Vec<Record<'a>>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    role: &'a str,
+    content: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+}
+
+/// Messages that failed to send are left out.
+fn conversation(chat: &Chat, options: JsonOptions) -> Conversation<'_> {
+    let meta = |value| if options.meta { Some(value) } else { None };
+    let messages = chat
+        .messages
+        .iter()
+        .filter(|m| m.failed.is_none() && (options.system || m.role != "system"))
+        .map(|m| Record {
+            role: &m.role,
+            content: &m.content,
+            timestamp: m.timestamp.filter(|_| options.meta),
+            model: m.model.as_deref().filter(|_| options.meta),
+        })
+        .collect();
+    Conversation {
+        id: meta(chat.id.as_str()),
+        title: meta(chat.title.as_str()),
+        model: meta(chat.model.as_str()),
+        messages,
+    }
+}
+
+/// The chats as a pretty-printed JSON array of conversations.
+pub fn json(chats: &[&Chat], options: JsonOptions) -> String {
+    let conversations: Vec<Conversation> = chats.iter().map(|c| conversation(c, options)).collect();
+    let mut out = serde_json::to_string_pretty(&conversations).unwrap_or_default();
+    out.push('\n');
+    out
+}
+
+/// The chats as JSON Lines, one conversation per line, the layout
+/// fine-tuning and eval tools read.
+pub fn jsonl(chats: &[&Chat], options: JsonOptions) -> String {
+    chats
+        .iter()
+        .filter_map(|c| serde_json::to_string(&conversation(c, options)).ok())
+        .map(|line| line + "\n")
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
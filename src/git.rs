@@ -0,0 +1,133 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Runs git in `dir`, returning its output if it succeeded.
+fn git(dir: &Path, args: &[&str]) -> Result<Output, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if output.status.success() {
+        return Ok(output);
+    }
+    // The subcommand, past any `-c name=value` options.
+    let name = args
+        .iter()
+        .find(|arg| !arg.starts_with('-') && !arg.contains('='))
+        .unwrap_or(&"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(stderr
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .map(|line| format!("git {}: {}", name, line))
+        .unwrap_or_else(|| format!("git {} failed", name)))
+}
+
+/// Makes `dir` a git repository if it is not one yet. The `.gitignore`
+/// written with it leaves out everything but the files and directories in
+/// `keep`, so backups, the search index and `config.toml` (if it lives there
+/// too) are never committed or pushed. Returns true if it created the
+/// repository.
+fn init(dir: &Path, keep: &[&str]) -> Result<bool, String> {
+    if dir.join(".git").exists() {
+        return Ok(false);
+    }
+    git(dir, &["init", "--quiet"])?;
+    let ignore = dir.join(".gitignore");
+    if !ignore.exists() {
+        let mut rules =
+            "# Written by meowi: only the chat history is versioned.\n/*\n!/.gitignore\n"
+                .to_string();
+        for name in keep {
+            rules.push_str(&format!("!/{}\n", name));
+        }
+        rules.push_str("*.tmp\n");
+        fs::write(&ignore, rules)
+            .map_err(|e| format!("Cannot write {}: {}", ignore.display(), e))?;
+    }
+    Ok(true)
+}
+
+/// Commits the changes to the files in `keep` in `dir`, creating the
+/// repository on first use. Nothing else is staged or committed, so a
+/// history kept inside a repository of the user's own leaves the rest of it
+/// alone. `template` is the commit message, with `{date}` replaced by the
+/// local date and time and `{files}` by the number of changed files.
+/// Returns false if there was nothing to commit.
+pub fn commit(dir: &Path, keep: &[&str], template: &str) -> Result<bool, String> {
+    let created = init(dir, keep)?;
+    // git refuses paths it neither finds nor tracks, such as an attachment
+    // store that was never written.
+    let mut paths = Vec::new();
+    for &path in keep {
+        if dir.join(path).exists() || !git(dir, &["ls-files", "--", path])?.stdout.is_empty() {
+            paths.push(path);
+        }
+    }
+    if created {
+        paths.push(".gitignore");
+    }
+    if paths.is_empty() {
+        return Ok(false);
+    }
+    let mut add = vec!["add", "--all", "--"];
+    add.extend(&paths);
+    git(dir, &add)?;
+    let mut changed = vec!["diff", "--cached", "--name-only", "--"];
+    changed.extend(&paths);
+    let status = git(dir, &changed)?;
+    let files = String::from_utf8_lossy(&status.stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .count();
+    if files == 0 {
+        return Ok(false);
+    }
+    let message = template
+        .replace(
+            "{date}",
+            &chrono::Local::now().format("%Y-%m-%d %H:%M").to_string(),
+        )
+        .replace("{files}", &files.to_string());
+    let mut args = Vec::new();
+    // Without an identity git refuses to commit; the history's commits do
+    // not need a real one.
+    if git(dir, &["config", "user.email"]).is_err() {
+        args.extend(["-c", "user.name=meowi", "-c", "user.email=meowi@localhost"]);
+    }
+    args.extend(["commit", "--quiet", "--no-verify", "-m", &message, "--"]);
+    args.extend(&paths);
+    git(dir, &args)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_leaves_the_rest_of_a_users_repository_alone() {
+        let dir = std::env::temp_dir().join(format!("meowi-git-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        git(&dir, &["init", "--quiet"]).unwrap();
+        fs::write(dir.join("notes.txt"), "mine").unwrap();
+        fs::write(dir.join("history.json"), "[]").unwrap();
+
+        assert_eq!(
+            commit(&dir, &["history.json", "attachments"], "{files} file(s)"),
+            Ok(true)
+        );
+        let committed = git(&dir, &["show", "--name-only", "--format=%s", "HEAD"]).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&committed.stdout).trim(),
+            "1 file(s)\n\nhistory.json"
+        );
+        let status = git(&dir, &["status", "--porcelain"]).unwrap();
+        assert_eq!(String::from_utf8_lossy(&status.stdout), "?? notes.txt\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::app::{Attachment, Chat, Message, Role};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, fs, path::Path};
+
+/// One conversation of a ChatGPT data export (`conversations.json`). The
+/// messages form a tree, so edits and regenerations keep their branches;
+/// `current_node` is the last message of the branch that was shown.
+#[derive(Deserialize)]
+struct ChatGptConversation {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    conversation_id: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    create_time: Option<f64>,
+    mapping: HashMap<String, ChatGptNode>,
+    #[serde(default)]
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatGptNode {
+    #[serde(default)]
+    message: Option<ChatGptMessage>,
+    #[serde(default)]
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatGptMessage {
+    author: ChatGptAuthor,
+    content: ChatGptContent,
+    #[serde(default)]
+    create_time: Option<f64>,
+    #[serde(default)]
+    metadata: ChatGptMetadata,
+}
+
+#[derive(Deserialize)]
+struct ChatGptAuthor {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct ChatGptContent {
+    /// Text parts; images and other attachments are objects and skipped.
+    #[serde(default)]
+    parts: Vec<Value>,
+    /// Code and other non-text content types.
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ChatGptMetadata {
+    #[serde(default)]
+    model_slug: Option<String>,
+    #[serde(default)]
+    is_visually_hidden_from_conversation: bool,
+}
+
+/// Reads a ChatGPT `conversations.json` export into chats, oldest first.
+/// Only the branch that was last shown of each conversation is kept, and
+/// tool calls and hidden messages are dropped. Chat ids are derived from the
+/// conversation ids, so importing the same export twice can be detected.
+pub fn chatgpt(path: &Path, fallback_model: &str) -> Result<Vec<Chat>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let mut conversations: Vec<ChatGptConversation> = serde_json::from_str(&text)
+        .map_err(|e| format!("{} is not a ChatGPT export: {}", path.display(), e))?;
+    conversations.sort_by(|a, b| {
+        a.create_time
+            .unwrap_or(0.0)
+            .total_cmp(&b.create_time.unwrap_or(0.0))
+    });
+    Ok(conversations
+        .into_iter()
+        .filter_map(|c| chatgpt_chat(c, fallback_model))
+        .collect())
+}
+
+fn chatgpt_chat(conversation: ChatGptConversation, fallback_model: &str) -> Option<Chat> {
+    let mapping = &conversation.mapping;
+    // Older exports may lack `current_node`; take the newest message then.
+    let leaf = conversation.current_node.clone().or_else(|| {
+        mapping
+            .iter()
+            .filter_map(|(id, node)| Some((id, node.message.as_ref()?.create_time?)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id.clone())
+    })?;
+    let mut thread = Vec::new();
+    let mut next = Some(leaf);
+    while let Some(id) = next {
+        let Some(node) = mapping.get(&id) else {
+            break;
+        };
+        if let Some(message) = &node.message {
+            thread.push(message);
+        }
+        next = node.parent.clone();
+        // A malformed export with a cycle must not hang the import.
+        if thread.len() > mapping.len() {
+            break;
+        }
+    }
+    thread.reverse();
+
+    let mut model = None;
+    let messages: Vec<Message> = thread
+        .into_iter()
+        .filter(|m| !m.metadata.is_visually_hidden_from_conversation)
+        .filter_map(|m| {
+            let role = match m.author.role.as_str() {
+                "user" => Role::User,
+                "assistant" => Role::Assistant,
+                "system" => Role::System,
+                _ => return None,
+            };
+            let parts: Vec<&str> = m.content.parts.iter().filter_map(Value::as_str).collect();
+            let text = if parts.is_empty() {
+                m.content.text.clone().unwrap_or_default()
+            } else {
+                parts.join("\n")
+            };
+            if text.trim().is_empty() {
+                return None;
+            }
+            let mut message = match (&role, &m.metadata.model_slug) {
+                (Role::Assistant, Some(slug)) => {
+                    let name = format!("OpenAI:{}", slug);
+                    model = Some(name.clone());
+                    Message::reply(&name, text)
+                }
+                _ => Message::new(role, text),
+            };
+            if let Some(time) = m.create_time {
+                message.timestamp = Some(time as i64);
+            }
+            Some(message)
+        })
+        .collect();
+    if messages.is_empty() {
+        return None;
+    }
+    let id = conversation.conversation_id.or(conversation.id)?;
+    let title = conversation
+        .title
+        .filter(|t| !t.trim().is_empty())
+        .unwrap_or_else(|| "Imported chat".to_string());
+    Some(Chat::new(
+        format!("chatgpt-{}", id),
+        title,
+        model.unwrap_or_else(|| fallback_model.to_string()),
+        messages,
+    ))
+}
+
+/// One conversation of a Claude.ai data export (`conversations.json`).
+#[derive(Deserialize)]
+struct ClaudeConversation {
+    uuid: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    created_at: Option<String>,
+    /// Only in newer exports.
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    chat_messages: Vec<ClaudeMessage>,
+}
+
+#[derive(Deserialize)]
+struct ClaudeMessage {
+    sender: String,
+    #[serde(default)]
+    text: String,
+    /// Newer exports split the text into typed blocks; `text` then only
+    /// holds the text blocks joined.
+    #[serde(default)]
+    content: Vec<ClaudeContent>,
+    #[serde(default)]
+    created_at: Option<String>,
+    #[serde(default)]
+    attachments: Vec<ClaudeAttachment>,
+}
+
+#[derive(Deserialize)]
+struct ClaudeContent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClaudeAttachment {
+    #[serde(default)]
+    file_name: String,
+    #[serde(default)]
+    extracted_content: Option<String>,
+}
+
+fn claude_time(time: Option<&str>) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(time?)
+        .ok()
+        .map(|t| t.timestamp())
+}
+
+/// Reads a Claude.ai `conversations.json` export into chats, oldest first.
+/// Attached files whose text the export includes become attachments again;
+/// tool use and thinking blocks are dropped.
+pub fn claude(path: &Path, fallback_model: &str) -> Result<Vec<Chat>, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let mut conversations: Vec<ClaudeConversation> = serde_json::from_str(&text)
+        .map_err(|e| format!("{} is not a Claude export: {}", path.display(), e))?;
+    conversations.sort_by_key(|c| claude_time(c.created_at.as_deref()).unwrap_or(0));
+    Ok(conversations
+        .into_iter()
+        .filter_map(|c| claude_chat(c, fallback_model))
+        .collect())
+}
+
+fn claude_chat(conversation: ClaudeConversation, fallback_model: &str) -> Option<Chat> {
+    let model = conversation.model.map(|m| format!("Anthropic:{}", m));
+    let messages: Vec<Message> = conversation
+        .chat_messages
+        .into_iter()
+        .filter_map(|m| {
+            let text: Vec<&str> = m
+                .content
+                .iter()
+                .filter(|c| c.kind == "text")
+                .filter_map(|c| c.text.as_deref())
+                .collect();
+            let text = if text.is_empty() {
+                m.text.clone()
+            } else {
+                text.join("\n")
+            };
+            let attachments: Vec<Attachment> = m
+                .attachments
+                .into_iter()
+                .filter_map(|a| {
+                    Some(Attachment {
+                        name: a.file_name,
+                        content: a.extracted_content?,
+                        blob: None,
+                    })
+                })
+                .collect();
+            if text.trim().is_empty() && attachments.is_empty() {
+                return None;
+            }
+            let mut message = match (m.sender.as_str(), &model) {
+                ("human", _) => Message::new(Role::User, text),
+                ("assistant", Some(model)) => Message::reply(model, text),
+                ("assistant", None) => Message::new(Role::Assistant, text),
+                _ => return None,
+            };
+            message.attachments = attachments;
+            if let Some(time) = claude_time(m.created_at.as_deref()) {
+                message.timestamp = Some(time);
+            }
+            Some(message)
+        })
+        .collect();
+    if messages.is_empty() {
+        return None;
+    }
+    let title = conversation
+        .name
+        .filter(|t| !t.trim().is_empty())
+        .unwrap_or_else(|| "Imported chat".to_string());
+    Some(Chat::new(
+        format!("claude-{}", conversation.uuid),
+        title,
+        model.unwrap_or_else(|| fallback_model.to_string()),
+        messages,
+    ))
+}
+
+/// The role a markdown heading such as `## User` or `### Assistant` opens a
+/// section for.
+fn section_role(line: &str) -> Option<Role> {
+    let heading = line.strip_prefix('#')?.trim_start_matches('#');
+    if line.len() - heading.len() > 3 || !heading.starts_with(' ') {
+        return None;
+    }
+    let word = heading
+        .trim()
+        .split(|c: char| !c.is_alphanumeric())
+        .next()?
+        .to_lowercase();
+    match word.as_str() {
+        "user" | "you" | "human" => Some(Role::User),
+        "assistant" | "ai" | "model" => Some(Role::Assistant),
+        "system" => Some(Role::System),
+        _ => None,
+    }
+}
+
+/// Reads a text or markdown file into a new chat. A file split into
+/// `## User` / `## Assistant` (or `## System`) sections becomes one message
+/// per section; any other file becomes a single user message. The title is
+/// the file's first `# ` heading, or else its name. Headings inside code
+/// blocks are left alone.
+pub fn markdown(path: &Path, model: &str) -> Result<Chat, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let mut title = None;
+    let mut sections: Vec<(Role, Vec<&str>)> = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        let role = if in_code { None } else { section_role(line) };
+        if let Some(role) = role {
+            sections.push((role, Vec::new()));
+        } else if let Some((_, lines)) = sections.last_mut() {
+            lines.push(line);
+        } else if !in_code
+            && title.is_none()
+            && let Some(heading) = line.strip_prefix("# ")
+        {
+            title = Some(heading.trim().to_string());
+        }
+    }
+    let messages: Vec<Message> = if sections.is_empty() {
+        vec![Message::new(Role::User, text.trim())]
+    } else {
+        sections
+            .into_iter()
+            .map(|(role, lines)| Message::new(role, lines.join("\n").trim()))
+            .filter(|m| !m.content.is_empty())
+            .collect()
+    };
+    if messages.iter().all(|m| m.content.is_empty()) {
+        return Err(format!("{} has no text to import", path.display()));
+    }
+    let title = title.filter(|t| !t.is_empty()).unwrap_or_else(|| {
+        path.file_stem().map_or_else(
+            || "Imported chat".into(),
+            |s| s.to_string_lossy().into_owned(),
+        )
+    });
+    Ok(Chat::new(
+        uuid::Uuid::new_v4().to_string(),
+        title,
+        model.to_string(),
+        messages,
+    ))
+}
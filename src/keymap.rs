@@ -0,0 +1,119 @@
+use crate::app::Mode;
+use crate::config::{KEY_ACTIONS, KeyBindings};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Formats a key event the way bindings are written in config.toml, e.g.
+/// `n`, `Tab`, `Ctrl-n`, `Alt-Enter`. Returns `None` for keys that cannot be
+/// bound.
+pub fn format_key(key: &KeyEvent) -> Option<String> {
+    let base = match key.code {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Enter => "Enter".to_string(),
+        KeyCode::Tab => "Tab".to_string(),
+        KeyCode::BackTab => "BackTab".to_string(),
+        KeyCode::Esc => "Esc".to_string(),
+        KeyCode::Backspace => "Backspace".to_string(),
+        KeyCode::Delete => "Delete".to_string(),
+        KeyCode::Up => "Up".to_string(),
+        KeyCode::Down => "Down".to_string(),
+        KeyCode::Left => "Left".to_string(),
+        KeyCode::Right => "Right".to_string(),
+        KeyCode::Home => "Home".to_string(),
+        KeyCode::End => "End".to_string(),
+        KeyCode::PageUp => "PageUp".to_string(),
+        KeyCode::PageDown => "PageDown".to_string(),
+        KeyCode::F(n) => format!("F{}", n),
+        _ => return None,
+    };
+
+    let mut chord = String::new();
+    if key.modifiers.contains(KeyModifiers::CONTROL) {
+        chord.push_str("Ctrl-");
+    }
+    if key.modifiers.contains(KeyModifiers::ALT) {
+        chord.push_str("Alt-");
+    }
+    // Shifted characters already arrive upper-cased.
+    if key.modifiers.contains(KeyModifiers::SHIFT) && !matches!(key.code, KeyCode::Char(_)) {
+        chord.push_str("Shift-");
+    }
+    chord.push_str(&base);
+    Some(chord)
+}
+
+/// Actions whose keys come from `[keybindings]` in config.toml.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    NewChat,
+    ToggleSidebar,
+    SwitchFocus,
+    LockFocus,
+    DeleteChat,
+    YankSelection,
+    InsertMode,
+    ExitInsertMode,
+    CommandMode,
+    OpenSettings,
+    StopGeneration,
+    Paste,
+}
+
+/// Every action with its config key and the modes it is available in.
+const ACTIONS: &[(Action, &str, &[Mode])] = &[
+    (Action::NewChat, "new_chat", &[Mode::Normal]),
+    (Action::ToggleSidebar, "toggle_sidebar", &[Mode::Normal]),
+    (Action::SwitchFocus, "switch_focus", &[Mode::Normal]),
+    (Action::LockFocus, "lock_focus", &[Mode::Normal]),
+    (Action::DeleteChat, "delete_chat", &[Mode::Normal]),
+    (Action::YankSelection, "copy_code", &[Mode::Visual]),
+    (Action::InsertMode, "insert_mode", &[Mode::Normal]),
+    (Action::ExitInsertMode, "exit_insert_mode", &[Mode::Insert]),
+    (
+        Action::CommandMode,
+        "command_mode",
+        &[Mode::Normal, Mode::Visual],
+    ),
+    (Action::OpenSettings, "open_settings", &[Mode::Normal]),
+    (
+        Action::StopGeneration,
+        "stop_generation",
+        &[Mode::Normal, Mode::Insert, Mode::Visual],
+    ),
+    (Action::Paste, "paste", &[Mode::Insert]),
+];
+
+/// The action `key` is bound to in `mode`, if any.
+pub fn resolve(bindings: &KeyBindings, mode: Mode, key: &KeyEvent) -> Option<Action> {
+    let chord = format_key(key)?;
+    ACTIONS
+        .iter()
+        .filter(|(_, _, modes)| modes.contains(&mode))
+        .find(|(_, name, _)| bindings.get(name) == Some(chord.as_str()))
+        .map(|(action, _, _)| *action)
+}
+
+/// The code block, counting from 0, that `key` copies through the
+/// `copy_code_blocks` list.
+pub fn copy_block(bindings: &KeyBindings, key: &KeyEvent) -> Option<usize> {
+    let chord = format_key(key)?;
+    bindings.copy_code_blocks.iter().position(|k| *k == chord)
+}
+
+/// Labels of the other actions bound to `chord` in a mode that `action` is
+/// also available in.
+pub fn conflicts(bindings: &KeyBindings, action: &str, chord: &str) -> Vec<&'static str> {
+    let modes = |name: &str| {
+        ACTIONS
+            .iter()
+            .find(|(_, n, _)| *n == name)
+            .map_or(&[][..], |(_, _, modes)| *modes)
+    };
+    let own = modes(action);
+    KEY_ACTIONS
+        .iter()
+        .filter(|(name, _)| *name != action && bindings.get(name) == Some(chord))
+        .filter(|(name, _)| modes(name).iter().any(|mode| own.contains(mode)))
+        .map(|(_, label)| *label)
+        .collect()
+}
@@ -1,114 +1,2427 @@
 mod api;
 use crate::config::CustomModel;
 mod app;
+mod attachments;
 mod clipboard;
 mod config;
+mod export;
+mod git;
+mod import;
+mod keymap;
+mod palette;
+mod patch;
+mod prune;
+mod redact;
+mod remote;
+mod search;
+mod secrets;
+mod stats;
 mod storage;
 mod ui;
 
-use crate::app::Focus;
-use crate::app::{App, Mode, SettingsTab};
-use crate::config::{load_or_create_config, save_config};
-use crate::storage::{load_history, save_history};
+use crate::api::GenerationParams;
+use crate::app::{
+    App, Candidate, Comparison, GeneralSetting, Message, Mode, PendingAction, Role, SettingsTab,
+};
+use crate::app::{Chat, Focus};
+use crate::config::{
+    ColorMode, RedactAction, SidebarPosition, StartupChat, load_or_create_config, save_config,
+};
+use crate::keymap::Action;
+use crate::storage::{Transfer, Transferred, save_history};
 use anyhow::Result;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent},
+    cursor,
+    event::{
+        self, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+        Event, KeyCode, KeyEvent, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
     execute,
-    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
+    terminal::{
+        EnterAlternateScreen, LeaveAlternateScreen, SetTitle, disable_raw_mode, enable_raw_mode,
+        supports_keyboard_enhancement,
+    },
 };
+use futures_util::FutureExt;
 use ratatui::{Terminal, backend::CrosstermBackend};
-use std::env;
-use std::{io, time::Duration};
-use tokio::task;
+use std::{
+    io::{self, Write},
+    panic::AssertUnwindSafe,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::UnboundedReceiver;
 use url::Url;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut app = App::new();
-    app.chats = load_history();
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = parse_args()?;
+    if args.encrypt_keys {
+        return encrypt_keys();
+    }
+    if let Some(path) = &args.export_config {
+        let config = config::read_config().map_err(anyhow::Error::msg)?;
+        config::export_config(&config, path).map_err(anyhow::Error::msg)?;
+        println!("Exported settings without API keys to {}", path.display());
+        return Ok(());
+    }
+    if let Some(path) = &args.import_config {
+        // Only a missing config.toml starts from the defaults; one that does
+        // not parse is never replaced.
+        let current = if config::get_config_path()?.exists() {
+            config::read_config().map_err(anyhow::Error::msg)?
+        } else {
+            config::Settings::default()
+        };
+        let imported = config::import_config(path, &current).map_err(anyhow::Error::msg)?;
+        save_config(&imported).map_err(anyhow::Error::msg)?;
+        println!("Imported settings from {}", path.display());
+        return Ok(());
+    }
+    let (mut config, config_error, config_notes) = load_or_create_config()?;
+    storage::configure(&config.storage)?;
+    let encrypted = storage::history_encrypted();
+    if encrypted || config.storage.encrypt {
+        storage::set_cipher(history_cipher(encrypted)?);
+    }
+    storage::prepare(&config.storage)?;
+    storage::expire_trash(config.storage.trash_days);
+    // Reads every copy of the history, so it stays off the startup path.
+    std::thread::spawn(|| {
+        let _ = attachments::collect_garbage();
+    });
+    let mut app = App::new();
+    let history_error = match storage::load_history() {
+        Ok(chats) => {
+            app.chats = chats;
+            app.mark_saved();
+            None
+        }
+        Err(e) => Some(e),
+    };
+    sync_history(&mut app);
+
+    if secrets::api_keys(&config)
+        .iter()
+        .any(|key| secrets::is_encrypted(key))
+    {
+        app.vault = Some(unlock_vault(&config)?);
+    }
+
+    apply_config(&mut app, &config);
+    if let Some(path) = &args.import {
+        if let Some(e) = history_error {
+            anyhow::bail!("Not importing: the chat history could not be read: {}", e);
+        }
+        let model = app
+            .new_chat_model()
+            .unwrap_or_else(|| app.current_model.clone());
+        let chat = import::markdown(path, &model).map_err(anyhow::Error::msg)?;
+        println!(
+            "Imported {} as the chat \"{}\" ({} messages)",
+            path.display(),
+            chat.title,
+            chat.messages.len()
+        );
+        app.chats.push(chat);
+        save_history(&app.chats)?;
+        storage::finish_transfers();
+        return Ok(());
+    }
+    if !config_notes.is_empty() {
+        app.set_info(&config_notes.join("; "));
+    }
+    if let Some(e) = config_error {
+        app.set_error(&e);
+    }
+
+    // Retention spares the open chat; at startup that is the latest one.
+    app.current_chat = app.chats_by_recency().first().copied().unwrap_or(0);
+    let expired = app.apply_retention();
+    report_retention(&mut app, expired);
+    start_session(&mut app, &config.startup);
+    report_unreadable(&mut app);
+    if let Some(e) = history_error {
+        app.set_error("The chat history could not be read; nothing is saved over it");
+        app.pending_action = Some(PendingAction::ReleaseHistory(e.to_string()));
+        app.mode = Mode::Confirm;
+    }
+    if let Some(model) = &app.default_model
+        && let Err(e) = app.resolve_endpoint(model)
+    {
+        app.set_error(&format!("default_model {}: {}", model, e));
+    }
+    // Panics on the UI thread would otherwise leave the shell in raw mode on
+    // the alternate screen, hiding the message.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().name() == Some("main") {
+            let _ = restore_terminal();
+        }
+        default_hook(info);
+    }));
+
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    // Save the terminal's title so it can be restored on exit.
+    write!(stdout, "\x1b[22;0t")?;
+    execute!(
+        stdout,
+        EnterAlternateScreen,
+        EnableMouseCapture,
+        EnableBracketedPaste
+    )?;
+    enhance_keyboard()?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let mut transfers = storage::transfers();
+    let res = AssertUnwindSafe(run_app(
+        &mut terminal,
+        &mut app,
+        &mut config,
+        &mut transfers,
+    ))
+    .catch_unwind()
+    .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(panic) => {
+            // The history file is left alone in case the chats are what broke.
+            app.sync_draft();
+            match storage::save_recovery(&app.chats) {
+                Ok(path) => eprintln!("meowi crashed; your chats were saved to {}", path.display()),
+                Err(e) => eprintln!("meowi crashed and could not save your chats: {}", e),
+            }
+            std::panic::resume_unwind(panic);
+        }
+    };
+
+    restore_terminal()?;
+
+    app.sync_draft();
+    sync_history(&mut app);
+    if app.history_changed()
+        && let Err(e) = save_history(&app.chats)
+    {
+        eprintln!("Could not save the chat history: {}", e);
+    }
+    storage::finish_transfers();
+    if let Some(transfers) = &mut transfers {
+        while let Ok((_, result)) = transfers.try_recv() {
+            if let Err(e) = result {
+                eprintln!("Could not sync with [storage.remote]: {}", e);
+            }
+        }
+    }
+    let _ = storage::save_session(&app.session());
+    config.prompts = app.prompts.clone();
+    if let Err(e) = save_config(&config) {
+        eprintln!("{}", e);
+    }
+    if storage::history_held() {
+        eprintln!(
+            "The chat history could not be read, so this session's chats were not saved over it"
+        );
+    }
+    if config.storage.git
+        && let Err(e) = storage::commit_history(&config.storage.git_message)
+    {
+        eprintln!("Could not commit the chat history: {}", e);
+    }
+
+    if let Err(err) = res {
+        println!("{:?}", err);
+    }
+
+    Ok(())
+}
+
+/// Opens the chat `[startup]` asks for and shows or hides the sidebar.
+fn start_session(app: &mut App<'_>, startup: &config::Startup) {
+    app.current_model = app
+        .new_chat_model()
+        .unwrap_or_else(|| "No model selected".to_string());
+    app.sidebar_visible = startup.sidebar;
+    if app.chats.is_empty() {
+        return;
+    }
+    // The picker opens over the latest chat, which Esc leaves open.
+    let latest = app.chats_by_recency()[0];
+    app.switch_chat(latest);
+    app.focus = Focus::Chat;
+    if startup.restore
+        && let Some(session) = storage::load_session()
+    {
+        app.restore_session(session);
+    }
+    match startup.open {
+        StartupChat::Last => {}
+        StartupChat::New => app.create_new_chat(),
+        StartupChat::Picker => {
+            app.start_search();
+            app.selected_picker_idx = 0;
+            app.mode = Mode::ChatPicker;
+        }
+    }
+    app.need_rebuild_cache = true;
+}
+
+/// Command-line options.
+#[derive(Default)]
+struct Args {
+    encrypt_keys: bool,
+    export_config: Option<std::path::PathBuf>,
+    import_config: Option<std::path::PathBuf>,
+    /// `meowi import <file>`: a text or markdown file to add as a chat.
+    import: Option<std::path::PathBuf>,
+}
+
+/// Parses the command line. `--config <file>` and `--data-dir <dir>` take
+/// precedence over `MEOWI_CONFIG_DIR` and `MEOWI_DATA_DIR`.
+fn parse_args() -> Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .map(std::path::PathBuf::from)
+                .ok_or_else(|| anyhow::anyhow!("{} needs a path", flag))
+        };
+        match flag.as_str() {
+            "--config" => config::set_config_path(value()?),
+            "--data-dir" => storage::set_data_dir(value()?),
+            "--encrypt-keys" => parsed.encrypt_keys = true,
+            "--export-config" => parsed.export_config = Some(value()?),
+            "--import-config" => parsed.import_config = Some(value()?),
+            "import" => parsed.import = Some(value()?),
+            _ => anyhow::bail!(
+                "Unknown option {}\nUsage: meowi [--config <file>] [--data-dir <dir>] [--encrypt-keys] \
+                 [--export-config <file>] [--import-config <file>] [import <file>]",
+                flag
+            ),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Asks for the passphrase of the encrypted API keys, up to three times.
+fn unlock_vault(config: &config::Settings) -> Result<secrets::Vault> {
+    for _ in 0..3 {
+        let passphrase = rpassword::prompt_password("Passphrase for config.toml API keys: ")?;
+        let mut vault = secrets::Vault::new(passphrase);
+        match vault.unlock(config) {
+            Ok(()) => return Ok(vault),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    anyhow::bail!("Could not decrypt the API keys in config.toml")
+}
+
+/// Asks for the history passphrase: up to three times when the history is
+/// already encrypted, else twice to set a new one.
+fn history_cipher(encrypted: bool) -> Result<secrets::FileCipher> {
+    if encrypted {
+        for _ in 0..3 {
+            let passphrase = rpassword::prompt_password("Passphrase for the chat history: ")?;
+            let cipher = secrets::FileCipher::new(passphrase);
+            match storage::check_cipher(&cipher) {
+                Ok(()) => return Ok(cipher),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        anyhow::bail!("Could not decrypt the chat history");
+    }
+    let passphrase = rpassword::prompt_password("New passphrase for the chat history: ")?;
+    if passphrase.is_empty() {
+        anyhow::bail!("The passphrase cannot be empty");
+    }
+    if rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+        anyhow::bail!("Passphrases do not match");
+    }
+    Ok(secrets::FileCipher::new(passphrase))
+}
+
+/// `meowi --encrypt-keys`: encrypts the plain API keys in config.toml with a
+/// passphrase. Keys that are already encrypted or name an environment
+/// variable are left as they are.
+fn encrypt_keys() -> Result<()> {
+    let mut config = config::read_config().map_err(anyhow::Error::msg)?;
+    let already = secrets::api_keys(&config)
+        .iter()
+        .any(|key| secrets::is_encrypted(key));
+    let mut vault = if already {
+        unlock_vault(&config)?
+    } else {
+        let passphrase = rpassword::prompt_password("New passphrase: ")?;
+        if passphrase.is_empty() {
+            anyhow::bail!("The passphrase cannot be empty");
+        }
+        if rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+            anyhow::bail!("Passphrases do not match");
+        }
+        secrets::Vault::new(passphrase)
+    };
+    let plain = |key: &str| !key.is_empty() && !secrets::is_encrypted(key) && !key.contains("${");
+    let mut count = 0;
+    for p in &mut config.providers {
+        if plain(&p.api_key) {
+            p.api_key = vault.encrypt(&p.api_key);
+            count += 1;
+        }
+    }
+    for cm in &mut config.custom_models {
+        if let CustomModel::Standalone {
+            api_key: Some(key), ..
+        } = cm
+            && plain(key)
+        {
+            *key = vault.encrypt(key);
+            count += 1;
+        }
+    }
+    save_config(&config).map_err(anyhow::Error::msg)?;
+    println!(
+        "Encrypted {} API key(s) in {}",
+        count,
+        config::get_config_path()?.display()
+    );
+    Ok(())
+}
+
+/// Copies the settings the app works from out of `config`. Used at startup
+/// and whenever config.toml changes on disk.
+fn apply_config(app: &mut App<'_>, config: &config::Settings) {
+    app.prompts = config.prompts.clone();
+    app.chains = config.chains.clone();
+    app.auto_continue = config.auto_continue;
+    let (redactor, redaction_errors) = redact::Redactor::new(&config.redaction);
+    app.redactor = redactor;
+    if let Some(e) = redaction_errors.first() {
+        app.set_error(e);
+    }
+    if let Some(vault) = app.vault.as_mut()
+        && let Err(e) = vault.unlock(config)
+    {
+        app.set_error(&e);
+    }
+    for saved in &config.providers {
+        if let Some(p) = app.providers.iter_mut().find(|p| p.name == saved.name) {
+            p.api_key = saved.api_key.clone();
+            p.enabled_models = saved.enabled_models.clone();
+            p.params = saved.params.clone();
+            for m in &saved.enabled_models {
+                if !p.models.contains(m) {
+                    p.models.push(m.clone());
+                }
+            }
+        }
+    }
+
+    app.custom_models = config.custom_models.clone();
+    app.loading_animation = config.loading_animation.clone();
+    app.ui_settings = config.ui.clone();
+    if let Err(e) = app.load_theme() {
+        app.set_error(&e);
+    }
+    app.pricing = config.pricing.clone();
+    app.context_windows = config.context_windows.clone();
+    app.model_prompts = config.model_prompts.clone();
+    app.keybindings = config.keybindings.clone();
+    app.default_model = config.default_model.clone();
+    app.autosave_secs = config.autosave_secs;
+    app.auto_title = config.auto_title.clone();
+    app.retention = config.retention.clone();
+    app.large_chats = config.large_chats.clone();
+    app.trash_days = config.storage.trash_days;
+    app.need_rebuild_cache = true;
+}
+
+/// Re-reads config.toml after it changed on disk. Our own writes match the
+/// settings in memory and are skipped; a file that does not parse is left
+/// for the user to fix while the current settings stay in effect.
+/// `saved` is the config as last written or read; settings changed here
+/// and not yet written are kept unless the file changed them too.
+fn reload_config(app: &mut App<'_>, config: &mut config::Settings, saved: &mut config::Settings) {
+    match config::read_config() {
+        Ok(fresh) => {
+            if toml::to_string(&fresh).ok() == toml::to_string(config).ok() {
+                *saved = fresh;
+                app.config_dirty = false;
+                return;
+            }
+            let merged = if app.config_dirty {
+                config::merge_settings(saved, config, &fresh)
+            } else {
+                Ok(fresh.clone())
+            };
+            match merged {
+                Ok(merged) => {
+                    *config = merged;
+                    *saved = fresh;
+                    apply_config(app, config);
+                    app.set_info("Reloaded config.toml");
+                }
+                Err(e) => app.set_error(&format!("Not reloaded: {}", e)),
+            }
+        }
+        Err(e) => app.set_error(&format!("Not reloaded: {}", e)),
+    }
+}
+
+/// Set while the terminal reports keys in the kitty keyboard protocol.
+static KEYBOARD_ENHANCED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the terminal to report keys unambiguously where it can, so that
+/// `Ctrl-i` arrives as itself rather than as `Tab`.
+fn enhance_keyboard() -> io::Result<()> {
+    if supports_keyboard_enhancement().unwrap_or(false) {
+        execute!(
+            io::stdout(),
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+        )?;
+        KEYBOARD_ENHANCED.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Undoes `enhance_keyboard`.
+fn restore_keyboard() -> io::Result<()> {
+    if KEYBOARD_ENHANCED.swap(false, Ordering::Relaxed) {
+        execute!(io::stdout(), PopKeyboardEnhancementFlags)?;
+    }
+    Ok(())
+}
+
+/// Leaves raw mode and the alternate screen and restores the title.
+fn restore_terminal() -> io::Result<()> {
+    restore_keyboard()?;
+    disable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(
+        stdout,
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableBracketedPaste,
+        cursor::Show
+    )?;
+    write!(stdout, "\x1b[23;0t")?;
+    stdout.flush()
+}
+
+async fn run_app<B: ratatui::backend::Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut App<'_>,
+    config: &mut config::Settings,
+    transfers: &mut Option<UnboundedReceiver<Transferred>>,
+) -> Result<()> {
+    let mut last_autosave = Instant::now();
+    let mut saved_config = config.clone();
+    // Kept alive for the whole session; without it there is no hot-reload.
+    let mut config_watch = config::watch_config(config);
+    if let Err(e) = &config_watch {
+        app.set_error(&format!("Not watching config.toml: {}", e));
+    }
+    let mut watched_prompts = config.prompts_dir.clone();
+    let mut title = String::new();
+    // Set when a reply finishes, until the next key press.
+    let mut finished = false;
+    loop {
+        let streaming_before = app.chats.iter().filter(|c| c.streaming).count();
+        app.process_stream();
+        app.process_block_runs();
+        process_transfers(app, transfers);
+        if let Ok((_, changes)) = &config_watch
+            && changes.try_iter().count() > 0
+        {
+            reload_config(app, config, &mut saved_config);
+            if config.prompts_dir != watched_prompts {
+                config_watch = config::watch_config(config);
+                if let Err(e) = &config_watch {
+                    app.set_error(&format!("Not watching config.toml: {}", e));
+                }
+                watched_prompts = config.prompts_dir.clone();
+            }
+        }
+        app.dispatch_queued();
+        // Whatever put another chat on screen, its messages are read now.
+        app.load_chat(app.current_chat);
+        app.warn_large_chat();
+        terminal.draw(|f| ui::draw(f, app))?;
+
+        let streaming = app.chats.iter().filter(|c| c.streaming).count();
+        finished |= streaming_before > 0 && streaming == 0;
+        let new_title = ui::terminal_title(app, finished);
+        if new_title != title {
+            execute!(io::stdout(), SetTitle(&new_title))?;
+            title = new_title;
+        }
+
+        // Save as soon as a reply is complete or a chat is renamed, and
+        // otherwise every `autosave_secs` if anything changed. Settings are
+        // written when the Settings screen is closed.
+        let autosave_due =
+            app.autosave_secs > 0 && last_autosave.elapsed().as_secs() >= app.autosave_secs;
+        if autosave_due {
+            storage::fetch();
+            sync_history(app);
+        }
+        if app.history_dirty && (streaming < streaming_before || autosave_due || app.save_soon) {
+            flush_history(app);
+        }
+        if app.config_dirty && (autosave_due || !app.in_settings()) {
+            // A failed write is reported once; exiting tries again.
+            match save_config(config) {
+                Ok(()) => saved_config = config.clone(),
+                Err(e) => app.set_error(&e),
+            }
+            app.config_dirty = false;
+        }
+        if autosave_due {
+            last_autosave = Instant::now();
+        }
+
+        if event::poll(Duration::from_millis(50))? {
+            match event::read()? {
+                Event::Key(key) => {
+                    finished = false;
+                    let before = app.edit_fingerprint();
+                    handle_key(app, key, config).await?;
+                    app.history_dirty |= app.edit_fingerprint() != before;
+                }
+                Event::Resize(_, _) => app.handle_resize(),
+                Event::Paste(text) => {
+                    let before = app.edit_fingerprint();
+                    app.paste_text(&text);
+                    app.history_dirty |= app.edit_fingerprint() != before;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(block) = app.pending_editor.take() {
+            open_in_editor(terminal, app, &block)?;
+        }
+    }
+}
+
+/// Merges what other machines changed in a synced history folder.
+fn sync_history(app: &mut App<'_>) {
+    match storage::sync(&mut app.chats) {
+        Ok(0) => {}
+        Ok(merged) => {
+            app.history_dirty = true;
+            app.need_rebuild_cache = true;
+            app.set_info(&format!(
+                "Merged changes to {} chat(s) from another machine",
+                merged
+            ));
+        }
+        Err(e) => app.set_error(&format!("Cannot merge synced changes: {}", e)),
+    }
+}
+
+/// Merges what a finished download brought in and reports transfers to
+/// or from `[storage.remote]` that failed.
+fn process_transfers(app: &mut App<'_>, transfers: &mut Option<UnboundedReceiver<Transferred>>) {
+    let Some(transfers) = transfers else {
+        return;
+    };
+    while let Ok((transfer, result)) = transfers.try_recv() {
+        match result {
+            Ok(()) if transfer == Transfer::Pull => sync_history(app),
+            Ok(()) => {}
+            Err(e) => app.set_error(&format!("Cannot sync with [storage.remote]: {}", e)),
+        }
+    }
+}
+
+/// Says where the chats removed by the retention limits went.
+fn report_retention(app: &mut App<'_>, expired: usize) {
+    if expired == 0 || app.error_message.is_some() {
+        return;
+    }
+    let place = if app.trash_days > 0 {
+        "the trash (:trash brings them back)"
+    } else {
+        "archives/ (:import archive brings them back)"
+    };
+    app.set_info(&format!(
+        "Moved {} chat(s) past the retention limits to {}",
+        expired, place
+    ));
+}
+
+/// Writes the chats (with the shown chat's draft) to disk if any changed.
+fn flush_history(app: &mut App<'_>) {
+    app.sync_draft();
+    let expired = app.apply_retention();
+    report_retention(app, expired);
+    if !app.history_changed() {
+        app.mark_saved();
+    } else if !save_chats(app) {
+        // Tried again at the next autosave rather than on every frame.
+        app.save_soon = false;
+    }
+}
+
+/// Writes the chats now and records them as saved. A failure is shown and
+/// leaves them unsaved, so autosave and exit try again.
+fn save_chats(app: &mut App<'_>) -> bool {
+    match save_history(&app.chats) {
+        Ok(()) => {
+            app.mark_saved();
+            true
+        }
+        Err(e) => {
+            app.set_error(&format!("Cannot save the chat history: {}", e));
+            false
+        }
+    }
+}
+
+/// Names the chats whose files could not be read. They stay listed, and in
+/// the index, until their files are repaired.
+fn report_unreadable(app: &mut App<'_>) {
+    let titles: Vec<String> = app
+        .chats
+        .iter()
+        .filter(|c| c.stub.as_ref().is_some_and(|s| s.unreadable))
+        .map(|c| format!("\"{}\"", c.title))
+        .collect();
+    if !titles.is_empty() {
+        app.set_error(&format!(
+            "Cannot read {} chat file(s): {}; they are left in place for repair",
+            titles.len(),
+            titles.join(", ")
+        ));
+    }
+}
+
+/// Replaces the history with the backup selected in `:restore`. The history
+/// it replaces is backed up first, so a restore can itself be undone.
+fn restore_backup(app: &mut App<'_>) {
+    let Some(backup) = app.backups.get(app.selected_backup_idx).cloned() else {
+        return;
+    };
+    if app.chats.iter().any(|c| c.streaming) {
+        app.set_error("Wait for replies to finish before restoring");
+        return;
+    }
+    let chats = match storage::load_backup(&backup.path) {
+        Ok(chats) => chats,
+        Err(e) => {
+            app.set_error(&format!("Cannot read {}: {}", backup.path.display(), e));
+            return;
+        }
+    };
+    // The unreadable history is kept aside rather than restored over.
+    if storage::history_held()
+        && let Err(e) = storage::release_history()
+    {
+        app.set_error(&format!(
+            "Not restoring; cannot copy the history aside: {}",
+            e
+        ));
+        return;
+    }
+    app.sync_draft();
+    if !save_chats(app) {
+        return;
+    }
+    if let Err(e) = storage::backup(true) {
+        app.set_error(&format!("Not restoring; cannot back up the history: {}", e));
+        return;
+    }
+    app.chats = chats;
+    app.chat_views.clear();
+    app.previous_chat = None;
+    app.current_chat = 0;
+    if let Some(&latest) = app.chats_by_recency().first() {
+        app.switch_chat(latest);
+    }
+    app.need_rebuild_cache = true;
+    if !save_chats(app) {
+        return;
+    }
+    app.set_info(&format!(
+        "Restored {} chats from {}; the replaced history is the newest backup",
+        app.chats.len(),
+        backup.time.format("%Y-%m-%d %H:%M:%S")
+    ));
+}
+
+/// Suspends the UI and opens `block` in `$VISUAL`/`$EDITOR`. If the file
+/// was changed, the edited text goes to the unnamed register.
+fn open_in_editor<B: ratatui::backend::Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut App<'_>,
+    block: &crate::app::CodeBlock,
+) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let path = std::env::temp_dir().join(format!(
+        "meowi-{}-{}",
+        std::process::id(),
+        block.suggested_filename()
+    ));
+    if let Err(e) = std::fs::write(&path, &block.content) {
+        app.set_error(&format!("Cannot write {}: {}", path.display(), e));
+        return Ok(());
+    }
+
+    restore_keyboard()?;
+    disable_raw_mode()?;
+    execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableBracketedPaste
+    )?;
+    let mut words = editor.split_whitespace();
+    let status = std::process::Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&path)
+        .status();
+    enable_raw_mode()?;
+    execute!(
+        io::stdout(),
+        EnterAlternateScreen,
+        EnableMouseCapture,
+        EnableBracketedPaste
+    )?;
+    enhance_keyboard()?;
+    terminal.clear()?;
+
+    let edited = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    match status {
+        Err(e) => app.set_error(&format!("Cannot run {}: {}", editor, e)),
+        Ok(status) if !status.success() => {
+            app.set_error(&format!("{} exited with {}", editor, status))
+        }
+        Ok(_) => match edited {
+            Ok(text) if text.trim_end() != block.content.trim_end() => {
+                app.registers.insert('"', text);
+                app.set_info("Edited block is in register \"");
+            }
+            _ => app.set_info("Code block unchanged"),
+        },
+    }
+    Ok(())
+}
+
+async fn run_command(app: &mut App<'_>, cmd: &str, config: &mut config::Settings) -> Result<()> {
+    let (name, args) = cmd
+        .split_once(' ')
+        .map_or((cmd, ""), |(name, args)| (name, args.trim()));
+    match name {
+        "q" => return Err(anyhow::anyhow!("Quit")),
+        "compare" => start_comparison(app, args),
+        "fork" => fork_at_cursor(app),
+        "prompt" => chat_prompt_command(app, args),
+        "registers" | "reg" => {
+            let mut names: Vec<&char> = app.registers.keys().collect();
+            names.sort();
+            let listing: Vec<String> = names
+                .into_iter()
+                .map(|name| {
+                    let text = &app.registers[name];
+                    let first = text.lines().next().unwrap_or("");
+                    format!("\"{} {}", name, first.chars().take(20).collect::<String>())
+                })
+                .collect();
+            if listing.is_empty() {
+                app.set_info("All registers are empty");
+            } else {
+                app.set_info(&listing.join("  "));
+            }
+        }
+        "trash" => open_trash(app),
+        "stats" => {
+            app.load_all_chats();
+            show_stats(app, app.stats.period);
+            app.mode = Mode::Stats;
+        }
+        "prune" => prune_command(app, args),
+        "large" if app.has_valid_chat() => app.mode = Mode::LargeChat,
+        "large" => app.set_error("No chat selected"),
+        "restore" => match storage::backups() {
+            Ok(backups) if backups.is_empty() => app.set_info("No backups yet"),
+            Ok(backups) => {
+                app.backups = backups;
+                app.selected_backup_idx = 0;
+                app.mode = Mode::Restore;
+            }
+            Err(e) => app.set_error(&format!("Cannot list backups: {}", e)),
+        },
+        "bookmarks" => {
+            app.load_all_chats();
+            if app.bookmarks().is_empty() {
+                app.set_info("No bookmarks yet; press mb on a message");
+            } else {
+                app.selected_bookmark_idx = 0;
+                app.mode = Mode::Bookmarks;
+            }
+        }
+        "set" | "set!" => set_command(app, config, args, name == "set!"),
+        "config" => config_command(app, config, args),
+        "chats" => {
+            app.start_search();
+            app.picker_filtering = false;
+            app.selected_picker_idx = 0;
+            app.mode = Mode::ChatPicker;
+        }
+        "sidebar" => {
+            let side = match args {
+                "" => match app.ui_settings.sidebar {
+                    SidebarPosition::Left => "right",
+                    SidebarPosition::Right => "left",
+                },
+                side => side,
+            };
+            match app.ui_settings.set("sidebar", side) {
+                Ok(()) => app.set_info(&format!("Sidebar on the {}", side)),
+                Err(e) => app.set_error(&e),
+            }
+        }
+        "theme" | "theme!" => theme_command(app, config, args, name == "theme!"),
+        "attach" => attach_command(app, args),
+        "run" => run_block_command(app),
+        "apply" => apply_patch_command(app),
+        "wblock" | "wblock!" => write_block_command(app, args, name == "wblock!"),
+        "w" | "w!" => write_selection_command(app, args, name == "w!"),
+        "export" | "export!" => export_command(app, args, name == "export!"),
+        "import" => import_command(app, args),
+        "queue" => queue_command(app, args),
+        "note" | "notes" => note_command(app, args),
+        "readonly" | "ro" => match app.chats.get_mut(app.current_chat) {
+            Some(chat) => {
+                chat.read_only = !chat.read_only;
+                let info = if chat.read_only {
+                    "Chat is now read-only"
+                } else {
+                    "Chat can be edited again"
+                };
+                if save_chats(app) {
+                    app.set_info(info);
+                }
+            }
+            None => app.set_error("No chat selected"),
+        },
+        "continue" => match app.continue_reply(app.current_chat) {
+            Ok(()) => app.set_info("Continuing…"),
+            Err(e) => app.set_error(&e),
+        },
+        "chain" => {
+            let (chain, input) = args
+                .split_once(' ')
+                .map_or((args, ""), |(name, input)| (name, input.trim()));
+            if chain.is_empty() {
+                let names: Vec<&str> = app.chains.iter().map(|c| c.name.as_str()).collect();
+                if names.is_empty() {
+                    app.set_info("No chains configured; add [[chains]] to config.toml");
+                } else {
+                    app.set_info(&format!("Chains: {}", names.join(", ")));
+                }
+            } else {
+                match app.start_chain(chain, input) {
+                    Ok(()) => app.set_info(&format!("Running chain \"{}\"…", chain)),
+                    Err(e) => app.set_error(&e),
+                }
+            }
+        }
+        "detach" => {
+            let count = app.pending_attachments.len();
+            app.pending_attachments.clear();
+            app.set_info(&format!("Removed {} pending attachment(s)", count));
+        }
+        "origin" => {
+            let origin = app
+                .chats
+                .get(app.current_chat)
+                .and_then(|c| c.forked_from.clone());
+            match origin.and_then(|o| app.chats.iter().position(|c| c.id == o.chat_id)) {
+                Some(idx) => app.switch_chat(idx),
+                None => app.set_error("This chat is not a fork of an existing chat"),
+            }
+        }
+        "regen" => match app.regenerate(Some(args).filter(|a| !a.is_empty())) {
+            Ok(()) => app.set_info("Regenerating…"),
+            Err(e) => app.set_error(&e),
+        },
+        _ => app.set_error(&format!("Unknown command: :{}", cmd)),
+    }
+    Ok(())
+}
+
+/// Sends `message` in the current chat, or queues it while a reply is still
+/// streaming.
+fn send_or_queue(app: &mut App<'_>, message: Message) {
+    let chat = &mut app.chats[app.current_chat];
+    chat.draft.clear();
+    if chat.streaming {
+        chat.queued.push(message);
+        let count = chat.queued.len();
+        app.set_info(&format!(
+            "Queued; {} message(s) will be sent when the reply finishes",
+            count
+        ));
+    } else if let Err(e) = app.send_message(app.current_chat, message) {
+        app.set_error(&format!("{} (press r to retry)", e));
+    }
+}
+
+/// `:note <text>` adds a line to the current chat's notes, `:note` shows
+/// them, `:note pop` removes the last line and `:note clear` all of them.
+fn note_command(app: &mut App<'_>, args: &str) {
+    let Some(chat) = app.chats.get_mut(app.current_chat) else {
+        app.set_error("No chat selected");
+        return;
+    };
+    match args {
+        "" if chat.notes.is_empty() => app.set_info("No notes yet. Usage: :note <text>"),
+        "" => {
+            app.notes_scroll = 0;
+            app.mode = Mode::Notes;
+        }
+        "pop" => {
+            let kept = chat.notes.lines().count().saturating_sub(1);
+            chat.notes = chat.notes.lines().take(kept).collect::<Vec<_>>().join("\n");
+            if save_chats(app) {
+                app.set_info("Removed the last note");
+            }
+        }
+        "clear" => {
+            chat.notes.clear();
+            if save_chats(app) {
+                app.set_info("Notes cleared");
+            }
+        }
+        text => {
+            if !chat.notes.is_empty() {
+                chat.notes.push('\n');
+            }
+            chat.notes.push_str(text);
+            if save_chats(app) {
+                app.set_info("Note added; :note shows all notes");
+            }
+        }
+    }
+}
+
+/// `:queue` lists the current chat's queued messages; `:queue clear` drops
+/// them.
+fn queue_command(app: &mut App<'_>, args: &str) {
+    let Some(chat) = app.chats.get_mut(app.current_chat) else {
+        app.set_error("No chat selected");
+        return;
+    };
+    match args {
+        "" if chat.queued.is_empty() => app.set_info("No queued messages"),
+        "" => {
+            let listing: Vec<String> = chat
+                .queued
+                .iter()
+                .enumerate()
+                .map(|(i, m)| format!("{}. {}", i + 1, ui::one_line_preview(&m.content, 30)))
+                .collect();
+            app.set_info(&listing.join("  "));
+        }
+        "clear" => {
+            let count = chat.queued.len();
+            chat.queued.clear();
+            app.set_info(&format!("Dropped {} queued message(s)", count));
+        }
+        _ => app.set_error("Usage: :queue [clear]"),
+    }
+}
+
+/// `:run` asks to run the code block under the cursor.
+fn run_block_command(app: &mut App<'_>) {
+    let argv = app.block_under_cursor().and_then(|block| {
+        block.run_command().ok_or_else(|| {
+            format!(
+                "Don't know how to run {} blocks",
+                block.language.as_deref().unwrap_or("untagged")
+            )
+        })
+    });
+    match argv.and_then(|argv| app.chats[app.current_chat].writable().map(|_| argv)) {
+        Ok(argv) => {
+            app.pending_action = Some(PendingAction::RunBlock(argv));
+            app.mode = Mode::Confirm;
+            app.set_info("Run this code block? (y/n)");
+        }
+        Err(e) => app.set_error(&e),
+    }
+}
+
+/// `:apply` dry-runs the diff under the cursor against the working
+/// directory and asks before applying it.
+fn apply_patch_command(app: &mut App<'_>) {
+    let block = match app.block_under_cursor() {
+        Ok(block) => block,
+        Err(e) => {
+            app.set_error(&e);
+            return;
+        }
+    };
+    if !patch::is_diff(&block.content) {
+        app.set_error("The code block is not a unified diff");
+        return;
+    }
+    let diff = block.content.clone();
+    match patch::check(&diff) {
+        Ok(strip) => {
+            let files = patch::changed_files(&diff).len();
+            app.pending_action = Some(PendingAction::ApplyPatch { diff, strip });
+            app.mode = Mode::Confirm;
+            app.set_info(&format!("Apply patch to {} file(s)? (y/n)", files));
+        }
+        Err(e) => app.set_error(&format!("Patch does not apply: {}", e)),
+    }
+}
+
+/// `:wblock <path>` saves the code block under the cursor; `:wblock!`
+/// overwrites an existing file. Without a path, the command line is
+/// reopened with a name suggested by the block's language.
+fn write_block_command(app: &mut App<'_>, args: &str, overwrite: bool) {
+    let block = match app.block_under_cursor() {
+        Ok(block) => block,
+        Err(e) => {
+            app.set_error(&e);
+            return;
+        }
+    };
+    if args.is_empty() {
+        let name = block.suggested_filename();
+        // A path the model gave is relative to the project, not to the
+        // directory saved to last.
+        let suggestion = match &app.save_dir {
+            Some(dir) if !name.contains('/') => dir.join(name).display().to_string(),
+            _ => name,
+        };
+        app.command = format!("wblock {}", suggestion);
+        app.mode = Mode::Command;
+        return;
+    }
+    let path = std::path::Path::new(args);
+    if path.exists() && !overwrite {
+        app.set_error(&format!("{} exists; use :wblock! to overwrite", args));
+        return;
+    }
+    let mut content = block.content.clone();
+    content.push('\n');
+    let lines = block.content.lines().count();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty())
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        app.set_error(&format!("Cannot create {}: {}", dir.display(), e));
+        return;
+    }
+    match std::fs::write(path, content) {
+        Ok(()) => {
+            // Later suggestions go to the same directory, unless it is the
+            // current one.
+            let dir = std::path::absolute(path)
+                .ok()
+                .and_then(|p| p.parent().map(|dir| dir.to_path_buf()));
+            let here = std::env::current_dir().ok();
+            app.save_dir = dir.filter(|dir| Some(dir) != here.as_ref());
+            app.set_info(&format!("Wrote {} ({} lines)", args, lines));
+        }
+        Err(e) => app.set_error(&format!("Cannot write {}: {}", args, e)),
+    }
+}
+
+/// `:w [raw] <path>`, typed from Visual mode, writes the selected lines to a
+/// file as `y` would copy them; with `raw` it writes the whole messages the
+/// selection touches, as written.
+fn write_selection_command(app: &mut App<'_>, args: &str, overwrite: bool) {
+    const USAGE: &str = "Usage: select lines with v, then :w [raw] <path>";
+    let Some((lo, hi)) = app.selection() else {
+        app.set_error(USAGE);
+        return;
+    };
+    let (raw, path) = match args.split_once(' ') {
+        Some(("raw", path)) => (true, path.trim()),
+        _ => (false, args),
+    };
+    if path.is_empty() {
+        app.set_error(USAGE);
+        return;
+    }
+    let text = if raw {
+        app.selection_messages(lo, hi).join("\n\n")
+    } else {
+        app.selection_text(lo, hi).join("\n")
+    };
+    let target = std::path::Path::new(path);
+    if target.exists() && !overwrite {
+        app.set_error(&format!("{} exists; use :w! to overwrite", path));
+        return;
+    }
+    let lines = text.lines().count();
+    match std::fs::write(target, text + "\n") {
+        Ok(()) => app.set_info(&format!("Wrote {} ({} lines)", path, lines)),
+        Err(e) => app.set_error(&format!("Cannot write {}: {}", path, e)),
+    }
+}
+
+/// `:export html|json|jsonl [options] <path>` writes the current chat to a
+/// file; without a path it suggests one named after the chat. The JSON
+/// formats take `all` (every chat), `nosystem` and `meta`. `:export site
+/// <dir>` writes every chat as a static site.
+fn export_command(app: &mut App<'_>, args: &str, overwrite: bool) {
+    const USAGE: &str = "Usage: :export html|json|jsonl [all] [nosystem] [meta] [path] \
+         or :export site [dir]";
+    if args
+        .split_whitespace()
+        .enumerate()
+        .any(|(i, word)| word == "all" || (i == 0 && word == "site"))
+    {
+        app.load_all_chats();
+    }
+    let Some(chat) = app.chats.get(app.current_chat) else {
+        app.set_error("No chat to export");
+        return;
+    };
+    let mut words = args.split_whitespace();
+    let format = words.next().unwrap_or("");
+    let json = matches!(format, "json" | "jsonl");
+    let mut options = export::JsonOptions {
+        system: true,
+        meta: false,
+    };
+    let mut all = false;
+    let mut flags = Vec::new();
+    let mut words = words.peekable();
+    while let Some(&word) = words.peek() {
+        match word {
+            "all" if json => all = true,
+            "system" | "nosystem" if json => options.system = word == "system",
+            "meta" | "nometa" if json => options.meta = word == "meta",
+            _ => break,
+        }
+        flags.push(word);
+        words.next();
+    }
+    let path = words.collect::<Vec<_>>().join(" ");
+    if !matches!(format, "html" | "json" | "jsonl" | "site") {
+        app.set_error(USAGE);
+        return;
+    }
+    if format == "site" {
+        export_site(app, &path, overwrite);
+        return;
+    }
+    if path.is_empty() {
+        let stem = if all {
+            "chats".to_string()
+        } else {
+            export::file_stem(chat)
+        };
+        let flags: String = flags.iter().map(|f| format!("{} ", f)).collect();
+        app.command = format!("export {} {}{}.{}", format, flags, stem, format);
+        app.mode = Mode::Command;
+        return;
+    }
+    let chats: Vec<&Chat> = if all {
+        app.chats.iter().collect()
+    } else {
+        vec![chat]
+    };
+    let text = match format {
+        "html" => {
+            let code_theme = app.theme.code.as_deref().unwrap_or(&app.ui_settings.theme);
+            export::html(chat, code_theme, app.ui_settings.time_pattern())
+        }
+        "json" => export::json(&chats, options),
+        _ => export::jsonl(&chats, options),
+    };
+    let what = if all {
+        format!("{} chats", chats.len())
+    } else {
+        format!("{} messages", chat.messages.len())
+    };
+    let path = path.as_str();
+    let file = std::path::Path::new(path);
+    if file.exists() && !overwrite {
+        app.set_error(&format!("{} exists; use :export! to overwrite", path));
+        return;
+    }
+    match std::fs::write(file, text) {
+        Ok(()) => app.set_info(&format!("Exported {} to {}", what, path)),
+        Err(e) => app.set_error(&format!("Cannot write {}: {}", path, e)),
+    }
+}
+
+/// `:export site <dir>`: every chat as a static site in `dir`.
+fn export_site(app: &mut App<'_>, dir: &str, overwrite: bool) {
+    if dir.is_empty() {
+        app.command = "export site chats-site".to_string();
+        app.mode = Mode::Command;
+        return;
+    }
+    let path = config::expand_home(std::path::Path::new(dir));
+    let used = std::fs::read_dir(&path).is_ok_and(|mut entries| entries.next().is_some());
+    if used && !overwrite {
+        app.set_error(&format!(
+            "{} is not empty; use :export! to write into it",
+            dir
+        ));
+        return;
+    }
+    let chats: Vec<&Chat> = app.chats.iter().filter(|c| c.stub.is_none()).collect();
+    let code_theme = app.theme.code.as_deref().unwrap_or(&app.ui_settings.theme);
+    match export::site(&path, &chats, code_theme, app.ui_settings.time_pattern()) {
+        Ok(pages) => app.set_info(&format!(
+            "Exported {} chats to {}",
+            pages,
+            path.join("index.html").display()
+        )),
+        Err(e) => app.set_error(&format!("Cannot write {}: {}", dir, e)),
+    }
+}
+
+/// `:import chatgpt|claude <path>` adds the conversations of a data export
+/// as new chats. Chats imported before are skipped, so re-importing a newer export
+/// only adds what is new.
+fn import_command(app: &mut App<'_>, args: &str) {
+    const USAGE: &str = "Usage: :import [chatgpt|claude|archive] <path>";
+    let (source, path) = match args.split_once(' ').unwrap_or((args, "")) {
+        (source @ ("chatgpt" | "claude" | "archive"), path) => (source, path.trim()),
+        _ => ("", args),
+    };
+    if path.is_empty() {
+        app.set_error(USAGE);
+        return;
+    }
+    let path = config::expand_home(std::path::Path::new(path));
+    if source.is_empty() {
+        let model = app
+            .new_chat_model()
+            .unwrap_or_else(|| app.current_model.clone());
+        match import::markdown(&path, &model) {
+            Ok(chat) => {
+                let count = chat.messages.len();
+                app.chats.push(chat);
+                app.switch_chat(app.chats.len() - 1);
+                app.history_dirty = true;
+                app.set_info(&format!(
+                    "Imported {} as a chat ({} messages)",
+                    path.display(),
+                    count
+                ));
+            }
+            Err(e) => app.set_error(&e),
+        }
+        return;
+    }
+    let imported = match source {
+        "chatgpt" => import::chatgpt(&path, &app.current_model),
+        "claude" => import::claude(&path, &app.current_model),
+        _ => storage::read_archive(&path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e)),
+    };
+    let chats = match imported {
+        Ok(chats) => chats,
+        Err(e) => {
+            app.set_error(&e);
+            return;
+        }
+    };
+    let total = chats.len();
+    let known: std::collections::HashSet<String> = app.chats.iter().map(|c| c.id.clone()).collect();
+    app.chats
+        .extend(chats.into_iter().filter(|c| !known.contains(&c.id)));
+    let added = app.chats.len() - known.len();
+    if added > 0 {
+        app.history_dirty = true;
+        app.need_rebuild_cache = true;
+    }
+    let mut info = format!("Imported {} chats", added);
+    if added < total {
+        info.push_str(&format!(" ({} already present)", total - added));
+    }
+    app.set_info(&info);
+}
+
+/// `:attach <path>` queues a text file for the next message; without a path
+/// it lists the queued files.
+fn attach_command(app: &mut App<'_>, args: &str) {
+    if args.is_empty() {
+        if app.pending_attachments.is_empty() {
+            app.set_info("No pending attachments. Usage: :attach <path>");
+        } else {
+            let names: Vec<String> = app
+                .pending_attachments
+                .iter()
+                .map(|a| a.summary())
+                .collect();
+            app.set_info(&format!("Attached: {}", names.join(", ")));
+        }
+        return;
+    }
+    let attachment = match crate::app::Attachment::read(args) {
+        Ok(attachment) => attachment,
+        Err(e) => {
+            app.set_error(&e);
+            return;
+        }
+    };
+    let total: usize = app
+        .pending_attachments
+        .iter()
+        .filter(|a| a.name != attachment.name)
+        .map(|a| a.content.len())
+        .sum::<usize>()
+        + attachment.content.len();
+    if total > crate::app::MAX_ATTACHMENTS_TOTAL_BYTES {
+        app.set_error(&format!(
+            "Attachments for one message are limited to {} KB in total",
+            crate::app::MAX_ATTACHMENTS_TOTAL_BYTES / 1024
+        ));
+        return;
+    }
+    let summary = attachment.summary();
+    app.pending_attachments
+        .retain(|a| a.name != attachment.name);
+    app.pending_attachments.push(attachment);
+    app.set_info(&format!(
+        "Attached {}; it will be sent with your next message",
+        summary
+    ));
+}
+
+/// Sends the chat's last user prompt to the chat model plus every model in
+/// `args`, showing the answers side by side. An existing reply to that prompt
+/// is reused as the chat model's column.
+fn start_comparison(app: &mut App<'_>, args: &str) {
+    let Some(chat) = app.chats.get(app.current_chat) else {
+        app.set_error("No chat selected");
+        return;
+    };
+    if chat.streaming {
+        app.set_error("Wait for the current response to finish");
+        return;
+    }
+    if args.is_empty() {
+        app.set_error("Usage: :compare <Provider:model> [Provider:model ...]");
+        return;
+    }
+    let mut messages = app.outgoing_messages(chat, &chat.messages);
+    let existing_answer = match messages.last() {
+        Some(last) if last.role == "assistant" => messages.pop().map(|m| m.content),
+        _ => None,
+    };
+    if messages.last().is_none_or(|m| m.role != "user") {
+        app.set_error("Nothing to compare: the chat has no user prompt");
+        return;
+    }
+
+    let params = chat.params.clone();
+    let mut models = vec![chat.model.clone()];
+    for model in args.split_whitespace() {
+        if !models.iter().any(|m| m == model) {
+            models.push(model.to_string());
+        }
+    }
+    let mut endpoints = Vec::with_capacity(models.len());
+    for (idx, model) in models.iter().enumerate() {
+        if idx == 0 && existing_answer.is_some() {
+            endpoints.push(None);
+            continue;
+        }
+        match app.resolve_endpoint(model) {
+            Ok(endpoint) => endpoints.push(Some(endpoint)),
+            Err(e) => {
+                app.set_error(&format!("{}: {}", model, e));
+                return;
+            }
+        }
+    }
+
+    let comparison_id = uuid::Uuid::new_v4().to_string();
+    app.comparison = Some(Comparison {
+        id: comparison_id.clone(),
+        chat_id: chat.id.clone(),
+        candidates: models
+            .iter()
+            .enumerate()
+            .map(|(idx, model)| Candidate {
+                model: model.clone(),
+                content: if idx == 0 {
+                    existing_answer.clone().unwrap_or_default()
+                } else {
+                    String::new()
+                },
+                done: idx == 0 && existing_answer.is_some(),
+            })
+            .collect(),
+        selected: 0,
+        scroll: 0,
+    });
+    for (idx, endpoint) in endpoints.into_iter().enumerate() {
+        if let Some(endpoint) = endpoint {
+            app.start_candidate_stream(
+                &comparison_id,
+                idx,
+                endpoint,
+                messages.clone(),
+                app.request_params(&models[idx], params.clone()),
+            );
+        }
+    }
+    app.mode = Mode::Compare;
+    app.info_message = None;
+    app.error_message = None;
+}
+
+/// `:prompt [use|only|drop|clear] <name>`: manages the library prompts
+/// attached to the current chat. Without arguments, lists them.
+fn chat_prompt_command(app: &mut App<'_>, args: &str) {
+    let (action, name) = args
+        .split_once(' ')
+        .map_or((args, ""), |(action, name)| (action, name.trim()));
+    let library_name = app
+        .prompts
+        .iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+        .map(|p| p.name.to_string());
+    let Some(chat) = app.chats.get_mut(app.current_chat) else {
+        app.set_error("No chat selected");
+        return;
+    };
+    let message = match action {
+        "" => {
+            if chat.prompts.is_empty() {
+                "No prompts attached to this chat".to_string()
+            } else {
+                format!(
+                    "Chat prompts: {}{}",
+                    chat.prompts.join(", "),
+                    if chat.prompts_override { " (only)" } else { "" }
+                )
+            }
+        }
+        "use" | "only" => {
+            let Some(name) = library_name else {
+                app.set_error(&format!("No prompt named '{}'", name));
+                return;
+            };
+            if !chat.prompts.contains(&name) {
+                chat.prompts.push(name.clone());
+            }
+            chat.prompts_override = action == "only";
+            if chat.prompts_override {
+                format!("Using '{}' instead of the default prompts", name)
+            } else {
+                format!("Added prompt '{}' to this chat", name)
+            }
+        }
+        "drop" => {
+            let before = chat.prompts.len();
+            chat.prompts.retain(|p| !p.eq_ignore_ascii_case(name));
+            if chat.prompts.len() == before {
+                app.set_error(&format!("'{}' is not attached to this chat", name));
+                return;
+            }
+            if chat.prompts.is_empty() {
+                chat.prompts_override = false;
+            }
+            format!("Removed prompt '{}'", name)
+        }
+        "clear" => {
+            chat.prompts.clear();
+            chat.prompts_override = false;
+            "Chat prompts cleared".to_string()
+        }
+        _ => {
+            app.set_error("Usage: :prompt [use|only|drop|clear] <name>");
+            return;
+        }
+    };
+    app.set_info(&message);
+}
+
+/// `:set key=value ...` changes the current chat's generation parameters,
+/// its `model` and the display options in [`config::UiSettings`] for this
+/// session; an empty value resets a key. Without arguments, shows the current
+/// values. `:set!` also writes the display options (and the model, as
+/// `default_model`) to config.toml.
+fn set_command(app: &mut App<'_>, config: &mut config::Settings, args: &str, persist: bool) {
+    let Some(chat) = app.chats.get(app.current_chat) else {
+        app.set_error("No chat selected");
+        return;
+    };
+    if args.is_empty() {
+        let mut values: Vec<String> = GenerationParams::KEYS
+            .iter()
+            .map(|key| {
+                format!(
+                    "{}={}",
+                    key,
+                    chat.params.get(key).unwrap_or_else(|| "default".into())
+                )
+            })
+            .collect();
+        values.push(format!("model={}", chat.model));
+        values.extend(
+            config::UiSettings::OPTIONS
+                .iter()
+                .filter_map(|key| Some(format!("{}={}", key, app.ui_settings.get(key)?))),
+        );
+        app.set_info(&values.join("  "));
+        return;
+    }
+    let mut params = chat.params.clone();
+    let mut ui_settings = app.ui_settings.clone();
+    let mut model = None;
+    for assignment in args.split_whitespace() {
+        let (key, value) = assignment.split_once('=').unwrap_or((assignment, ""));
+        let result = if GenerationParams::KEYS.contains(&key) {
+            params.set(key, value)
+        } else if key == "model" {
+            app.resolve_endpoint(value)
+                .map(|_| model = Some(value.to_string()))
+        } else if let Some(flag) = key.strip_prefix("no")
+            && config::UiSettings::is_flag(flag)
+            && value.is_empty()
+        {
+            ui_settings.set(flag, "off")
+        } else if key == "theme" && !ui::theme_set().themes.contains_key(value) {
+            Err(format!("Unknown theme: {}", value))
+        } else {
+            ui_settings.set(key, value)
+        };
+        if let Err(e) = result {
+            app.set_error(&e);
+            return;
+        }
+    }
+
+    app.chats[app.current_chat].params = params;
+    if let Some(model) = &model {
+        app.set_chat_model(app.current_chat, model.clone());
+        app.current_model = model.clone();
+    }
+    app.ui_settings = ui_settings;
+    app.need_rebuild_cache = true;
+    if persist {
+        config.ui = app.ui_settings.clone();
+        if model.is_some() {
+            config.default_model = model;
+        }
+        match save_config(config) {
+            Ok(()) => app.set_info(&format!("Set and saved {}", args)),
+            Err(e) => app.set_error(&e),
+        }
+    } else {
+        app.set_info(&format!("Set {}", args));
+    }
+}
+
+/// `:config export <path>` writes the settings without API keys;
+/// `:config import <path>` replaces them with an exported file, keeping this
+/// machine's keys.
+fn config_command(app: &mut App<'_>, config: &mut config::Settings, args: &str) {
+    let (action, path) = args
+        .split_once(' ')
+        .map_or((args, ""), |(action, path)| (action, path.trim()));
+    if path.is_empty() {
+        app.set_error("Usage: :config export|import <path>");
+        return;
+    }
+    let path = std::path::Path::new(path);
+    match action {
+        "export" => {
+            config.prompts = app.prompts.clone();
+            match config::export_config(config, path) {
+                Ok(()) => app.set_info(&format!(
+                    "Exported settings without API keys to {}",
+                    path.display()
+                )),
+                Err(e) => app.set_error(&e),
+            }
+        }
+        "import" => match config::import_config(path, config) {
+            Ok(imported) => {
+                *config = imported;
+                apply_config(app, config);
+                match save_config(config) {
+                    Ok(()) => app.set_info(&format!("Imported settings from {}", path.display())),
+                    Err(e) => app.set_error(&e),
+                }
+            }
+            Err(e) => app.set_error(&e),
+        },
+        _ => app.set_error("Usage: :config export|import <path>"),
+    }
+}
+
+/// `:theme <name>` switches to a theme file for this session (`:theme!`
+/// also saves it); `:theme default` goes back to the built-in colors and a
+/// bare `:theme` lists the available themes.
+fn theme_command(app: &mut App<'_>, config: &mut config::Settings, args: &str, persist: bool) {
+    if args.is_empty() {
+        let names = config::theme_names();
+        let current = app.theme.name.as_deref().unwrap_or("default");
+        let dir = config::themes_dir().map(|d| d.display().to_string());
+        if names.is_empty() {
+            app.set_info(&format!(
+                "Theme: {}. No theme files in {}",
+                current,
+                dir.unwrap_or_default()
+            ));
+        } else {
+            app.set_info(&format!(
+                "Theme: {}. Available: {}",
+                current,
+                names.join(", ")
+            ));
+        }
+        return;
+    }
+    let previous = app.ui_settings.color_theme.take();
+    if args != "default" {
+        app.ui_settings.color_theme = Some(args.to_string());
+    }
+    match app.load_theme() {
+        Ok(()) if persist => {
+            config.ui.color_theme = app.ui_settings.color_theme.clone();
+            match save_config(config) {
+                Ok(()) => app.set_info(&format!("Theme {} (saved)", args)),
+                Err(e) => app.set_error(&e),
+            }
+        }
+        Ok(()) => app.set_info(&format!("Theme {}", args)),
+        Err(e) => {
+            app.ui_settings.color_theme = previous;
+            app.set_error(&e);
+        }
+    }
+}
+
+fn fork_at_cursor(app: &mut App<'_>) {
+    let Some(&(msg_idx, _)) = app.line_to_message.get(app.cursor_line) else {
+        app.set_error("No message at the cursor");
+        return;
+    };
+    match app.fork_chat(msg_idx) {
+        Ok(()) => app.set_info("Forked chat; :origin jumps back"),
+        Err(e) => app.set_error(&e),
+    }
+}
 
-    if !app.chats.is_empty() {
-        app.current_chat = 0;
-        app.selected_sidebar_idx = 0;
-        app.focus = Focus::Chat;
-        app.need_rebuild_cache = true;
-        if let Some(chat) = app.chats.get(0) {
-            app.current_model = chat.model.clone();
+/// Copies the whole message under the cursor: its markdown source, or the
+/// text as shown with code fences removed.
+async fn yank_message(app: &mut App<'_>, as_markdown: bool) {
+    let message = app
+        .line_to_message
+        .get(app.cursor_line)
+        .and_then(|&(idx, _)| app.chats.get(app.current_chat)?.messages.get(idx));
+    let Some(message) = message else {
+        app.set_error("No message at the cursor");
+        return;
+    };
+    let text = if as_markdown {
+        message.content.clone()
+    } else {
+        ui::plain_message_text(&message.content)
+    };
+    deliver_yank(app, text, "message").await;
+}
+
+/// Puts yanked text in the pending register, or on the clipboard if none
+/// was chosen.
+async fn deliver_yank(app: &mut App<'_>, text: String, what: &str) {
+    let lines = text.lines().count().max(1);
+    if let Some(register) = app.store_yank(&text) {
+        app.set_info(&format!(
+            "Yanked {} ({} lines) into \"{}",
+            what, lines, register
+        ));
+        return;
+    }
+    match clipboard::copy_to_clipboard(&text).await {
+        Ok(_) => app.set_info(&format!("Yanked {} ({} lines)", what, lines)),
+        Err(e) => app.set_error(&format!("Copy failed: {}", e)),
+    }
+}
+
+/// Yanks the `block`th code block of a message.
+async fn copy_code_block(app: &mut App<'_>, msg_idx: usize, block: usize) {
+    match app.code_blocks.get(&msg_idx).and_then(|b| b.get(block)) {
+        Some(cb) => {
+            let text = cb.content.clone();
+            deliver_yank(app, text, &format!("code block {}", block + 1)).await;
         }
+        None => app.set_error(&format!("No code block {} in this message", block + 1)),
     }
-    let mut config = load_or_create_config();
+}
 
-    app.prompts = config.prompts.clone();
-    for saved in &config.providers {
-        if let Some(p) = app.providers.iter_mut().find(|p| p.name == saved.name) {
-            p.api_key = saved.api_key.clone();
-            p.enabled_models = saved.enabled_models.clone();
-            for m in &saved.enabled_models {
-                if !p.models.contains(m) {
-                    p.models.push(m.clone());
-                }
+/// Takes the prompt library into the settings after an edit in the Prompts
+/// tab; it is written with them when Settings is closed.
+fn save_prompts(app: &mut App<'_>, config: &mut config::Settings) {
+    config.prompts = app.prompts.clone();
+    app.config_dirty = true;
+}
+
+/// Inserts a register's contents into the input line.
+fn paste_register(app: &mut App<'_>, register: char) {
+    match app.registers.get(&register.to_ascii_lowercase()) {
+        Some(text) => {
+            let text = text.clone();
+            if let Err(e) = app.begin_insert() {
+                app.set_error(&e);
+                return;
             }
+            app.input.push_str(&text);
+            app.set_info(&format!("Pasted \"{}", register.to_ascii_lowercase()));
         }
+        None => app.set_error(&format!("Register \"{} is empty", register)),
     }
+}
 
-    app.custom_models = config.custom_models.clone();
+/// Steps the selected General-tab setting forward (`delta > 0`) or back,
+/// then applies and persists it.
+fn adjust_general_setting(app: &mut App<'_>, config: &mut config::Settings, delta: i64) {
+    let Some(&setting) = GeneralSetting::ALL.get(app.selected_general_idx) else {
+        return;
+    };
+    let cycle = |len: usize, current: usize| -> usize {
+        (current as i64 + delta).rem_euclid(len.max(1) as i64) as usize
+    };
+    let step = |value: u64, by: u64| -> u64 {
+        if delta > 0 {
+            value.saturating_add(by)
+        } else {
+            value.saturating_sub(by)
+        }
+    };
 
-    let enabled = app.enabled_models_flat();
-    if let Some((provider, model)) = enabled.get(0) {
-        if app.chats.is_empty() {
-            app.current_model = format!("{}:{}", provider, model);
+    match setting {
+        GeneralSetting::Theme => {
+            let names: Vec<&String> = ui::theme_set().themes.keys().collect();
+            let current = names
+                .iter()
+                .position(|n| **n == config.ui.theme)
+                .unwrap_or(0);
+            config.ui.theme = names[cycle(names.len(), current)].clone();
+        }
+        GeneralSetting::SidebarWidth => {
+            config.ui.sidebar_width = step(config.ui.sidebar_width as u64, 2).clamp(12, 60) as u16;
+        }
+        GeneralSetting::SidebarPosition => {
+            config.ui.sidebar = match config.ui.sidebar {
+                SidebarPosition::Left => SidebarPosition::Right,
+                SidebarPosition::Right => SidebarPosition::Left,
+            }
+        }
+        GeneralSetting::Timestamps => config.ui.timestamps = !config.ui.timestamps,
+        GeneralSetting::Colors => {
+            let modes = [ColorMode::Truecolor, ColorMode::Basic, ColorMode::None];
+            let current = modes
+                .iter()
+                .position(|m| *m == config.ui.colors)
+                .unwrap_or(0);
+            config.ui.colors = modes[cycle(modes.len(), current)];
         }
-    } else if app.chats.is_empty() {
-        app.current_model = "No model selected".to_string();
+        GeneralSetting::TruncateLines => {
+            config.ui.truncate_lines = step(config.ui.truncate_lines as u64, 1) as usize;
+        }
+        GeneralSetting::Scrolloff => {
+            config.ui.scrolloff = step(config.ui.scrolloff as u64, 1) as usize;
+        }
+        GeneralSetting::DefaultModel => {
+            let mut choices: Vec<Option<String>> = vec![None];
+            choices.extend(
+                app.enabled_models_flat()
+                    .iter()
+                    .map(|(p, m)| Some(format!("{}:{}", p, m))),
+            );
+            let current = choices
+                .iter()
+                .position(|c| *c == config.default_model)
+                .unwrap_or(0);
+            config.default_model = choices[cycle(choices.len(), current)].clone();
+        }
+        GeneralSetting::AutosaveSecs => config.autosave_secs = step(config.autosave_secs, 30),
     }
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
 
-    let res = run_app(&mut terminal, &mut app, &mut config).await;
+    app.ui_settings = config.ui.clone();
+    app.default_model = config.default_model.clone();
+    app.autosave_secs = config.autosave_secs;
+    app.need_rebuild_cache = true;
+    app.config_dirty = true;
+    app.set_info(&format!(
+        "{}: {}",
+        setting.label(),
+        app.general_setting_value(setting)
+    ));
+}
 
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+/// Runs an action reached through the configured key bindings.
+async fn run_action(app: &mut App<'_>, action: Action) {
+    match action {
+        Action::NewChat => {
+            app.create_new_chat();
+            app.info_message = Some("New chat created".to_string());
+        }
+        Action::ToggleSidebar => app.toggle_sidebar(),
+        Action::SwitchFocus if app.focus_locked => {
+            app.set_info("Focus is locked; press the lock key again to unlock")
+        }
+        Action::SwitchFocus => {
+            if app.sidebar_visible {
+                app.focus = match app.focus {
+                    Focus::Sidebar => Focus::Chat,
+                    Focus::Chat => Focus::Sidebar,
+                };
+            }
+        }
+        Action::LockFocus => {
+            app.focus_locked = !app.focus_locked;
+            app.set_info(if app.focus_locked {
+                "Focus locked"
+            } else {
+                "Focus unlocked"
+            });
+        }
+        Action::DeleteChat => {
+            if app.sidebar_visible && app.selected_sidebar_idx < app.chats.len() {
+                delete_selected_chat(app);
+            }
+        }
+        Action::YankSelection => {
+            if let (Some(start_idx), Some(end_idx)) = (app.visual_start, app.visual_end) {
+                let (lo, hi) = if start_idx <= end_idx {
+                    (start_idx, end_idx)
+                } else {
+                    (end_idx, start_idx)
+                };
 
-    save_history(&app.chats);
-    config.prompts = app.prompts.clone();
-    save_config(&config);
+                let selected_lines = app.selection_text(lo, hi);
 
-    if let Err(err) = res {
-        println!("{:?}", err);
+                if !selected_lines.is_empty() {
+                    deliver_yank(app, selected_lines.join("\n"), "selection").await;
+                } else {
+                    app.set_info("Nothing to yank");
+                }
+            } else {
+                app.set_info("Visual selection not active");
+            }
+            app.mode = Mode::Normal;
+            app.visual_start = None;
+            app.visual_end = None;
+        }
+        Action::InsertMode => {
+            app.error_message = None;
+            app.info_message = None;
+            if let Err(e) = app.begin_insert() {
+                app.set_error(&e);
+            }
+        }
+        Action::ExitInsertMode => {
+            app.info_message = None;
+            if app.end_insert() && save_chats(app) {
+                app.set_info("Draft saved");
+            }
+        }
+        Action::CommandMode => {
+            if app.mode != Mode::Visual {
+                app.visual_start = None;
+                app.visual_end = None;
+            }
+            app.mode = Mode::Command;
+            app.command.clear();
+            app.info_message = None;
+            app.error_message = None;
+        }
+        Action::OpenSettings => {
+            app.mode = Mode::Settings;
+            app.info_message = None;
+            app.error_message = None;
+        }
+        Action::StopGeneration => {}
+        Action::Paste => match clipboard::paste_from_clipboard().await {
+            Ok(text) if text.is_empty() => app.set_info("Clipboard is empty"),
+            Ok(text) => app.paste_text(&text),
+            Err(e) => app.set_error(&format!("Paste failed: {}", e)),
+        },
     }
+}
 
-    Ok(())
+/// Deletes the chat selected in the sidebar and shows its neighbour. The
+/// chat goes to the trash unless `trash_days` is 0 or its file could not be
+/// read.
+fn delete_selected_chat(app: &mut App<'_>) {
+    let idx = app.selected_sidebar_idx;
+    app.load_chat(idx);
+    let trashed = app.trash_days > 0 && app.chats[idx].stub.is_none();
+    if trashed && let Err(e) = storage::trash(&app.chats[idx]) {
+        app.set_error(&format!(
+            "Not deleted; cannot move the chat to the trash: {}",
+            e
+        ));
+        return;
+    }
+    app.stash_view();
+    let removed = app.chats.remove(idx);
+    app.stream_tasks.remove(&removed.id);
+    app.chat_views.remove(&removed.id);
+    if app.chats.is_empty() {
+        app.current_chat = 0;
+        app.selected_sidebar_idx = 0;
+        app.cursor_line = 0;
+        app.line_cache.clear();
+        app.line_to_message.clear();
+        app.need_rebuild_cache = true;
+    } else {
+        if app.selected_sidebar_idx >= app.chats.len() {
+            app.selected_sidebar_idx = app.chats.len() - 1;
+        }
+        app.current_chat = app.selected_sidebar_idx;
+        app.cursor_line = 0;
+        app.need_rebuild_cache = true;
+        let shown = app.chats[app.current_chat].id.clone();
+        app.truncated_messages = app
+            .chat_views
+            .remove(&shown)
+            .map(|view| view.truncated_messages)
+            .unwrap_or_default();
+    }
+    app.set_info(if trashed {
+        "Chat moved to the trash; :trash restores it"
+    } else {
+        "Chat deleted"
+    });
 }
 
-async fn run_app<B: ratatui::backend::Backend>(
-    terminal: &mut Terminal<B>,
-    app: &mut App<'_>,
-    config: &mut config::Settings,
-) -> Result<()> {
-    loop {
-        app.process_stream();
-        app.loading_frame = app.loading_frame.wrapping_add(1);
-        terminal.draw(|f| ui::draw(f, app))?;
+/// Lists the chats matching the `:prune` filters for review. Protected,
+/// streaming and open chats are never offered.
+fn prune_command(app: &mut App<'_>, args: &str) {
+    let filter = match prune::Filter::parse(args) {
+        Ok(filter) => filter,
+        Err(e) => {
+            app.set_error(&e);
+            return;
+        }
+    };
+    let now = chrono::Utc::now().timestamp();
+    let current = app.chats.get(app.current_chat).map(|c| c.id.clone());
+    let mut kept = 0;
+    let mut doomed = Vec::new();
+    for idx in app.chats_by_recency().into_iter().rev() {
+        let chat = &app.chats[idx];
+        if !filter.matches(chat, now) {
+            continue;
+        }
+        if chat.protected() || chat.streaming || Some(&chat.id) == current.as_ref() {
+            kept += 1;
+        } else {
+            doomed.push(chat.id.clone());
+        }
+    }
+    let kept = if kept > 0 {
+        format!(
+            "; {} read-only, bookmarked, pinned, streaming or open chat(s) are kept",
+            kept
+        )
+    } else {
+        String::new()
+    };
+    if doomed.is_empty() {
+        app.set_info(&format!("No chats to prune{}", kept));
+        return;
+    }
+    app.prune = doomed;
+    app.selected_prune_idx = 0;
+    app.mode = Mode::Prune;
+    app.set_info(&format!("{} chat(s) match{}", app.prune.len(), kept));
+}
 
-        if event::poll(Duration::from_millis(50))? {
-            if let Event::Key(key) = event::read()? {
-                handle_key(app, key, config).await?;
+/// Removes the chats listed by `:prune`, writing them to a file in
+/// `archives/` first if `archive` is set and to the trash otherwise.
+fn finish_prune(app: &mut App<'_>, archive: bool) {
+    app.mode = Mode::Normal;
+    let ids: std::collections::HashSet<String> =
+        std::mem::take(&mut app.prune).into_iter().collect();
+    let mut chats = Vec::new();
+    for idx in 0..app.chats.len() {
+        if ids.contains(&app.chats[idx].id) {
+            app.load_chat(idx);
+            // A chat that cannot be read stays, rather than being lost.
+            if app.chats[idx].stub.is_none() {
+                chats.push(app.chats[idx].clone());
+            }
+        }
+    }
+    let info = if archive {
+        match storage::archive("prune", &chats) {
+            Ok(path) => format!(
+                "Archived {} chat(s) to {}; :import archive <path> brings them back",
+                chats.len(),
+                path.display()
+            ),
+            Err(e) => {
+                app.set_error(&format!("Not pruned; cannot write the archive: {}", e));
+                return;
+            }
+        }
+    } else if app.trash_days > 0 {
+        for chat in &chats {
+            if let Err(e) = storage::trash(chat) {
+                app.set_error(&format!(
+                    "Not pruned; cannot move chats to the trash: {}",
+                    e
+                ));
+                return;
             }
         }
+        format!("Moved {} chat(s) to the trash", chats.len())
+    } else {
+        format!("Deleted {} chat(s)", chats.len())
+    };
+    let removed: std::collections::HashSet<String> = chats.into_iter().map(|c| c.id).collect();
+    app.remove_chats(&removed);
+    if save_chats(app) {
+        app.set_info(&info);
+    }
+}
+
+/// Writes the open chat to a file in `archives/` and removes it from the
+/// history, for `a` in the `:large` popup.
+fn archive_current(app: &mut App<'_>) {
+    let Some(chat) = app.chats.get(app.current_chat).cloned() else {
+        return;
+    };
+    if chat.streaming {
+        app.set_error("Wait for the current response to finish");
+        return;
+    }
+    let path = match storage::archive("large", std::slice::from_ref(&chat)) {
+        Ok(path) => path,
+        Err(e) => {
+            app.set_error(&format!("Not archived; cannot write the archive: {}", e));
+            return;
+        }
+    };
+    app.remove_chats(&std::collections::HashSet::from([chat.id]));
+    app.switch_chat(app.current_chat);
+    if !save_chats(app) {
+        return;
+    }
+    app.set_info(&format!(
+        "Archived \"{}\" to {}; :import archive <path> brings it back",
+        chat.title,
+        path.display()
+    ));
+}
+
+/// Sums the recorded usage over `period` for `:stats`.
+fn show_stats(app: &mut App<'_>, period: stats::Period) {
+    app.stats = stats::collect(&app.chats, period, chrono::Utc::now().timestamp());
+}
+
+/// Opens `:trash`, or reports that it is empty.
+fn open_trash(app: &mut App<'_>) {
+    app.trash = storage::trashed();
+    if app.trash.is_empty() {
+        app.mode = Mode::Normal;
+        app.set_info("The trash is empty");
+        return;
+    }
+    app.selected_trash_idx = app.selected_trash_idx.min(app.trash.len() - 1);
+    app.mode = Mode::Trash;
+}
+
+/// Puts the chat selected in `:trash` back into the history, with the new
+/// chats at the end of the sidebar.
+fn restore_trashed(app: &mut App<'_>) {
+    let Some(trashed) = app.trash.get(app.selected_trash_idx).cloned() else {
+        return;
+    };
+    let chat = trashed.chat;
+    if app.chats.iter().any(|c| c.id == chat.id) {
+        app.set_error(&format!("\"{}\" is already in the history", chat.title));
+        return;
+    }
+    if let Err(e) = storage::remove_from_trash(&chat.id) {
+        app.set_error(&format!(
+            "Cannot take \"{}\" out of the trash: {}",
+            chat.title, e
+        ));
+        return;
     }
+    let title = chat.title.clone();
+    app.chats.push(chat);
+    app.switch_chat(app.chats.len() - 1);
+    app.history_dirty = true;
+    app.mode = Mode::Normal;
+    app.set_info(&format!("Restored \"{}\"", title));
 }
 
+// Conditions stay inside the arms: as a guard, a failed check would fall
+// through to a later arm for the same key, such as a destructive one.
+#[allow(clippy::collapsible_match)]
 async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Settings) -> Result<()> {
+    if keymap::resolve(&app.keybindings, app.mode, &key) == Some(Action::StopGeneration)
+        && let Some(chat) = app.chats.get(app.current_chat).filter(|c| c.streaming)
+    {
+        let chat_id = chat.id.clone();
+        if app.stop_stream(&chat_id) {
+            app.set_info("Generation stopped");
+        }
+        return Ok(());
+    }
+
+    if matches!(app.mode, Mode::Normal | Mode::Visual | Mode::Insert)
+        && let Some(first) = app.pending_key.take()
+    {
+        let mut digits = std::mem::take(&mut app.pending_block);
+        match (first, key.code) {
+            ('"', KeyCode::Char(register)) if register.is_ascii_alphanumeric() => {
+                app.pending_register = Some(register);
+                app.set_info(&format!("\"{}", register));
+            }
+            ('\x12', KeyCode::Char(register)) => {
+                // Ctrl-r {register} in Insert mode.
+                paste_register(app, register);
+            }
+            ('c', KeyCode::Char(digit @ '0'..='9')) if digit != '0' || !digits.is_empty() => {
+                if let Some(&(msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
+                    digits.push(digit);
+                    let count = app.code_blocks.get(&msg_idx).map_or(0, Vec::len);
+                    let number: usize = digits.parse().unwrap_or(usize::MAX);
+                    // Wait for another digit while it could still name a block.
+                    if number.saturating_mul(10) <= count {
+                        app.set_info(&format!("Copy which code block? 1-{}: {}", count, digits));
+                        app.pending_block = digits;
+                        app.pending_key = Some('c');
+                    } else {
+                        copy_code_block(app, msg_idx, number - 1).await;
+                    }
+                }
+            }
+            ('c', KeyCode::Enter) if !digits.is_empty() => {
+                if let Some(&(msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
+                    let number: usize = digits.parse().unwrap_or(usize::MAX);
+                    copy_code_block(app, msg_idx, number - 1).await;
+                }
+            }
+            ('y', KeyCode::Char(second @ ('y' | 'm'))) => {
+                yank_message(app, second == 'm').await;
+            }
+            (object @ ('i' | 'a'), KeyCode::Char('b')) if app.mode == Mode::Visual => {
+                match app.code_block_range(app.cursor_line, object == 'a') {
+                    Some((lo, hi)) => {
+                        app.visual_start = Some(lo);
+                        app.visual_end = Some(hi);
+                        app.cursor_line = hi;
+                        app.set_info(&format!("Selected code block ({} lines)", hi - lo + 1));
+                    }
+                    None => app.set_error("The cursor is not inside a code block"),
+                }
+            }
+            ('M', KeyCode::Char(name)) if name.is_ascii_lowercase() && name != 'b' => {
+                match app.set_mark(name) {
+                    Ok(()) => app.set_info(&format!("Mark '{}' set", name)),
+                    Err(e) => app.set_error(&e),
+                }
+            }
+            ('\'', KeyCode::Char(name)) if name.is_ascii_lowercase() => {
+                if let Err(e) = app.jump_to_mark(name) {
+                    app.set_error(&e);
+                }
+            }
+            ('M', KeyCode::Char('b')) => {
+                let message = app.cursor_message().and_then(|idx| {
+                    app.chats
+                        .get_mut(app.current_chat)
+                        .and_then(|c| c.messages.get_mut(idx))
+                });
+                match message {
+                    Some(message) => {
+                        message.bookmarked = !message.bookmarked;
+                        let info = if message.bookmarked {
+                            "Bookmarked message"
+                        } else {
+                            "Bookmark removed"
+                        };
+                        app.need_rebuild_cache = true;
+                        app.set_info(info);
+                    }
+                    None => app.set_error("No message at the cursor"),
+                }
+            }
+            _ => app.info_message = None,
+        }
+        return Ok(());
+    }
+
+    // Inside a code block `o` opens it in $EDITOR, whatever else it is bound to.
+    if app.mode == Mode::Normal
+        && key.code == KeyCode::Char('o')
+        && key.modifiers == KeyModifiers::NONE
+        && app.focus == Focus::Chat
+        && app.code_block_at(app.cursor_line).is_some()
+    {
+        match app.block_under_cursor() {
+            Ok(block) => app.pending_editor = Some(block.clone()),
+            Err(e) => app.set_error(&e),
+        }
+        return Ok(());
+    }
+
+    if let Some(action) = keymap::resolve(&app.keybindings, app.mode, &key) {
+        run_action(app, action).await;
+        return Ok(());
+    }
+
+    if app.mode == Mode::Normal
+        && let Some(block) = keymap::copy_block(&app.keybindings, &key)
+    {
+        if let Some(&(msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
+            copy_code_block(app, msg_idx, block).await;
+        }
+        return Ok(());
+    }
+
     match app.mode {
         Mode::Normal => match key.code {
+            KeyCode::Char('y') if app.focus == crate::app::Focus::Chat => {
+                app.pending_key = Some('y');
+                app.set_info("y: yank message as text, m: as markdown");
+            }
+            KeyCode::Char('"') => {
+                app.pending_key = Some('"');
+            }
+            KeyCode::Char('p') if app.focus == crate::app::Focus::Chat => {
+                let register = app.pending_register.take().unwrap_or('"');
+                paste_register(app, register);
+            }
+            KeyCode::Char('M') if app.focus == crate::app::Focus::Chat => {
+                app.pending_key = Some('M');
+                app.set_info("b: toggle bookmark, a-z: set mark");
+            }
+            KeyCode::Char('\'') if app.focus == crate::app::Focus::Chat => {
+                app.pending_key = Some('\'');
+            }
+            // Terminals report Ctrl-^ as Ctrl-6.
+            KeyCode::Char('^' | '6') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                if let Err(e) = app.switch_to_alternate_chat() {
+                    app.set_error(&e);
+                }
+            }
+            KeyCode::Char('o') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let moved = app.jump_back(false);
+                if !moved {
+                    app.set_info("Already at the oldest jump");
+                }
+            }
+            KeyCode::Char('i') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let moved = app.jump_back(true);
+                if !moved {
+                    app.set_info("Already at the newest jump");
+                }
+            }
+            KeyCode::Char('F') if app.focus == crate::app::Focus::Chat => fork_at_cursor(app),
+            KeyCode::Char(c @ ('J' | 'K')) if app.focus == crate::app::Focus::Sidebar => {
+                let moved = app.move_selected_chat(c == 'K');
+                if moved {
+                    save_chats(app);
+                }
+            }
+            KeyCode::Char('D') if app.focus == crate::app::Focus::Chat => {
+                match app.line_to_message.get(app.cursor_line) {
+                    Some(&(msg_idx, _)) => {
+                        app.pending_action = Some(PendingAction::DeleteMessage(msg_idx));
+                        app.mode = Mode::Confirm;
+                        app.set_info("Delete this message? (y/n)");
+                    }
+                    None => app.set_error("No message at the cursor"),
+                }
+            }
+            KeyCode::Char('r')
+                if app.focus == crate::app::Focus::Chat
+                    && app
+                        .chats
+                        .get(app.current_chat)
+                        .and_then(|c| c.messages.last())
+                        .is_some_and(|m| m.failed.is_some() || m.cut_off) =>
+            {
+                let cut_off = app.chats[app.current_chat]
+                    .messages
+                    .last()
+                    .is_some_and(|m| m.cut_off);
+                let result = if cut_off {
+                    app.continue_reply(app.current_chat).map(|()| "Continuing…")
+                } else {
+                    app.retry_failed().map(|()| "Retrying…")
+                };
+                match result {
+                    Ok(info) => app.set_info(info),
+                    Err(e) => app.set_error(&e),
+                }
+            }
+            KeyCode::Char('P') if app.focus == crate::app::Focus::Chat => {
+                let message = app.cursor_message().and_then(|idx| {
+                    app.chats
+                        .get_mut(app.current_chat)
+                        .and_then(|c| c.messages.get_mut(idx))
+                });
+                match message {
+                    Some(message) => {
+                        message.pinned = !message.pinned;
+                        let info = if message.pinned {
+                            "Pinned: always sent, even when older messages are trimmed"
+                        } else {
+                            "Unpinned message"
+                        };
+                        app.need_rebuild_cache = true;
+                        app.set_info(info);
+                    }
+                    None => app.set_error("No message at the cursor"),
+                }
+            }
+            KeyCode::Char('R') if app.focus == crate::app::Focus::Chat => {
+                match app.regenerate(None) {
+                    Ok(()) => app.set_info("Regenerating…"),
+                    Err(e) => app.set_error(&e),
+                }
+            }
+            KeyCode::Char('[') | KeyCode::Char(']') if app.focus == crate::app::Focus::Chat => {
+                let forward = key.code == KeyCode::Char(']');
+                let msg_idx = app.line_to_message.get(app.cursor_line).map(|(m, _)| *m);
+                let message = msg_idx.and_then(|idx| {
+                    app.chats
+                        .get_mut(app.current_chat)
+                        .filter(|c| !c.streaming)
+                        .and_then(|c| c.messages.get_mut(idx))
+                });
+                let total = message.and_then(|m| {
+                    m.cycle_alternative(forward)
+                        .then_some(m.alternatives.len() + 1)
+                });
+                match total {
+                    Some(total) => {
+                        app.need_rebuild_cache = true;
+                        app.set_info(&format!("Showing another answer ({} total)", total));
+                    }
+                    None => app.set_error("No alternative answers for this message"),
+                }
+            }
             KeyCode::Char('v') => {
                 app.mode = Mode::Visual;
                 app.visual_start = Some(app.cursor_line);
@@ -140,7 +2453,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                     .contains(crossterm::event::KeyModifiers::CONTROL) =>
             {
                 if app.focus == crate::app::Focus::Chat {
-                    let viewport_height = 10;
+                    let viewport_height = app.chat_viewport_height;
                     let lines = app.display_buffer_text_content.len();
                     let half_page = (viewport_height.max(1) / 2).max(1);
                     app.cursor_line = (app.cursor_line + half_page).min(lines.saturating_sub(1));
@@ -152,7 +2465,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                     .contains(crossterm::event::KeyModifiers::CONTROL) =>
             {
                 if app.focus == crate::app::Focus::Chat {
-                    let viewport_height = 10;
+                    let viewport_height = app.chat_viewport_height;
                     let half_page = (viewport_height.max(1) / 2).max(1);
                     app.cursor_line = app.cursor_line.saturating_sub(half_page);
                 }
@@ -162,41 +2475,34 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                 if app.focus == crate::app::Focus::Sidebar {
                     app.selected_sidebar_idx = 0;
                 } else {
+                    app.push_jump();
                     app.cursor_line = 0;
                 }
             }
+            KeyCode::Char('/') => {
+                app.start_search();
+                app.mode = Mode::Search;
+            }
             KeyCode::Char('G') => {
                 if app.focus == crate::app::Focus::Sidebar {
                     app.selected_sidebar_idx = app.chats.len();
                 } else {
+                    app.push_jump();
                     app.jump_to_last_message();
                 }
             }
-            KeyCode::Tab => {
-                if app.sidebar_visible {
-                    app.focus = match app.focus {
-                        crate::app::Focus::Sidebar => crate::app::Focus::Chat,
-                        crate::app::Focus::Chat => crate::app::Focus::Sidebar,
-                    };
-                }
-            }
             KeyCode::PageUp => {
                 if !app.sidebar_visible {
-                    let viewport_height = 10;
+                    let viewport_height = app.chat_viewport_height;
                     app.cursor_line = app.cursor_line.saturating_sub(viewport_height);
                 }
             }
             KeyCode::PageDown => {
                 if !app.sidebar_visible {
-                    let viewport_height = 10;
+                    let viewport_height = app.chat_viewport_height;
                     app.cursor_line = app.cursor_line.saturating_add(viewport_height);
                 }
             }
-            KeyCode::Char('o') => {
-                app.mode = Mode::Settings;
-                app.info_message = None;
-                app.error_message = None;
-            }
             KeyCode::Char('e') => {
                 if let Some((msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
                     app.toggle_message_truncation(*msg_idx);
@@ -209,132 +2515,39 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                 app.info_message = None;
                 app.error_message = None;
             }
-            KeyCode::Char(':') => {
-                app.mode = Mode::Command;
-                app.command.clear();
-                app.info_message = None;
-                app.error_message = None;
-            }
-            KeyCode::Char('i') => {
-                app.mode = Mode::Insert;
-                app.error_message = None;
-                app.info_message = None;
-            }
-            KeyCode::Char('n') => {
-                app.create_new_chat();
-                app.info_message = Some("New chat created".to_string());
-            }
-            KeyCode::Char('s') => app.toggle_sidebar(),
-            KeyCode::Char('m') => {
-                app.mode = Mode::ModelSelect;
-                app.selected_model_idx = 0;
-                app.info_message = None;
-                app.error_message = None;
-            }
-            KeyCode::Char('r') => {
-                if app.sidebar_visible && app.selected_sidebar_idx < app.chats.len() {
-                    app.input = app.chats[app.selected_sidebar_idx].title.clone();
-                    app.mode = Mode::RenameChat;
-                    app.info_message = None;
-                    app.error_message = None;
-                }
-            }
-            KeyCode::Char('d') => {
-                if app.sidebar_visible && app.selected_sidebar_idx < app.chats.len() {
-                    app.chats.remove(app.selected_sidebar_idx);
-                    if app.chats.is_empty() {
-                        app.current_chat = 0;
-                        app.selected_sidebar_idx = 0;
-                        app.cursor_line = 0;
-                        app.line_cache.clear();
-                        app.line_to_message.clear();
-                        app.need_rebuild_cache = true;
-                    } else {
-                        if app.selected_sidebar_idx >= app.chats.len() {
-                            app.selected_sidebar_idx = app.chats.len() - 1;
-                        }
-                        app.current_chat = app.selected_sidebar_idx;
-                        app.cursor_line = 0;
-                        app.need_rebuild_cache = true;
-                    }
-                    app.set_info("Chat deleted");
-                }
-            }
-            KeyCode::Char('c') => {
-                if let Some((msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
-                    if let Some((_, cb)) =
-                        app.code_blocks.iter().find(|(m_idx, _)| m_idx == msg_idx)
-                    {
-                        match clipboard::copy_to_clipboard(&cb.content).await {
-                            Ok(_) => app.set_info("Code block copied (1st)"),
-                            Err(e) => app.set_error(&format!("Copy failed: {}", e)),
-                        }
-                    } else {
-                        app.set_info("No code block found at cursor");
-                    }
-                }
-            }
-            KeyCode::Char('C') => {
-                if let Some((msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
-                    if let Some((_, cb)) = app
-                        .code_blocks
-                        .iter()
-                        .filter(|(m_idx, _)| m_idx == msg_idx)
-                        .nth(1)
-                    {
-                        match clipboard::copy_to_clipboard(&cb.content).await {
-                            Ok(_) => app.set_info("Code block copied (2nd)"),
-                            Err(e) => app.set_error(&format!("Copy failed: {}", e)),
-                        }
-                    } else {
-                        app.set_info("No 2nd code block found for this message");
-                    }
-                }
-            }
-            KeyCode::Char('x') => {
-                if let Some((msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
-                    if let Some((_, cb)) = app
-                        .code_blocks
-                        .iter()
-                        .filter(|(m_idx, _)| m_idx == msg_idx)
-                        .nth(2)
-                    {
-                        match clipboard::copy_to_clipboard(&cb.content).await {
-                            Ok(_) => app.set_info("Code block copied (3rd)"),
-                            Err(e) => app.set_error(&format!("Copy failed: {}", e)),
-                        }
-                    } else {
-                        app.set_info("No 3rd code block found for this message");
-                    }
-                }
+            KeyCode::Char('m') => {
+                app.mode = Mode::ModelSelect;
+                app.selected_model_idx = 0;
+                app.info_message = None;
+                app.error_message = None;
             }
-            KeyCode::Char('X') => {
-                if let Some((msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
-                    if let Some((_, cb)) = app
-                        .code_blocks
-                        .iter()
-                        .filter(|(m_idx, _)| m_idx == msg_idx)
-                        .nth(3)
-                    {
-                        match clipboard::copy_to_clipboard(&cb.content).await {
-                            Ok(_) => app.set_info("Code block copied (4th)"),
-                            Err(e) => app.set_error(&format!("Copy failed: {}", e)),
+            KeyCode::Char('r')
+                if app.sidebar_visible && app.selected_sidebar_idx < app.chats.len() =>
+            {
+                app.input = app.chats[app.selected_sidebar_idx].title.clone();
+                app.mode = Mode::RenameChat;
+                app.info_message = None;
+                app.error_message = None;
+            }
+            KeyCode::Char('c') => {
+                if let Some((msg_idx, block)) = app.code_block_at(app.cursor_line) {
+                    copy_code_block(app, msg_idx, block).await;
+                } else if let Some(&(msg_idx, _)) = app.line_to_message.get(app.cursor_line) {
+                    match app.code_blocks.get(&msg_idx).map_or(0, Vec::len) {
+                        0 => app.set_info("No code block in this message"),
+                        1 => copy_code_block(app, msg_idx, 0).await,
+                        count => {
+                            app.pending_key = Some('c');
+                            app.set_info(&format!("Copy which code block? 1-{}", count));
                         }
-                    } else {
-                        app.set_info("No 4th code block found for this message");
                     }
                 }
             }
             KeyCode::Enter => {
                 if app.focus == crate::app::Focus::Sidebar {
                     if app.selected_sidebar_idx < app.chats.len() {
-                        app.current_chat = app.selected_sidebar_idx;
-                        if let Some(chat) = app.chats.get(app.current_chat) {
-                            app.current_model = chat.model.clone();
-                            app.jump_to_last_message();
-                            app.chat_scroll = u16::MAX;
-                            app.need_rebuild_cache = true;
-                        }
+                        app.push_jump();
+                        app.switch_chat(app.selected_sidebar_idx);
                     } else if app.selected_sidebar_idx == app.chats.len() {
                         app.mode = Mode::Settings;
                         app.info_message = None;
@@ -345,9 +2558,8 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
             _ => {}
         },
         Mode::Insert => match key.code {
-            KeyCode::Esc => {
-                app.mode = Mode::Normal;
-                app.info_message = None;
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.pending_key = Some('\x12');
             }
             KeyCode::Enter => {
                 if !app.has_valid_chat() {
@@ -355,154 +2567,23 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                     app.mode = Mode::Normal;
                     return Ok(());
                 }
-                let msg = app.input.clone();
-                app.input.clear();
-
-                let (chat_id, messages, provider_name, model_name, api_key) = {
-                    let chat = app
-                        .chats
-                        .get(app.current_chat)
-                        .ok_or_else(|| anyhow::anyhow!("No chat selected"))?;
-                    if chat.streaming {
-                        app.mode = Mode::Normal;
-                        return Ok(());
-                    }
-                    let chat_id = chat.id.clone();
-                    let mut messages = chat.messages.clone();
-                    messages.push(crate::app::Message {
-                        role: "user".to_string(),
-                        content: msg.clone(),
-                    });
-
-                    let model_parts: Vec<&str> = chat.model.split(':').collect();
-                    if model_parts.len() != 2 {
-                        app.set_error("Invalid model format");
-                        app.mode = Mode::Normal;
-                        return Ok(());
-                    }
-                    let provider_name = model_parts[0];
-                    let model_name = model_parts[1];
-
-                    let api_key = if provider_name == "Custom" {
-                        let mut custom_model_data = None;
-                        if let Some(cm) = app.custom_models.iter().find(|cm| {
-                            if let CustomModel::Standalone { name, .. } = cm {
-                                name == model_name
-                            } else {
-                                false
-                            }
-                        }) {
-                            if let CustomModel::Standalone {
-                                endpoint,
-                                model,
-                                api_key,
-                                use_key_from,
-                                ..
-                            } = cm
-                            {
-                                let key = api_key.clone().or_else(|| {
-                                    use_key_from.as_ref().and_then(|p_name| {
-                                        app.providers.iter().find(|p| &p.name == p_name).and_then(
-                                            |p| {
-                                                if !p.api_key.is_empty() {
-                                                    Some(p.api_key.clone())
-                                                } else {
-                                                    None
-                                                }
-                                            },
-                                        )
-                                    })
-                                });
-                                custom_model_data = Some((endpoint.clone(), model.clone(), key));
-                            }
-                        }
-
-                        if let Some((endpoint, model_id, key)) = custom_model_data {
-                            app.add_user_message(msg.clone());
-                            let chat = app.chats.get_mut(app.current_chat).unwrap();
-                            chat.streaming = true;
-                            let tx = app.start_stream(chat_id.clone());
-                            app.need_rebuild_cache = true;
-                            app.jump_to_last_message();
-
-                            task::spawn(async move {
-                                if let Err(e) = api::stream_openai_compatible(
-                                    &endpoint,
-                                    key.as_deref(),
-                                    &model_id,
-                                    &messages,
-                                    tx,
-                                )
-                                .await
-                                {
-                                    eprintln!("Stream error: {:?}", e);
-                                }
-                            });
-                            app.mode = Mode::Normal;
-                            return Ok(());
-                        } else {
-                            app.set_error("Custom model not found");
-                            app.mode = Mode::Normal;
-                            return Ok(());
-                        }
-                    } else {
-                        let provider = app.providers.iter().find(|p| p.name == provider_name);
-                        match provider {
-                            Some(p) if !p.api_key.is_empty() => p.api_key.clone(),
-                            _ => {
-                                let env_key = match provider_name {
-                                    "OpenAI" => "OPENAI_API_KEY",
-                                    "Grok" => "GROK_API_KEY",
-                                    "Anthropic" => "ANTHROPIC_API_KEY",
-                                    _ => {
-                                        app.set_error(&format!(
-                                            "No API key set for provider {}",
-                                            provider_name
-                                        ));
-                                        app.mode = Mode::Normal;
-                                        return Ok(());
-                                    }
-                                };
-                                match env::var(env_key) {
-                                    Ok(key) if !key.is_empty() => key,
-                                    _ => {
-                                        app.set_error(&format!(
-                                            "No API key set for provider {}. Set {} or configure in settings.",
-                                            provider_name, env_key
-                                        ));
-                                        app.mode = Mode::Normal;
-                                        return Ok(());
-                                    }
-                                }
-                            }
-                        }
-                    };
-
-                    (
-                        chat_id,
-                        messages,
-                        provider_name.to_string(),
-                        model_name.to_string(),
-                        api_key,
-                    )
-                };
-
-                app.add_user_message(msg);
-                let chat = app.chats.get_mut(app.current_chat).unwrap();
-                chat.streaming = true;
-                let tx = app.start_stream(chat_id.clone());
-                app.need_rebuild_cache = true;
-                app.jump_to_last_message();
-
-                task::spawn(async move {
-                    if let Err(e) =
-                        api::stream_message(&api_key, &provider_name, &model_name, &messages, tx)
-                            .await
-                    {
-                        eprintln!("Stream error: {:?}", e);
-                    }
-                });
-
+                let mut message = Message::new(Role::User, std::mem::take(&mut app.input));
+                message.attachments = std::mem::take(&mut app.pending_attachments);
+                let findings = app.redactor.findings(&message);
+                if app.redactor.action == RedactAction::Warn && !findings.is_empty() {
+                    let question = format!(
+                        "This message looks like it contains a secret ({}). Send anyway? (y/n)",
+                        findings.join(", ")
+                    );
+                    app.input = message.content;
+                    app.pending_attachments = message.attachments;
+                    app.end_insert();
+                    app.pending_action = Some(PendingAction::SendDraft);
+                    app.mode = Mode::Confirm;
+                    app.set_info(&question);
+                    return Ok(());
+                }
+                send_or_queue(app, message);
                 app.mode = Mode::Normal;
             }
             KeyCode::Char(c) => app.input.push(c),
@@ -535,8 +2616,9 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                 if let Some((provider_owned, model_owned)) = selected_model_details {
                     let new_model_str = format!("{}:{}", provider_owned, model_owned);
                     app.current_model = new_model_str.clone();
-                    if let Some(chat) = app.chats.get_mut(app.current_chat) {
-                        chat.model = new_model_str;
+                    if app.current_chat < app.chats.len() {
+                        app.set_chat_model(app.current_chat, new_model_str);
+                        app.need_rebuild_cache = true;
                     }
                     app.set_info(&format!("Model set to {}:{}", provider_owned, model_owned));
                 }
@@ -559,16 +2641,18 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
             KeyCode::Char('s') => app.toggle_sidebar(),
             KeyCode::Char('h') | KeyCode::Left => {
                 app.settings_tab = match app.settings_tab {
-                    SettingsTab::Providers => SettingsTab::Prompts,
+                    SettingsTab::Providers => SettingsTab::General,
                     SettingsTab::Shortcuts => SettingsTab::Providers,
                     SettingsTab::Prompts => SettingsTab::Shortcuts,
+                    SettingsTab::General => SettingsTab::Prompts,
                 };
             }
             KeyCode::Char('l') | KeyCode::Right => {
                 app.settings_tab = match app.settings_tab {
                     SettingsTab::Providers => SettingsTab::Shortcuts,
                     SettingsTab::Shortcuts => SettingsTab::Prompts,
-                    SettingsTab::Prompts => SettingsTab::Providers,
+                    SettingsTab::Prompts => SettingsTab::General,
+                    SettingsTab::General => SettingsTab::Providers,
                 };
             }
             KeyCode::Char('j') | KeyCode::Down => match app.settings_tab {
@@ -580,7 +2664,16 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         app.selected_prompt_idx += 1;
                     }
                 }
-                SettingsTab::Shortcuts => {}
+                SettingsTab::Shortcuts => {
+                    if app.selected_shortcut_idx + 1 < config::KEY_ACTIONS.len() {
+                        app.selected_shortcut_idx += 1;
+                    }
+                }
+                SettingsTab::General => {
+                    if app.selected_general_idx + 1 < GeneralSetting::ALL.len() {
+                        app.selected_general_idx += 1;
+                    }
+                }
             },
             KeyCode::Char('k') | KeyCode::Up => match app.settings_tab {
                 SettingsTab::Providers => {
@@ -593,7 +2686,12 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         app.selected_prompt_idx -= 1;
                     }
                 }
-                SettingsTab::Shortcuts => {}
+                SettingsTab::Shortcuts => {
+                    app.selected_shortcut_idx = app.selected_shortcut_idx.saturating_sub(1);
+                }
+                SettingsTab::General => {
+                    app.selected_general_idx = app.selected_general_idx.saturating_sub(1);
+                }
             },
             KeyCode::Enter => match app.settings_tab {
                 SettingsTab::Providers => {
@@ -605,7 +2703,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         }
                         idx += 1;
                         if p.expanded {
-                            let mut all_models: Vec<String> = p.models.iter().cloned().collect();
+                            let mut all_models: Vec<String> = p.models.to_vec();
                             for m in &p.enabled_models {
                                 if !all_models.contains(m) {
                                     all_models.push(m.clone());
@@ -624,7 +2722,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                                     {
                                         saved.enabled_models = p.enabled_models.clone();
                                     }
-                                    save_config(config);
+                                    app.config_dirty = true;
                                     app.set_info("Model enabled/disabled");
                                     return Ok(());
                                 }
@@ -663,7 +2761,13 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         );
                     }
                 }
-                SettingsTab::Shortcuts => {}
+                SettingsTab::Shortcuts => {
+                    if let Some((_, label, _)) = app.keybindings.entry(app.selected_shortcut_idx) {
+                        app.mode = Mode::KeyCapture;
+                        app.set_info(&format!("Press a key for '{}' (Esc to cancel)", label));
+                    }
+                }
+                SettingsTab::General => adjust_general_setting(app, config, 1),
             },
             KeyCode::Char('e') => match app.settings_tab {
                 SettingsTab::Providers => {
@@ -682,7 +2786,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         }
                         current_line_iter += 1;
                         if p.expanded {
-                            let mut all_models: Vec<String> = p.models.iter().cloned().collect();
+                            let mut all_models: Vec<String> = p.models.to_vec();
                             for m_enabled in &p.enabled_models {
                                 if !all_models.contains(m_enabled) {
                                     all_models.push(m_enabled.clone());
@@ -702,7 +2806,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                             Some("Editing prompt. Press Enter to save, Esc to cancel.".to_string());
                     }
                 }
-                SettingsTab::Shortcuts => {}
+                SettingsTab::Shortcuts | SettingsTab::General => {}
             },
             KeyCode::Char('d') => match app.settings_tab {
                 SettingsTab::Providers => {
@@ -710,7 +2814,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                     for p in &app.providers {
                         provider_header_lines += 1;
                         if p.expanded {
-                            let mut all_models: Vec<String> = p.models.iter().cloned().collect();
+                            let mut all_models: Vec<String> = p.models.to_vec();
                             for m_enabled in &p.enabled_models {
                                 if !all_models.contains(m_enabled) {
                                     all_models.push(m_enabled.clone());
@@ -729,7 +2833,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         if cm_idx_to_remove < app.custom_models.len() {
                             app.custom_models.remove(cm_idx_to_remove);
                             config.custom_models = app.custom_models.clone();
-                            save_config(config);
+                            app.config_dirty = true;
                             app.set_info("Custom model deleted");
                             if app.selected_line
                                 >= custom_models_start_line + app.custom_models.len()
@@ -753,17 +2857,60 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         } else if app.prompts.is_empty() {
                             app.selected_prompt_idx = 0;
                         }
+                        save_prompts(app, config);
                         app.set_info("Prompt deleted");
                     }
                 }
-                SettingsTab::Shortcuts => {}
+                SettingsTab::Shortcuts | SettingsTab::General => {}
             },
+            KeyCode::Char('r') if app.settings_tab == SettingsTab::Shortcuts => {
+                if let Some((action, label, _)) = app.keybindings.entry(app.selected_shortcut_idx)
+                    && let Some(key) = app.keybindings.reset(action)
+                {
+                    config.keybindings = app.keybindings.clone();
+                    app.config_dirty = true;
+                    app.set_info(&format!("{} reset to '{}'", label, key));
+                }
+            }
+            KeyCode::Char('R') if app.settings_tab == SettingsTab::Shortcuts => {
+                let copy_code_blocks = std::mem::take(&mut app.keybindings.copy_code_blocks);
+                app.keybindings = config::KeyBindings {
+                    copy_code_blocks,
+                    ..Default::default()
+                };
+                config.keybindings = app.keybindings.clone();
+                app.config_dirty = true;
+                app.set_info("All shortcuts reset to their defaults");
+            }
+            KeyCode::Char('r')
+                if app.settings_tab == SettingsTab::Prompts
+                    && app.selected_prompt_idx < app.prompts.len() =>
+            {
+                app.input = app.prompts[app.selected_prompt_idx].name.to_string();
+                app.prompt_edit_idx = Some(app.selected_prompt_idx);
+                app.mode = Mode::PromptRename;
+                app.set_info("Renaming prompt. Press Enter to save, Esc to cancel.");
+            }
+            KeyCode::Char('p')
+                if app.settings_tab == SettingsTab::Prompts
+                    && app.selected_prompt_idx < app.prompts.len() =>
+            {
+                app.prompt_preview_scroll = 0;
+                app.mode = Mode::PromptPreview;
+            }
+            KeyCode::Char('+') | KeyCode::Char('=') if app.settings_tab == SettingsTab::General => {
+                adjust_general_setting(app, config, 1)
+            }
+            KeyCode::Char('-') if app.settings_tab == SettingsTab::General => {
+                adjust_general_setting(app, config, -1)
+            }
             KeyCode::Char(' ') => {
                 if app.settings_tab == SettingsTab::Prompts
                     && app.selected_prompt_idx < app.prompts.len()
                 {
                     let prompt = &mut app.prompts[app.selected_prompt_idx];
                     prompt.active = !prompt.active;
+                    save_prompts(app, config);
                     app.set_info("Prompt active status toggled");
                 }
             }
@@ -805,11 +2952,15 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                     app.set_error("API key too short (min 8 chars)");
                 } else {
                     let p = &mut app.providers[app.selected_provider_idx];
-                    p.api_key = inp.to_string();
+                    // Keep new keys encrypted once the config uses a passphrase.
+                    p.api_key = match app.vault.as_mut() {
+                        Some(vault) if !inp.is_empty() && !inp.contains("${") => vault.encrypt(inp),
+                        _ => inp.to_string(),
+                    };
                     if let Some(saved) = config.providers.iter_mut().find(|c| c.name == p.name) {
                         saved.api_key = p.api_key.clone();
                     }
-                    save_config(config);
+                    app.config_dirty = true;
                     app.mode = Mode::Settings;
                     app.api_key_input.clear();
                     app.api_key_old.clear();
@@ -826,11 +2977,10 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                 app.info_message = None;
             }
             KeyCode::Enter => {
-                if app.selected_sidebar_idx < app.chats.len() {
-                    if !app.input.trim().is_empty() {
-                        app.chats[app.selected_sidebar_idx].title = app.input.clone();
-                        app.set_info("Chat renamed");
-                    }
+                if app.selected_sidebar_idx < app.chats.len() && !app.input.trim().is_empty() {
+                    app.chats[app.selected_sidebar_idx].title = app.input.clone();
+                    app.save_soon = true;
+                    app.set_info("Chat renamed");
                 }
                 app.input.clear();
                 app.mode = Mode::Normal;
@@ -902,7 +3052,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
             }
             KeyCode::Down | KeyCode::Up => match app.custom_model_input_stage.unwrap() {
                 crate::app::CustomModelStage::TypeChoice => {
-                    let items = vec!["Derived", "Standalone"];
+                    let items = ["Derived", "Standalone"];
                     let cur = app
                         .custom_model_api_key_choice
                         .as_ref()
@@ -1004,7 +3154,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         };
                         app.custom_models.push(new_cm.clone());
                         config.custom_models = app.custom_models.clone();
-                        save_config(config);
+                        app.config_dirty = true;
                         app.mode = Mode::Settings;
                         app.custom_model_input_stage = None;
                         app.custom_model_name_input.clear();
@@ -1017,8 +3167,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         for p_iter in &app.providers {
                             current_line_iter += 1;
                             if p_iter.expanded {
-                                let mut all_models_iter: Vec<String> =
-                                    p_iter.models.iter().cloned().collect();
+                                let mut all_models_iter: Vec<String> = p_iter.models.to_vec();
                                 for m_enabled_iter in &p_iter.enabled_models {
                                     if !all_models_iter.contains(m_enabled_iter) {
                                         all_models_iter.push(m_enabled_iter.clone());
@@ -1094,7 +3243,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                             };
                             app.custom_models.push(new_cm.clone());
                             config.custom_models = app.custom_models.clone();
-                            save_config(config);
+                            app.config_dirty = true;
                             app.mode = Mode::Settings;
                             app.custom_model_input_stage = None;
                             app.custom_model_name_input.clear();
@@ -1107,8 +3256,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                             for p_iter in &app.providers {
                                 current_line_iter += 1;
                                 if p_iter.expanded {
-                                    let mut all_models_iter: Vec<String> =
-                                        p_iter.models.iter().cloned().collect();
+                                    let mut all_models_iter: Vec<String> = p_iter.models.to_vec();
                                     for m_enabled_iter in &p_iter.enabled_models {
                                         if !all_models_iter.contains(m_enabled_iter) {
                                             all_models_iter.push(m_enabled_iter.clone());
@@ -1137,7 +3285,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         };
                         app.custom_models.push(new_cm.clone());
                         config.custom_models = app.custom_models.clone();
-                        save_config(config);
+                        app.config_dirty = true;
                         app.mode = Mode::Settings;
                         app.custom_model_input_stage = None;
                         app.custom_model_name_input.clear();
@@ -1150,8 +3298,7 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                         for p_iter in &app.providers {
                             current_line_iter += 1;
                             if p_iter.expanded {
-                                let mut all_models_iter: Vec<String> =
-                                    p_iter.models.iter().cloned().collect();
+                                let mut all_models_iter: Vec<String> = p_iter.models.to_vec();
                                 for m_enabled_iter in &p_iter.enabled_models {
                                     if !all_models_iter.contains(m_enabled_iter) {
                                         all_models_iter.push(m_enabled_iter.clone());
@@ -1173,15 +3320,18 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                 app.mode = Mode::Normal;
                 app.command.clear();
                 app.info_message = None;
+                app.visual_start = None;
+                app.visual_end = None;
             }
             KeyCode::Enter => {
-                let cmd = app.command.trim();
-                if cmd == "q" {
-                    return Err(anyhow::anyhow!("Quit"));
-                } else {
-                    app.set_error(&format!("Unknown command: :{}", cmd));
-                    app.mode = Mode::Normal;
-                    app.command.clear();
+                let cmd = app.command.trim().to_string();
+                app.command.clear();
+                app.mode = Mode::Normal;
+                run_command(app, &cmd, config).await?;
+                // A selection the command was typed from ends with it.
+                if app.mode != Mode::Command {
+                    app.visual_start = None;
+                    app.visual_end = None;
                 }
             }
             KeyCode::Backspace => {
@@ -1209,13 +3359,17 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
                             app.set_info("Prompt updated");
                         }
                     } else {
-                        app.prompts.push(crate::config::Prompt::new(
-                            format!("Prompt {}", app.prompts.len() + 1),
-                            prompt_content,
-                            false,
-                        ));
+                        // The first number no other prompt is named after,
+                        // as two prompts cannot share a file.
+                        let name = (app.prompts.len() + 1..)
+                            .map(|n| format!("Prompt {}", n))
+                            .find(|name| app.prompts.iter().all(|p| *p.name != *name))
+                            .unwrap_or_default();
+                        app.prompts
+                            .push(crate::config::Prompt::new(name, prompt_content, false));
                         app.set_info("New prompt added");
                     }
+                    save_prompts(app, config);
                 } else {
                     app.set_info("Prompt content cannot be empty");
                 }
@@ -1231,36 +3385,375 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
             }
             _ => {}
         },
-        Mode::Visual => match key.code {
-            KeyCode::Char('y') => {
-                if let (Some(start_idx), Some(end_idx)) = (app.visual_start, app.visual_end) {
-                    let (lo, hi) = if start_idx <= end_idx {
-                        (start_idx, end_idx)
-                    } else {
-                        (end_idx, start_idx)
-                    };
-
-                    let selected_lines: Vec<String> = (lo..=hi)
-                        .filter_map(|i| app.display_buffer_text_content.get(i).cloned())
-                        .collect();
-
-                    if !selected_lines.is_empty() {
-                        let text_to_copy = selected_lines.join("\n");
-                        match clipboard::copy_to_clipboard(&text_to_copy).await {
-                            Ok(_) => {
-                                app.set_info(&format!("Yanked {} line(s)", selected_lines.len()));
-                            }
-                            Err(e) => app.set_error(&format!("Copy failed: {}", e)),
-                        }
-                    } else {
-                        app.set_info("Nothing to yank");
+        Mode::PromptRename => match key.code {
+            KeyCode::Esc => {
+                app.input.clear();
+                app.mode = Mode::Settings;
+                app.prompt_edit_idx = None;
+                app.set_info("Prompt rename cancelled");
+            }
+            KeyCode::Enter => {
+                let name = app.input.trim().to_string();
+                let taken = app
+                    .prompts
+                    .iter()
+                    .enumerate()
+                    .any(|(i, p)| Some(i) != app.prompt_edit_idx && *p.name == *name);
+                if let Err(e) = config::check_prompt_name(&name) {
+                    app.set_error(&e);
+                } else if taken {
+                    app.set_error(&format!("A prompt named '{}' already exists", name));
+                } else {
+                    if let Some(prompt) = app.prompt_edit_idx.and_then(|i| app.prompts.get_mut(i)) {
+                        prompt.name = name.into();
                     }
+                    save_prompts(app, config);
+                    app.input.clear();
+                    app.mode = Mode::Settings;
+                    app.prompt_edit_idx = None;
+                    app.set_info("Prompt renamed");
+                }
+            }
+            KeyCode::Backspace => {
+                app.input.pop();
+            }
+            KeyCode::Char(c) => {
+                app.input.push(c);
+            }
+            _ => {}
+        },
+        Mode::PromptPreview => match key.code {
+            KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('p') => {
+                app.mode = Mode::Settings;
+            }
+            KeyCode::Char('j') | KeyCode::Down => {
+                app.prompt_preview_scroll = app.prompt_preview_scroll.saturating_add(1);
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.prompt_preview_scroll = app.prompt_preview_scroll.saturating_sub(1);
+            }
+            KeyCode::Char('g') => app.prompt_preview_scroll = 0,
+            _ => {}
+        },
+        Mode::KeyCapture => {
+            app.mode = Mode::Settings;
+            if key.code == KeyCode::Esc && key.modifiers.is_empty() {
+                app.set_info("Shortcut unchanged");
+                return Ok(());
+            }
+            let Some(chord) = keymap::format_key(&key) else {
+                app.set_error("That key cannot be bound");
+                return Ok(());
+            };
+            let Some((action, _, _)) = app.keybindings.entry(app.selected_shortcut_idx) else {
+                return Ok(());
+            };
+            if let Some(binding) = app.keybindings.get_mut(action) {
+                *binding = chord.clone();
+                config.keybindings = app.keybindings.clone();
+                app.config_dirty = true;
+                let conflicts = keymap::conflicts(&app.keybindings, action, &chord);
+                if conflicts.is_empty() {
+                    app.set_info(&format!("Bound to '{}'", chord));
+                } else {
+                    app.set_error(&format!(
+                        "Bound to '{}', but it is also {} in the same mode",
+                        chord,
+                        conflicts.join(", ")
+                    ));
+                }
+            }
+        }
+        Mode::Compare => match key.code {
+            KeyCode::Char('h') | KeyCode::Left => {
+                if let Some(cmp) = app.comparison.as_mut() {
+                    cmp.selected = cmp.selected.saturating_sub(1);
+                }
+            }
+            KeyCode::Char('l') | KeyCode::Right => {
+                if let Some(cmp) = app.comparison.as_mut()
+                    && cmp.selected + 1 < cmp.candidates.len()
+                {
+                    cmp.selected += 1;
+                }
+            }
+            KeyCode::Char('j') | KeyCode::Down => {
+                if let Some(cmp) = app.comparison.as_mut() {
+                    cmp.scroll = cmp.scroll.saturating_add(1);
+                }
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                if let Some(cmp) = app.comparison.as_mut() {
+                    cmp.scroll = cmp.scroll.saturating_sub(1);
+                }
+            }
+            KeyCode::Enter => match app.promote_candidate() {
+                Ok(model) => {
+                    app.mode = Mode::Normal;
+                    app.set_info(&format!("Promoted answer from {}", model));
+                }
+                Err(e) => app.set_error(&e),
+            },
+            KeyCode::Esc => {
+                app.close_comparison();
+                app.mode = Mode::Normal;
+                app.set_info("Comparison discarded");
+            }
+            _ => {}
+        },
+        Mode::Notes => match key.code {
+            KeyCode::Char('j') | KeyCode::Down => {
+                app.notes_scroll = app.notes_scroll.saturating_add(1);
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.notes_scroll = app.notes_scroll.saturating_sub(1);
+            }
+            KeyCode::Char('g') => app.notes_scroll = 0,
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = Mode::Normal,
+            _ => {}
+        },
+        Mode::ChatPicker if app.picker_filtering => match key.code {
+            KeyCode::Esc => {
+                app.picker_filtering = false;
+                app.search_query.clear();
+                app.refresh_search();
+            }
+            KeyCode::Enter | KeyCode::Down | KeyCode::Up => app.picker_filtering = false,
+            KeyCode::Backspace => {
+                app.search_query.pop();
+                app.refresh_search();
+            }
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.search_query.push(c);
+                app.refresh_search();
+            }
+            _ => {}
+        },
+        Mode::ChatPicker => match key.code {
+            KeyCode::Char('j') | KeyCode::Down
+                if app.selected_picker_idx + 1 < app.picker_chats().len() =>
+            {
+                app.selected_picker_idx += 1;
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.selected_picker_idx = app.selected_picker_idx.saturating_sub(1);
+            }
+            KeyCode::Char('/') => app.picker_filtering = true,
+            KeyCode::Enter => {
+                app.mode = Mode::Normal;
+                if let Some(&idx) = app.picker_chats().get(app.selected_picker_idx) {
+                    app.switch_chat(idx);
+                    app.focus = crate::app::Focus::Chat;
+                }
+            }
+            KeyCode::Char('n') => {
+                app.mode = Mode::Normal;
+                app.create_new_chat();
+                app.focus = crate::app::Focus::Chat;
+            }
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = Mode::Normal,
+            _ => {}
+        },
+        Mode::Search => match key.code {
+            KeyCode::Esc => app.mode = Mode::Normal,
+            KeyCode::Down | KeyCode::Tab if app.selected_search_idx + 1 < app.search_hits.len() => {
+                app.selected_search_idx += 1;
+            }
+            KeyCode::Up | KeyCode::BackTab => {
+                app.selected_search_idx = app.selected_search_idx.saturating_sub(1);
+            }
+            KeyCode::Enter => {
+                app.mode = Mode::Normal;
+                if let Some(hit) = app.search_hits.get(app.selected_search_idx).cloned() {
+                    app.push_jump();
+                    app.go_to_message(&hit.chat_id, hit.message.unwrap_or(0));
+                    app.focus = crate::app::Focus::Chat;
+                }
+            }
+            KeyCode::Backspace => {
+                app.search_query.pop();
+                app.refresh_search();
+            }
+            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                app.search_query.push(c);
+                app.refresh_search();
+            }
+            _ => {}
+        },
+        Mode::Restore => match key.code {
+            KeyCode::Char('j') | KeyCode::Down
+                if app.selected_backup_idx + 1 < app.backups.len() =>
+            {
+                app.selected_backup_idx += 1;
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.selected_backup_idx = app.selected_backup_idx.saturating_sub(1);
+            }
+            KeyCode::Enter => {
+                app.mode = Mode::Normal;
+                restore_backup(app);
+            }
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = Mode::Normal,
+            _ => {}
+        },
+        Mode::Stats => match key.code {
+            KeyCode::Char('l') | KeyCode::Right | KeyCode::Tab => {
+                show_stats(app, app.stats.period.step(true));
+            }
+            KeyCode::Char('h') | KeyCode::Left | KeyCode::BackTab => {
+                show_stats(app, app.stats.period.step(false));
+            }
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = Mode::Normal,
+            _ => {}
+        },
+        Mode::Prune => match key.code {
+            KeyCode::Char('j') | KeyCode::Down if app.selected_prune_idx + 1 < app.prune.len() => {
+                app.selected_prune_idx += 1;
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.selected_prune_idx = app.selected_prune_idx.saturating_sub(1);
+            }
+            KeyCode::Char(' ') if app.selected_prune_idx < app.prune.len() => {
+                app.prune.remove(app.selected_prune_idx);
+                if app.prune.is_empty() {
+                    app.mode = Mode::Normal;
+                    app.set_info("Nothing left to prune");
                 } else {
-                    app.set_info("Visual selection not active");
+                    app.selected_prune_idx = app.selected_prune_idx.min(app.prune.len() - 1);
                 }
+            }
+            KeyCode::Char('a') => finish_prune(app, true),
+            KeyCode::Char('d') => finish_prune(app, false),
+            KeyCode::Esc | KeyCode::Char('q') => {
+                app.prune.clear();
                 app.mode = Mode::Normal;
-                app.visual_start = None;
-                app.visual_end = None;
+                app.set_info("Cancelled");
+            }
+            _ => {}
+        },
+        Mode::LargeChat => {
+            app.mode = Mode::Normal;
+            match key.code {
+                KeyCode::Char('s') => match app.summarize_chat() {
+                    Ok(()) => app.set_info("Summarizing into a new chat…"),
+                    Err(e) => app.set_error(&e),
+                },
+                KeyCode::Char('p') => match app.split_chat() {
+                    Ok(moved) => app.set_info(&format!(
+                        "Moved the first {} message(s) into a new chat below this one",
+                        moved
+                    )),
+                    Err(e) => app.set_error(&e),
+                },
+                KeyCode::Char('a') => archive_current(app),
+                _ => app.set_info("Cancelled"),
+            }
+        }
+        Mode::Trash => match key.code {
+            KeyCode::Char('j') | KeyCode::Down if app.selected_trash_idx + 1 < app.trash.len() => {
+                app.selected_trash_idx += 1;
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.selected_trash_idx = app.selected_trash_idx.saturating_sub(1);
+            }
+            KeyCode::Enter | KeyCode::Char('r') => restore_trashed(app),
+            KeyCode::Char('d') => {
+                if let Some(trashed) = app.trash.get(app.selected_trash_idx) {
+                    let question = format!("Delete \"{}\" for good? (y/n)", trashed.chat.title);
+                    app.pending_action = Some(PendingAction::PurgeTrash(trashed.chat.id.clone()));
+                    app.mode = Mode::Confirm;
+                    app.set_info(&question);
+                }
+            }
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = Mode::Normal,
+            _ => {}
+        },
+        Mode::Bookmarks => match key.code {
+            KeyCode::Char('j') | KeyCode::Down
+                if app.selected_bookmark_idx + 1 < app.bookmarks().len() =>
+            {
+                app.selected_bookmark_idx += 1;
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.selected_bookmark_idx = app.selected_bookmark_idx.saturating_sub(1);
+            }
+            KeyCode::Enter => {
+                app.mode = Mode::Normal;
+                if let Some(&(chat_idx, msg_idx)) = app.bookmarks().get(app.selected_bookmark_idx) {
+                    app.push_jump();
+                    let chat_id = app.chats[chat_idx].id.clone();
+                    app.go_to_message(&chat_id, msg_idx);
+                    app.focus = crate::app::Focus::Chat;
+                }
+            }
+            KeyCode::Esc | KeyCode::Char('q') => app.mode = Mode::Normal,
+            _ => {}
+        },
+        Mode::Confirm => {
+            app.mode = Mode::Normal;
+            let action = app.pending_action.take();
+            if key.code != KeyCode::Char('y') {
+                if matches!(action, Some(PendingAction::ReleaseHistory(_))) {
+                    app.set_error("The chat history could not be read; this session is not saved");
+                } else {
+                    app.set_info("Cancelled");
+                }
+                return Ok(());
+            }
+            match action {
+                Some(PendingAction::DeleteMessage(msg_idx)) => match app.delete_message(msg_idx) {
+                    Ok(()) => app.set_info("Message deleted"),
+                    Err(e) => app.set_error(&e),
+                },
+                Some(PendingAction::SendDraft) => {
+                    let Some(chat) = app.chats.get_mut(app.current_chat) else {
+                        return Ok(());
+                    };
+                    let mut message = Message::new(Role::User, std::mem::take(&mut chat.draft));
+                    message.attachments = std::mem::take(&mut app.pending_attachments);
+                    app.info_message = None;
+                    send_or_queue(app, message);
+                }
+                Some(PendingAction::ApplyPatch { diff, strip }) => {
+                    match patch::apply(&diff, strip) {
+                        Ok(()) => app.set_info(&format!(
+                            "Patched {} file(s)",
+                            patch::changed_files(&diff).len()
+                        )),
+                        Err(e) => app.set_error(&format!("Patch failed: {}", e)),
+                    }
+                }
+                Some(PendingAction::PurgeTrash(id)) => {
+                    if let Err(e) = storage::remove_from_trash(&id) {
+                        app.set_error(&format!("Cannot delete the chat: {}", e));
+                        return Ok(());
+                    }
+                    open_trash(app);
+                    app.set_info("Chat deleted for good");
+                }
+                Some(PendingAction::ReleaseHistory(_)) => match storage::release_history() {
+                    Ok(copy) => {
+                        app.history_dirty = true;
+                        app.set_info(&format!(
+                            "The unreadable history was copied to {}; chats are saved again",
+                            copy.display()
+                        ));
+                    }
+                    Err(e) => app.set_error(&format!("Cannot copy the history aside: {}", e)),
+                },
+                Some(PendingAction::RunBlock(argv)) => match app.run_block(argv) {
+                    Ok(()) => app.set_info("Running code block…"),
+                    Err(e) => app.set_error(&e),
+                },
+                None => {}
+            }
+        }
+        Mode::Visual => match key.code {
+            KeyCode::Char('"') => {
+                app.pending_key = Some('"');
+            }
+            KeyCode::Char(object @ ('i' | 'a')) => {
+                app.pending_key = Some(object);
             }
             KeyCode::Esc => {
                 app.mode = Mode::Normal;
@@ -1284,27 +3777,25 @@ async fn handle_key(app: &mut App<'_>, key: KeyEvent, config: &mut config::Setti
             KeyCode::Char('d')
                 if key
                     .modifiers
-                    .contains(crossterm::event::KeyModifiers::CONTROL) =>
+                    .contains(crossterm::event::KeyModifiers::CONTROL)
+                    && app.focus == crate::app::Focus::Chat =>
             {
-                if app.focus == crate::app::Focus::Chat {
-                    let viewport_height = 10;
-                    let lines = app.display_buffer_text_content.len();
-                    let half_page = (viewport_height.max(1) / 2).max(1);
-                    app.cursor_line = (app.cursor_line + half_page).min(lines.saturating_sub(1));
-                    app.visual_end = Some(app.cursor_line);
-                }
+                let viewport_height = app.chat_viewport_height;
+                let lines = app.display_buffer_text_content.len();
+                let half_page = (viewport_height.max(1) / 2).max(1);
+                app.cursor_line = (app.cursor_line + half_page).min(lines.saturating_sub(1));
+                app.visual_end = Some(app.cursor_line);
             }
             KeyCode::Char('u')
                 if key
                     .modifiers
-                    .contains(crossterm::event::KeyModifiers::CONTROL) =>
+                    .contains(crossterm::event::KeyModifiers::CONTROL)
+                    && app.focus == crate::app::Focus::Chat =>
             {
-                if app.focus == crate::app::Focus::Chat {
-                    let viewport_height = 10;
-                    let half_page = (viewport_height.max(1) / 2).max(1);
-                    app.cursor_line = app.cursor_line.saturating_sub(half_page);
-                    app.visual_end = Some(app.cursor_line);
-                }
+                let viewport_height = app.chat_viewport_height;
+                let half_page = (viewport_height.max(1) / 2).max(1);
+                app.cursor_line = app.cursor_line.saturating_sub(half_page);
+                app.visual_end = Some(app.cursor_line);
             }
 
             _ => {}
@@ -0,0 +1,188 @@
+use crate::config::{ColorMode, ThemeFile};
+use ratatui::{buffer::Buffer, style::Color, style::Modifier};
+use std::{collections::HashMap, str::FromStr};
+use unicode_width::UnicodeWidthStr;
+
+/// xterm's default RGB values for the 16 ANSI colors, in index order.
+const ANSI: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// A loaded theme file: color replacements applied to each drawn frame,
+/// and optionally a code highlighting theme.
+#[derive(Debug, Clone, Default)]
+pub struct Theme {
+    pub name: Option<String>,
+    pub code: Option<String>,
+    colors: HashMap<Color, Color>,
+}
+
+impl Theme {
+    pub fn new(name: &str, file: &ThemeFile) -> Result<Self, String> {
+        let parse = |text: &str| {
+            Color::from_str(text).map_err(|_| format!("Theme {}: unknown color {}", name, text))
+        };
+        let mut colors = HashMap::new();
+        for (from, to) in &file.colors {
+            colors.insert(parse(from)?, parse(to)?);
+        }
+        Ok(Self {
+            name: Some(name.to_string()),
+            code: file.code.clone(),
+            colors,
+        })
+    }
+}
+
+/// Replaces the UI's colors with the theme's. Runs before `apply`, so a
+/// themed frame is still reduced for limited terminals.
+pub fn recolor(buf: &mut Buffer, theme: &Theme) {
+    if theme.colors.is_empty() {
+        return;
+    }
+    for cell in buf.content.iter_mut() {
+        if let Some(fg) = theme.colors.get(&cell.fg) {
+            cell.fg = *fg;
+        }
+        if let Some(bg) = theme.colors.get(&cell.bg) {
+            cell.bg = *bg;
+        }
+    }
+}
+
+/// Rewrites the colors of an already drawn frame for terminals that cannot
+/// show them. Widgets keep using the full palette; this runs once per draw.
+pub fn apply(buf: &mut Buffer, mode: ColorMode) {
+    match mode {
+        ColorMode::Truecolor => {}
+        ColorMode::Basic => {
+            for cell in buf.content.iter_mut() {
+                cell.fg = to_basic(cell.fg);
+                cell.bg = to_basic(cell.bg);
+            }
+        }
+        ColorMode::None => {
+            for cell in buf.content.iter_mut() {
+                // Selections, cursors and the status line are drawn as a
+                // background; keep them visible as reverse video.
+                if cell.bg != Color::Reset {
+                    cell.modifier.insert(Modifier::REVERSED);
+                }
+                cell.fg = Color::Reset;
+                cell.bg = Color::Reset;
+            }
+        }
+    }
+}
+
+/// Replaces emoji, box drawing and other symbols in an already drawn frame
+/// with ASCII, for terminals and fonts that show them as tofu. A wide glyph
+/// becomes up to two characters, so the layout does not shift.
+pub fn ascii(buf: &mut Buffer) {
+    let width = buf.area.width as usize;
+    for i in 0..buf.content.len() {
+        let symbol = buf.content[i].symbol();
+        let Some(replacement) = symbol.chars().next().and_then(ascii_glyph) else {
+            continue;
+        };
+        let cells = symbol.width().max(1);
+        let style = buf.content[i].style();
+        let mut chars = replacement.chars();
+        for offset in 0..cells {
+            if offset > 0 && (i + offset) % width == 0 {
+                break;
+            }
+            let cell = &mut buf.content[i + offset];
+            cell.set_char(chars.next().unwrap_or(' '));
+            cell.set_style(style);
+        }
+    }
+}
+
+/// `text` with the glyphs `ascii` replaces swapped for their ASCII forms,
+/// for output that does not go through the frame, like the window title.
+pub fn ascii_text(text: &str) -> String {
+    text.chars()
+        .map(|c| ascii_glyph(c).map_or_else(|| c.to_string(), str::to_string))
+        .collect()
+}
+
+/// The ASCII form of a glyph, no wider than the glyph itself.
+fn ascii_glyph(c: char) -> Option<&'static str> {
+    let ascii = match c {
+        '─' | '━' | '═' | '┄' | '┅' | '┈' | '┉' | '╌' | '╍' => "-",
+        '│' | '┃' | '║' | '┆' | '┇' | '┊' | '┋' | '╎' | '╏' => "|",
+        '\u{2500}'..='\u{257f}' => "+",
+        '█' | '▓' | '▒' => "#",
+        '░' => ".",
+        '▲' | '↑' => "^",
+        '▼' | '↓' => "v",
+        '◄' | '←' | '‹' => "<",
+        '►' | '→' | '›' | '↳' => ">",
+        '·' | '—' | '–' => "-",
+        '…' => ".",
+        '★' | '✎' => "*",
+        '✓' | '✔' => "v",
+        '✗' | '✘' => "x",
+        '⚠' => "!",
+        '⏳' => "~",
+        '🐱' => ":3",
+        '🔒' => "#",
+        '📌' => "^",
+        '📎' => "@",
+        // Braille spinner frames, in order, become a turning bar.
+        '⠋' | '⠼' | '⠇' => "|",
+        '⠙' | '⠴' | '⠏' => "/",
+        '⠹' | '⠦' => "-",
+        '⠸' | '⠧' => "\\",
+        '\u{2800}'..='\u{28ff}' => ".",
+        _ => return None,
+    };
+    Some(ascii)
+}
+
+fn to_basic(color: Color) -> Color {
+    let rgb = match color {
+        Color::Rgb(r, g, b) => (r, g, b),
+        Color::Indexed(n) if n < 16 => return ANSI[n as usize].0,
+        Color::Indexed(n) => indexed_rgb(n),
+        other => return other,
+    };
+    nearest_ansi(rgb)
+}
+
+/// RGB value of a 256-color palette entry above the 16 ANSI colors.
+fn indexed_rgb(n: u8) -> (u8, u8, u8) {
+    if n >= 232 {
+        let level = 8 + (n - 232) * 10;
+        return (level, level, level);
+    }
+    let n = n - 16;
+    let step = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+    (step(n / 36), step((n / 6) % 6), step(n % 6))
+}
+
+fn nearest_ansi((r, g, b): (u8, u8, u8)) -> Color {
+    let distance = |(cr, cg, cb): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, cr) + d(g, cg) + d(b, cb)
+    };
+    ANSI.iter()
+        .min_by_key(|(_, rgb)| distance(*rgb))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
@@ -0,0 +1,113 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Lines added and removed in one file of a unified diff.
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub path: String,
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// True if `text` looks like a unified diff.
+pub fn is_diff(text: &str) -> bool {
+    let has = |prefix: &str| text.lines().any(|line| line.starts_with(prefix));
+    has("--- ") && has("+++ ") && has("@@")
+}
+
+/// The files touched by a unified diff, in order.
+pub fn changed_files(patch: &str) -> Vec<FileChange> {
+    let mut files: Vec<FileChange> = Vec::new();
+    let mut lines = patch.lines().peekable();
+    while let Some(line) = lines.next() {
+        // Headers come in `---`/`+++` pairs; lone ones are changed lines
+        // that happen to start with "-- " or "++ ".
+        if let Some(old) = line.strip_prefix("--- ")
+            && let Some(new) = lines.next_if(|next| next.starts_with("+++ "))
+        {
+            let new = header_path(&new[4..]);
+            // A deleted file is named by its old path.
+            let path = if new == "/dev/null" {
+                header_path(old)
+            } else {
+                new
+            };
+            files.push(FileChange {
+                path,
+                added: 0,
+                removed: 0,
+            });
+        } else if let Some(file) = files.last_mut() {
+            if line.starts_with('+') {
+                file.added += 1;
+            } else if line.starts_with('-') {
+                file.removed += 1;
+            }
+        }
+    }
+    files
+}
+
+/// The path of a `---`/`+++` header without its `a/`/`b/` prefix or
+/// trailing timestamp.
+fn header_path(header: &str) -> String {
+    let path = header.split('\t').next().unwrap_or(header).trim();
+    path.strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path)
+        .to_string()
+}
+
+/// Dry-runs the patch against the working directory, first with the usual
+/// `a/`/`b/` prefixes stripped and then as plain paths. Returns the strip
+/// level that applies cleanly.
+pub fn check(patch: &str) -> Result<u8, String> {
+    let mut last_error = String::new();
+    for strip in [1, 0] {
+        match git_apply(patch, strip, true) {
+            Ok(()) => return Ok(strip),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Applies the patch to the working directory.
+pub fn apply(patch: &str, strip: u8) -> Result<(), String> {
+    git_apply(patch, strip, false)
+}
+
+fn git_apply(patch: &str, strip: u8, dry_run: bool) -> Result<(), String> {
+    let mut command = Command::new("git");
+    command.arg("apply").arg(format!("-p{}", strip));
+    if dry_run {
+        command.arg("--check");
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Cannot run git apply: {}", e))?;
+    let mut text = patch.to_string();
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(text.as_bytes())
+            .map_err(|e| format!("Cannot pass the patch to git apply: {}", e))?;
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("git apply failed: {}", e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(stderr
+        .lines()
+        .next()
+        .unwrap_or("git apply failed")
+        .to_string())
+}
@@ -0,0 +1,105 @@
+use crate::app::Chat;
+
+pub const USAGE: &str = "Usage: :prune [older-than 90d] [larger-than 1mb] [smaller-than 2kb] \
+     [fewer-than 3] [model <text>] [title <text>]";
+
+/// Which chats `:prune` offers to remove; every filter given must match.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    /// Seconds since the chat's latest message.
+    older_than: Option<i64>,
+    larger_than: Option<u64>,
+    smaller_than: Option<u64>,
+    /// Number of messages.
+    fewer_than: Option<usize>,
+    /// Text in the model name, lowercase.
+    model: Option<String>,
+    /// Text in the title, lowercase.
+    title: Option<String>,
+}
+
+/// `90d`, `12w`, `6m` or `1y` in seconds; a month is 30 days.
+fn duration(text: &str) -> Option<i64> {
+    let split = text.find(|c: char| !c.is_ascii_digit())?;
+    let count: i64 = text[..split].parse().ok()?;
+    let day = 24 * 60 * 60;
+    let unit = match &text[split..] {
+        "h" => 60 * 60,
+        "d" => day,
+        "w" => 7 * day,
+        "m" => 30 * day,
+        "y" => 365 * day,
+        _ => return None,
+    };
+    Some(count * unit)
+}
+
+/// `512`, `2kb`, `1.5mb` or `1gb` in bytes.
+fn size(text: &str) -> Option<u64> {
+    let text = text.to_ascii_lowercase();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let count: f64 = text[..split].parse().ok()?;
+    let unit = match &text[split..] {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1024.0,
+        "m" | "mb" => 1024.0 * 1024.0,
+        "g" | "gb" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((count * unit) as u64)
+}
+
+impl Filter {
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        let mut words = args.split_whitespace();
+        while let Some(name) = words.next() {
+            let value = words
+                .next()
+                .ok_or_else(|| format!("{} needs a value. {}", name, USAGE))?;
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            match name {
+                "older-than" => filter.older_than = Some(duration(value).ok_or_else(invalid)?),
+                "larger-than" => filter.larger_than = Some(size(value).ok_or_else(invalid)?),
+                "smaller-than" => filter.smaller_than = Some(size(value).ok_or_else(invalid)?),
+                "fewer-than" => filter.fewer_than = Some(value.parse().map_err(|_| invalid())?),
+                "model" => filter.model = Some(value.to_lowercase()),
+                "title" => filter.title = Some(value.to_lowercase()),
+                _ => return Err(format!("Unknown filter {}. {}", name, USAGE)),
+            }
+        }
+        if args.trim().is_empty() {
+            return Err(USAGE.to_string());
+        }
+        Ok(filter)
+    }
+
+    /// Whether `chat` matches at the time `now`. Chats without message
+    /// times never match `older-than`.
+    pub fn matches(&self, chat: &Chat, now: i64) -> bool {
+        if let Some(age) = self.older_than
+            && chat.last_activity().is_none_or(|t| t >= now - age)
+        {
+            return false;
+        }
+        if self.larger_than.is_some_and(|bytes| chat.size() <= bytes)
+            || self.smaller_than.is_some_and(|bytes| chat.size() >= bytes)
+            || self.fewer_than.is_some_and(|n| chat.message_count() >= n)
+        {
+            return false;
+        }
+        if let Some(model) = &self.model
+            && !chat.model.to_lowercase().contains(model)
+        {
+            return false;
+        }
+        if let Some(title) = &self.title
+            && !chat.title.to_lowercase().contains(title)
+        {
+            return false;
+        }
+        true
+    }
+}
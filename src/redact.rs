@@ -0,0 +1,94 @@
+use crate::app::Message;
+use crate::config::{RedactAction, Redaction};
+use regex_lite::Regex;
+
+/// Secrets looked for in every outgoing request, as `(label, pattern)`.
+const BUILTIN_RULES: &[(&str, &str)] = &[
+    ("AWS access key", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+    ("API key", r"\bsk-[A-Za-z0-9_-]{20,}"),
+    ("GitHub token", r"\bgh[pousr]_[A-Za-z0-9]{36,}\b"),
+    ("Slack token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+    (
+        "private key",
+        r"(?s)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?-----END [A-Z ]*PRIVATE KEY-----",
+    ),
+];
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Finds secrets in outgoing messages and attachments.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    pub action: RedactAction,
+    rules: Vec<(String, Regex)>,
+}
+
+impl Redactor {
+    /// Compiles the built-in rules plus the configured patterns. Patterns
+    /// that do not compile are skipped and returned as errors.
+    pub fn new(config: &Redaction) -> (Self, Vec<String>) {
+        let mut errors = Vec::new();
+        let builtin = BUILTIN_RULES
+            .iter()
+            .map(|(label, pattern)| (label.to_string(), Regex::new(pattern).unwrap()));
+        let custom = config
+            .patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(re) => Some((format!("match for {}", pattern), re)),
+                Err(e) => {
+                    errors.push(format!("Invalid redaction pattern {}: {}", pattern, e));
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let redactor = Self {
+            action: config.action,
+            rules: builtin.chain(custom).collect(),
+        };
+        (redactor, errors)
+    }
+
+    /// Labels of the rules matching anything in `message`, without repeats.
+    pub fn findings(&self, message: &Message) -> Vec<&str> {
+        if self.action == RedactAction::Off {
+            return Vec::new();
+        }
+        let texts: Vec<&str> = std::iter::once(message.content.as_str())
+            .chain(message.attachments.iter().map(|a| a.content.as_str()))
+            .collect();
+        self.rules
+            .iter()
+            .filter(|(_, re)| texts.iter().any(|text| re.is_match(text)))
+            .map(|(label, _)| label.as_str())
+            .collect()
+    }
+
+    /// Replaces every match in `messages` when the action is `redact`.
+    /// Returns the number of replacements.
+    pub fn scrub(&self, messages: &mut [Message]) -> usize {
+        if self.action != RedactAction::Redact {
+            return 0;
+        }
+        let mut count = 0;
+        for message in messages {
+            count += self.scrub_text(&mut message.content);
+            for attachment in &mut message.attachments {
+                count += self.scrub_text(&mut attachment.content);
+            }
+        }
+        count
+    }
+
+    fn scrub_text(&self, text: &mut String) -> usize {
+        let mut count = 0;
+        for (_, re) in &self.rules {
+            let found = re.find_iter(text).count();
+            if found > 0 {
+                *text = re.replace_all(text, REDACTED).into_owned();
+                count += found;
+            }
+        }
+        count
+    }
+}
@@ -0,0 +1,466 @@
+use crate::config::{self, Remote as RemoteConfig};
+use hmac::{Hmac, Mac};
+use regex_lite::Regex;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, future::Future, io, time::Duration};
+use url::Url;
+
+/// How long a single request to the remote may take.
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A flat folder of files on a server, each with a version tag (its ETag)
+/// that changes whenever the file does.
+pub trait Remote: Send + Sync {
+    /// The files in the folder with their versions.
+    fn list(&self) -> io::Result<HashMap<String, String>>;
+    /// A file's contents.
+    fn get(&self, name: &str) -> io::Result<Vec<u8>>;
+    /// Writes a file unless it is no longer at version `current` (`None`:
+    /// unless it exists), returning its new version, or `None` if another
+    /// machine changed it first. The version may be empty if the server
+    /// does not say.
+    fn put(&self, name: &str, data: Vec<u8>, current: Option<&str>) -> io::Result<Option<String>>;
+    fn delete(&self, name: &str) -> io::Result<()>;
+}
+
+/// Connects to the remote `[storage.remote]` describes: WebDAV for
+/// `http(s)://` URLs, S3 for `s3://bucket/prefix`.
+pub fn open(config: &RemoteConfig) -> io::Result<Box<dyn Remote>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let url = Url::parse(&config.url)
+        .map_err(|e| invalid(format!("[storage.remote] url {}: {}", config.url, e)))?;
+    let client = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(io::Error::other)?;
+    match url.scheme() {
+        "http" | "https" => {
+            let mut base = url;
+            // Files are resolved against the folder, not next to it.
+            if !base.path().ends_with('/') {
+                base.set_path(&format!("{}/", base.path()));
+            }
+            Ok(Box::new(WebDav {
+                client,
+                base,
+                username: config.username.clone(),
+                password: config::expand_env(&config.password).map_err(invalid)?,
+            }))
+        }
+        "s3" => {
+            let bucket = url.host_str().unwrap_or("").to_string();
+            if bucket.is_empty() {
+                return Err(invalid(
+                    "[storage.remote] url needs a bucket: s3://bucket/prefix".into(),
+                ));
+            }
+            // As written: the parsed URL has the path percent-encoded.
+            let path = config.url["s3://".len()..]
+                .split_once('/')
+                .map_or("", |(_, p)| p);
+            let prefix = path.trim_matches('/');
+            let endpoint = match &config.endpoint {
+                Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+                None => format!("https://s3.{}.amazonaws.com", config.region),
+            };
+            Ok(Box::new(S3 {
+                client,
+                endpoint: Url::parse(&endpoint).map_err(|e| {
+                    invalid(format!("[storage.remote] endpoint {}: {}", endpoint, e))
+                })?,
+                bucket,
+                prefix: if prefix.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", prefix)
+                },
+                region: config.region.clone(),
+                access_key: config::expand_env(&config.access_key).map_err(invalid)?,
+                secret_key: config::expand_env(&config.secret_key).map_err(invalid)?,
+            }))
+        }
+        scheme => Err(invalid(format!(
+            "[storage.remote] url must start with https://, http:// or s3://, not {}://",
+            scheme
+        ))),
+    }
+}
+
+/// Runs a request to completion on the storage's transfer thread, which
+/// has the app's runtime entered when there is one. Never called from the
+/// UI thread.
+fn block_on<F: Future>(future: F) -> io::Result<F::Output> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => Ok(handle.block_on(future)),
+        Err(_) => Ok(tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(future)),
+    }
+}
+
+/// Sends `request`, turning failures into errors that name the file.
+fn send(request: RequestBuilder, what: &str) -> io::Result<(StatusCode, Vec<u8>, Option<String>)> {
+    block_on(async {
+        let response: Response = request
+            .send()
+            .await
+            .map_err(|e| io::Error::other(format!("{}: {}", what, e)))?;
+        let status = response.status();
+        let version = response
+            .headers()
+            .get("etag")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| io::Error::other(format!("{}: {}", what, e)))?;
+        Ok((status, body.to_vec(), version))
+    })?
+}
+
+/// An error for an unexpected answer, with the start of the server's reply.
+fn failed(what: &str, status: StatusCode, body: &[u8]) -> io::Error {
+    let kind = match status {
+        StatusCode::NOT_FOUND => io::ErrorKind::NotFound,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    let detail: String = String::from_utf8_lossy(body).chars().take(200).collect();
+    io::Error::new(kind, format!("{}: {} {}", what, status, detail.trim()))
+}
+
+/// Undoes the XML escaping of a text node.
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// A folder on a WebDAV server (Nextcloud, ownCloud, Apache mod_dav, ...).
+struct WebDav {
+    client: reqwest::Client,
+    /// The folder, ending in `/`.
+    base: Url,
+    username: String,
+    password: String,
+}
+
+impl WebDav {
+    fn request(&self, method: Method, name: &str) -> io::Result<RequestBuilder> {
+        let url = self.base.join(name).map_err(io::Error::other)?;
+        let request = self.client.request(method, url);
+        Ok(if self.username.is_empty() {
+            request
+        } else {
+            request.basic_auth(&self.username, Some(&self.password))
+        })
+    }
+}
+
+impl Remote for WebDav {
+    fn list(&self) -> io::Result<HashMap<String, String>> {
+        let body = "<?xml version=\"1.0\"?><d:propfind xmlns:d=\"DAV:\"><d:prop>\
+                    <d:getetag/><d:getlastmodified/><d:getcontentlength/></d:prop></d:propfind>";
+        let request = self
+            .request(
+                Method::from_bytes(b"PROPFIND").map_err(io::Error::other)?,
+                "",
+            )?
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(body);
+        let (status, body, _) = send(request, "Listing the remote")?;
+        if status == StatusCode::NOT_FOUND {
+            return Ok(HashMap::new());
+        }
+        if !status.is_success() {
+            return Err(failed("Listing the remote", status, &body));
+        }
+        let body = String::from_utf8_lossy(&body);
+        let response_re = Regex::new(r"(?s)<(?:\w+:)?response\b.*?</(?:\w+:)?response>").unwrap();
+        let href_re = Regex::new(r"<(?:\w+:)?href>([^<]*)</").unwrap();
+        let prop_re =
+            Regex::new(r"<(?:\w+:)?(getetag|getlastmodified|getcontentlength)>([^<]*)</").unwrap();
+        let mut files = HashMap::new();
+        for response in response_re.find_iter(&body) {
+            let response = response.as_str();
+            let Some(href) = href_re.captures(response).map(|c| unescape(&c[1])) else {
+                continue;
+            };
+            // The folder itself, and any folders in it.
+            if href.ends_with('/') {
+                continue;
+            }
+            let Some(name) = href.rsplit('/').next().filter(|n| !n.is_empty()) else {
+                continue;
+            };
+            // Without an ETag, the time and size stand in for it.
+            let mut props: HashMap<String, String> = prop_re
+                .captures_iter(response)
+                .map(|c| (c[1].to_string(), unescape(&c[2])))
+                .collect();
+            let version = props.remove("getetag").unwrap_or_else(|| {
+                format!(
+                    "{}/{}",
+                    props.remove("getlastmodified").unwrap_or_default(),
+                    props.remove("getcontentlength").unwrap_or_default()
+                )
+            });
+            files.insert(name.to_string(), version);
+        }
+        Ok(files)
+    }
+
+    fn get(&self, name: &str) -> io::Result<Vec<u8>> {
+        let what = format!("Downloading {}", name);
+        let (status, body, _) = send(self.request(Method::GET, name)?, &what)?;
+        if !status.is_success() {
+            return Err(failed(&what, status, &body));
+        }
+        Ok(body)
+    }
+
+    fn put(&self, name: &str, data: Vec<u8>, current: Option<&str>) -> io::Result<Option<String>> {
+        let what = format!("Uploading {}", name);
+        for attempt in 0..2 {
+            let request = self.request(Method::PUT, name)?.body(data.clone());
+            let request = match current {
+                Some(version) => request.header("If-Match", version),
+                None => request.header("If-None-Match", "*"),
+            };
+            let (status, body, version) = send(request, &what)?;
+            match status {
+                StatusCode::PRECONDITION_FAILED => return Ok(None),
+                // The folder does not exist yet.
+                StatusCode::NOT_FOUND | StatusCode::CONFLICT if attempt == 0 => {
+                    let mkcol = Method::from_bytes(b"MKCOL").map_err(io::Error::other)?;
+                    send(self.request(mkcol, "")?, "Creating the remote folder")?;
+                }
+                status if status.is_success() => return Ok(Some(version.unwrap_or_default())),
+                status => return Err(failed(&what, status, &body)),
+            }
+        }
+        Err(io::Error::other(format!(
+            "{}: the remote folder cannot be created",
+            what
+        )))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let what = format!("Deleting {}", name);
+        let (status, body, _) = send(self.request(Method::DELETE, name)?, &what)?;
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            Err(failed(&what, status, &body))
+        }
+    }
+}
+
+/// A prefix in an S3 bucket (AWS, MinIO, Cloudflare R2, Backblaze B2, ...),
+/// addressed path-style and signed with AWS Signature Version 4.
+struct S3 {
+    client: reqwest::Client,
+    endpoint: Url,
+    bucket: String,
+    /// Empty, or ending in `/`.
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+/// Percent-encodes everything but the characters SigV4 leaves alone (and
+/// `/` in paths).
+fn uri_encode(text: &str, path: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if path => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3 {
+    /// A signed request for `key` in the bucket (the bucket itself if
+    /// empty). `headers` are signed along with the required ones.
+    fn request(
+        &self,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+        headers: &[(&str, &str)],
+    ) -> RequestBuilder {
+        let path = uri_encode(&format!("/{}/{}", self.bucket, key), true);
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, false), uri_encode(v, false)))
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query(Some(&query).filter(|q| !q.is_empty()).map(|q| q.as_str()));
+
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload = format!("{:x}", Sha256::digest(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+            None => url.host_str().unwrap_or("").to_string(),
+        };
+        let mut signed: Vec<(String, String)> = vec![
+            ("host".into(), host),
+            ("x-amz-content-sha256".into(), payload.clone()),
+            ("x-amz-date".into(), amz_date.clone()),
+        ];
+        signed.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_lowercase(), value.trim().to_string())),
+        );
+        signed.sort();
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_names = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method, path, query, canonical_headers, signed_names, payload
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            amz_date,
+            scope,
+            Sha256::digest(canonical_request.as_bytes())
+        );
+        let mut key = hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac(&key, part);
+        }
+        let signature: String = hmac(&key, &string_to_sign)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let mut request = self.client.request(method, url).header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_names, signature
+            ),
+        );
+        for (name, value) in signed.iter().filter(|(name, _)| name != "host") {
+            request = request.header(name, value);
+        }
+        request.body(body)
+    }
+}
+
+impl Remote for S3 {
+    fn list(&self) -> io::Result<HashMap<String, String>> {
+        let object_re =
+            Regex::new(r"(?s)<Contents>.*?<Key>([^<]*)</Key>.*?<ETag>([^<]*)</ETag>.*?</Contents>")
+                .unwrap();
+        let token_re = Regex::new(r"<NextContinuationToken>([^<]*)<").unwrap();
+        let mut files = HashMap::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let request = self.request(Method::GET, "", &query, Vec::new(), &[]);
+            let (status, body, _) = send(request, "Listing the remote")?;
+            if !status.is_success() {
+                return Err(failed("Listing the remote", status, &body));
+            }
+            let body = String::from_utf8_lossy(&body);
+            for object in object_re.captures_iter(&body) {
+                let key = unescape(&object[1]);
+                let Some(name) = key.strip_prefix(&self.prefix) else {
+                    continue;
+                };
+                if !name.is_empty() && !name.contains('/') {
+                    files.insert(name.to_string(), unescape(&object[2]));
+                }
+            }
+            token = token_re.captures(&body).map(|c| unescape(&c[1]));
+            if token.is_none() || !body.contains("<IsTruncated>true</IsTruncated>") {
+                return Ok(files);
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> io::Result<Vec<u8>> {
+        let what = format!("Downloading {}", name);
+        let key = format!("{}{}", self.prefix, name);
+        let request = self.request(Method::GET, &key, &[], Vec::new(), &[]);
+        let (status, body, _) = send(request, &what)?;
+        if !status.is_success() {
+            return Err(failed(&what, status, &body));
+        }
+        Ok(body)
+    }
+
+    fn put(&self, name: &str, data: Vec<u8>, current: Option<&str>) -> io::Result<Option<String>> {
+        let what = format!("Uploading {}", name);
+        let key = format!("{}{}", self.prefix, name);
+        let condition = match current {
+            Some(version) => ("if-match", version),
+            None => ("if-none-match", "*"),
+        };
+        let request = self.request(Method::PUT, &key, &[], data.clone(), &[condition]);
+        let (mut status, mut body, mut version) = send(request, &what)?;
+        // Services without conditional writes get a plain one.
+        if status == StatusCode::NOT_IMPLEMENTED {
+            let request = self.request(Method::PUT, &key, &[], data, &[]);
+            (status, body, version) = send(request, &what)?;
+        }
+        match status {
+            StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT => Ok(None),
+            status if status.is_success() => Ok(Some(version.unwrap_or_default())),
+            status => Err(failed(&what, status, &body)),
+        }
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let what = format!("Deleting {}", name);
+        let key = format!("{}{}", self.prefix, name);
+        let request = self.request(Method::DELETE, &key, &[], Vec::new(), &[]);
+        let (status, body, _) = send(request, &what)?;
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            Err(failed(&what, status, &body))
+        }
+    }
+}
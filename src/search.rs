@@ -0,0 +1,169 @@
+use crate::app::Chat;
+use crate::storage;
+use rusqlite::{Connection, params};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    sync::{Mutex, OnceLock},
+};
+
+/// Row `msg` value for a chat's title, which is indexed on its own.
+const TITLE: i64 = -1;
+
+/// A message (or chat title) matching a search, best matches first.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub chat_id: String,
+    /// Index of the message in the chat; `None` when the title matched.
+    pub message: Option<usize>,
+    pub snippet: String,
+}
+
+/// The index while the history is encrypted, when a file would give the
+/// messages away.
+static MEMORY: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+/// Runs `f` on the full-text index over all messages, kept in
+/// `search.sqlite3` in the data directory. It is a cache: deleting it only
+/// costs a rebuild.
+fn with_index<T>(f: impl FnOnce(&mut Connection) -> io::Result<T>) -> io::Result<T> {
+    if storage::encrypting() {
+        let memory = match MEMORY.get() {
+            Some(memory) => memory,
+            None => {
+                let conn = Connection::open_in_memory().map_err(io::Error::other)?;
+                MEMORY.get_or_init(|| Mutex::new(conn))
+            }
+        };
+        let mut conn = memory.lock().unwrap_or_else(|e| e.into_inner());
+        create_tables(&conn)?;
+        return f(&mut conn);
+    }
+    let dir = storage::data_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    let mut conn = Connection::open(dir.join("search.sqlite3")).map_err(io::Error::other)?;
+    create_tables(&conn)?;
+    f(&mut conn)
+}
+
+fn create_tables(conn: &Connection) -> io::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS indexed (chat_id TEXT PRIMARY KEY, hash INTEGER NOT NULL);
+         CREATE VIRTUAL TABLE IF NOT EXISTS messages
+             USING fts5(chat_id UNINDEXED, msg UNINDEXED, text);",
+    )
+    .map_err(io::Error::other)
+}
+
+/// Fingerprint of what the index holds for a chat.
+fn fingerprint(chat: &Chat) -> i64 {
+    let mut hasher = DefaultHasher::new();
+    chat.title.hash(&mut hasher);
+    for message in &chat.messages {
+        message.content.hash(&mut hasher);
+    }
+    hasher.finish() as i64
+}
+
+/// Re-indexes the chats that changed since the last update and drops the
+/// ones that are gone.
+pub fn update(chats: &[Chat]) -> io::Result<()> {
+    with_index(|conn| update_index(conn, chats))
+}
+
+fn update_index(conn: &mut Connection, chats: &[Chat]) -> io::Result<()> {
+    let tx = conn.transaction().map_err(io::Error::other)?;
+    let indexed: HashMap<String, i64> = {
+        let mut statement = tx
+            .prepare("SELECT chat_id, hash FROM indexed")
+            .map_err(io::Error::other)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(io::Error::other)?;
+        rows.collect::<Result<_, _>>().map_err(io::Error::other)?
+    };
+    let forget = |id: &str| -> rusqlite::Result<()> {
+        tx.execute("DELETE FROM messages WHERE chat_id = ?1", [id])?;
+        tx.execute("DELETE FROM indexed WHERE chat_id = ?1", [id])?;
+        Ok(())
+    };
+    for chat in chats {
+        // Chats not loaded yet have not changed; ones never indexed are read
+        // just for this.
+        let read;
+        let chat = match &chat.stub {
+            Some(_) if indexed.contains_key(&chat.id) => continue,
+            Some(_) => match storage::read_chat(&chat.id) {
+                Ok(chat) => {
+                    read = chat;
+                    &read
+                }
+                Err(_) => continue,
+            },
+            None => chat,
+        };
+        let hash = fingerprint(chat);
+        if indexed.get(&chat.id) == Some(&hash) {
+            continue;
+        }
+        forget(&chat.id).map_err(io::Error::other)?;
+        let rows = std::iter::once((TITLE, chat.title.as_str())).chain(
+            chat.messages
+                .iter()
+                .enumerate()
+                .map(|(idx, m)| (idx as i64, m.content.as_str())),
+        );
+        for (msg, text) in rows {
+            tx.execute(
+                "INSERT INTO messages (chat_id, msg, text) VALUES (?1, ?2, ?3)",
+                params![chat.id, msg, text],
+            )
+            .map_err(io::Error::other)?;
+        }
+        tx.execute(
+            "INSERT INTO indexed (chat_id, hash) VALUES (?1, ?2)",
+            params![chat.id, hash],
+        )
+        .map_err(io::Error::other)?;
+    }
+    let current: HashSet<&str> = chats.iter().map(|c| c.id.as_str()).collect();
+    for id in indexed.keys().filter(|id| !current.contains(id.as_str())) {
+        forget(id).map_err(io::Error::other)?;
+    }
+    tx.commit().map_err(io::Error::other)
+}
+
+/// Up to `limit` messages and titles containing every word of `query`, the
+/// last one also as a prefix, so results show up while typing.
+pub fn search(query: &str, limit: usize) -> io::Result<Vec<Hit>> {
+    let words: Vec<String> = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    let Some(last) = words.last() else {
+        return Ok(Vec::new());
+    };
+    let fts_query = format!("{} {}*", words[..words.len() - 1].join(" "), last);
+    with_index(|conn| find(conn, &fts_query, limit))
+}
+
+fn find(conn: &Connection, fts_query: &str, limit: usize) -> io::Result<Vec<Hit>> {
+    let mut statement = conn
+        .prepare(
+            "SELECT chat_id, msg, snippet(messages, 2, '', '', '…', 16) FROM messages
+             WHERE messages MATCH ?1 ORDER BY rank LIMIT ?2",
+        )
+        .map_err(io::Error::other)?;
+    let rows = statement
+        .query_map(params![fts_query, limit as i64], |row| {
+            let msg: i64 = row.get(1)?;
+            Ok(Hit {
+                chat_id: row.get(0)?,
+                message: usize::try_from(msg).ok(),
+                snippet: row.get(2)?,
+            })
+        })
+        .map_err(io::Error::other)?;
+    rows.collect::<Result<_, _>>().map_err(io::Error::other)
+}
@@ -0,0 +1,249 @@
+use crate::config::{CustomModel, Settings};
+use argon2::Argon2;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng, rand_core::RngCore},
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Marks an `api_key` value encrypted with the config passphrase.
+const PREFIX: &str = "enc:";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+/// The `api_key` values in `config`, including those of custom models.
+pub fn api_keys(config: &Settings) -> Vec<&String> {
+    let providers = config.providers.iter().map(|p| &p.api_key);
+    let custom = config.custom_models.iter().filter_map(|cm| match cm {
+        CustomModel::Standalone { api_key, .. } => api_key.as_ref(),
+        CustomModel::Derived { .. } => None,
+    });
+    providers.chain(custom).collect()
+}
+
+/// Encrypts and decrypts `api_key` values with a key derived from the
+/// passphrase entered at startup. Decrypted values are kept in memory only;
+/// config.toml always holds the `enc:` form.
+pub struct Vault {
+    passphrase: String,
+    plain: HashMap<String, String>,
+}
+
+impl Vault {
+    pub fn new(passphrase: String) -> Self {
+        Self {
+            passphrase,
+            plain: HashMap::new(),
+        }
+    }
+
+    /// Decrypts every encrypted key in `config` not seen before. Fails on
+    /// the first value the passphrase does not open.
+    pub fn unlock(&mut self, config: &Settings) -> Result<(), String> {
+        for value in api_keys(config) {
+            if is_encrypted(value) && !self.plain.contains_key(value) {
+                let plain = self.decrypt(value)?;
+                self.plain.insert(value.clone(), plain);
+            }
+        }
+        Ok(())
+    }
+
+    /// The plaintext of an encrypted value unlocked earlier.
+    pub fn get(&self, value: &str) -> Option<&str> {
+        self.plain.get(value).map(String::as_str)
+    }
+
+    pub fn encrypt(&mut self, plain: &str) -> String {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let cipher = ChaCha20Poly1305::new(&self.key(&salt));
+        let ciphertext = cipher
+            .encrypt(&nonce, plain.as_bytes())
+            .expect("encrypting an in-memory buffer cannot fail");
+        let mut bytes = salt.to_vec();
+        bytes.extend_from_slice(&nonce);
+        bytes.extend(ciphertext);
+        let value = format!("{}{}", PREFIX, STANDARD.encode(bytes));
+        self.plain.insert(value.clone(), plain.to_string());
+        value
+    }
+
+    fn decrypt(&self, value: &str) -> Result<String, String> {
+        let bytes = STANDARD
+            .decode(&value[PREFIX.len()..])
+            .map_err(|_| "Encrypted API key is not valid base64".to_string())?;
+        if bytes.len() < SALT_LEN + NONCE_LEN {
+            return Err("Encrypted API key is truncated".to_string());
+        }
+        let (salt, rest) = bytes.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let cipher = ChaCha20Poly1305::new(&self.key(salt));
+        let plain = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Wrong passphrase for the encrypted API keys".to_string())?;
+        String::from_utf8(plain).map_err(|_| "Encrypted API key is not valid UTF-8".to_string())
+    }
+
+    fn key(&self, salt: &[u8]) -> Key {
+        derive_key(&self.passphrase, salt)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Key {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .expect("salt and key lengths are valid for argon2");
+    key
+}
+
+/// Starts history files encrypted with `[storage] encrypt`.
+const FILE_MAGIC: &[u8] = b"meowi-encrypted-v1\n";
+
+/// Encrypts history files with a key derived from the history passphrase.
+/// Deriving a key is slow on purpose, so files written in a session share
+/// the salt of the first file read, and keys are cached by salt.
+pub struct FileCipher {
+    passphrase: String,
+    salt: Mutex<Option<[u8; SALT_LEN]>>,
+    keys: Mutex<HashMap<[u8; SALT_LEN], Key>>,
+}
+
+impl FileCipher {
+    pub fn new(passphrase: String) -> Self {
+        Self {
+            passphrase,
+            salt: Mutex::new(None),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(FILE_MAGIC)
+    }
+
+    pub fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+        let salt = *self
+            .salt
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(|| {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                salt
+            });
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let cipher = ChaCha20Poly1305::new(&self.key(salt));
+        let ciphertext = cipher
+            .encrypt(&nonce, plain)
+            .expect("encrypting an in-memory buffer cannot fail");
+        let mut data = FILE_MAGIC.to_vec();
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&nonce);
+        data.extend(ciphertext);
+        data
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let bytes = data
+            .strip_prefix(FILE_MAGIC)
+            .filter(|b| b.len() >= SALT_LEN + NONCE_LEN)
+            .ok_or_else(|| "Encrypted file is truncated".to_string())?;
+        let (salt, rest) = bytes.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let salt: [u8; SALT_LEN] = salt.try_into().expect("split at SALT_LEN");
+        let cipher = ChaCha20Poly1305::new(&self.key(salt));
+        let plain = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Wrong passphrase for the encrypted history".to_string())?;
+        self.salt
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(salt);
+        Ok(plain)
+    }
+
+    fn key(&self, salt: [u8; SALT_LEN]) -> Key {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        *keys
+            .entry(salt)
+            .or_insert_with(|| derive_key(&self.passphrase, &salt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_key(api_key: &str) -> Settings {
+        let mut config = Settings::default();
+        config.providers[0].api_key = api_key.to_string();
+        config
+    }
+
+    #[test]
+    fn vault_unlocks_what_it_encrypted() {
+        let value = Vault::new("hunter2".into()).encrypt("sk-test");
+        assert!(is_encrypted(&value));
+        assert!(!value.contains("sk-test"));
+
+        let config = config_with_key(&value);
+        assert_eq!(api_keys(&config).len(), config.providers.len());
+        let mut vault = Vault::new("hunter2".into());
+        vault.unlock(&config).unwrap();
+        assert_eq!(vault.get(&value), Some("sk-test"));
+    }
+
+    #[test]
+    fn vault_rejects_wrong_passphrase() {
+        let value = Vault::new("hunter2".into()).encrypt("sk-test");
+        let mut vault = Vault::new("hunter3".into());
+        assert_eq!(
+            vault.unlock(&config_with_key(&value)),
+            Err("Wrong passphrase for the encrypted API keys".to_string())
+        );
+        assert_eq!(
+            vault.unlock(&config_with_key("enc:AAAA")),
+            Err("Encrypted API key is truncated".to_string())
+        );
+    }
+
+    #[test]
+    fn file_cipher_round_trip() {
+        let cipher = FileCipher::new("hunter2".into());
+        let first = cipher.encrypt(b"[]");
+        let second = cipher.encrypt(b"{\"id\":\"c1\"}");
+        assert!(FileCipher::is_encrypted(&first));
+        assert!(!FileCipher::is_encrypted(b"[]"));
+        // One salt per session, so the key is derived once.
+        let salt = |data: &[u8]| data[FILE_MAGIC.len()..][..SALT_LEN].to_vec();
+        assert_eq!(salt(&first), salt(&second));
+
+        let reader = FileCipher::new("hunter2".into());
+        assert_eq!(reader.decrypt(&first).unwrap(), b"[]");
+        assert_eq!(reader.decrypt(&second).unwrap(), b"{\"id\":\"c1\"}");
+        // Files written after reading keep the salt that was read.
+        assert_eq!(salt(&reader.encrypt(b"x")), salt(&first));
+    }
+
+    #[test]
+    fn file_cipher_rejects_wrong_passphrase_and_truncation() {
+        let data = FileCipher::new("hunter2".into()).encrypt(b"[]");
+        assert_eq!(
+            FileCipher::new("hunter3".into()).decrypt(&data),
+            Err("Wrong passphrase for the encrypted history".to_string())
+        );
+        assert_eq!(
+            FileCipher::new("hunter2".into()).decrypt(&data[..FILE_MAGIC.len() + 4]),
+            Err("Encrypted file is truncated".to_string())
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use crate::app::Chat;
+use chrono::{Local, TimeZone};
+use std::collections::HashMap;
+
+/// Days shown in the `:stats` sparkline.
+pub const DAYS: usize = 30;
+
+/// The time span `:stats` sums over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Period {
+    Today,
+    Week,
+    #[default]
+    Month,
+    Year,
+    All,
+}
+
+impl Period {
+    pub const ALL: [Period; 5] = [
+        Period::Today,
+        Period::Week,
+        Period::Month,
+        Period::Year,
+        Period::All,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Period::Today => "Today",
+            Period::Week => "7 days",
+            Period::Month => "30 days",
+            Period::Year => "365 days",
+            Period::All => "All time",
+        }
+    }
+
+    /// The period after this one, or before it if `forward` is false.
+    pub fn step(self, forward: bool) -> Self {
+        let idx = Self::ALL.iter().position(|&p| p == self).unwrap_or(0);
+        let len = Self::ALL.len();
+        Self::ALL[if forward {
+            (idx + 1) % len
+        } else {
+            (idx + len - 1) % len
+        }]
+    }
+
+    /// Unix time the period starts at; days start at local midnight.
+    fn start(self, now: i64) -> i64 {
+        let days = match self {
+            Period::Today => 0,
+            Period::Week => 6,
+            Period::Month => 29,
+            Period::Year => 364,
+            Period::All => return i64::MIN,
+        };
+        day_start(now) - days * 86_400
+    }
+}
+
+/// Unix time of the local midnight starting the day of `time`.
+fn day_start(time: i64) -> i64 {
+    Local
+        .timestamp_opt(time, 0)
+        .single()
+        .and_then(|t| t.date_naive().and_hms_opt(0, 0, 0))
+        .and_then(|t| Local.from_local_datetime(&t).earliest())
+        .map_or(time - time.rem_euclid(86_400), |t| t.timestamp())
+}
+
+/// Usage summed over some replies.
+#[derive(Debug, Clone, Default)]
+pub struct Totals {
+    pub replies: usize,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+    /// Some replies had no price in `[pricing]`.
+    pub unpriced: bool,
+    /// Some counts are local estimates.
+    pub estimated: bool,
+}
+
+impl Totals {
+    pub fn tokens(&self) -> u64 {
+        self.input_tokens + self.output_tokens
+    }
+}
+
+/// What `:stats` shows.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub period: Period,
+    pub total: Totals,
+    /// Sorted by tokens, most first.
+    pub by_provider: Vec<(String, Totals)>,
+    pub by_model: Vec<(String, Totals)>,
+    /// Tokens per day over the last `DAYS` days, oldest first.
+    pub daily: Vec<u64>,
+}
+
+fn sorted(map: HashMap<String, Totals>) -> Vec<(String, Totals)> {
+    let mut rows: Vec<(String, Totals)> = map.into_iter().collect();
+    rows.sort_by(|a, b| b.1.tokens().cmp(&a.1.tokens()).then(a.0.cmp(&b.0)));
+    rows
+}
+
+/// Sums the usage recorded on the replies in `chats` (which must be loaded)
+/// over `period`, as of `now`.
+pub fn collect(chats: &[Chat], period: Period, now: i64) -> Stats {
+    let start = period.start(now);
+    let today = day_start(now);
+    let mut stats = Stats {
+        period,
+        daily: vec![0; DAYS],
+        ..Stats::default()
+    };
+    let mut by_provider: HashMap<String, Totals> = HashMap::new();
+    let mut by_model: HashMap<String, Totals> = HashMap::new();
+    for message in chats.iter().flat_map(|c| &c.messages) {
+        let (Some(usage), Some(time)) = (message.usage, message.timestamp) else {
+            continue;
+        };
+        let days_ago = (today - day_start(time)) / 86_400;
+        if (0..DAYS as i64).contains(&days_ago) {
+            stats.daily[DAYS - 1 - days_ago as usize] += usage.total();
+        }
+        if time < start {
+            continue;
+        }
+        let model = message.model.clone().unwrap_or_else(|| "unknown".into());
+        let provider = model
+            .split_once(':')
+            .map_or("unknown", |(provider, _)| provider)
+            .to_string();
+        for totals in [
+            &mut stats.total,
+            by_provider.entry(provider).or_default(),
+            by_model.entry(model).or_default(),
+        ] {
+            totals.replies += 1;
+            totals.input_tokens += usage.input_tokens;
+            totals.output_tokens += usage.output_tokens;
+            totals.cost += usage.cost.unwrap_or(0.0);
+            totals.unpriced |= usage.cost.is_none();
+            totals.estimated |= usage.estimated;
+        }
+    }
+    stats.by_provider = sorted(by_provider);
+    stats.by_model = sorted(by_model);
+    stats
+}
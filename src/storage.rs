@@ -1,43 +1,1675 @@
-use crate::app::Chat;
+use crate::app::{Chat, ChatStub, ForkOrigin, Session};
+use crate::attachments;
+use crate::config::{self, Storage, StorageBackend};
+use crate::git;
+use crate::remote::{self, Remote};
+use crate::search;
+use crate::secrets::FileCipher;
+use chrono::{Local, NaiveDateTime, TimeDelta};
 use directories::ProjectDirs;
-use serde_json;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::{
+    collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{BufReader, BufWriter},
-    path::PathBuf,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
 };
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
 
-pub fn get_history_path() -> Result<PathBuf, std::io::Error> {
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps history in `dir` instead of the default location (`--data-dir`).
+pub fn set_data_dir(dir: PathBuf) {
+    let _ = DATA_DIR.set(dir);
+}
+
+/// The `--data-dir` path, else `$MEOWI_DATA_DIR`, else the platform's data
+/// directory.
+pub fn data_dir() -> Result<PathBuf, std::io::Error> {
+    if let Some(dir) = DATA_DIR.get() {
+        return Ok(dir.clone());
+    }
+    if let Some(dir) = std::env::var_os("MEOWI_DATA_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
     let proj_dirs = ProjectDirs::from("com", "yourname", "meowi").ok_or_else(|| {
-        std::io::Error::new(std::io::ErrorKind::NotFound, "ProjectDirs not found")
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No data directory found; set MEOWI_DATA_DIR or pass --data-dir",
+        )
     })?;
-    let data_dir = proj_dirs.data_dir();
-    fs::create_dir_all(data_dir)?;
-    Ok(data_dir.join("history.json"))
-}
-
-pub fn load_history() -> Vec<Chat> {
-    match get_history_path()
-        .and_then(|path| File::open(path).map(BufReader::new))
-        .and_then(|reader| {
-            serde_json::from_reader(reader)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
-        }) {
-        Ok(chats) => chats,
-        Err(_) => Vec::new(),
-    }
-}
-
-pub fn save_history(chats: &[Chat]) {
-    if let Ok(path) = get_history_path() {
-        let tmp_path = path.with_extension("json.tmp");
-        if let Ok(file) = File::create(&tmp_path) {
-            let writer = BufWriter::new(file);
-            if serde_json::to_writer_pretty(writer, chats).is_ok() {
-                let _ = fs::rename(&tmp_path, &path);
+    Ok(proj_dirs.data_dir().to_path_buf())
+}
+
+/// A place chats are loaded from and saved to.
+pub trait Store: Send + Sync {
+    fn load(&self) -> io::Result<Vec<Chat>>;
+    /// The chats for startup; stores that keep an index may return stubs
+    /// and read the rest with `load_chat` when a chat is opened.
+    fn load_index(&self) -> io::Result<Vec<Chat>> {
+        self.load()
+    }
+    fn load_chat(&self, _id: &str) -> io::Result<Chat> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+    /// Saves the chats. Stubs are left as they are on disk.
+    fn save(&self, chats: &[Chat]) -> io::Result<()>;
+    /// The file or directory holding the history.
+    fn path(&self) -> &Path;
+    /// Copies the saved history to `to`, laid out like `path()`.
+    fn backup(&self, to: &Path) -> io::Result<()> {
+        fs::copy(self.path(), to).map(|_| ())
+    }
+    /// Reads the chats of a copy made by `backup`.
+    fn load_backup(&self, path: &Path) -> io::Result<Vec<Chat>>;
+    /// The file whose header shows whether the history is encrypted.
+    fn marker(&self) -> PathBuf {
+        self.path().to_path_buf()
+    }
+    /// Merges changes other machines made to the saved history into
+    /// `chats`; returns how many chats changed.
+    fn sync(&self, _chats: &mut Vec<Chat>) -> io::Result<usize> {
+        Ok(0)
+    }
+    /// Writes every chat again, e.g. to encrypt or decrypt all of them.
+    fn rewrite(&self) -> io::Result<()> {
+        self.save(&self.load()?)
+    }
+    /// Starts bringing in what other machines changed, for `sync` to merge
+    /// once the transfer is reported.
+    fn fetch(&self) {}
+    /// The channel background transfers are reported on; handed out once.
+    fn transfers(&self) -> Option<UnboundedReceiver<Transferred>> {
+        None
+    }
+    /// Waits for the background transfers started so far to end.
+    fn finish(&self) {}
+}
+
+static STORE: OnceLock<Box<dyn Store>> = OnceLock::new();
+
+/// Selects the backend and location from `[storage]`. Takes effect only
+/// before history is first loaded or saved.
+pub fn configure(config: &Storage) -> io::Result<()> {
+    let path = match &config.history_path {
+        Some(path) => config::expand_home(path),
+        None => data_dir()?.join(match config.backend {
+            StorageBackend::Json => "history.json",
+            StorageBackend::Sqlite => "history.sqlite3",
+            StorageBackend::Files => "chats",
+        }),
+    };
+    if config.encrypt && config.backend == StorageBackend::Sqlite {
+        return Err(io::Error::other(
+            "[storage] encrypt needs the files or json backend",
+        ));
+    }
+    if config.remote.is_some() && config.backend != StorageBackend::Files {
+        return Err(io::Error::other("[storage.remote] needs the files backend"));
+    }
+    let _ = BACKUPS.set((config.backups, config.backup_minutes));
+    ENCRYPT.store(config.encrypt, Ordering::Relaxed);
+    let store: Box<dyn Store> = match config.backend {
+        StorageBackend::Json => Box::new(JsonStore { path }),
+        StorageBackend::Sqlite => Box::new(SqliteStore { path }),
+        StorageBackend::Files => match &config.remote {
+            Some(remote) => Box::new(RemoteStore::new(
+                FilesStore::new(path),
+                remote::open(remote)?,
+            )),
+            None => Box::new(FilesStore::new(path)),
+        },
+    };
+    let _ = STORE.set(store);
+    Ok(())
+}
+
+/// Brings the history on disk in line with `[storage]` once the passphrase
+/// is known: moves an old `history.json` into `chats/`, and encrypts or
+/// decrypts the history, its backups and other copies when `encrypt`
+/// changed.
+pub fn prepare(config: &Storage) -> io::Result<()> {
+    if config.backend == StorageBackend::Files && config.history_path.is_none() {
+        let history = data_dir()?.join("history.json");
+        if !store().marker().exists() && history.exists() {
+            let chats: Vec<Chat> = read_json(&history).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Cannot move {} to {}: {}",
+                        history.display(),
+                        store().path().display(),
+                        e
+                    ),
+                )
+            })?;
+            get_history_path()?;
+            store().save(&chats)?;
+            fs::rename(&history, history.with_extension("json.migrated"))?;
+        }
+    }
+    if history_encrypted() == config.encrypt || !store().marker().exists() {
+        return Ok(());
+    }
+    store().rewrite()?;
+    // Backups, other copies of the history and attachments follow along.
+    let mut copies = copies()?;
+    copies.extend(attachments::files()?);
+    for path in copies {
+        let Ok(data) = fs::read(&path) else {
+            continue;
+        };
+        if FileCipher::is_encrypted(&data) != config.encrypt {
+            write_atomic(&path, &open_data(data)?)?;
+        }
+    }
+    if config.encrypt {
+        // The search index holds every message in plain text; with
+        // encryption it is kept in memory instead.
+        let _ = fs::remove_file(data_dir()?.join("search.sqlite3"));
+    }
+    Ok(())
+}
+
+/// Backups and other copies of the history: `history.json.migrated`,
+/// recovery files and the files in `backups/`, `trash/` and `archives/`.
+fn copies() -> io::Result<Vec<PathBuf>> {
+    let mut copies = vec![data_dir()?.join("history.json.migrated")];
+    copies.extend(trash_files());
+    for entry in fs::read_dir(archives_dir()).into_iter().flatten().flatten() {
+        copies.push(entry.path());
+    }
+    for entry in fs::read_dir(backups_dir()).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            copies.extend(fs::read_dir(&path)?.flatten().map(|e| e.path()));
+        } else {
+            copies.push(path);
+        }
+    }
+    let beside = store().path().parent().unwrap_or(Path::new("."));
+    for entry in fs::read_dir(beside).into_iter().flatten().flatten() {
+        if entry.file_name().to_string_lossy().starts_with("recovery-") {
+            copies.push(entry.path());
+        }
+    }
+    Ok(copies)
+}
+
+/// Every file that holds saved chats: the history and all its copies.
+pub fn history_files() -> io::Result<Vec<PathBuf>> {
+    let path = store().path();
+    let mut files = match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+        Err(_) => vec![path.to_path_buf()],
+    };
+    files.extend(copies()?);
+    Ok(files)
+}
+
+/// The history passphrase, once entered.
+static CIPHER: OnceLock<FileCipher> = OnceLock::new();
+/// `[storage] encrypt`: files are written encrypted.
+static ENCRYPT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_cipher(cipher: FileCipher) {
+    let _ = CIPHER.set(cipher);
+}
+
+/// Whether history is written encrypted this session.
+pub fn encrypting() -> bool {
+    ENCRYPT.load(Ordering::Relaxed)
+}
+
+/// Whether the saved history is encrypted.
+pub fn history_encrypted() -> bool {
+    let mut header = [0u8; 64];
+    let read = File::open(store().marker()).and_then(|mut f| f.read(&mut header));
+    read.is_ok_and(|n| FileCipher::is_encrypted(&header[..n]))
+}
+
+/// Checks that `cipher` opens the saved history.
+pub fn check_cipher(cipher: &FileCipher) -> Result<(), String> {
+    let data = fs::read(store().marker()).map_err(|e| e.to_string())?;
+    cipher.decrypt(&data).map(|_| ())
+}
+
+/// File contents as written: encrypted when `encrypt` is on.
+fn seal(data: &[u8]) -> io::Result<Vec<u8>> {
+    if !encrypting() {
+        return Ok(data.to_vec());
+    }
+    let cipher = CIPHER
+        .get()
+        .ok_or_else(|| io::Error::other("No passphrase for the encrypted history"))?;
+    Ok(cipher.encrypt(data))
+}
+
+/// File contents as read, decrypted if needed.
+fn open_data(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if !FileCipher::is_encrypted(&data) {
+        return Ok(data);
+    }
+    let cipher = CIPHER.get().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The history is encrypted; set encrypt = true in [storage]",
+        )
+    })?;
+    cipher
+        .decrypt(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Reads a file written by `write_atomic`, decrypting it if needed.
+pub fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    open_data(fs::read(path)?)
+}
+
+fn store() -> &'static dyn Store {
+    STORE
+        .get_or_init(|| {
+            let path = data_dir()
+                .map(|dir| dir.join("history.json"))
+                .unwrap_or_else(|_| PathBuf::from("history.json"));
+            Box::new(JsonStore { path })
+        })
+        .as_ref()
+}
+
+pub fn get_history_path() -> Result<PathBuf, std::io::Error> {
+    let path = store().path();
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Set when the saved history exists but could not be read. Saves are held
+/// until `release_history`, so the damaged file is not replaced by
+/// whatever this session writes.
+static HELD: AtomicBool = AtomicBool::new(false);
+
+/// The saved chats; none if there is no history yet. Any other failure holds
+/// saves and is returned, so a damaged history is never taken for a fresh
+/// install.
+pub fn load_history() -> io::Result<Vec<Chat>> {
+    match store().load_index() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => {
+            HELD.store(true, Ordering::Relaxed);
+            Err(e)
+        }
+        chats => chats,
+    }
+}
+
+/// Whether saves are held because the history could not be read.
+pub fn history_held() -> bool {
+    HELD.load(Ordering::Relaxed)
+}
+
+/// Copies the unreadable history aside as `<name>.damaged-<time>` and lets
+/// saves replace it again. Returns where the copy is.
+pub fn release_history() -> io::Result<PathBuf> {
+    let history = get_history_path()?;
+    let mut name = history.file_name().unwrap_or_default().to_os_string();
+    name.push(Local::now().format(".damaged-%Y%m%d-%H%M%S").to_string());
+    let to = history.with_file_name(name);
+    if history.exists() {
+        store().backup(&to)?;
+    }
+    HELD.store(false, Ordering::Relaxed);
+    Ok(to)
+}
+
+/// Replaces a stub with the full chat, keeping what the index holds, which
+/// may be newer than the chat's file.
+pub fn load_chat(chat: &mut Chat) -> io::Result<()> {
+    if chat.stub.is_none() {
+        return Ok(());
+    }
+    let full = read_chat(&chat.id)?;
+    let stub = std::mem::replace(chat, full);
+    chat.title = stub.title;
+    chat.model = stub.model;
+    chat.read_only = stub.read_only;
+    chat.forked_from = stub.forked_from;
+    chat.draft = stub.draft;
+    Ok(())
+}
+
+/// Merges what other machines changed in the saved history into `chats`
+/// and returns how many chats changed.
+pub fn sync(chats: &mut Vec<Chat>) -> io::Result<usize> {
+    if history_held() {
+        return Ok(0);
+    }
+    store().sync(chats)
+}
+
+/// Starts downloading what other machines changed in a remote history;
+/// a `Transfer::Pull` on `transfers()` says when `sync` can merge it.
+pub fn fetch() {
+    if !history_held() {
+        store().fetch();
+    }
+}
+
+/// Where transfers to and from `[storage.remote]` report how they ended,
+/// if the history has a remote. Handed out once.
+pub fn transfers() -> Option<UnboundedReceiver<Transferred>> {
+    store().transfers()
+}
+
+/// Waits for uploads still running, e.g. before exiting.
+pub fn finish_transfers() {
+    store().finish();
+}
+
+/// A chat as saved, without touching the loaded history.
+pub fn read_chat(id: &str) -> io::Result<Chat> {
+    store().load_chat(id)
+}
+
+/// Writes the chats, unless the history is held because it could not be
+/// read. The search index is brought up to date on a best-effort basis.
+pub fn save_history(chats: &[Chat]) -> io::Result<()> {
+    if history_held() {
+        return Ok(());
+    }
+    get_history_path()?;
+    // A failed backup must not keep the history from being saved.
+    let _ = backup(false);
+    store().save(chats)?;
+    let _ = search::update(chats);
+    Ok(())
+}
+
+/// Commits the history to the git repository in its directory (`git` in
+/// `[storage]`); false if nothing changed since the last commit.
+pub fn commit_history(template: &str) -> Result<bool, String> {
+    let path = store().path();
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(format!("{} is not in a directory", path.display()));
+    };
+    git::commit(dir, &[&name.to_string_lossy(), "attachments"], template)
+}
+
+/// `backups` and `backup_minutes` from `[storage]`.
+static BACKUPS: OnceLock<(usize, u64)> = OnceLock::new();
+
+/// A copy of the saved history in `backups/`.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub time: NaiveDateTime,
+    pub bytes: u64,
+}
+
+const BACKUP_NAME: &str = "%Y%m%d-%H%M%S";
+
+fn backups_dir() -> PathBuf {
+    store().path().with_file_name("backups")
+}
+
+/// A deleted chat, kept in `trash/` until it is restored or expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trashed {
+    pub deleted_at: i64,
+    pub chat: Chat,
+}
+
+fn trash_dir() -> PathBuf {
+    store().path().with_file_name("trash")
+}
+
+fn trash_files() -> Vec<PathBuf> {
+    fs::read_dir(trash_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect()
+}
+
+/// Moves a deleted chat to the trash. The chat must be loaded.
+pub fn trash(chat: &Chat) -> io::Result<()> {
+    if chat.stub.is_some() {
+        return Err(io::Error::other("the chat is not loaded"));
+    }
+    let dir = trash_dir();
+    fs::create_dir_all(&dir)?;
+    let trashed = Trashed {
+        deleted_at: Local::now().timestamp(),
+        chat: chat.clone(),
+    };
+    write_json(&dir.join(file_name(&chat.id)), &trashed)
+}
+
+/// The chats in the trash, most recently deleted first. Unreadable files are
+/// left out.
+pub fn trashed() -> Vec<Trashed> {
+    let mut trashed: Vec<Trashed> = trash_files()
+        .iter()
+        .filter_map(|path| read_json(path).ok())
+        .collect();
+    trashed.sort_by_key(|t| std::cmp::Reverse(t.deleted_at));
+    trashed
+}
+
+/// Removes a chat from the trash, e.g. once it is restored.
+pub fn remove_from_trash(id: &str) -> io::Result<()> {
+    fs::remove_file(trash_dir().join(file_name(id)))
+}
+
+/// Removes the chats deleted more than `days` days ago from the trash,
+/// returning how many were removed.
+pub fn expire_trash(days: u64) -> usize {
+    let cutoff = Local::now().timestamp() - (days * 24 * 60 * 60) as i64;
+    let mut removed = 0;
+    for path in trash_files() {
+        let expired = read_json::<Trashed>(&path).is_ok_and(|t| t.deleted_at < cutoff);
+        if expired && fs::remove_file(&path).is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+fn session_path() -> PathBuf {
+    store().path().with_file_name("session.json")
+}
+
+/// The view saved at the last exit, if any could be read.
+pub fn load_session() -> Option<Session> {
+    read_json(&session_path()).ok()
+}
+
+pub fn save_session(session: &Session) -> io::Result<()> {
+    get_history_path()?;
+    write_json(&session_path(), session)
+}
+
+fn archives_dir() -> PathBuf {
+    store().path().with_file_name("archives")
+}
+
+/// Writes chats removed by `:prune`, `:large` or retention to a new file in
+/// `archives/` named after `kind` and the time, returning its path. An
+/// archive never replaces another, even one written in the same second.
+pub fn archive(kind: &str, chats: &[Chat]) -> io::Result<PathBuf> {
+    let dir = archives_dir();
+    fs::create_dir_all(&dir)?;
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    let mut n = 1;
+    loop {
+        let name = match n {
+            1 => format!("{}-{}.json", kind, stamp),
+            n => format!("{}-{}-{}.json", kind, stamp, n),
+        };
+        n += 1;
+        let path = dir.join(name);
+        // Claims the name; the contents then replace the empty file.
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+        if let Err(e) = write_json(&path, chats) {
+            let _ = fs::remove_file(&path);
+            return Err(e);
+        }
+        return Ok(path);
+    }
+}
+
+/// The chats in a file written by `archive`.
+pub fn read_archive(path: &Path) -> io::Result<Vec<Chat>> {
+    read_json(path)
+}
+
+/// The attachment store, next to the history so that it moves and syncs
+/// along with it.
+pub fn attachments_dir() -> PathBuf {
+    store().path().with_file_name("attachments")
+}
+
+/// Size of a file, or of the files in a directory.
+fn disk_size(path: &Path) -> u64 {
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|e| e.metadata().ok())
+            .map(|m| m.len())
+            .sum(),
+        Err(_) => fs::metadata(path).map_or(0, |m| m.len()),
+    }
+}
+
+/// The backups of the current backend, newest first.
+pub fn backups() -> io::Result<Vec<Backup>> {
+    let entries = match fs::read_dir(backups_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let history = store().path();
+    let mut backups: Vec<Backup> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == history.extension())
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let time = NaiveDateTime::parse_from_str(stem, BACKUP_NAME).ok()?;
+            let bytes = disk_size(&path);
+            Some(Backup { path, time, bytes })
+        })
+        .collect();
+    backups.sort_by_key(|b| std::cmp::Reverse(b.time));
+    Ok(backups)
+}
+
+/// Copies the saved history into `backups/`, unless `force` is off and the
+/// newest backup is younger than `backup_minutes`, then removes the oldest
+/// backups beyond `backups`.
+pub fn backup(force: bool) -> io::Result<()> {
+    let (keep, minutes) = BACKUPS.get().copied().unwrap_or((0, 0));
+    let history = store().path();
+    if keep == 0 || !history.exists() {
+        return Ok(());
+    }
+    let now = Local::now().naive_local();
+    let recent = backups()?
+        .first()
+        .is_some_and(|b| now - b.time < TimeDelta::minutes(minutes as i64));
+    if recent && !force {
+        return Ok(());
+    }
+    let mut name = now.format(BACKUP_NAME).to_string();
+    if let Some(extension) = history.extension().and_then(|e| e.to_str()) {
+        name = format!("{}.{}", name, extension);
+    }
+    let to = backups_dir().join(name);
+    // A save earlier in the same second backed up what it replaced.
+    if force && to.is_dir() {
+        fs::remove_dir_all(&to)?;
+    } else if force && to.exists() {
+        fs::remove_file(&to)?;
+    }
+    if !to.exists() {
+        fs::create_dir_all(backups_dir())?;
+        store().backup(&to)?;
+    }
+    for old in backups()?.into_iter().skip(keep) {
+        let _ = if old.path.is_dir() {
+            fs::remove_dir_all(&old.path)
+        } else {
+            fs::remove_file(&old.path)
+        };
+    }
+    Ok(())
+}
+
+pub fn load_backup(path: &Path) -> io::Result<Vec<Chat>> {
+    store().load_backup(path)
+}
+
+/// Writes `value` as JSON to a temporary file and moves it over `path`, so
+/// a failed write never leaves a truncated file behind.
+fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> io::Result<()> {
+    write_atomic(path, &serde_json::to_vec_pretty(value)?)
+}
+
+/// Writes `data` (encrypted when `encrypt` is on) through a temporary file,
+/// so a crash never leaves half a file behind.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    replace_file(path, &seal(data)?)
+}
+
+/// Writes `data` as it is through a temporary file.
+pub fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    if let Err(e) = fs::write(&tmp_path, data) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, path)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    serde_json::from_slice(&read_file(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+struct JsonStore {
+    path: PathBuf,
+}
+
+impl Store for JsonStore {
+    fn load(&self) -> io::Result<Vec<Chat>> {
+        read_json(&self.path)
+    }
+
+    fn save(&self, chats: &[Chat]) -> io::Result<()> {
+        write_json(&self.path, chats)
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load_backup(&self, path: &Path) -> io::Result<Vec<Chat>> {
+        read_json(path)
+    }
+}
+
+/// Chats as JSON documents in a SQLite table, in sidebar order.
+struct SqliteStore {
+    path: PathBuf,
+}
+
+impl SqliteStore {
+    fn open(&self) -> io::Result<Connection> {
+        let conn = Connection::open(&self.path).map_err(io::Error::other)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS chats (
+                id TEXT PRIMARY KEY,
+                position INTEGER NOT NULL,
+                data TEXT NOT NULL
+            )",
+        )
+        .map_err(io::Error::other)?;
+        Ok(conn)
+    }
+}
+
+impl Store for SqliteStore {
+    fn load(&self) -> io::Result<Vec<Chat>> {
+        if !self.path.exists() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let conn = self.open()?;
+        let mut statement = conn
+            .prepare("SELECT data FROM chats ORDER BY position")
+            .map_err(io::Error::other)?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(io::Error::other)?;
+        rows.map(|data| {
+            let data = data.map_err(io::Error::other)?;
+            serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+    }
+
+    fn save(&self, chats: &[Chat]) -> io::Result<()> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction().map_err(io::Error::other)?;
+        tx.execute("DELETE FROM chats", [])
+            .map_err(io::Error::other)?;
+        for (position, chat) in chats.iter().enumerate() {
+            let data = serde_json::to_string(chat)?;
+            tx.execute(
+                "INSERT INTO chats (id, position, data) VALUES (?1, ?2, ?3)",
+                params![chat.id, position as i64, data],
+            )
+            .map_err(io::Error::other)?;
+        }
+        tx.commit().map_err(io::Error::other)
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn load_backup(&self, path: &Path) -> io::Result<Vec<Chat>> {
+        SqliteStore {
+            path: path.to_path_buf(),
+        }
+        .load()
+    }
+}
+
+/// One `<id>.json` file per chat and an `index.json` listing the chats in
+/// sidebar order with enough metadata to show them without their messages.
+/// Only chats that changed since they were last read or written are saved.
+struct FilesStore {
+    dir: PathBuf,
+    /// Hash of each chat file's contents as last read or written.
+    written: Mutex<HashMap<String, u64>>,
+}
+
+/// Everything the sidebar and the chat picker show, so chats can be listed
+/// before their files are read.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    id: String,
+    title: String,
+    model: String,
+    /// Unix time of the chat's last message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<i64>,
+    /// Missing in indexes that only gave the order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    messages: Option<usize>,
+    /// Has bookmarked or pinned messages.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    protected: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    read_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    forked_from: Option<ForkOrigin>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    draft: String,
+}
+
+/// `index.json` used to be a plain list of ids, then entries without the
+/// metadata a stub needs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IndexItem {
+    Entry(IndexEntry),
+    Id(String),
+}
+
+impl IndexItem {
+    fn id(&self) -> &str {
+        match self {
+            IndexItem::Entry(entry) => &entry.id,
+            IndexItem::Id(id) => id,
+        }
+    }
+}
+
+/// `<id>.json`, with anything but letters, digits, `-` and `_` in the id
+/// turned into `_`.
+fn file_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
             } else {
-                let _ = fs::remove_file(&tmp_path);
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.json", name)
+}
+
+/// Key of `index.json` in `FilesStore::written`; no chat id can produce it.
+const INDEX_KEY: &str = "/index";
+
+fn content_hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl FilesStore {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            written: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The file of chat `id`; ids from imports may hold any character.
+    fn chat_path(&self, id: &str) -> PathBuf {
+        self.dir.join(file_name(id))
+    }
+
+    /// Reads `index.json`, remembering what it held.
+    fn read_index(&self, written: &mut HashMap<String, u64>) -> io::Result<Vec<IndexItem>> {
+        let data = read_file(&self.dir.join("index.json"))?;
+        written.insert(INDEX_KEY.to_string(), content_hash(&data));
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// A chat known only from its index entry.
+    fn stub(&self, entry: IndexEntry) -> Chat {
+        let bytes = fs::metadata(self.chat_path(&entry.id)).map_or(0, |m| m.len());
+        let mut chat = Chat::new(entry.id, entry.title, entry.model, Vec::new());
+        chat.read_only = entry.read_only;
+        chat.forked_from = entry.forked_from;
+        chat.draft = entry.draft;
+        chat.stub = Some(ChatStub {
+            updated_at: entry.updated_at,
+            messages: entry.messages.unwrap_or_default(),
+            protected: entry.protected,
+            bytes,
+            unreadable: false,
+        });
+        chat
+    }
+
+    /// A chat whose file could not be read or parsed, listed from its index
+    /// entry, so saves keep it in the index and a repaired file is read at the
+    /// next start.
+    fn unreadable(&self, item: IndexItem) -> Chat {
+        let entry = match item {
+            IndexItem::Entry(entry) => entry,
+            IndexItem::Id(id) => IndexEntry {
+                title: id.clone(),
+                id,
+                model: String::new(),
+                updated_at: None,
+                messages: None,
+                protected: false,
+                read_only: false,
+                forked_from: None,
+                draft: String::new(),
+            },
+        };
+        let mut chat = self.stub(entry);
+        if let Some(stub) = &mut chat.stub {
+            stub.unreadable = true;
+        }
+        chat
+    }
+
+    /// Whether the file at `path` is no longer what this store last read or
+    /// wrote there, because a sync tool brought in another machine's copy.
+    fn changed_on_disk(&self, path: &Path, known: Option<&u64>) -> bool {
+        match (known, read_file(path)) {
+            (Some(&known), Ok(data)) if known != 0 => content_hash(&data) != known,
+            _ => false,
+        }
+    }
+
+    /// Files sync tools leave when two machines changed the same file, e.g.
+    /// `c1.sync-conflict-20240101-120000-ABCDEFG.json` (Syncthing) or
+    /// `c1 (conflicted copy 2024-01-01).json` (Dropbox).
+    fn conflict_copies(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|path| {
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                name.ends_with(".json") && is_conflict_copy(name)
+            })
+            .collect()
+    }
+
+    /// Forgets what this store wrote to file `name`, so a chat still open
+    /// is written there again by the next save.
+    fn forget(&self, name: &str) {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        written.retain(|id, _| id == INDEX_KEY || file_name(id) != name);
+    }
+}
+
+fn is_conflict_copy(name: &str) -> bool {
+    name.contains(".sync-conflict-") || name.contains(" (conflicted copy")
+}
+
+impl Store for FilesStore {
+    fn load_index(&self) -> io::Result<Vec<Chat>> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        let index = self.read_index(&mut written)?;
+        let mut chats = Vec::with_capacity(index.len());
+        for item in index {
+            let entry = match item {
+                IndexItem::Entry(entry) if entry.messages.is_some() => entry,
+                // An index from before it carried metadata.
+                _ => {
+                    drop(written);
+                    return self.load();
+                }
+            };
+            let chat = self.stub(entry);
+            // Not read yet, but deleted along with the chat.
+            written.insert(chat.id.clone(), 0);
+            chats.push(chat);
+        }
+        Ok(chats)
+    }
+
+    fn load_chat(&self, id: &str) -> io::Result<Chat> {
+        let data = read_file(&self.chat_path(id))?;
+        let chat: Chat = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        written.insert(id.to_string(), content_hash(&data));
+        Ok(chat)
+    }
+
+    fn load(&self) -> io::Result<Vec<Chat>> {
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        let index = self.read_index(&mut written)?;
+        let mut chats = Vec::with_capacity(index.len());
+        for item in index {
+            // A damaged chat file costs that chat, not the whole history; the
+            // file is left in place for repair.
+            let read = read_file(&self.chat_path(item.id())).and_then(|data| {
+                let chat = serde_json::from_slice::<Chat>(&data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok((chat, data))
+            });
+            match read {
+                Ok((chat, data)) => {
+                    written.insert(chat.id.clone(), content_hash(&data));
+                    chats.push(chat);
+                }
+                Err(_) => chats.push(self.unreadable(item)),
+            }
+        }
+        Ok(chats)
+    }
+
+    fn save(&self, chats: &[Chat]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        for chat in chats.iter().filter(|c| c.stub.is_none()) {
+            let data = serde_json::to_vec_pretty(chat)?;
+            let hash = content_hash(&data);
+            let path = self.chat_path(&chat.id);
+            // Another machine's changes wait for `sync` to merge them.
+            if written.get(&chat.id) != Some(&hash)
+                && !self.changed_on_disk(&path, written.get(&chat.id))
+            {
+                write_atomic(&path, &data)?;
+                written.insert(chat.id.clone(), hash);
             }
         }
+        let index: Vec<IndexEntry> = chats
+            .iter()
+            .map(|c| IndexEntry {
+                id: c.id.clone(),
+                title: c.title.clone(),
+                model: c.model.clone(),
+                updated_at: c.last_activity(),
+                messages: Some(c.message_count()),
+                protected: c.has_kept_messages(),
+                read_only: c.read_only,
+                forked_from: c.forked_from.clone(),
+                draft: c.draft.clone(),
+            })
+            .collect();
+        // Chats another machine added stay listed until `sync` picks them up.
+        let mut index = index;
+        let index_path = self.dir.join("index.json");
+        if self.changed_on_disk(&index_path, written.get(INDEX_KEY)) {
+            let on_disk: Vec<IndexItem> = read_json(&index_path).unwrap_or_default();
+            index.extend(on_disk.into_iter().filter_map(|item| match item {
+                IndexItem::Entry(entry) if !written.contains_key(&entry.id) => Some(entry),
+                _ => None,
+            }));
+        }
+        let index_data = serde_json::to_vec_pretty(&index)?;
+        let index_hash = content_hash(&index_data);
+        if written.get(INDEX_KEY) != Some(&index_hash) {
+            write_atomic(&self.dir.join("index.json"), &index_data)?;
+            written.insert(INDEX_KEY.to_string(), index_hash);
+        }
+        // Files of deleted chats go only after the index stops listing them.
+        // Files this store never read, such as damaged ones, are kept.
+        let gone: Vec<String> = written
+            .keys()
+            .filter(|id| *id != INDEX_KEY && !chats.iter().any(|c| &c.id == *id))
+            .cloned()
+            .collect();
+        for id in gone {
+            let _ = fs::remove_file(self.chat_path(&id));
+            written.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Chat files are replaced, never rewritten in place, so hard links
+    /// make a full copy that costs no space until the chats change.
+    fn backup(&self, to: &Path) -> io::Result<()> {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(&self.dir)?.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let target = to.join(entry.file_name());
+            if fs::hard_link(&path, &target).is_err() {
+                fs::copy(&path, &target)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_backup(&self, path: &Path) -> io::Result<Vec<Chat>> {
+        FilesStore::new(path.to_path_buf()).load()
+    }
+
+    fn marker(&self) -> PathBuf {
+        self.dir.join("index.json")
+    }
+
+    fn sync(&self, chats: &mut Vec<Chat>) -> io::Result<usize> {
+        let mut changed = 0;
+        for path in self.conflict_copies() {
+            let Ok(data) = read_file(&path) else {
+                continue;
+            };
+            if let Ok(theirs) = serde_json::from_slice::<Chat>(&data) {
+                match chats.iter_mut().find(|c| c.id == theirs.id) {
+                    Some(chat) if chat.streaming => continue,
+                    Some(chat) => {
+                        load_chat(chat)?;
+                        chat.merge(theirs);
+                    }
+                    None => chats.push(theirs),
+                }
+                changed += 1;
+            }
+            // Conflicting indexes need nothing: chats missing from the
+            // index are found below.
+            fs::remove_file(&path)?;
+        }
+        let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+        for chat in chats.iter_mut() {
+            if chat.stub.is_some() || chat.streaming {
+                continue;
+            }
+            let Some(&known) = written.get(&chat.id) else {
+                continue;
+            };
+            let Ok(data) = read_file(&self.chat_path(&chat.id)) else {
+                continue;
+            };
+            let hash = content_hash(&data);
+            if hash == known {
+                continue;
+            }
+            if let Ok(theirs) = serde_json::from_slice::<Chat>(&data) {
+                chat.merge(theirs);
+                written.insert(chat.id.clone(), hash);
+                changed += 1;
+            }
+        }
+        let index = self.read_index(&mut written).unwrap_or_default();
+        for item in index {
+            if written.contains_key(item.id()) || chats.iter().any(|c| c.id == item.id()) {
+                continue;
+            }
+            let chat = match item {
+                IndexItem::Entry(entry) if entry.messages.is_some() => self.stub(entry),
+                _ => match read_file(&self.chat_path(item.id()))
+                    .and_then(|data| serde_json::from_slice(&data).map_err(io::Error::other))
+                {
+                    Ok(chat) => chat,
+                    Err(_) => continue,
+                },
+            };
+            written.insert(chat.id.clone(), 0);
+            chats.push(chat);
+            changed += 1;
+        }
+        Ok(changed)
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        let chats = self.load()?;
+        self.written
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.save(&chats)
+    }
+}
+
+/// What a file held when it was last pulled from or pushed to the remote.
+#[derive(Serialize, Deserialize)]
+struct Mirrored {
+    /// The remote's version tag.
+    version: String,
+    hash: u64,
+}
+
+/// A transfer between the chats directory and `[storage.remote]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// Downloading what other machines changed.
+    Pull,
+    /// Uploading what changed here.
+    Push,
+}
+
+/// How a background transfer ended.
+pub type Transferred = (Transfer, Result<(), String>);
+
+/// What the transfer thread is asked to do.
+enum Request {
+    Run(Transfer),
+    /// Answer once everything asked for before is done.
+    Wait(mpsc::Sender<()>),
+}
+
+/// The `files` backend with a copy on a server (`[storage.remote]`). The
+/// chats directory stays the working copy; the server is kept in step with
+/// it the way a sync tool would: `fetch` downloads what other machines
+/// changed, a chat changed on both sides arriving as a conflict copy to
+/// merge, and every save uploads the files it changed. Transfers run on a
+/// thread of their own so a slow server never holds up the UI.
+struct RemoteStore {
+    mirror: Arc<Mirror>,
+    requests: Mutex<mpsc::Sender<Request>>,
+    transfers: Mutex<Option<UnboundedReceiver<Transferred>>>,
+}
+
+/// The chats directory and the server it is mirrored to.
+struct Mirror {
+    files: FilesStore,
+    remote: Box<dyn Remote>,
+    /// Each mirrored file's state as of the last transfer, kept in
+    /// `remote.json` next to the chats directory.
+    mirrored: Mutex<HashMap<String, Mirrored>>,
+    /// Chat files another machine deleted this session.
+    deleted: Mutex<HashSet<String>>,
+    /// Held while the chats directory is written, so a pull never replaces
+    /// a file a save is writing.
+    writing: Mutex<()>,
+}
+
+impl RemoteStore {
+    /// Starts the transfer thread and a first download; chats it brings in
+    /// are merged by `sync` when it is reported.
+    fn new(files: FilesStore, remote: Box<dyn Remote>) -> Self {
+        let state_path = files.dir.with_file_name("remote.json");
+        // Plain JSON: it is read before the passphrase is known.
+        let mirrored = fs::read(&state_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        let mirror = Arc::new(Mirror {
+            files,
+            remote,
+            mirrored: Mutex::new(mirrored),
+            deleted: Mutex::new(HashSet::new()),
+            writing: Mutex::new(()),
+        });
+        let (requests, queue) = mpsc::channel();
+        let (report, transfers) = unbounded_channel();
+        let runtime = tokio::runtime::Handle::try_current().ok();
+        let worker = Arc::clone(&mirror);
+        std::thread::spawn(move || {
+            // Requests reuse the app's runtime when there is one.
+            let _entered = runtime.as_ref().map(|r| r.enter());
+            worker.serve(queue, report);
+        });
+        let _ = requests.send(Request::Run(Transfer::Pull));
+        Self {
+            mirror,
+            requests: Mutex::new(requests),
+            transfers: Mutex::new(Some(transfers)),
+        }
+    }
+
+    fn request(&self, request: Request) {
+        let _ = self
+            .requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .send(request);
+    }
+}
+
+impl Mirror {
+    /// Runs the transfers asked for until the store is gone. Requests that
+    /// piled up during a slow transfer are run once.
+    fn serve(&self, queue: mpsc::Receiver<Request>, report: UnboundedSender<Transferred>) {
+        while let Ok(first) = queue.recv() {
+            let mut batch = vec![first];
+            batch.extend(queue.try_iter());
+            for transfer in [Transfer::Pull, Transfer::Push] {
+                if !batch
+                    .iter()
+                    .any(|r| matches!(r, Request::Run(t) if *t == transfer))
+                {
+                    continue;
+                }
+                let result = match transfer {
+                    Transfer::Pull => self.pull(),
+                    Transfer::Push => self.push(),
+                };
+                let _ = report.send((transfer, result.map_err(|e| e.to_string())));
+            }
+            for request in batch {
+                if let Request::Wait(done) = request {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.files.dir.with_file_name("remote.json")
+    }
+
+    /// The files that are mirrored: `index.json` and the chat files.
+    fn local_files(&self) -> HashMap<String, PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.files.dir) else {
+            return HashMap::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                mirrored_name(&name).then(|| (name, entry.path()))
+            })
+            .collect()
+    }
+
+    /// Brings in what changed on the remote since the last transfer. Files
+    /// changed here as well are left alone and the remote's copy is saved
+    /// as a conflict copy, except the index, whose entries are combined.
+    fn pull(&self) -> io::Result<()> {
+        let listing = self.remote.list()?;
+        let mut mirrored = self.mirrored.lock().unwrap_or_else(|e| e.into_inner());
+        // Everything is downloaded before the directory is touched, so saves
+        // wait for disk writes only.
+        let mut fetched = Vec::new();
+        for (name, version) in &listing {
+            if !mirrored_name(name) || mirrored.get(name).is_some_and(|m| &m.version == version) {
+                continue;
+            }
+            fetched.push((name, version, self.remote.get(name)?));
+        }
+        let _writing = self.writing.lock().unwrap_or_else(|e| e.into_inner());
+        fs::create_dir_all(&self.files.dir)?;
+        for (name, version, data) in fetched {
+            let hash = content_hash(&data);
+            let path = self.files.dir.join(name);
+            let local = fs::read(&path).ok();
+            let local_hash = local.as_deref().map(content_hash);
+            if local_hash == Some(hash) {
+                // Already the same here.
+            } else if local_hash.is_none() || local_hash == mirrored.get(name).map(|m| m.hash) {
+                replace_file(&path, &data)?;
+            } else if name == "index.json" {
+                match merge_indexes(&data, &local.unwrap_or_default()) {
+                    Some(merged) => write_atomic(&path, &merged)?,
+                    // Encrypted and no passphrase yet: try again next time.
+                    None => continue,
+                }
+            } else {
+                let stem = name.trim_end_matches(".json");
+                let copy = format!(
+                    "{}.sync-conflict-{}-remote.json",
+                    stem,
+                    Local::now().format("%Y%m%d-%H%M%S")
+                );
+                replace_file(&self.files.dir.join(copy), &data)?;
+            }
+            mirrored.insert(
+                name.clone(),
+                Mirrored {
+                    version: version.clone(),
+                    hash,
+                },
+            );
+        }
+        // Deleted on another machine: delete here too, unless changed here
+        // since, in which case the next push uploads it again.
+        let gone: Vec<String> = mirrored
+            .keys()
+            .filter(|name| !listing.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in gone {
+            let path = self.files.dir.join(&name);
+            let unchanged = fs::read(&path)
+                .is_ok_and(|data| Some(content_hash(&data)) == mirrored.get(&name).map(|m| m.hash));
+            if unchanged && name != "index.json" {
+                fs::remove_file(&path)?;
+                self.files.forget(&name);
+                self.deleted
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(name.clone());
+            }
+            mirrored.remove(&name);
+        }
+        self.save_state(&mirrored)
+    }
+
+    /// Uploads the files changed here since the last transfer and deletes
+    /// the ones deleted here. A file another machine changed first is
+    /// skipped; the next `pull` brings in its copy to merge.
+    fn push(&self) -> io::Result<()> {
+        let local = self.local_files();
+        let mut mirrored = self.mirrored.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, path) in &local {
+            let data = fs::read(path)?;
+            let hash = content_hash(&data);
+            let known = mirrored.get(name);
+            if known.is_some_and(|m| m.hash == hash) {
+                continue;
+            }
+            let current = known.map(|m| m.version.as_str());
+            if let Some(version) = self.remote.put(name, data, current)? {
+                mirrored.insert(name.clone(), Mirrored { version, hash });
+            }
+        }
+        let gone: Vec<String> = mirrored
+            .keys()
+            .filter(|name| !local.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in gone {
+            self.remote.delete(&name)?;
+            mirrored.remove(&name);
+        }
+        self.save_state(&mirrored)
+    }
+
+    fn save_state(&self, mirrored: &HashMap<String, Mirrored>) -> io::Result<()> {
+        replace_file(&self.state_path(), &serde_json::to_vec(mirrored)?)
+    }
+}
+
+/// Whether file `name` in the chats directory is copied to the remote.
+fn mirrored_name(name: &str) -> bool {
+    name.ends_with(".json") && !is_conflict_copy(name)
+}
+
+/// The remote's index with the entries only the local one has appended,
+/// or `None` if either cannot be read.
+fn merge_indexes(remote: &[u8], local: &[u8]) -> Option<Vec<u8>> {
+    let read = |data: &[u8]| -> Option<Vec<serde_json::Value>> {
+        serde_json::from_slice(&open_data(data.to_vec()).ok()?).ok()
+    };
+    let id = |item: &serde_json::Value| {
+        item.as_str()
+            .or_else(|| item.get("id").and_then(|id| id.as_str()))
+            .map(str::to_string)
+    };
+    let mut merged = read(remote)?;
+    let local = read(local)?;
+    for item in local {
+        if !merged.iter().any(|m| id(m) == id(&item)) {
+            merged.push(item);
+        }
+    }
+    serde_json::to_vec_pretty(&merged).ok()
+}
+
+impl Store for RemoteStore {
+    fn load(&self) -> io::Result<Vec<Chat>> {
+        self.mirror.files.load()
+    }
+
+    fn load_index(&self) -> io::Result<Vec<Chat>> {
+        self.mirror.files.load_index()
+    }
+
+    fn load_chat(&self, id: &str) -> io::Result<Chat> {
+        self.mirror.files.load_chat(id)
+    }
+
+    /// Saves locally and leaves the upload to the transfer thread; an
+    /// unreachable remote only delays it.
+    fn save(&self, chats: &[Chat]) -> io::Result<()> {
+        let mirror = &self.mirror;
+        let writing = mirror.writing.lock().unwrap_or_else(|e| e.into_inner());
+        // A chat another machine deleted is not listed again just because
+        // it is still in the sidebar here, unread.
+        let deleted = mirror.deleted.lock().unwrap_or_else(|e| e.into_inner());
+        let gone = |c: &Chat| c.stub.is_some() && deleted.contains(&file_name(&c.id));
+        if chats.iter().any(gone) {
+            let kept: Vec<Chat> = chats.iter().filter(|c| !gone(c)).cloned().collect();
+            drop(deleted);
+            mirror.files.save(&kept)?;
+        } else {
+            drop(deleted);
+            mirror.files.save(chats)?;
+        }
+        drop(writing);
+        self.request(Request::Run(Transfer::Push));
+        Ok(())
+    }
+
+    fn path(&self) -> &Path {
+        self.mirror.files.path()
+    }
+
+    fn backup(&self, to: &Path) -> io::Result<()> {
+        self.mirror.files.backup(to)
+    }
+
+    fn load_backup(&self, path: &Path) -> io::Result<Vec<Chat>> {
+        self.mirror.files.load_backup(path)
+    }
+
+    fn marker(&self) -> PathBuf {
+        self.mirror.files.marker()
+    }
+
+    /// Merges what the last pull brought in; does not wait for the server.
+    fn sync(&self, chats: &mut Vec<Chat>) -> io::Result<usize> {
+        let _writing = self
+            .mirror
+            .writing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        self.mirror.files.sync(chats)
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        let writing = self
+            .mirror
+            .writing
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        self.mirror.files.rewrite()?;
+        drop(writing);
+        self.request(Request::Run(Transfer::Push));
+        Ok(())
+    }
+
+    fn fetch(&self) {
+        self.request(Request::Run(Transfer::Pull));
+    }
+
+    fn transfers(&self) -> Option<UnboundedReceiver<Transferred>> {
+        self.transfers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+
+    fn finish(&self) {
+        let (done, wait) = mpsc::channel();
+        self.request(Request::Wait(done));
+        let _ = wait.recv();
+    }
+}
+
+/// Writes the chats to a timestamped recovery file next to the history
+/// and returns its path. Chats never loaded are still safe on disk and are
+/// left out.
+pub fn save_recovery(chats: &[Chat]) -> Result<PathBuf, std::io::Error> {
+    let chats: Vec<&Chat> = chats.iter().filter(|c| c.stub.is_none()).collect();
+    let history = get_history_path()?;
+    let name = format!(
+        "recovery-{}.json",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    );
+    let path = history.with_file_name(name);
+    write_json(&path, &chats)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{Message, Role};
+
+    /// An empty directory for one test, removed when it ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("meowi-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn chat(id: &str, messages: &[&str]) -> Chat {
+        let messages = messages
+            .iter()
+            .map(|&text| Message::new(Role::User, text))
+            .collect();
+        Chat::new(id.into(), format!("Chat {}", id), "gpt-4o".into(), messages)
+    }
+
+    fn contents(chats: &[Chat]) -> Vec<(String, Vec<String>)> {
+        chats
+            .iter()
+            .map(|c| {
+                let messages = c.messages.iter().map(|m| m.content.clone()).collect();
+                (c.id.clone(), messages)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn files_store_round_trip() {
+        let dir = TempDir::new("round-trip");
+        let chats = vec![chat("c1", &["hi", "there"]), chat("c/2", &["odd id"])];
+        FilesStore::new(dir.0.join("chats")).save(&chats).unwrap();
+        assert!(dir.0.join("chats/c_2.json").exists());
+
+        let store = FilesStore::new(dir.0.join("chats"));
+        assert_eq!(contents(&store.load().unwrap()), contents(&chats));
+
+        // The index alone lists the chats, in order, without their messages.
+        let stubs = store.load_index().unwrap();
+        assert_eq!(stubs[0].id, "c1");
+        assert_eq!(stubs[0].stub.as_ref().map(|s| s.messages), Some(2));
+        assert!(stubs[1].messages.is_empty());
+        assert_eq!(
+            contents(&[store.load_chat("c/2").unwrap()]),
+            contents(&chats[1..])
+        );
+    }
+
+    #[test]
+    fn files_store_writes_only_changed_chats() {
+        let dir = TempDir::new("dirty");
+        let store = FilesStore::new(dir.0.clone());
+        let mut chats = vec![chat("c1", &["a"]), chat("c2", &["b"])];
+        store.save(&chats).unwrap();
+
+        // Removed behind the store's back: an unchanged chat is not written
+        // again, so it stays gone.
+        fs::remove_file(dir.0.join("c2.json")).unwrap();
+        chats[0].messages.push(Message::new(Role::User, "more"));
+        store.save(&chats).unwrap();
+        assert!(!dir.0.join("c2.json").exists());
+        let saved: Chat = read_json(&dir.0.join("c1.json")).unwrap();
+        assert_eq!(saved.messages.len(), 2);
+
+        // A deleted chat's file goes once the index no longer lists it.
+        chats.remove(0);
+        store.save(&chats).unwrap();
+        assert!(!dir.0.join("c1.json").exists());
+    }
+
+    #[test]
+    fn files_store_keeps_unreadable_chats_in_the_index() {
+        let dir = TempDir::new("unreadable");
+        let store = FilesStore::new(dir.0.clone());
+        store
+            .save(&[chat("c1", &["a"]), chat("c2", &["b"])])
+            .unwrap();
+        fs::write(dir.0.join("c2.json"), "{ damaged").unwrap();
+
+        let chats = store.load().unwrap();
+        assert_eq!(chats.len(), 2);
+        assert!(chats[1].stub.as_ref().is_some_and(|s| s.unreadable));
+
+        // Saving leaves the damaged file, and its index entry, for repair.
+        store.save(&chats).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.0.join("c2.json")).unwrap(),
+            "{ damaged"
+        );
+        let stubs = store.load_index().unwrap();
+        assert_eq!(
+            stubs.iter().map(|c| c.id.as_str()).collect::<Vec<_>>(),
+            ["c1", "c2"]
+        );
+    }
+
+    #[test]
+    fn files_store_merges_instead_of_overwriting_other_machines() {
+        let dir = TempDir::new("sync");
+        let store = FilesStore::new(dir.0.clone());
+        let mut chats = vec![chat("c1", &["a"])];
+        store.save(&chats).unwrap();
+
+        // A sync tool brings in another machine's copy of the chat.
+        let mut theirs = chats[0].clone();
+        theirs
+            .messages
+            .push(Message::new(Role::User, "from elsewhere"));
+        write_json(&dir.0.join("c1.json"), &theirs).unwrap();
+
+        chats[0]
+            .messages
+            .push(Message::new(Role::User, "from here"));
+        store.save(&chats).unwrap();
+        let on_disk: Chat = read_json(&dir.0.join("c1.json")).unwrap();
+        assert_eq!(contents(&[on_disk]), contents(&[theirs]));
+
+        assert_eq!(store.sync(&mut chats).unwrap(), 1);
+        let mut merged: Vec<String> = chats[0]
+            .messages
+            .iter()
+            .map(|m| m.content.clone())
+            .collect();
+        merged.sort();
+        assert_eq!(merged, ["a", "from elsewhere", "from here"]);
+    }
+
+    #[test]
+    fn merged_index_keeps_remote_order_and_adds_local_entries() {
+        let remote = br#"[{"id":"a"},{"id":"b"}]"#;
+        let local = br#"[{"id":"b"},"c"]"#;
+        let merged: Vec<serde_json::Value> =
+            serde_json::from_slice(&merge_indexes(remote, local).unwrap()).unwrap();
+        let ids: Vec<&str> = merged
+            .iter()
+            .map(|item| item.as_str().or_else(|| item["id"].as_str()).unwrap())
+            .collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert!(merge_indexes(b"not json", local).is_none());
     }
 }
@@ -1,6 +1,13 @@
-use crate::app::{App, CustomModelStage, Mode, SettingsTab};
+use crate::app::{
+    App, CachedMessage, CustomModelStage, DisplayLine, GeneralSetting, LineKind, Message, Mode,
+    PendingAction, SettingsTab, size_label,
+};
 use crate::config;
-use crate::config::CustomModel;
+use crate::config::{ColorMode, SidebarPosition};
+use crate::config::{CustomModel, MessageLayout};
+use crate::palette;
+use crate::stats::{Period, Totals};
+use chrono::{Local, TimeZone};
 use ratatui::prelude::Alignment;
 use ratatui::prelude::Margin;
 use ratatui::widgets::ListState;
@@ -12,50 +19,235 @@ use ratatui::{
     style::{Color, Modifier, Style},
     text::{Line, Span},
     widgets::{
-        Block, Borders, List, ListItem, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState,
-        Tabs,
+        Block, Borders, Cell, Clear, List, ListItem, Paragraph, Row, Scrollbar,
+        ScrollbarOrientation, ScrollbarState, Sparkline, Table, Tabs,
     },
 };
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::OnceLock;
 use syntect::easy::HighlightLines;
 use syntect::highlighting::{Theme, ThemeSet};
 use syntect::parsing::SyntaxSet;
 use textwrap::wrap;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 pub fn draw(f: &mut Frame<'_>, app: &mut App) {
-    let chunks = Layout::default()
+    let sidebar_width = if app.sidebar_visible {
+        app.ui_settings.sidebar_width
+    } else {
+        0
+    };
+    let sidebar_right = app.ui_settings.sidebar == SidebarPosition::Right;
+    let mut constraints = [Constraint::Length(sidebar_width), Constraint::Min(0)];
+    if sidebar_right {
+        constraints.reverse();
+    }
+    let mut chunks = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints(if app.sidebar_visible {
-            [Constraint::Length(20), Constraint::Min(0)].as_ref()
-        } else {
-            [Constraint::Length(0), Constraint::Min(0)].as_ref()
-        })
-        .split(f.area());
+        .constraints(constraints)
+        .split(f.area())
+        .to_vec();
+    // Keep `chunks[0]` the sidebar and `chunks[1]` the main view.
+    if sidebar_right {
+        chunks.reverse();
+    }
 
     if app.sidebar_visible {
         draw_sidebar(f, app, chunks[0]);
     }
 
     match app.mode {
-        Mode::Settings | Mode::ApiKeyInput | Mode::CustomModelInput | Mode::PromptInput => {
-            draw_settings(f, app, chunks[1])
-        }
+        Mode::Settings
+        | Mode::ApiKeyInput
+        | Mode::CustomModelInput
+        | Mode::PromptInput
+        | Mode::PromptRename
+        | Mode::PromptPreview
+        | Mode::KeyCapture => draw_settings(f, app, chunks[1]),
         Mode::ModelSelect => draw_model_select(f, app, chunks[1]),
+        Mode::Compare => draw_compare(f, app, chunks[1]),
         _ => draw_chat(f, app, chunks[1]),
     }
+    if app.mode == Mode::ChatPicker {
+        draw_chat_picker(f, app, chunks[1]);
+    }
+    if app.mode == Mode::Bookmarks {
+        draw_bookmarks(f, app, chunks[1]);
+    }
+    if app.mode == Mode::Search {
+        draw_search(f, app, chunks[1]);
+    }
+    if app.mode == Mode::Restore {
+        draw_backups(f, app, chunks[1]);
+    }
+    if app.mode == Mode::Trash {
+        draw_trash(f, app, chunks[1]);
+    }
+    if app.mode == Mode::Prune {
+        draw_prune(f, app, chunks[1]);
+    }
+    if app.mode == Mode::Stats {
+        draw_stats(f, app, chunks[1]);
+    }
+    if app.mode == Mode::LargeChat
+        && let Some(chat) = app.chats.get(app.current_chat)
+    {
+        let size = app.large_chat(app.current_chat).map_or_else(
+            || format!("\"{}\" is within the [large_chats] limits.", chat.title),
+            |reason| format!("\"{}\" is large: {}.", chat.title, reason),
+        );
+        let lines = vec![
+            Line::from(size),
+            Line::raw(""),
+            Line::from("s  Summarize it into a new chat"),
+            Line::from(format!(
+                "p  Split all but the latest {} messages off into \"{} (earlier)\"",
+                app.large_chats.keep.max(1),
+                chat.title
+            )),
+            Line::from("a  Archive it to archives/ and remove it from the history"),
+            Line::raw(""),
+            Line::from("Esc  Cancel"),
+        ];
+        draw_confirmation(f, "Large chat", lines, chunks[1]);
+    }
+    if app.mode == Mode::Notes
+        && let Some(chat) = app.chats.get(app.current_chat)
+    {
+        let popup = centered_rect(80, 60, chunks[1]);
+        let paragraph = Paragraph::new(chat.notes.as_str())
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("Notes · {} (j/k scroll, Esc close)", chat.title))
+                    .padding(Padding::horizontal(1)),
+            )
+            .wrap(Wrap { trim: false })
+            .scroll((app.notes_scroll, 0));
+        f.render_widget(Clear, popup);
+        f.render_widget(paragraph, popup);
+    }
+
+    if app.mode == Mode::Confirm {
+        match &app.pending_action {
+            Some(PendingAction::RunBlock(argv)) => draw_run_confirmation(f, argv, chunks[1]),
+            Some(PendingAction::ApplyPatch { diff, .. }) => {
+                draw_patch_confirmation(f, diff, chunks[1])
+            }
+            Some(PendingAction::ReleaseHistory(error)) => draw_history_warning(f, error, chunks[1]),
+            _ => {}
+        }
+    }
+
+    palette::recolor(f.buffer_mut(), &app.theme);
+    palette::apply(f.buffer_mut(), app.ui_settings.colors.effective());
+    if app.ui_settings.ascii {
+        palette::ascii(f.buffer_mut());
+    }
+}
+
+/// Shows the exact command a code block would run with.
+fn draw_run_confirmation(f: &mut Frame<'_>, argv: &[String], area: Rect) {
+    // Quoted the way a shell would need it, so it can be run by hand too.
+    let quoted: Vec<String> = argv
+        .iter()
+        .map(|arg| {
+            if !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+            {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    let command = format!("$ {}", quoted.join(" "));
+    let lines: Vec<Line> = command
+        .lines()
+        .map(|line| {
+            Line::styled(
+                line.to_string(),
+                Style::default().add_modifier(Modifier::BOLD),
+            )
+        })
+        .collect();
+    draw_confirmation(f, "Run code block? (y/n)", lines, area);
+}
+
+/// Explains that the history could not be read and what `y` does about it.
+fn draw_history_warning(f: &mut Frame<'_>, error: &str, area: Rect) {
+    let path = crate::storage::get_history_path()
+        .map(|p| p.display().to_string())
+        .unwrap_or_default();
+    let warning = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+    let lines = vec![
+        Line::styled(format!("{} exists but could not be read:", path), warning),
+        Line::styled(error.to_string(), warning),
+        Line::raw(""),
+        Line::raw("Nothing is saved over it until you allow it, so it can still be repaired."),
+        Line::raw(""),
+        Line::raw("y  copy it aside (.damaged-<time>) and save this session's chats"),
+        Line::raw("n  leave it untouched; chats written now are lost when meowi exits"),
+        Line::raw(""),
+        Line::raw("Or quit, fix the file and start again, or pick a backup with :restore."),
+    ];
+    draw_confirmation(f, "Chat history unreadable (y/n)", lines, area);
+}
+
+/// Lists the files a patch would change, with its added/removed line counts.
+fn draw_patch_confirmation(f: &mut Frame<'_>, diff: &str, area: Rect) {
+    let mut lines = vec![
+        Line::raw("The dry run passed. Files to change:"),
+        Line::raw(""),
+    ];
+    for file in crate::patch::changed_files(diff) {
+        lines.push(Line::from(vec![
+            Span::styled(
+                format!("+{:<5}", file.added),
+                Style::default().fg(Color::Green),
+            ),
+            Span::styled(
+                format!("-{:<5}", file.removed),
+                Style::default().fg(Color::Red),
+            ),
+            Span::raw(file.path),
+        ]));
+    }
+    draw_confirmation(f, "Apply patch? (y/n)", lines, area);
+}
+
+fn draw_confirmation(f: &mut Frame<'_>, title: &str, lines: Vec<Line>, area: Rect) {
+    let popup = centered_rect(80, 60, area);
+    let paragraph = Paragraph::new(lines)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(title)
+                .padding(Padding::horizontal(1)),
+        )
+        .wrap(Wrap { trim: false });
+    f.render_widget(Clear, popup);
+    f.render_widget(paragraph, popup);
 }
 
 static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
-static THEME: OnceLock<Theme> = OnceLock::new();
+static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
 
-fn get_syntax_set() -> &'static SyntaxSet {
-    SYNTAX_SET.get_or_init(|| SyntaxSet::load_defaults_newlines())
+pub fn get_syntax_set() -> &'static SyntaxSet {
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
 }
-fn get_theme() -> &'static Theme {
-    THEME.get_or_init(|| {
-        let ts = ThemeSet::load_defaults();
-        ts.themes["base16-ocean.dark"].clone()
-    })
+pub fn theme_set() -> &'static ThemeSet {
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+pub fn get_theme(name: &str) -> &'static Theme {
+    let themes = &theme_set().themes;
+    themes
+        .get(name)
+        .unwrap_or_else(|| &themes[config::DEFAULT_THEME])
 }
 
 fn draw_sidebar(f: &mut Frame<'_>, app: &App, area: Rect) {
@@ -86,10 +278,21 @@ fn draw_sidebar(f: &mut Frame<'_>, app: &App, area: Rect) {
         .chats
         .iter()
         .map(|chat| {
+            let fork_mark = if chat.forked_from.is_some() {
+                "↳ "
+            } else {
+                ""
+            };
+            let draft_mark = if chat.draft.is_empty() { "" } else { " ✎" };
+            let fork_mark = if chat.read_only {
+                format!("🔒 {}", fork_mark)
+            } else {
+                fork_mark.to_string()
+            };
             let title = if chat.streaming {
-                format!("{} ⏳", chat.title)
+                format!("{}{}{} ⏳", fork_mark, chat.title, draft_mark)
             } else {
-                chat.title.clone()
+                format!("{}{}{}", fork_mark, chat.title, draft_mark)
             };
             ListItem::new(title)
         })
@@ -127,7 +330,7 @@ fn draw_sidebar(f: &mut Frame<'_>, app: &App, area: Rect) {
 }
 
 #[derive(Debug)]
-enum MessageSegment {
+pub enum MessageSegment {
     Text(String),
     Code {
         language: Option<String>,
@@ -135,7 +338,20 @@ enum MessageSegment {
     },
 }
 
-fn parse_message_segments(content: &str) -> Vec<MessageSegment> {
+/// A message as it reads on screen: prose as-is, code blocks without their
+/// fences.
+pub fn plain_message_text(content: &str) -> String {
+    parse_message_segments(content)
+        .into_iter()
+        .map(|segment| match segment {
+            MessageSegment::Text(text) => text,
+            MessageSegment::Code { content, .. } => content,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub fn parse_message_segments(content: &str) -> Vec<MessageSegment> {
     let mut segments = Vec::new();
     let mut lines = content.lines().peekable();
     let mut current_text = Vec::new();
@@ -152,22 +368,21 @@ fn parse_message_segments(content: &str) -> Vec<MessageSegment> {
                 None
             };
             let mut code_lines = Vec::new();
-            while let Some(code_line) = lines.next() {
+            for code_line in lines.by_ref() {
                 if code_line.trim() == "```" {
                     break;
                 }
                 code_lines.push(code_line);
             }
-            let code_content =
-                if let (Some(ref l), Some(first)) = (lang.as_ref(), code_lines.first()) {
-                    if first.trim().eq_ignore_ascii_case(l.trim()) {
-                        code_lines[1..].join("\n")
-                    } else {
-                        code_lines.join("\n")
-                    }
+            let code_content = if let (Some(l), Some(first)) = (lang.as_ref(), code_lines.first()) {
+                if first.trim().eq_ignore_ascii_case(l.trim()) {
+                    code_lines[1..].join("\n")
                 } else {
                     code_lines.join("\n")
-                };
+                }
+            } else {
+                code_lines.join("\n")
+            };
             segments.push(MessageSegment::Code {
                 language: lang,
                 content: code_content,
@@ -182,26 +397,172 @@ fn parse_message_segments(content: &str) -> Vec<MessageSegment> {
     segments
 }
 
-fn draw_chat(f: &mut Frame<'_>, app: &mut App, area: Rect) {
-    const MAX_VISIBLE_LINES_PER_MESSAGE: usize = 10;
+/// Splits styled text into rows of at most `width` terminal columns.
+fn split_by_width(segments: &[(Style, &str)], width: usize) -> Vec<Vec<Span<'static>>> {
+    let mut rows = vec![Vec::new()];
+    let mut used = 0;
+    for (style, text) in segments {
+        let mut current = String::new();
+        for c in text.chars() {
+            let w = c.width().unwrap_or(0);
+            if used + w > width && used > 0 {
+                if !current.is_empty() {
+                    rows.last_mut()
+                        .unwrap()
+                        .push(Span::styled(std::mem::take(&mut current), *style));
+                }
+                rows.push(Vec::new());
+                used = 0;
+            }
+            current.push(c);
+            used += w;
+        }
+        if !current.is_empty() {
+            rows.last_mut().unwrap().push(Span::styled(current, *style));
+        }
+    }
+    rows
+}
 
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([Constraint::Min(1), Constraint::Length(3)])
-        .split(area);
+/// Renders one wrapped line of a message as part of a padded "bubble":
+/// user messages hug the right edge, assistant messages the left one.
+fn bubble_line(text: &str, is_user: bool, bubble_width: usize, style: Style) -> Line<'static> {
+    let padding = bubble_width.saturating_sub(text.width());
+    let content = format!(" {}{} ", text, " ".repeat(padding));
+    let bg = if is_user {
+        Color::Indexed(237)
+    } else {
+        Color::Indexed(235)
+    };
+    let line = Line::from(Span::styled(content, style.bg(bg)));
+    if is_user {
+        line.alignment(Alignment::Right)
+    } else {
+        line
+    }
+}
 
-    if app.cursor_line == usize::MAX {
-        app.jump_to_last_message();
+fn message_header(
+    message: &Message,
+    chat_model: &str,
+    align_right: bool,
+    time_format: Option<&str>,
+) -> Line<'static> {
+    let is_user = message.role == "user";
+    let author = if is_user {
+        "You".to_string()
+    } else {
+        // Replies saved before models were recorded fall back to the chat's.
+        let model = message
+            .model
+            .as_deref()
+            .filter(|m| !m.is_empty())
+            .unwrap_or(chat_model);
+        model
+            .split_once(':')
+            .map_or(model, |(_, name)| name)
+            .to_string()
+    };
+    let mut spans = vec![Span::styled(
+        author,
+        Style::default()
+            .fg(if is_user { Color::Yellow } else { Color::Green })
+            .add_modifier(Modifier::BOLD),
+    )];
+    if let Some(time_format) = time_format
+        && let Some(ts) = message
+            .timestamp
+            .and_then(|t| Local.timestamp_opt(t, 0).single())
+    {
+        spans.push(Span::styled(
+            format!(" · {}", ts.format(time_format)),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    if message.bookmarked {
+        spans.push(Span::styled(" ★", Style::default().fg(Color::Yellow)));
     }
+    if message.pinned {
+        spans.push(Span::raw(" 📌"));
+    }
+    if message.cut_off {
+        spans.push(Span::styled(
+            " · cut off, r to continue",
+            Style::default().fg(Color::Red),
+        ));
+    }
+    if !message.alternatives.is_empty() {
+        spans.push(Span::styled(
+            format!(" · {} alt [ ]", message.alternatives.len()),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    let line = Line::from(spans);
+    if align_right {
+        line.alignment(Alignment::Right)
+    } else {
+        line
+    }
+}
+
+/// Where the model changes between replies: the index of the message the
+/// divider goes above (the start of the turn that got the new model's
+/// reply) and the new model. Also returns the model of the last reply.
+fn model_switches(messages: &[Message]) -> (HashMap<usize, String>, Option<String>) {
+    let mut switches = HashMap::new();
+    let mut last: Option<&str> = None;
+    for (idx, message) in messages.iter().enumerate() {
+        let Some(model) = message.model.as_deref().filter(|m| !m.is_empty()) else {
+            continue;
+        };
+        if last.is_some_and(|previous| previous != model) {
+            let mut turn_start = idx;
+            while turn_start > 0 && messages[turn_start - 1].role == "user" {
+                turn_start -= 1;
+            }
+            switches.insert(turn_start, model.to_string());
+        }
+        last = Some(model);
+    }
+    (switches, last.map(str::to_string))
+}
+
+fn model_divider(model: &str, width: usize) -> Line<'static> {
+    let text = format!("— switched to {} —", model);
+    Line::from(Span::styled(
+        text.chars().take(width.max(1)).collect::<String>(),
+        Style::default().fg(Color::DarkGray),
+    ))
+    .alignment(Alignment::Center)
+}
 
-    let mut buffer_lines: Vec<Line> = Vec::new();
-    let mut line_to_message_map: Vec<(usize, bool)> = Vec::new();
+/// The line under a message collapsed by `truncate_lines`, saying how much
+/// is hidden.
+fn collapsed_note(hidden: usize) -> String {
+    format!("… {} more line(s) · e to expand", hidden)
+}
+
+fn draw_chat(f: &mut Frame<'_>, app: &mut App, area: Rect) {
+    let input_height = match app.mode {
+        Mode::Insert | Mode::Command | Mode::RenameChat | Mode::PromptInput => 3,
+        _ => 0,
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(1),
+            Constraint::Length(input_height),
+            Constraint::Length(1),
+        ])
+        .split(area);
 
     let visual_selection_style = Style::default().bg(Color::Indexed(57));
     let cursor_style = Style::default().bg(Color::Blue);
     let user_style = Style::default().fg(Color::Yellow);
     let assistant_style = Style::default().fg(Color::Green);
+    let failed_style = Style::default().fg(Color::Red);
     let border_style = Style::default().fg(Color::LightGreen);
+    let bubble_layout = app.ui_settings.message_layout == MessageLayout::Bubble;
 
     if app.has_valid_chat() {
         let text_width = (chunks[0].width as usize).saturating_sub(4);
@@ -211,260 +572,475 @@ fn draw_chat(f: &mut Frame<'_>, app: &mut App, area: Rect) {
             .map(|c| c.streaming)
             .unwrap_or(false);
 
-        if app.need_rebuild_cache || text_width != app.last_width {
+        let chat_id = app.chats.get(app.current_chat).map(|c| c.id.clone());
+        let rebuild =
+            app.need_rebuild_cache || text_width != app.last_width || chat_id != app.display_chat;
+        app.display_chat = chat_id;
+        if rebuild {
             app.last_width = text_width;
-            app.line_cache.clear();
             app.code_blocks.clear();
 
             let current_chat_messages = app
                 .chats
                 .get(app.current_chat)
                 .map_or_else(Vec::new, |chat| chat.messages.clone());
+            let chat_model = app
+                .chats
+                .get(app.current_chat)
+                .map_or_else(String::new, |chat| chat.model.clone());
+
+            // Messages that hash the same as last time keep their lines, so
+            // a reply streaming into a long chat only re-renders itself.
+            let layout = {
+                let mut hasher = DefaultHasher::new();
+                (
+                    text_width,
+                    chunks[0].width,
+                    format!("{:?}", app.ui_settings),
+                    &app.theme.code,
+                    &app.keybindings.copy_code_blocks,
+                    &chat_model,
+                )
+                    .hash(&mut hasher);
+                hasher.finish()
+            };
+            let mut previous: HashMap<u64, CachedMessage> = if layout == app.cache_layout {
+                std::mem::take(&mut app.line_cache)
+                    .into_iter()
+                    .filter_map(|cached| Some((cached.key?, cached)))
+                    .collect()
+            } else {
+                HashMap::new()
+            };
+            app.cache_layout = layout;
+            app.line_cache.clear();
+
+            let (switches, last_model) = model_switches(&current_chat_messages);
 
             for (original_msg_idx, message) in current_chat_messages.iter().enumerate() {
                 if message.role == "system" {
                     continue;
                 }
 
+                let key = {
+                    let mut hasher = DefaultHasher::new();
+                    (
+                        serde_json::to_string(message).unwrap_or_default(),
+                        switches.get(&original_msg_idx),
+                        app.truncated_messages.contains(&original_msg_idx),
+                    )
+                        .hash(&mut hasher);
+                    hasher.finish()
+                };
+                if let Some(mut cached) = previous.remove(&key) {
+                    cached.msg_idx = original_msg_idx;
+                    app.line_cache.push(cached);
+                    continue;
+                }
+
                 let role = &message.role;
                 let content = &message.content;
-                let mut msg_lines_for_cache = Vec::new();
-                let mut is_truncated_for_cache = false;
+                let mut cached = CachedMessage {
+                    msg_idx: original_msg_idx,
+                    key: Some(key),
+                    ..Default::default()
+                };
+
+                if let Some(model) = switches.get(&original_msg_idx) {
+                    cached.push(model_divider(model, text_width), LineKind::Text);
+                }
+
+                if app.ui_settings.role_headers {
+                    cached.push(
+                        message_header(
+                            message,
+                            &chat_model,
+                            bubble_layout && *role == "user",
+                            app.ui_settings
+                                .timestamps
+                                .then(|| app.ui_settings.time_pattern()),
+                        ),
+                        LineKind::Text,
+                    );
+                }
+
+                if let Some(error) = &message.failed {
+                    let line = Line::from(Span::styled(
+                        format!("✗ Not sent: {} · r to retry", error)
+                            .chars()
+                            .take(text_width.max(1))
+                            .collect::<String>(),
+                        Style::default().fg(Color::Red),
+                    ));
+                    cached.push(
+                        if bubble_layout && *role == "user" {
+                            line.alignment(Alignment::Right)
+                        } else {
+                            line
+                        },
+                        LineKind::Text,
+                    );
+                }
+                for attachment in &message.attachments {
+                    let line = Line::from(Span::styled(
+                        format!("📎 {}", attachment.summary()),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                    cached.push(
+                        if bubble_layout && *role == "user" {
+                            line.alignment(Alignment::Right)
+                        } else {
+                            line
+                        },
+                        LineKind::Text,
+                    );
+                }
 
                 let segments = parse_message_segments(content);
                 let mut code_block_count_for_message = 0;
+                let code_blocks_in_message = segments
+                    .iter()
+                    .filter(|s| matches!(s, MessageSegment::Code { .. }))
+                    .count();
 
                 for segment in segments {
                     match segment {
                         MessageSegment::Text(text_content) => {
-                            let wrapped_lines = wrap(&text_content, text_width.max(1));
-                            let is_trunc = app.truncated_messages.contains(&original_msg_idx)
-                                && wrapped_lines.len() > MAX_VISIBLE_LINES_PER_MESSAGE;
-                            let lines_to_render: Vec<Line> = wrapped_lines
+                            let wrap_width = if bubble_layout {
+                                (text_width * 3 / 4).saturating_sub(2).max(1)
+                            } else {
+                                text_width.max(1)
+                            };
+                            let wrapped_lines = wrap(&text_content, wrap_width);
+                            let bubble_width =
+                                wrapped_lines.iter().map(|l| l.width()).max().unwrap_or(0);
+                            let max_lines = app.ui_settings.truncate_lines;
+                            let is_trunc = max_lines > 0
+                                && app.truncated_messages.contains(&original_msg_idx)
+                                && wrapped_lines.len() > max_lines;
+                            let lines_to_render = wrapped_lines
                                 .iter()
                                 .take(if is_trunc {
-                                    MAX_VISIBLE_LINES_PER_MESSAGE
+                                    max_lines
                                 } else {
                                     wrapped_lines.len()
                                 })
                                 .map(|line| {
-                                    Line::from(line.to_string()).style(if *role == "user" {
+                                    let is_user = *role == "user";
+                                    let style = if message.failed.is_some() {
+                                        failed_style
+                                    } else if is_user {
                                         user_style
                                     } else {
                                         assistant_style
-                                    })
-                                })
-                                .collect();
-                            msg_lines_for_cache.extend(lines_to_render);
+                                    };
+                                    if bubble_layout {
+                                        bubble_line(line, is_user, bubble_width, style)
+                                    } else {
+                                        Line::from(line.to_string()).style(style)
+                                    }
+                                });
+                            for line in lines_to_render {
+                                cached.push(line, LineKind::Text);
+                            }
                             if is_trunc {
-                                is_truncated_for_cache = true;
+                                cached.hidden += wrapped_lines.len() - max_lines;
                             }
                         }
                         MessageSegment::Code {
                             language,
                             content: code_block_content,
                         } => {
-                            app.code_blocks.push((
-                                original_msg_idx,
-                                crate::app::CodeBlock {
-                                    content: code_block_content.clone(),
-                                },
-                            ));
-                            msg_lines_for_cache.push(Line::raw(""));
+                            cached.code_blocks.push(crate::app::CodeBlock {
+                                language: language.clone(),
+                                content: code_block_content.clone(),
+                            });
+                            cached.push(Line::raw(""), LineKind::Text);
                             let lang_display = language.as_deref().unwrap_or("code");
 
                             let block_width = chunks[0].width as usize;
                             let label = format!(" {} ", lang_display);
-                            let border_len = block_width.saturating_sub(2 + label.len());
+                            let border_len = block_width.saturating_sub(2 + label.width());
                             let right = if border_len > 0 {
                                 border_len - border_len / 2
                             } else {
                                 0
                             };
                             let top_border_str = format!("┌{}{}┐", label, "─".repeat(right));
-                            msg_lines_for_cache
-                                .push(Line::from(vec![Span::styled(top_border_str, border_style)]));
+                            cached.push(
+                                Line::from(vec![Span::styled(top_border_str, border_style)]),
+                                LineKind::CodeBorder,
+                            );
 
                             let syntax_set = get_syntax_set();
-                            let theme = get_theme();
+                            let theme = get_theme(
+                                app.theme.code.as_deref().unwrap_or(&app.ui_settings.theme),
+                            );
+                            let code_background =
+                                app.ui_settings.colors.effective() == ColorMode::Truecolor;
                             let syntax = syntax_set
-                                .find_syntax_by_token(lang_display)
+                                .find_syntax_by_token(crate::app::fence_language(lang_display))
                                 .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
                             let mut h = HighlightLines::new(syntax, theme);
 
-                            for code_line_content in code_block_content.lines() {
+                            let line_count = code_block_content.lines().count();
+                            let number_width = line_count.to_string().len();
+                            for (line_no, code_line_content) in
+                                code_block_content.lines().enumerate()
+                            {
                                 let ranges = h
                                     .highlight_line(code_line_content, syntax_set)
                                     .unwrap_or_default();
-                                let mut spans_for_line = vec![Span::styled("│ ", border_style)];
+                                let gutter = if app.ui_settings.code_line_numbers {
+                                    format!("{:>width$} ", line_no + 1, width = number_width)
+                                } else {
+                                    String::new()
+                                };
+                                let mut segments = Vec::with_capacity(ranges.len());
                                 for (style, text_segment) in ranges {
-                                    spans_for_line.push(Span::styled(
-                                        text_segment.to_string(),
-                                        Style::default()
-                                            .fg(Color::Rgb(
-                                                style.foreground.r,
-                                                style.foreground.g,
-                                                style.foreground.b,
-                                            ))
-                                            .bg(Color::Rgb(
-                                                style.background.r,
-                                                style.background.g,
-                                                style.background.b,
-                                            )),
+                                    let mut span_style = Style::default().fg(Color::Rgb(
+                                        style.foreground.r,
+                                        style.foreground.g,
+                                        style.foreground.b,
                                     ));
+                                    // Theme backgrounds assume a dark truecolor
+                                    // terminal; elsewhere use the terminal's own.
+                                    if code_background {
+                                        span_style = span_style.bg(Color::Rgb(
+                                            style.background.r,
+                                            style.background.g,
+                                            style.background.b,
+                                        ));
+                                    }
+                                    segments.push((span_style, text_segment));
+                                }
+                                // Lines wider than the view continue on extra
+                                // rows so every row maps to one display line.
+                                let code_width =
+                                    text_width.saturating_sub(2 + gutter.width()).max(1);
+                                let rows = split_by_width(&segments, code_width);
+                                for (row_idx, row) in rows.into_iter().enumerate() {
+                                    let mut spans_for_line = vec![Span::styled("│ ", border_style)];
+                                    if !gutter.is_empty() {
+                                        spans_for_line.push(Span::styled(
+                                            if row_idx == 0 {
+                                                gutter.clone()
+                                            } else {
+                                                " ".repeat(gutter.width())
+                                            },
+                                            Style::default().fg(Color::DarkGray),
+                                        ));
+                                    }
+                                    spans_for_line.extend(row);
+                                    let kind = if row_idx == 0 {
+                                        LineKind::Code(code_line_content.to_string())
+                                    } else {
+                                        LineKind::CodeWrap
+                                    };
+                                    cached.push(Line::from(spans_for_line), kind);
                                 }
-                                msg_lines_for_cache.push(Line::from(spans_for_line));
                             }
 
-                            let app_config = config::load_or_create_config();
-                            let shortcuts = &app_config.keybindings.copy_code_blocks;
-                            let hint_text = shortcuts
-                                .get(code_block_count_for_message)
-                                .map(|s| format!(" Copy [{}] ", s))
-                                .unwrap_or_default();
-                            let border_len = block_width.saturating_sub(2 + hint_text.len());
+                            let hint_text = if code_blocks_in_message > 1 {
+                                let number = code_block_count_for_message + 1;
+                                let key = app
+                                    .keybindings
+                                    .copy_code_blocks
+                                    .get(code_block_count_for_message)
+                                    .cloned()
+                                    .unwrap_or_else(|| format!("c {}", number));
+                                format!(" #{} · Copy [{}] ", number, key)
+                            } else {
+                                " Copy [c] ".to_string()
+                            };
+                            let border_len = block_width.saturating_sub(2 + hint_text.width());
                             let right = if border_len > 0 {
                                 border_len - border_len / 2
                             } else {
                                 0
                             };
                             let bottom_border_str = format!("└{}{}┘", hint_text, "─".repeat(right));
-                            msg_lines_for_cache.push(Line::from(vec![Span::styled(
-                                bottom_border_str,
-                                border_style,
-                            )]));
-                            msg_lines_for_cache.push(Line::raw(""));
+                            cached.push(
+                                Line::from(vec![Span::styled(bottom_border_str, border_style)]),
+                                LineKind::CodeBorder,
+                            );
+                            cached.push(Line::raw(""), LineKind::Text);
 
                             code_block_count_for_message += 1;
                         }
                     }
                 }
-                app.line_cache
-                    .push((msg_lines_for_cache, is_truncated_for_cache));
+                app.line_cache.push(cached);
+            }
+            // A switch made since the last reply shows up at the end right away.
+            if last_model.is_some_and(|m| m != chat_model)
+                && let Some(cached) = app.line_cache.last_mut()
+            {
+                cached.push(model_divider(&chat_model, text_width), LineKind::Text);
+                cached.key = None;
+            }
+            for cached in &app.line_cache {
+                if !cached.code_blocks.is_empty() {
+                    app.code_blocks
+                        .insert(cached.msg_idx, cached.code_blocks.clone());
+                }
             }
             app.need_rebuild_cache = false;
         }
+        if app.cursor_line == usize::MAX {
+            app.jump_to_last_message();
+        }
 
-        let mut current_displayable_message_cache_idx = 0;
-
-        let original_messages_count = app
-            .chats
-            .get(app.current_chat)
-            .map_or(0, |c| c.messages.len());
-
-        for original_msg_idx in 0..original_messages_count {
-            if app.chats[app.current_chat].messages[original_msg_idx].role == "system" {
-                continue;
-            }
-
-            if current_displayable_message_cache_idx < app.line_cache.len() {
-                let (lines_from_cache, is_truncated_from_cache) =
-                    &app.line_cache[current_displayable_message_cache_idx];
-
-                for line_content in lines_from_cache.iter() {
-                    buffer_lines.push(line_content.clone());
-                    line_to_message_map.push((original_msg_idx, false));
-                }
-                if *is_truncated_from_cache {
-                    buffer_lines.push(
-                        Line::from("...".to_string()).style(Style::default().fg(Color::Gray)),
+        if rebuild || is_streaming != app.display_streaming {
+            app.display_streaming = is_streaming;
+            app.display_lines.clear();
+            app.display_line_kinds.clear();
+            app.line_to_message.clear();
+            app.display_buffer_text_content.clear();
+            for (cache_idx, cached) in app.line_cache.iter().enumerate() {
+                for (line_idx, (line, kind)) in cached.lines.iter().zip(&cached.kinds).enumerate() {
+                    app.display_lines
+                        .push(DisplayLine::Cached(cache_idx, line_idx));
+                    app.display_line_kinds.push(kind.clone());
+                    app.line_to_message.push((cached.msg_idx, false));
+                    app.display_buffer_text_content.push(
+                        line.spans
+                            .iter()
+                            .map(|span| span.content.as_ref())
+                            .collect(),
                     );
-                    line_to_message_map.push((original_msg_idx, true));
                 }
-                buffer_lines.push(Line::raw(""));
-                line_to_message_map.push((original_msg_idx, false));
-
-                current_displayable_message_cache_idx += 1;
+                if cached.hidden > 0 {
+                    app.display_lines.push(DisplayLine::Ellipsis(cache_idx));
+                    app.display_line_kinds.push(LineKind::Text);
+                    app.line_to_message.push((cached.msg_idx, true));
+                    app.display_buffer_text_content
+                        .push(collapsed_note(cached.hidden));
+                }
+                app.display_lines.push(DisplayLine::Blank);
+                app.display_line_kinds.push(LineKind::Text);
+                app.line_to_message.push((cached.msg_idx, false));
+                app.display_buffer_text_content.push(String::new());
+            }
+            if !is_streaming && app.display_lines.last() == Some(&DisplayLine::Blank) {
+                app.display_lines.pop();
+                app.display_line_kinds.pop();
+                app.line_to_message.pop();
+                app.display_buffer_text_content.pop();
             }
         }
-        if !is_streaming
-            && !buffer_lines.is_empty()
-            && buffer_lines.last().map_or(false, |l| l.spans.is_empty())
-        {
-            buffer_lines.pop();
-            line_to_message_map.pop();
-        }
-
-        app.line_to_message = line_to_message_map.clone();
-
-        app.display_buffer_text_content = buffer_lines
-            .iter()
-            .map(|line| {
-                line.spans
-                    .iter()
-                    .map(|span| span.content.as_ref())
-                    .collect::<String>()
-            })
-            .collect();
 
-        if is_streaming {
-            if let Some(chat) = app.chats.get(app.current_chat) {
-                let last_visible_msg = chat.messages.iter().rev().find(|m| m.role != "system");
-                let show_loading = match last_visible_msg {
-                    Some(msg) if msg.role == "assistant" && msg.content.trim().is_empty() => true,
-                    None if !chat.messages.is_empty()
-                        && chat.messages.iter().all(|m| m.role == "system") =>
-                    {
-                        true
-                    }
-                    None if chat.messages.is_empty() => true,
-                    _ => false,
-                };
+        if let Some((msg_idx, offset)) = app.cursor_anchor.take() {
+            let mut lines = app
+                .line_to_message
+                .iter()
+                .enumerate()
+                .filter(|(_, (m, _))| *m == msg_idx)
+                .map(|(i, _)| i);
+            if let Some(line) = lines.clone().nth(offset).or_else(|| lines.next_back()) {
+                app.cursor_line = line;
+            }
+        }
 
-                if show_loading {
-                    let frames = ["🐱   ", "🐱.  ", "🐱.. ", "🐱...", "🐱 ..", "🐱  ."];
-                    let frame_idx = (app.loading_frame / 6) % frames.len();
-                    let frame_content = frames[frame_idx];
-                    buffer_lines.push(Line::from(vec![
-                        Span::styled(
-                            frame_content,
-                            Style::default()
-                                .fg(Color::Magenta)
-                                .add_modifier(Modifier::BOLD),
-                        ),
-                        Span::raw("  Waiting for response..."),
-                    ]));
+        let mut loading_line = None;
+        if is_streaming && let Some(chat) = app.chats.get(app.current_chat) {
+            let last_visible_msg = chat.messages.iter().rev().find(|m| m.role != "system");
+            let show_loading = match last_visible_msg {
+                Some(msg) if msg.role == "assistant" && msg.content.trim().is_empty() => true,
+                None if !chat.messages.is_empty()
+                    && chat.messages.iter().all(|m| m.role == "system") =>
+                {
+                    true
                 }
+                None if chat.messages.is_empty() => true,
+                _ => false,
+            };
+
+            if show_loading {
+                let frame_content = app
+                    .loading_animation
+                    .frame_at(app.started_at.elapsed())
+                    .to_string();
+                loading_line = Some(Line::from(vec![
+                    Span::styled(
+                        frame_content,
+                        Style::default()
+                            .fg(Color::Magenta)
+                            .add_modifier(Modifier::BOLD),
+                    ),
+                    Span::raw("  Waiting for response..."),
+                ]));
             }
         }
 
-        let total_lines = buffer_lines.len();
-        let viewport_height = chunks[0].height.saturating_sub(20).max(1) as usize;
+        let total_lines = app.display_lines.len() + usize::from(loading_line.is_some());
+        let viewport_height = chunks[0].height.saturating_sub(2).max(1) as usize;
+        app.chat_viewport_height = viewport_height;
 
         if total_lines > 0 && app.cursor_line >= total_lines {
             app.cursor_line = total_lines.saturating_sub(1);
         }
 
         let max_scroll = total_lines.saturating_sub(viewport_height);
-        app.max_chat_scroll = max_scroll as u16;
-
-        if app.chat_scroll == u16::MAX || (app.chat_scroll as usize) > max_scroll {
-            app.chat_scroll = max_scroll as u16;
-        }
-
-        if app.cursor_line < app.chat_scroll as usize {
-            app.chat_scroll = app.cursor_line as u16;
-        } else if app.cursor_line >= (app.chat_scroll as usize + viewport_height) {
-            app.chat_scroll = (app.cursor_line + 1).saturating_sub(viewport_height) as u16;
+        app.max_chat_scroll = max_scroll;
+        app.chat_scroll = app.chat_scroll.min(max_scroll);
+
+        // Like vim, a scrolloff too large for the window keeps the cursor centered.
+        let margin = app
+            .ui_settings
+            .scrolloff
+            .min(viewport_height.saturating_sub(1) / 2);
+        if app.cursor_line < app.chat_scroll + margin {
+            app.chat_scroll = app.cursor_line.saturating_sub(margin);
+        } else if app.cursor_line + margin >= app.chat_scroll + viewport_height {
+            app.chat_scroll = (app.cursor_line + margin + 1).saturating_sub(viewport_height);
         }
         app.chat_scroll = app.chat_scroll.min(app.max_chat_scroll);
 
-        let is_visual = app.mode == crate::app::Mode::Visual;
+        // A `:` command typed from Visual mode keeps the selection shown.
+        let is_visual = app.mode == crate::app::Mode::Visual
+            || (app.mode == crate::app::Mode::Command && app.visual_start.is_some());
         let (vstart, vend) = match (app.visual_start, app.visual_end) {
             (Some(s), Some(e)) => (s.min(e), s.max(e)),
             _ => (usize::MAX, 0),
         };
 
         let is_focused = app.focus == crate::app::Focus::Chat;
-        let title_text = if is_streaming {
-            format!("{} ⏳", app.current_model_name())
-        } else {
-            app.current_model_name().to_string()
-        };
+        let mut title_text = app.current_model_name().to_string();
+        if let Some(chat) = app.chats.get(app.current_chat)
+            && !chat.prompts.is_empty()
+        {
+            title_text.push_str(&format!(
+                " · {}{}",
+                chat.prompts.join(", "),
+                if chat.prompts_override { " (only)" } else { "" }
+            ));
+        }
+        if is_streaming {
+            title_text.push_str(" ⏳");
+        }
+        if let Some(reason) = app.large_chat(app.current_chat) {
+            title_text.push_str(&format!(" · ⚠ large ({}) · :large", reason));
+        }
 
-        let mut display_lines_for_paragraph = Vec::with_capacity(buffer_lines.len());
-        for (idx, line) in buffer_lines.iter().enumerate() {
-            let mut styled_line = line.clone();
+        // Only the rows in view are cloned and styled, however long the chat.
+        let visible = app.chat_scroll..(app.chat_scroll + viewport_height).min(total_lines);
+        let mut display_lines_for_paragraph = Vec::with_capacity(visible.len());
+        for idx in visible {
+            let mut styled_line = match app.display_lines.get(idx) {
+                Some(&DisplayLine::Cached(cache_idx, line_idx)) => app
+                    .line_cache
+                    .get(cache_idx)
+                    .and_then(|cached| cached.lines.get(line_idx))
+                    .cloned()
+                    .unwrap_or_default(),
+                Some(&DisplayLine::Ellipsis(cache_idx)) => Line::from(collapsed_note(
+                    app.line_cache.get(cache_idx).map_or(0, |c| c.hidden),
+                ))
+                .style(Style::default().fg(Color::Gray)),
+                Some(DisplayLine::Blank) => Line::raw(""),
+                None => loading_line.clone().unwrap_or_default(),
+            };
             if is_visual && idx >= vstart && idx <= vend {
                 styled_line = styled_line.patch_style(visual_selection_style);
             }
@@ -474,33 +1050,30 @@ fn draw_chat(f: &mut Frame<'_>, app: &mut App, area: Rect) {
             display_lines_for_paragraph.push(styled_line);
         }
 
-        let paragraph = Paragraph::new(display_lines_for_paragraph)
-            .block(
-                Block::default()
-                    .title(title_text)
-                    .borders(Borders::ALL)
-                    .padding(Padding {
-                        left: 1,
-                        right: 1,
-                        top: 0,
-                        bottom: 0,
-                    })
-                    .style(Style::default().fg(Color::LightBlue))
-                    .border_style(Style::default().fg(if is_focused {
-                        Color::Blue
-                    } else {
-                        Color::DarkGray
-                    })),
-            )
-            .wrap(Wrap { trim: false })
-            .scroll((app.chat_scroll, 0));
+        let paragraph = Paragraph::new(display_lines_for_paragraph).block(
+            Block::default()
+                .title(title_text)
+                .borders(Borders::ALL)
+                .padding(Padding {
+                    left: 1,
+                    right: 1,
+                    top: 0,
+                    bottom: 0,
+                })
+                .style(Style::default().fg(Color::LightBlue))
+                .border_style(Style::default().fg(if is_focused {
+                    Color::Blue
+                } else {
+                    Color::DarkGray
+                })),
+        );
 
         f.render_widget(paragraph, chunks[0]);
 
         let scrollbar_content_length = total_lines;
         if scrollbar_content_length > viewport_height {
             let mut scrollbar_state =
-                ScrollbarState::new(scrollbar_content_length).position(app.chat_scroll as usize);
+                ScrollbarState::new(scrollbar_content_length).position(app.chat_scroll);
             f.render_stateful_widget(
                 Scrollbar::new(ScrollbarOrientation::VerticalRight)
                     .style(Style::default().fg(Color::DarkGray)),
@@ -522,56 +1095,552 @@ fn draw_chat(f: &mut Frame<'_>, app: &mut App, area: Rect) {
             .alignment(Alignment::Center);
         f.render_widget(paragraph, chunks[0]);
         app.display_buffer_text_content.clear();
+        app.display_line_kinds.clear();
+        app.display_lines.clear();
+        app.display_chat = None;
     }
 
-    let mut current_status_text = String::new();
-    if let Some(e) = &app.error_message {
-        current_status_text = format!("Error: {}", e);
-    } else if let Some(i) = &app.info_message {
-        current_status_text = i.clone();
+    if input_height > 0 {
+        let (title, text) = match app.mode {
+            Mode::RenameChat => ("Rename Chat".to_string(), format!("> {}", app.input)),
+            Mode::Command => ("Command".to_string(), format!(":{}", app.command)),
+            Mode::PromptInput => ("Prompt".to_string(), format!("Prompt: {}", app.input)),
+            _ if !app.pending_attachments.is_empty() => (
+                format!("Insert · 📎 {}", app.pending_attachments.len()),
+                format!("> {}", app.input),
+            ),
+            _ => ("Insert".to_string(), format!("> {}", app.input)),
+        };
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(title)
+            .style(Style::default().fg(Color::White));
+        render_input(f, chunks[1], block, &text);
     }
 
-    let input_text_display = match app.mode {
-        Mode::Insert | Mode::RenameChat => format!("> {}", app.input),
-        Mode::Command => format!(":{}", app.command),
-        Mode::PromptInput => format!("Prompt: {}", app.input),
-        Mode::Visual => {
-            if !current_status_text.is_empty() {
-                format!("-- VISUAL -- ({})", current_status_text)
-            } else {
-                "-- VISUAL --".to_string()
-            }
-        }
-        Mode::Normal => current_status_text,
-        _ => String::new(),
+    draw_status_line(f, app, chunks[2]);
+}
+
+fn format_tokens(tokens: usize) -> String {
+    let (value, unit) = match tokens {
+        1_000_000.. => (tokens as f64 / 1_000_000.0, "M"),
+        1000.. => (tokens as f64 / 1000.0, "k"),
+        _ => return tokens.to_string(),
+    };
+    let text = format!("{:.1}", value);
+    format!("{}{}", text.strip_suffix(".0").unwrap_or(&text), unit)
+}
+
+/// The terminal window title: the shown chat, with a spinner while any
+/// reply streams and a check mark once one has finished.
+pub fn terminal_title(app: &App, finished: bool) -> String {
+    const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+    let mut title = match app.chats.get(app.current_chat) {
+        Some(chat) => format!("meowi — {}", chat.title),
+        None => "meowi".to_string(),
+    };
+    if app.chats.iter().any(|c| c.streaming) {
+        let frame = app.started_at.elapsed().as_millis() / 100;
+        title.push(' ');
+        title.push(SPINNER[frame as usize % SPINNER.len()]);
+    } else if finished {
+        title.push_str(" ✓");
+    }
+    if app.ui_settings.ascii {
+        title = palette::ascii_text(&title);
+    }
+    title
+}
+
+/// Expands the `ui.status_format` placeholders: `{mode}`, `{focus}`, `{model}`,
+/// `{streaming}`, `{queued}`, `{tokens}`, `{context}`, `{cost}` and `{message}`.
+/// In Visual mode, `{message}` counts the selection unless there is an error.
+fn status_line_text(app: &App) -> String {
+    let chat = app.chats.get(app.current_chat);
+    let streaming = if chat.is_some_and(|c| c.streaming) {
+        app.loading_animation
+            .frame_at(app.started_at.elapsed())
+            .trim_end()
+            .to_string()
+    } else {
+        String::new()
+    };
+    let tokens = chat.map_or(0, crate::app::estimate_chat_tokens);
+    let cost = app
+        .pricing
+        .get(app.current_model_name())
+        .map(|per_million| format!("${:.4}", tokens as f64 * per_million / 1_000_000.0))
+        .unwrap_or_default();
+    let mut queued = match chat.map_or(0, |c| c.queued.len()) {
+        0 => String::new(),
+        n => format!("+{} queued", n),
+    };
+    if let Some(run) = chat.and_then(|c| c.chain.as_ref()) {
+        queued = format!("{} {} {}/{}", queued, run.name, run.next, run.steps.len())
+            .trim_start()
+            .to_string();
+    }
+    let context = match chat.and_then(|c| app.context_window(&c.model)) {
+        Some(window) => format!("{} / {}", format_tokens(tokens), format_tokens(window)),
+        None => format_tokens(tokens),
+    };
+    let message = if let Some(e) = &app.error_message {
+        format!("Error: {}", e)
+    } else if app.mode == Mode::Visual
+        && let Some(stats) = app.selection_stats()
+    {
+        stats
+    } else {
+        app.info_message.clone().unwrap_or_default()
     };
+    let focus = match app.focus {
+        crate::app::Focus::Sidebar => "sidebar",
+        crate::app::Focus::Chat => "chat",
+    };
+
+    app.ui_settings
+        .status_format
+        .replace("{mode}", app.mode.label())
+        .replace("{focus}", focus)
+        .replace("{model}", app.current_model_name())
+        .replace("{streaming}", &streaming)
+        .replace("{queued}", &queued)
+        .replace("{tokens}", &format_tokens(tokens))
+        .replace("{context}", &context)
+        .replace("{cost}", &cost)
+        .replace("{message}", &message)
+}
 
-    let input_block_style = Style::default().fg(if app.error_message.is_some() {
+fn draw_status_line(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let fg = if app.error_message.is_some() {
         Color::Red
-    } else if app.info_message.is_some() && app.error_message.is_none() {
+    } else if app.info_message.is_some() {
         Color::Green
     } else {
         Color::White
-    });
-
-    let input_block_title_str = match app.mode {
-        Mode::Insert => "Insert",
-        Mode::RenameChat => "Rename Chat",
-        Mode::Command => "Command",
-        Mode::Visual => "Visual",
-        Mode::Normal => "Status",
-        _ => "Input",
     };
+    let mode_style = Style::default()
+        .fg(Color::Black)
+        .bg(if app.mode == Mode::Normal {
+            Color::Blue
+        } else {
+            Color::Magenta
+        })
+        .add_modifier(Modifier::BOLD);
+    let line = Line::from(vec![
+        Span::styled(format!(" {} ", app.mode.label()), mode_style),
+        Span::raw(" "),
+        Span::styled(status_line_text(app), Style::default().fg(fg)),
+    ]);
+    f.render_widget(
+        Paragraph::new(line).style(Style::default().bg(Color::Indexed(236))),
+        area,
+    );
+}
+
+fn draw_bookmarks(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let popup = centered_rect(80, 60, area);
+    let width = popup.width.saturating_sub(4) as usize;
+    let items: Vec<ListItem> = app
+        .bookmarks()
+        .into_iter()
+        .map(|(chat_idx, msg_idx)| {
+            let chat = &app.chats[chat_idx];
+            let prefix = format!("{} › ", chat.title);
+            let preview = one_line_preview(
+                &chat.messages[msg_idx].content,
+                width.saturating_sub(prefix.width()),
+            );
+            ListItem::new(Line::from(vec![
+                Span::styled(prefix, Style::default().fg(Color::Yellow)),
+                Span::raw(preview),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(app.selected_bookmark_idx));
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Bookmarks (Enter jump, Esc close)"),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+fn draw_backups(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let popup = centered_rect(60, 60, area);
+    let date_format = format!("%Y-%m-%d {}", app.ui_settings.time_pattern());
+    let items: Vec<ListItem> = app
+        .backups
+        .iter()
+        .map(|backup| {
+            let size = if backup.bytes >= 1024 * 1024 {
+                format!("{:.1} MB", backup.bytes as f64 / (1024.0 * 1024.0))
+            } else {
+                format!("{} KB", backup.bytes.div_ceil(1024))
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw(backup.time.format(&date_format).to_string()),
+                Span::styled(format!("  {}", size), Style::default().fg(Color::DarkGray)),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(app.selected_backup_idx));
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Restore history (Enter restore, Esc close)"),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+fn draw_trash(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let popup = centered_rect(60, 60, area);
+    let date_format = format!("%Y-%m-%d {}", app.ui_settings.time_pattern());
+    let items: Vec<ListItem> = app
+        .trash
+        .iter()
+        .map(|trashed| {
+            let deleted = Local
+                .timestamp_opt(trashed.deleted_at, 0)
+                .single()
+                .map(|t| t.format(&date_format).to_string())
+                .unwrap_or_default();
+            ListItem::new(Line::from(vec![
+                Span::raw(trashed.chat.title.clone()),
+                Span::styled(
+                    format!(
+                        "  {} messages, deleted {}",
+                        trashed.chat.messages.len(),
+                        deleted
+                    ),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(app.selected_trash_idx));
+    let title = if app.trash_days > 0 {
+        format!(
+            "Trash, kept {} days (Enter restore, d delete, Esc close)",
+            app.trash_days
+        )
+    } else {
+        "Trash (Enter restore, d delete, Esc close)".to_string()
+    };
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+fn draw_prune(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let popup = centered_rect(70, 60, area);
+    let date_format = format!("%Y-%m-%d {}", app.ui_settings.time_pattern());
+    let items: Vec<ListItem> = app
+        .prune
+        .iter()
+        .filter_map(|id| app.chats.iter().find(|c| &c.id == id))
+        .map(|chat| {
+            let last = chat
+                .last_activity()
+                .and_then(|t| Local.timestamp_opt(t, 0).single())
+                .map(|t| format!(", last active {}", t.format(&date_format)))
+                .unwrap_or_default();
+            ListItem::new(Line::from(vec![
+                Span::raw(chat.title.clone()),
+                Span::styled(
+                    format!(
+                        "  {} messages, {}{}",
+                        chat.message_count(),
+                        size_label(chat.size()),
+                        last
+                    ),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(app.selected_prune_idx));
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(format!(
+            "Prune {} chats (a archive, d delete, Space keep, Esc cancel)",
+            app.prune.len()
+        )))
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+/// A table of usage totals, one row per provider or model.
+fn usage_table<'a>(title: &'a str, rows: &[(String, Totals)]) -> Table<'a> {
+    let cost = |totals: &Totals| {
+        let mark = if totals.unpriced { "+" } else { "" };
+        format!("${:.4}{}", totals.cost, mark)
+    };
+    let rows: Vec<Row> = rows
+        .iter()
+        .map(|(name, totals)| {
+            let estimated = if totals.estimated { "~" } else { "" };
+            Row::new(vec![
+                Cell::from(name.clone()),
+                Cell::from(totals.replies.to_string()),
+                Cell::from(format!(
+                    "{}{}",
+                    estimated,
+                    format_tokens(totals.input_tokens as usize)
+                )),
+                Cell::from(format!(
+                    "{}{}",
+                    estimated,
+                    format_tokens(totals.output_tokens as usize)
+                )),
+                Cell::from(cost(totals)),
+            ])
+        })
+        .collect();
+    let widths = [
+        Constraint::Min(20),
+        Constraint::Length(8),
+        Constraint::Length(9),
+        Constraint::Length(9),
+        Constraint::Length(11),
+    ];
+    Table::new(rows, widths)
+        .header(
+            Row::new(vec!["", "Replies", "Input", "Output", "Cost"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::default().borders(Borders::ALL).title(title))
+}
 
-    let input_block = Block::default()
+fn draw_stats(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let stats = &app.stats;
+    let popup = centered_rect(80, 90, area);
+    f.render_widget(Clear, popup);
+    let block = Block::default()
         .borders(Borders::ALL)
-        .title(input_block_title_str)
-        .style(input_block_style);
+        .title("Usage (h/l period, Esc close)");
+    let inner = block.inner(popup);
+    f.render_widget(block, popup);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Length(2),
+            Constraint::Length(5),
+            Constraint::Min(4),
+            Constraint::Min(4),
+        ])
+        .split(inner);
+
+    let selected = Period::ALL
+        .iter()
+        .position(|&p| p == stats.period)
+        .unwrap_or(0);
+    let tabs = Tabs::new(Period::ALL.iter().map(|p| p.label()).collect::<Vec<_>>())
+        .select(selected)
+        .highlight_style(
+            Style::default()
+                .fg(Color::LightGreen)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(tabs, chunks[0]);
 
-    let input_paragraph = Paragraph::new(input_text_display)
-        .block(input_block)
-        .wrap(Wrap { trim: true });
-    f.render_widget(input_paragraph, chunks[1]);
+    let total = &stats.total;
+    let mut summary = format!(
+        "{} replies · {} tokens in, {} out · ${:.4}",
+        total.replies,
+        format_tokens(total.input_tokens as usize),
+        format_tokens(total.output_tokens as usize),
+        total.cost
+    );
+    if total.unpriced {
+        summary.push_str(" + models without [pricing]");
+    }
+    if total.estimated {
+        summary.push_str(" · ~ estimated counts");
+    }
+    f.render_widget(Paragraph::new(summary), chunks[1]);
+
+    let sparkline = Sparkline::default()
+        .block(Block::default().borders(Borders::ALL).title(format!(
+            "Tokens per day, last {} days (peak {})",
+            crate::stats::DAYS,
+            format_tokens(stats.daily.iter().copied().max().unwrap_or(0) as usize)
+        )))
+        .data(&stats.daily)
+        .style(Style::default().fg(Color::Cyan));
+    f.render_widget(sparkline, chunks[2]);
+    f.render_widget(usage_table("By provider", &stats.by_provider), chunks[3]);
+    f.render_widget(usage_table("By model", &stats.by_model), chunks[4]);
+}
+
+fn draw_search(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let popup = centered_rect(80, 60, area);
+    let width = popup.width.saturating_sub(4) as usize;
+    let items: Vec<ListItem> = app
+        .search_hits
+        .iter()
+        .filter_map(|hit| {
+            let chat = app.chats.iter().find(|c| c.id == hit.chat_id)?;
+            let prefix = format!("{} › ", chat.title);
+            let snippet = hit.snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+            let preview = one_line_preview(&snippet, width.saturating_sub(prefix.width()));
+            Some(ListItem::new(Line::from(vec![
+                Span::styled(prefix, Style::default().fg(Color::Yellow)),
+                Span::raw(preview),
+            ])))
+        })
+        .collect();
+    let title = format!(
+        "/{} ({} found; Enter jump, Esc close)",
+        app.search_query,
+        items.len()
+    );
+    let mut state = ListState::default();
+    state.select(Some(app.selected_search_idx));
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+fn draw_chat_picker(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let popup = centered_rect(80, 60, area);
+    let width = popup.width.saturating_sub(4) as usize;
+    let date_format = format!("%Y-%m-%d {}", app.ui_settings.time_pattern());
+    let chats: Vec<(usize, String)> = app
+        .picker_chats()
+        .into_iter()
+        .map(|idx| {
+            let when = app.chats[idx]
+                .last_activity()
+                .and_then(|t| Local.timestamp_opt(t, 0).single())
+                .map(|ts| ts.format(&date_format).to_string())
+                .unwrap_or_default();
+            (idx, when)
+        })
+        .collect();
+    let when_width = chats
+        .iter()
+        .map(|(_, when)| when.width())
+        .max()
+        .unwrap_or(0);
+    let items: Vec<ListItem> = chats
+        .into_iter()
+        .map(|(idx, when)| {
+            let chat = &app.chats[idx];
+            let prefix = format!("{:<w$} ", when, w = when_width);
+            let title = one_line_preview(&chat.title, width.saturating_sub(prefix.width()));
+            ListItem::new(Line::from(vec![
+                Span::styled(prefix, Style::default().fg(Color::DarkGray)),
+                Span::raw(title),
+            ]))
+        })
+        .collect();
+    let mut state = ListState::default();
+    state.select(Some(app.selected_picker_idx));
+    let title = if app.picker_filtering || !app.search_query.is_empty() {
+        format!("Chats /{} (Enter open, Esc clear filter)", app.search_query)
+    } else {
+        "Chats (Enter open, / filter, n new chat, Esc close)".to_string()
+    };
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(
+            Style::default()
+                .bg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, &mut state);
+}
+
+fn draw_compare(f: &mut Frame<'_>, app: &App, area: Rect) {
+    let Some(cmp) = &app.comparison else {
+        return;
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(1)])
+        .split(area);
+    let count = cmp.candidates.len().max(1) as u32;
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(vec![Constraint::Ratio(1, count); count as usize])
+        .split(chunks[0]);
+
+    for (idx, (candidate, column)) in cmp.candidates.iter().zip(columns.iter()).enumerate() {
+        let title = if candidate.done {
+            candidate.model.clone()
+        } else {
+            format!(
+                "{} {}",
+                candidate.model,
+                app.loading_animation.frame_at(app.started_at.elapsed())
+            )
+        };
+        let paragraph = Paragraph::new(candidate.content.as_str())
+            .block(
+                Block::default()
+                    .title(title)
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(if idx == cmp.selected {
+                        Color::Blue
+                    } else {
+                        Color::DarkGray
+                    })),
+            )
+            .style(Style::default().fg(Color::Green))
+            .wrap(Wrap { trim: false })
+            .scroll((cmp.scroll, 0));
+        f.render_widget(paragraph, *column);
+    }
+
+    let hint = if let Some(e) = &app.error_message {
+        Line::from(Span::styled(
+            format!("Error: {}", e),
+            Style::default().fg(Color::Red),
+        ))
+    } else {
+        Line::from(Span::styled(
+            "h/l select · j/k scroll · Enter promote to chat · Esc discard",
+            Style::default().fg(Color::DarkGray),
+        ))
+    };
+    f.render_widget(Paragraph::new(hint), chunks[1]);
 }
 
 fn draw_model_select(f: &mut Frame<'_>, app: &App, area: Rect) {
@@ -608,13 +1677,82 @@ fn mask_api_key(k: &str) -> String {
     }
 }
 
+/// First line of `text`, cut to `width` characters with an ellipsis when
+/// anything was left out.
+pub fn one_line_preview(text: &str, width: usize) -> String {
+    let first = text.lines().next().unwrap_or("");
+    let clipped = first.width() > width || text.lines().nth(1).is_some();
+    if !clipped {
+        return first.to_string();
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in first.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    out.push('…');
+    out
+}
+
+/// Renders an editable text field inside `block` and places the terminal
+/// cursor after its last character. Text is wrapped per character so the
+/// cursor math stays exact, and scrolls to keep the end in view.
+fn render_input(f: &mut Frame<'_>, area: Rect, block: Block<'_>, text: &str) {
+    let inner = block.inner(area);
+    let width = inner.width.max(1) as usize;
+    let mut rows: Vec<String> = Vec::new();
+    for source_line in text.split('\n') {
+        rows.extend(
+            split_by_width(&[(Style::default(), source_line)], width)
+                .into_iter()
+                .map(|row| row.iter().map(|span| span.content.as_ref()).collect()),
+        );
+    }
+    let mut cursor_x = rows.last().map_or(0, |r| r.width());
+    if cursor_x >= width {
+        rows.push(String::new());
+        cursor_x = 0;
+    }
+    let skip = rows.len().saturating_sub(inner.height.max(1) as usize);
+    let cursor_y = rows.len() - 1 - skip;
+    let lines: Vec<Line> = rows.into_iter().skip(skip).map(Line::from).collect();
+    f.render_widget(Paragraph::new(lines).block(block), area);
+    if inner.width > 0 && inner.height > 0 {
+        f.set_cursor_position((inner.x + cursor_x as u16, inner.y + cursor_y as u16));
+    }
+}
+
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
+}
+
 pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Length(3), Constraint::Min(1)])
         .split(area);
 
-    let titles = ["Providers", "Shortcuts", "Prompts"]
+    let titles = ["Providers", "Shortcuts", "Prompts", "General"]
         .iter()
         .cloned()
         .map(String::from)
@@ -625,6 +1763,7 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
             SettingsTab::Providers => 0,
             SettingsTab::Shortcuts => 1,
             SettingsTab::Prompts => 2,
+            SettingsTab::General => 3,
         })
         .block(Block::default().borders(Borders::ALL).title("Settings"))
         .highlight_style(
@@ -647,12 +1786,10 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
     if app.mode == Mode::ApiKeyInput {
         let masked = mask_api_key(&app.api_key_old);
         let text = format!("Current: {}\nNew API Key: {}", masked, app.api_key_input);
-        let paragraph = Paragraph::new(text).block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title("Enter API Key"),
-        );
-        f.render_widget(paragraph, main_settings_content_area);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title("Enter API Key");
+        render_input(f, main_settings_content_area, block, &text);
     } else if app.mode == Mode::CustomModelInput {
         match app.custom_model_input_stage.unwrap() {
             CustomModelStage::TypeChoice => {
@@ -703,44 +1840,48 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
                 f.render_stateful_widget(list, main_settings_content_area, &mut state);
             }
             CustomModelStage::DerivedModelName => {
-                let p = Paragraph::new(format!("Model Name: {}", app.custom_model_model_input))
-                    .block(
-                        Block::default()
-                            .borders(Borders::ALL)
-                            .title("Add Derived Model—Name"),
-                    )
-                    .wrap(Wrap { trim: true });
-                f.render_widget(p, main_settings_content_area);
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .title("Add Derived Model—Name");
+                render_input(
+                    f,
+                    main_settings_content_area,
+                    block,
+                    &format!("Model Name: {}", app.custom_model_model_input),
+                );
             }
             CustomModelStage::StandaloneName => {
-                let p = Paragraph::new(format!("Model Name: {}", app.custom_model_name_input))
-                    .block(
-                        Block::default()
-                            .borders(Borders::ALL)
-                            .title("Add Standalone Model—Name"),
-                    )
-                    .wrap(Wrap { trim: true });
-                f.render_widget(p, main_settings_content_area);
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .title("Add Standalone Model—Name");
+                render_input(
+                    f,
+                    main_settings_content_area,
+                    block,
+                    &format!("Model Name: {}", app.custom_model_name_input),
+                );
             }
             CustomModelStage::StandaloneUrl => {
-                let p = Paragraph::new(format!("Endpoint URL: {}", app.custom_model_url_input))
-                    .block(
-                        Block::default()
-                            .borders(Borders::ALL)
-                            .title("Add Standalone Model—URL"),
-                    )
-                    .wrap(Wrap { trim: true });
-                f.render_widget(p, main_settings_content_area);
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .title("Add Standalone Model—URL");
+                render_input(
+                    f,
+                    main_settings_content_area,
+                    block,
+                    &format!("Endpoint URL: {}", app.custom_model_url_input),
+                );
             }
             CustomModelStage::StandaloneModelId => {
-                let p = Paragraph::new(format!("Model ID: {}", app.custom_model_model_input))
-                    .block(
-                        Block::default()
-                            .borders(Borders::ALL)
-                            .title("Add Standalone Model—Model ID"),
-                    )
-                    .wrap(Wrap { trim: true });
-                f.render_widget(p, main_settings_content_area);
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .title("Add Standalone Model—Model ID");
+                render_input(
+                    f,
+                    main_settings_content_area,
+                    block,
+                    &format!("Model ID: {}", app.custom_model_model_input),
+                );
             }
             CustomModelStage::StandaloneApiKeyChoice => {
                 let mut items = app
@@ -774,14 +1915,15 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
                 f.render_stateful_widget(list, main_settings_content_area, &mut state);
             }
             CustomModelStage::StandaloneApiKeyInput => {
-                let p = Paragraph::new(format!("API Key: {}", app.custom_model_api_key_input))
-                    .block(
-                        Block::default()
-                            .borders(Borders::ALL)
-                            .title("Enter API Key"),
-                    )
-                    .wrap(Wrap { trim: true });
-                f.render_widget(p, main_settings_content_area);
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .title("Enter API Key");
+                render_input(
+                    f,
+                    main_settings_content_area,
+                    block,
+                    &format!("API Key: {}", app.custom_model_api_key_input),
+                );
             }
         }
     } else if app.mode == Mode::PromptInput {
@@ -795,27 +1937,86 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
         } else {
             "Add New Prompt".to_string()
         };
-        let text_to_display = format!("Content: {}", app.input);
-        let text_input_paragraph = Paragraph::new(text_to_display)
-            .block(Block::default().borders(Borders::ALL).title(title))
-            .wrap(Wrap { trim: true });
-
-        f.render_widget(text_input_paragraph, main_settings_content_area);
+        let block = Block::default().borders(Borders::ALL).title(title);
+        render_input(
+            f,
+            main_settings_content_area,
+            block,
+            &format!("Content: {}", app.input),
+        );
+    } else if app.mode == Mode::PromptRename {
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title("Rename Prompt");
+        render_input(
+            f,
+            main_settings_content_area,
+            block,
+            &format!("Name: {}", app.input),
+        );
     } else if app.settings_tab == SettingsTab::Prompts {
+        // Leave room for the borders and the "[x] name: " prefix.
+        let inner_width = main_settings_content_area.width.saturating_sub(2) as usize;
         let mut items = Vec::new();
         for prompt in &app.prompts {
             let status = if prompt.active { "[x]" } else { "[ ]" };
-            items.push(ListItem::new(format!(
-                "{} {}: {}",
-                status, prompt.name, prompt.content
-            )));
+            let prefix = format!("{} {}: ", status, prompt.name);
+            let preview =
+                one_line_preview(&prompt.content, inner_width.saturating_sub(prefix.width()));
+            items.push(ListItem::new(format!("{}{}", prefix, preview)));
         }
         items.push(ListItem::new("  [Add New Prompt]"));
 
         let mut state = ListState::default();
         state.select(Some(app.selected_prompt_idx));
         let list = List::new(items)
-            .block(Block::default().borders(Borders::ALL).title("Prompts"))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Prompts (Enter edit, r rename, p preview)"),
+            )
+            .highlight_style(
+                Style::default()
+                    .bg(Color::Blue)
+                    .add_modifier(Modifier::BOLD),
+            );
+        f.render_stateful_widget(list, main_settings_content_area, &mut state);
+
+        if app.mode == Mode::PromptPreview
+            && let Some(prompt) = app.prompts.get(app.selected_prompt_idx)
+        {
+            let popup = centered_rect(80, 70, main_settings_content_area);
+            let paragraph = Paragraph::new(prompt.content.as_ref())
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!("{} (j/k scroll, Esc close)", prompt.name))
+                        .padding(Padding::horizontal(1)),
+                )
+                .wrap(Wrap { trim: false })
+                .scroll((app.prompt_preview_scroll, 0));
+            f.render_widget(Clear, popup);
+            f.render_widget(paragraph, popup);
+        }
+    } else if app.settings_tab == SettingsTab::General {
+        let items: Vec<ListItem> = GeneralSetting::ALL
+            .iter()
+            .map(|&setting| {
+                ListItem::new(format!(
+                    "{:<24} {}",
+                    setting.label(),
+                    app.general_setting_value(setting)
+                ))
+            })
+            .collect();
+        let mut state = ListState::default();
+        state.select(Some(app.selected_general_idx));
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("General (Enter/+ next, - previous)"),
+            )
             .highlight_style(
                 Style::default()
                     .bg(Color::Blue)
@@ -870,9 +2071,39 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
             );
         f.render_stateful_widget(list, main_settings_content_area, &mut state);
     } else {
-        let paragraph = Paragraph::new("Shortcut customization coming soon!")
-            .block(Block::default().borders(Borders::ALL).title("Shortcuts"));
-        f.render_widget(paragraph, main_settings_content_area);
+        let items: Vec<ListItem> = (0..config::KEY_ACTIONS.len())
+            .filter_map(|idx| app.keybindings.entry(idx))
+            .map(|(action, label, key)| {
+                let conflicts = crate::keymap::conflicts(&app.keybindings, action, key);
+                let mut spans = vec![Span::raw(format!("{:<24} {:<10}", label, key))];
+                if !conflicts.is_empty() {
+                    spans.push(Span::styled(
+                        format!(" ⚠ also {}", conflicts.join(", ")),
+                        Style::default().fg(Color::Yellow),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let title = if app.mode == Mode::KeyCapture {
+            "Shortcuts — press the new key or chord (Esc to cancel)"
+        } else {
+            "Shortcuts (Enter to rebind, r reset, R reset all)"
+        };
+        let mut state = ListState::default();
+        state.select(Some(app.selected_shortcut_idx));
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(
+                Style::default()
+                    .bg(if app.mode == Mode::KeyCapture {
+                        Color::Magenta
+                    } else {
+                        Color::Blue
+                    })
+                    .add_modifier(Modifier::BOLD),
+            );
+        f.render_stateful_widget(list, main_settings_content_area, &mut state);
     }
 
     if let Some(err) = &app.error_message {
@@ -883,3 +2114,108 @@ pub fn draw_settings(f: &mut Frame<'_>, app: &App, area: Rect) {
         f.render_widget(p, settings_status_area);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{Chat, Role};
+    use ratatui::{Terminal, backend::TestBackend};
+    use std::time::{Duration, Instant};
+
+    /// A chat of `exchanges` questions, each answered with a code block.
+    fn large_chat(exchanges: usize) -> Chat {
+        let mut messages = Vec::new();
+        for i in 0..exchanges {
+            messages.push(Message::new(
+                Role::User,
+                format!("Question {}: how do I sum a list?", i),
+            ));
+            messages.push(Message::new(
+                Role::Assistant,
+                format!(
+                    "Like this:\n\n```rust\nfn sum_{}(xs: &[i64]) -> i64 {{\n    xs.iter().sum()\n}}\n```\n\nThat is all.",
+                    i
+                ),
+            ));
+        }
+        Chat::new("bench".into(), "Bench".into(), "gpt-4o".into(), messages)
+    }
+
+    /// Draws `frames` frames that each rebuild the chat's lines, after
+    /// `prepare` runs, and returns the total time.
+    fn redraw(app: &mut App, frames: u32, prepare: fn(&mut App)) -> Duration {
+        let mut terminal = Terminal::new(TestBackend::new(120, 40)).unwrap();
+        let mut total = Duration::ZERO;
+        for _ in 0..frames {
+            prepare(app);
+            app.need_rebuild_cache = true;
+            let start = Instant::now();
+            terminal.draw(|f| draw(f, app)).unwrap();
+            total += start.elapsed();
+        }
+        total
+    }
+
+    /// Rebuilding the lines of an unchanged chat reuses every cached
+    /// message rather than highlighting and wrapping it again.
+    #[test]
+    fn redraw_reuses_cached_lines() {
+        let mut app = App::new();
+        app.chats = vec![large_chat(20)];
+        app.current_chat = 0;
+        redraw(&mut app, 1, |_| {});
+        // A line the renderer never writes, so it survives only on reuse.
+        redraw(&mut app, 1, |app| {
+            for cached in &mut app.line_cache {
+                cached.push(Line::raw("marker"), LineKind::Text);
+            }
+        });
+        let reused = |app: &App| {
+            app.line_cache
+                .iter()
+                .filter(|c| c.lines.last().is_some_and(|l| l.to_string() == "marker"))
+                .count()
+        };
+        assert_eq!(reused(&app), 40);
+
+        redraw(&mut app, 1, |app| app.line_cache.clear());
+        assert_eq!(reused(&app), 0);
+    }
+
+    /// Drawing, code blocks and their copy hints included, never reads the
+    /// config: the path it would be read from is never even created.
+    #[test]
+    fn draw_does_no_config_io() {
+        let dir = std::env::temp_dir().join(format!("meowi-ui-config-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        config::set_config_path(dir.join("config.toml"));
+        let mut app = App::new();
+        app.chats = vec![large_chat(5)];
+        app.current_chat = 0;
+        redraw(&mut app, 2, |app| app.line_cache.clear());
+        assert!(!dir.exists());
+    }
+
+    /// Measures what the line cache saves on a large chat: a rebuild that
+    /// finds every message cached (a reply streaming in, a key press)
+    /// against one that highlights and wraps everything again. Timing
+    /// depends on the machine, so it runs only when asked for with
+    /// `cargo test redraw -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn redraw_with_cached_lines_is_cheaper() {
+        let mut app = App::new();
+        app.chats = vec![large_chat(200)];
+        app.current_chat = 0;
+        // Loads the syntax definitions, which happens once per process.
+        redraw(&mut app, 1, |_| {});
+
+        let uncached = redraw(&mut app, 5, |app| app.line_cache.clear());
+        let cached = redraw(&mut app, 5, |_| {});
+        eprintln!(
+            "5 redraws of 400 messages: {:?} uncached, {:?} cached",
+            uncached, cached
+        );
+        assert!(cached * 2 < uncached);
+    }
+}